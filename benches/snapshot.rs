@@ -0,0 +1,126 @@
+//! Benchmarks for crawling a source directory and generating snapshots and
+//! deltas, using synthetic source trees. Run with 'cargo bench'.
+
+#[macro_use]
+extern crate criterion;
+extern crate rrdpit;
+extern crate uuid;
+
+use std::fs;
+use std::path::PathBuf;
+
+use criterion::{BenchmarkId, Criterion, Throughput};
+use uuid::Uuid;
+
+use rrdpit::rrdp::Snapshot;
+use rrdpit::sync::{self, crawl_disk, RsyncUri};
+
+/// The numbers of files in the generated source trees.
+const FILES: &[usize] = &[1_000, 10_000];
+
+/// The size of every generated file, similar to a typical ROA.
+const FILE_SIZE: usize = 2_048;
+
+/// The number of files per directory in the generated source trees.
+const FILES_PER_DIR: usize = 100;
+
+/// Writes a source tree with the given number of files, and returns its
+/// path. Files are spread over directories, like a CA per directory. The
+/// generation is part of the file content, so that a tree generated again
+/// with another generation has changed files.
+fn generate_tree(name: &str, files: usize, generation: u8) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join("rrdpit-bench")
+        .join(format!("{}-{}", name, files));
+    let _ = fs::remove_dir_all(&dir);
+    for i in 0..files {
+        let sub_dir = dir.join(format!("ca-{}", i / FILES_PER_DIR));
+        fs::create_dir_all(&sub_dir).unwrap();
+        let mut content = vec![(i % 251) as u8; FILE_SIZE];
+        content[0] = generation;
+        fs::write(sub_dir.join(format!("{}.roa", i)), content).unwrap();
+    }
+    dir
+}
+
+fn rsync_base() -> RsyncUri {
+    RsyncUri::base_uri("rsync://localhost/repo/").unwrap()
+}
+
+fn crawl(c: &mut Criterion) {
+    let mut group = c.benchmark_group("crawl_disk");
+    group.sample_size(10);
+    for &files in FILES {
+        let dir = generate_tree("crawl", files, 0);
+        group.throughput(Throughput::Bytes((files * FILE_SIZE) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(files), &dir, |b, dir| {
+            b.iter(|| crawl_disk(dir, &rsync_base()).unwrap())
+        });
+        let _ = fs::remove_dir_all(dir);
+    }
+    group.finish();
+}
+
+fn read(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sync_read");
+    group.sample_size(10);
+    for &files in FILES {
+        let dir = generate_tree("read", files, 0);
+        let paths: Vec<PathBuf> = (0..files)
+            .map(|i| dir.join(format!("ca-{}/{}.roa", i / FILES_PER_DIR, i)))
+            .collect();
+        group.throughput(Throughput::Bytes((files * FILE_SIZE) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(files), &paths, |b, paths| {
+            b.iter(|| {
+                for path in paths {
+                    sync::read(path).unwrap();
+                }
+            })
+        });
+        let _ = fs::remove_dir_all(dir);
+    }
+    group.finish();
+}
+
+fn write_xml(c: &mut Criterion) {
+    let mut group = c.benchmark_group("snapshot_write_xml");
+    group.sample_size(10);
+    for &files in FILES {
+        let dir = generate_tree("write_xml", files, 0);
+        let objects = crawl_disk(&dir, &rsync_base()).unwrap();
+        let snapshot = Snapshot::new(Uuid::new_v4(), 1, objects);
+        group.throughput(Throughput::Bytes((files * FILE_SIZE) as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(files),
+            &snapshot,
+            |b, snapshot| b.iter(|| snapshot.write_xml()),
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+    group.finish();
+}
+
+/// Measures the delta between two snapshots where every file changed, the
+/// worst case for an update.
+fn snapshot_to(c: &mut Criterion) {
+    let mut group = c.benchmark_group("snapshot_to");
+    group.sample_size(10);
+    let session = Uuid::new_v4();
+    for &files in FILES {
+        let old = crawl_disk(&generate_tree("to", files, 0), &rsync_base()).unwrap();
+        let new = crawl_disk(&generate_tree("to", files, 1), &rsync_base()).unwrap();
+        let old = Snapshot::new(session, 1, old);
+        let new = Snapshot::new(session, 2, new);
+        group.throughput(Throughput::Elements(files as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(files),
+            &(old, new),
+            |b, (old, new)| b.iter(|| old.to(new).unwrap()),
+        );
+    }
+    let _ = fs::remove_dir_all(std::env::temp_dir().join("rrdpit-bench"));
+    group.finish();
+}
+
+criterion_group!(benches, crawl, read, write_xml, snapshot_to);
+criterion_main!(benches);
@@ -0,0 +1,9 @@
+//! Checks that parsing any input as a snapshot never panics.
+#![no_main]
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+use rrdpit::rrdp::Snapshot;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Snapshot::from_xml(Bytes::from(data));
+});
@@ -0,0 +1,105 @@
+//! Previews the delta between two source directories, without touching any
+//! state on disk.
+
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+use rrdp::{self, Delta, Snapshot};
+use sync::{self, crawl_disk_with, CrawlOptions, RsyncUri};
+
+//------------ DiffOptions ---------------------------------------------------
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiffOptions {
+    /// The source directory with the old content.
+    pub old: PathBuf,
+
+    /// The source directory with the new content.
+    pub new: PathBuf,
+
+    /// The base rsync uri for both directories.
+    pub rsync: RsyncUri,
+
+    /// Rsync base uris for top-level subdirs, see 'CrawlOptions::rsync_map'.
+    pub rsync_map: Vec<(String, RsyncUri)>,
+
+    /// Print the raw delta XML, rather than the element uris.
+    pub xml: bool,
+}
+
+/// Crawls both directories, and returns the delta from a snapshot of the old
+/// directory to one of the new directory. The snapshots have a new session,
+/// and serials 1 and 2.
+pub fn diff(options: &DiffOptions) -> Result<Delta, Error> {
+    let crawl_options = CrawlOptions {
+        rsync_map: options.rsync_map.clone(),
+        ..Default::default()
+    };
+    let crawl = |dir| crawl_disk_with(dir, &options.rsync, &crawl_options);
+
+    let session = Uuid::new_v4();
+    let old = Snapshot::new(session, 1, crawl(&options.old)?);
+    let new = Snapshot::new(session, 2, crawl(&options.new)?);
+    Ok(old.to(&new)?)
+}
+
+//------------ Error ---------------------------------------------------------
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "{}", _0)]
+    Sync(sync::Error),
+
+    #[display(fmt = "{}", _0)]
+    Rrdp(rrdp::Error),
+}
+
+impl From<sync::Error> for Error {
+    fn from(e: sync::Error) -> Self {
+        Error::Sync(e)
+    }
+}
+
+impl From<rrdp::Error> for Error {
+    fn from(e: rrdp::Error) -> Self {
+        Error::Rrdp(e)
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_source_dirs() {
+        let options = DiffOptions {
+            old: PathBuf::from("./test-resources/source-1/"),
+            new: PathBuf::from("./test-resources/source-2/"),
+            rsync: RsyncUri::base_uri("rsync://localhost/repo/").unwrap(),
+            rsync_map: vec![],
+            xml: false,
+        };
+        let delta = diff(&options).unwrap();
+        assert_eq!(2, delta.serial());
+
+        let elements = delta.elements();
+        assert_eq!(1, elements.publishes().len());
+        assert_eq!(1, elements.updates().len());
+        assert_eq!(1, elements.withdraws().len());
+        assert_eq!(
+            &RsyncUri::from("rsync://localhost/repo/file4.txt"),
+            elements.publishes()[0].uri()
+        );
+        assert_eq!(
+            &RsyncUri::from("rsync://localhost/repo/file1.txt"),
+            elements.updates()[0].uri()
+        );
+        assert_eq!(
+            &RsyncUri::from("rsync://localhost/repo/file3.txt"),
+            elements.withdraws()[0].uri()
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! Remembers the hashes of crawled files by their size and modification
+//! time, so that a later crawl can take the content of unchanged files from
+//! the previous snapshot, rather than reading, hashing and encoding them all
+//! over again.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use storage::Storage;
+use sync::{Base64, CurrentFile, EncodedHash, HashAlg, RsyncUri};
+
+/// The name of the index file in the target directory. It is hidden, so that
+/// cleaning the target leaves it alone.
+pub const INDEX_FILE: &str = ".rrdpit-index.json";
+
+//------------ Stamp ---------------------------------------------------------
+
+/// The size and modification time of a file.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct Stamp {
+    size: u64,
+    secs: u64,
+    nanos: u32,
+}
+
+impl Stamp {
+    fn for_path(path: &Path) -> Result<Self, io::Error> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Stamp {
+            size: metadata.len(),
+            secs: modified.as_secs(),
+            nanos: modified.subsec_nanos(),
+        })
+    }
+}
+
+//------------ IndexEntry ----------------------------------------------------
+
+/// The stamp of a file when it was last read, and the hash of its content.
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct IndexEntry {
+    stamp: Stamp,
+    hash: EncodedHash,
+}
+
+/// An index entry as saved, with the hash hex encoded.
+#[derive(Deserialize, Serialize)]
+struct StoredEntry {
+    stamp: Stamp,
+    hash: String,
+}
+
+//------------ FileIndex -----------------------------------------------------
+
+/// The files found by the previous crawl, and the files found by this one.
+///
+/// A file is only taken from the index if its size and modification time are
+/// unchanged, and the previous snapshot has content with the indexed hash.
+/// Otherwise it is read as usual. Note that a file which is rewritten with
+/// the same size within the resolution of the file system's timestamps looks
+/// unchanged, so this should not be used if files are rewritten in place
+/// this quickly.
+#[derive(Debug, Default)]
+pub struct FileIndex {
+    previous: HashMap<PathBuf, IndexEntry>,
+    content: HashMap<EncodedHash, Base64>,
+    current: Mutex<HashMap<PathBuf, IndexEntry>>,
+}
+
+impl FileIndex {
+    /// Loads the index from the path, with the content of the files in the
+    /// previous snapshot. An index that is missing or cannot be parsed is
+    /// treated as empty, so that all files are read.
+    pub fn load_from<S: Storage>(storage: &S, path: &Path, previous: &[CurrentFile]) -> Self {
+        let stored: HashMap<PathBuf, StoredEntry> = match storage.read(path) {
+            Ok(bytes) => serde_json::from_slice(bytes.as_ref()).unwrap_or_else(|e| {
+                warn!(
+                    "Ignoring invalid file index: {}, error: {}",
+                    path.display(),
+                    e
+                );
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        let entries = stored
+            .into_iter()
+            .filter_map(|(path, entry)| {
+                let hash = EncodedHash::from_hex(&entry.hash).ok()?;
+                let stamp = entry.stamp;
+                Some((path, IndexEntry { stamp, hash }))
+            })
+            .collect();
+        let content = previous
+            .iter()
+            .map(|file| (file.hash().clone(), file.base64().clone()))
+            .collect();
+        FileIndex {
+            previous: entries,
+            content,
+            current: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Saves the files found by this crawl to the path, for the next crawl.
+    pub fn save_to<S: Storage>(&self, storage: &S, path: &Path) -> Result<(), io::Error> {
+        let current = self.current.lock().expect("file index lock poisoned");
+        let stored: HashMap<&PathBuf, StoredEntry> = current
+            .iter()
+            .map(|(path, entry)| {
+                let stamp = entry.stamp;
+                let hash = entry.hash.to_string();
+                (path, StoredEntry { stamp, hash })
+            })
+            .collect();
+        let json = serde_json::to_vec(&stored)?;
+        storage.save_atomic(&json, path)
+    }
+
+    /// Returns the file at the path from the index if it is unchanged, and
+    /// else reads it. Either way, it is recorded in the index for the next
+    /// crawl.
+    pub(crate) fn read<F>(
+        &self,
+        alg: HashAlg,
+        uri: RsyncUri,
+        path: &Path,
+        read: F,
+    ) -> Result<CurrentFile, io::Error>
+    where
+        F: FnOnce(RsyncUri) -> Result<CurrentFile, io::Error>,
+    {
+        // The stamp is taken before reading, so that a file which changes
+        // meanwhile is read again by the next crawl.
+        let stamp = Stamp::for_path(path).ok();
+        let indexed = match (stamp, self.previous.get(path)) {
+            (Some(stamp), Some(entry)) if entry.stamp == stamp && entry.hash.alg() == alg => {
+                self.content.get(&entry.hash).map(|base64| {
+                    CurrentFile::from_parts(uri.clone(), base64.clone(), entry.hash.clone())
+                })
+            }
+            _ => None,
+        };
+
+        let file = match indexed {
+            Some(file) => {
+                debug!("Unchanged: {}", path.display());
+                file
+            }
+            None => read(uri)?,
+        };
+
+        if let Some(stamp) = stamp {
+            let hash = file.hash().clone();
+            self.current
+                .lock()
+                .expect("file index lock poisoned")
+                .insert(path.to_path_buf(), IndexEntry { stamp, hash });
+        }
+        Ok(file)
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    use storage::FsStorage;
+    use sync::{crawl_disk_with, crawl_sources_indexed, save, CrawlOptions};
+
+    fn set_modified(path: &Path, modified: SystemTime) {
+        let file = File::options().write(true).open(path).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn reuse_unchanged_files() {
+        const WORK_DIR: &str = "./test-work/reuse_unchanged_files/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let source = PathBuf::from(WORK_DIR).join("source");
+        let index_path = PathBuf::from(WORK_DIR).join(INDEX_FILE);
+        let storage = FsStorage::default();
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let options = CrawlOptions::default();
+        let sources = [source.clone()];
+        let earlier = SystemTime::now() - Duration::from_secs(60);
+
+        for name in &["a.cer", "b.cer", "c.cer"] {
+            save(name.as_bytes(), &source.join(name)).unwrap();
+            set_modified(&source.join(name), earlier);
+        }
+
+        let crawl = |previous: &[CurrentFile]| {
+            let index = FileIndex::load_from(&storage, &index_path, previous);
+            let files =
+                crawl_sources_indexed(&sources, &rsync_base, &options, Some(&index)).unwrap();
+            index.save_to(&storage, &index_path).unwrap();
+            files
+        };
+
+        // Without an index all files are read.
+        let first = crawl(&[]);
+        assert_eq!(
+            crawl_disk_with(&source, &rsync_base, &options).unwrap(),
+            first
+        );
+
+        // A changed file is read again, and the result is still correct.
+        save(b"changed", &source.join("a.cer")).unwrap();
+        let second = crawl(&first);
+        assert_eq!(
+            crawl_disk_with(&source, &rsync_base, &options).unwrap(),
+            second
+        );
+        assert_eq!(
+            CurrentFile::new(first[0].uri().clone(), b"changed"),
+            second[0]
+        );
+
+        // Files with unchanged metadata are taken from the index, which is
+        // shown by changing a file's content without changing its size and
+        // modification time.
+        save(b"B.CER", &source.join("b.cer")).unwrap();
+        set_modified(&source.join("b.cer"), earlier);
+        let third = crawl(&second);
+        assert_eq!(second, third);
+
+        // Without the previous content, the file is read after all.
+        let fourth = crawl(&[]);
+        assert_eq!(
+            CurrentFile::new(first[1].uri().clone(), b"B.CER"),
+            fourth[1]
+        );
+    }
+}
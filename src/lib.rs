@@ -4,12 +4,40 @@ extern crate clap;
 #[macro_use]
 extern crate derive_more;
 extern crate core;
+extern crate flate2;
+extern crate glob;
 extern crate hex;
+#[macro_use]
+extern crate log;
+extern crate memmap2;
+extern crate notify;
+extern crate percent_encoding;
 extern crate ring;
+extern crate serde;
+extern crate serde_json;
+extern crate tar;
+extern crate toml;
+extern crate ureq;
 extern crate uuid;
 extern crate xml as xmlrs;
 
+pub mod diff;
+pub mod index;
+pub mod lock;
+pub mod metrics;
 pub mod options;
+pub mod prune;
+pub mod repair;
 pub mod rrdp;
+pub mod run;
+#[cfg(feature = "server")]
+pub mod serve;
+pub mod snapshot;
+pub mod stats;
+pub mod storage;
 pub mod sync;
+#[cfg(test)]
+mod test_util;
+pub mod verify;
+pub mod watch;
 pub mod xml;
@@ -0,0 +1,81 @@
+//! Prevents concurrent runs against one target directory, which could
+//! interleave saving and cleaning, and remove files the other run just wrote.
+
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io;
+use std::path::Path;
+
+/// The name of the lock file in the target directory.
+pub const LOCK_FILE: &str = ".rrdpit.lock";
+
+//------------ TargetLock ----------------------------------------------------
+
+/// An exclusive advisory lock on a target directory. It is held until this
+/// is dropped, which also happens when returning early with an error. The
+/// operating system releases it if the process dies.
+///
+/// The lock file itself is left in place, removing it could let another
+/// run lock a new file while this one still holds the old one.
+#[derive(Debug)]
+pub struct TargetLock {
+    _file: File,
+}
+
+impl TargetLock {
+    /// Acquires the lock on the target directory, creating it if needed.
+    /// Fails right away if another process holds the lock.
+    pub fn acquire(target: &Path) -> Result<Self, Error> {
+        fs::create_dir_all(target)?;
+        let path = target.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(TargetLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(Error::Locked(path.to_string_lossy().to_string())),
+            Err(TryLockError::Error(e)) => Err(Error::Io(e)),
+        }
+    }
+}
+
+//------------ Error ---------------------------------------------------------
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "Target is locked by another rrdpit run: {}", _0)]
+    Locked(String),
+
+    #[display(fmt = "Cannot lock target: {}", _0)]
+    Io(io::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuse_second_lock() {
+        const WORK_DIR: &str = "./test-work/refuse_second_lock/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let target = Path::new(WORK_DIR);
+
+        let lock = TargetLock::acquire(target).unwrap();
+        match TargetLock::acquire(target) {
+            Err(Error::Locked(path)) => assert!(path.ends_with(LOCK_FILE)),
+            res => panic!("Expected Locked, got: {:?}", res),
+        }
+
+        drop(lock);
+        assert!(TargetLock::acquire(target).is_ok());
+    }
+}
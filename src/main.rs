@@ -1,66 +1,241 @@
 #[macro_use]
 extern crate derive_more;
+extern crate env_logger;
+extern crate log;
 extern crate rrdpit;
-extern crate uuid;
+extern crate serde_json;
 
 use std::fmt;
-use std::path::PathBuf;
-use uuid::Uuid;
+use std::io;
 
-use rrdpit::options::Options;
-use rrdpit::rrdp::{RepoState, Snapshot};
-use rrdpit::sync::crawl_disk;
-use rrdpit::sync::RsyncUri;
+use log::LevelFilter;
+use rrdpit::diff::{diff, DiffOptions};
+use rrdpit::options::{Command, Options, OutputFormat};
+use rrdpit::prune::{prune, PruneOptions};
+use rrdpit::repair::{repair, RepairOptions};
+use rrdpit::rrdp::SaveSummary;
+use rrdpit::run::run;
+#[cfg(feature = "server")]
+use rrdpit::serve::{ServeOptions, Server};
+use rrdpit::snapshot::{write_snapshot, SnapshotOptions};
+use rrdpit::stats::{stats, StatsOptions};
+use rrdpit::verify::{verify, VerifyOptions};
+use rrdpit::watch::{watch, DEFAULT_DEBOUNCE};
 
 fn main() {
-    match Options::from_args() {
-        Ok(options) => match sync(options) {
-            Ok(()) => {}
-            Err(e) => {
-                eprintln!("{}", e);
-                ::std::process::exit(1);
-            }
-        },
-        Err(e) => {
-            eprintln!("{}", e);
-            ::std::process::exit(1);
+    let res = match Command::from_args() {
+        Ok(Command::Sync(options)) => {
+            init_logging(options.log_level()).and_then(|_| sync(*options))
+        }
+        #[cfg(feature = "server")]
+        Ok(Command::Serve(options)) => init_logging(LevelFilter::Info).and_then(|_| serve(options)),
+        Ok(Command::Verify(options)) => {
+            init_logging(LevelFilter::Warn).and_then(|_| check(options))
+        }
+        Ok(Command::Repair(options)) => {
+            init_logging(LevelFilter::Warn).and_then(|_| rebuild(options))
         }
+        Ok(Command::Prune(options)) => init_logging(LevelFilter::Info).and_then(|_| clean(options)),
+        Ok(Command::Stats(options)) => init_logging(LevelFilter::Warn).and_then(|_| size(options)),
+        Ok(Command::Diff(options)) => {
+            init_logging(LevelFilter::Warn).and_then(|_| preview(options))
+        }
+        Ok(Command::Snapshot(options)) => {
+            init_logging(LevelFilter::Warn).and_then(|_| export(options))
+        }
+        Err(e) => Err(Error::custom(e)),
+    };
+
+    if let Err(e) = res {
+        eprintln!("{}", e);
+        ::std::process::exit(1);
     }
 }
 
-fn snapshot(
-    session: Uuid,
-    serial: u64,
-    source: &PathBuf,
-    rsync: &RsyncUri,
-) -> Result<Snapshot, Error> {
-    let files = crawl_disk(source, rsync).map_err(Error::custom)?;
-    Ok(Snapshot::new(session, serial, files))
+fn init_logging(level: LevelFilter) -> Result<(), Error> {
+    env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .try_init()
+        .map_err(Error::custom)
 }
 
 fn sync(options: Options) -> Result<(), Error> {
-    let state = match RepoState::reconstitute(options.https.clone(), options.target.clone()) {
-        Ok(mut state) => {
-            let snapshot = snapshot(
-                state.session(),
-                state.serial() + 1,
-                &options.source,
-                &options.rsync,
-            )
-            .map_err(Error::custom)?;
-            state.apply(snapshot).map_err(Error::custom)?;
-            state
+    if options.watch {
+        watch(&options, DEFAULT_DEBOUNCE, |res| {
+            match res {
+                Ok(Some(summary)) => {
+                    if let Err(e) = report(&options, &summary) {
+                        eprintln!("{}", e);
+                    }
+                }
+                Ok(None) => report_unchanged(&options),
+                Err(e) => eprintln!("{}", e),
+            }
+            true
+        })
+        .map_err(Error::custom)
+    } else {
+        match run(&options).map_err(Error::custom)? {
+            Some(summary) => report(&options, &summary),
+            None => {
+                report_unchanged(&options);
+                Ok(())
+            }
         }
-        Err(_) => {
-            let snapshot = snapshot(Uuid::new_v4(), 1, &options.source, &options.rsync)
-                .map_err(Error::custom)?;
-            RepoState::new(snapshot, options.https.clone(), options.target.clone())
+    }
+}
+
+/// Reports that the sources did not change, so nothing was saved. In JSON
+/// this is an object with changed set to false, so that stdout stays JSON.
+fn report_unchanged(options: &Options) {
+    if options.output_format == OutputFormat::Json {
+        println!("{}", serde_json::json!({ "changed": false }));
+    } else {
+        println!("No changes");
+    }
+}
+
+fn report(options: &Options, summary: &SaveSummary) -> Result<(), Error> {
+    if options.output_format == OutputFormat::Json {
+        return print_json(summary);
+    }
+
+    if options.dry_run {
+        println!(
+            "Dry run: session {} serial {}",
+            summary.session(),
+            summary.serial()
+        );
+        println!(
+            "Delta: {} publishes, {} updates, {} withdraws",
+            summary.publishes(),
+            summary.updates(),
+            summary.withdraws()
+        );
+        for path in summary.removed() {
+            println!("Would remove: {}", path.display());
         }
-    };
+    } else {
+        match options.output_format {
+            OutputFormat::Text => println!(
+                "Saved session {} serial {} with {} delta elements, keeping {} deltas",
+                summary.session(),
+                summary.serial(),
+                summary.delta_elements(),
+                summary.deltas()
+            ),
+            OutputFormat::KeyValue => println!(
+                "session={} serial={} delta_elements={} deltas={}",
+                summary.session(),
+                summary.serial(),
+                summary.delta_elements(),
+                summary.deltas()
+            ),
+            OutputFormat::Json => print_json(summary)?,
+        }
+    }
+    Ok(())
+}
 
-    state
-        .save(options.max_deltas, options.clean)
-        .map_err(Error::custom)
+#[cfg(feature = "server")]
+fn serve(options: ServeOptions) -> Result<(), Error> {
+    let server = Server::bind(&options).map_err(Error::custom)?;
+    eprintln!(
+        "Serving {} on http://{}/",
+        options.target.display(),
+        options.listen
+    );
+    server.run().map_err(Error::custom)
+}
+
+fn check(options: VerifyOptions) -> Result<(), Error> {
+    let state = verify(&options).map_err(Error::custom)?;
+    println!(
+        "Verified session {} serial {} with {} objects, and {} deltas",
+        state.session(),
+        state.serial(),
+        state.snapshot().len(),
+        state.deltas().len()
+    );
+    Ok(())
+}
+
+fn rebuild(options: RepairOptions) -> Result<(), Error> {
+    let summary = repair(&options).map_err(Error::custom)?;
+    println!(
+        "Repaired session {} serial {} with {} deltas, excluding {} files",
+        summary.session(),
+        summary.serial(),
+        summary.deltas(),
+        summary.excluded().len()
+    );
+    Ok(())
+}
+
+fn clean(options: PruneOptions) -> Result<(), Error> {
+    let summary = prune(&options).map_err(Error::custom)?;
+    println!(
+        "Pruned session {} serial {} with {} deltas, removed {} dirs",
+        summary.session(),
+        summary.serial(),
+        summary.deltas(),
+        summary.removed().len()
+    );
+    Ok(())
+}
+
+fn size(options: StatsOptions) -> Result<(), Error> {
+    let stats = stats(&options).map_err(Error::custom)?;
+    if options.json {
+        let json = serde_json::to_string(&stats).map_err(Error::custom)?;
+        println!("{}", json);
+        return Ok(());
+    }
+    println!("Session:  {}", stats.session());
+    println!("Serial:   {}", stats.serial());
+    println!(
+        "Snapshot: {} objects, {} bytes",
+        stats.objects(),
+        stats.snapshot_size()
+    );
+    println!(
+        "Deltas:   {} deltas, {} bytes",
+        stats.deltas(),
+        stats.deltas_size()
+    );
+    Ok(())
+}
+
+fn preview(options: DiffOptions) -> Result<(), Error> {
+    let delta = diff(&options).map_err(Error::custom)?;
+    if options.xml {
+        let xml = delta.write_xml();
+        println!("{}", String::from_utf8_lossy(xml.as_ref()));
+        return Ok(());
+    }
+    let elements = delta.elements();
+    for publish in elements.publishes() {
+        println!("publish  {}", publish.uri());
+    }
+    for update in elements.updates() {
+        println!("update   {}", update.uri());
+    }
+    for withdraw in elements.withdraws() {
+        println!("withdraw {}", withdraw.uri());
+    }
+    Ok(())
+}
+
+fn export(options: SnapshotOptions) -> Result<(), Error> {
+    let stdout = io::stdout();
+    write_snapshot(&options, stdout.lock()).map_err(Error::custom)
+}
+
+fn print_json(summary: &SaveSummary) -> Result<(), Error> {
+    let json = serde_json::to_string(summary).map_err(Error::custom)?;
+    println!("{}", json);
+    Ok(())
 }
 
 //------------ Error ---------------------------------------------------------
@@ -0,0 +1,189 @@
+//! Writes metrics about a run in the Prometheus text format, so that they
+//! can be picked up by e.g. the node exporter's textfile collector.
+
+use std::fmt::{self, Write};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use rrdp::SaveSummary;
+use stats::Stats;
+use sync;
+
+/// Writes the metrics for a run to the given path. The file is replaced
+/// atomically, so that a scraper never sees a partially written file.
+pub fn write_metrics(
+    path: &Path,
+    summary: &SaveSummary,
+    crawl_duration: Duration,
+) -> Result<(), io::Error> {
+    sync::save_atomic(format_metrics(summary, crawl_duration).as_bytes(), path)
+}
+
+/// Writes the metrics for a run which left the target unchanged, taking the
+/// current state from its stats. There is no new delta.
+pub fn write_unchanged_metrics(
+    path: &Path,
+    stats: &Stats,
+    crawl_duration: Duration,
+) -> Result<(), io::Error> {
+    let metrics = Metrics {
+        serial: stats.serial(),
+        snapshot_size: stats.snapshot_size(),
+        publishes: 0,
+        updates: 0,
+        withdraws: 0,
+        deltas: stats.deltas(),
+        deltas_size: stats.deltas_size(),
+        crawl_duration,
+    };
+    sync::save_atomic(metrics.format().as_bytes(), path)
+}
+
+/// Formats the metrics for a run.
+pub fn format_metrics(summary: &SaveSummary, crawl_duration: Duration) -> String {
+    Metrics {
+        serial: summary.serial(),
+        snapshot_size: summary.snapshot_size(),
+        publishes: summary.publishes(),
+        updates: summary.updates(),
+        withdraws: summary.withdraws(),
+        deltas: summary.deltas(),
+        deltas_size: summary.deltas_size(),
+        crawl_duration,
+    }
+    .format()
+}
+
+//------------ Metrics -------------------------------------------------------
+
+/// The values reported for a run.
+struct Metrics {
+    serial: u64,
+    snapshot_size: usize,
+    publishes: usize,
+    updates: usize,
+    withdraws: usize,
+    deltas: usize,
+    deltas_size: usize,
+    crawl_duration: Duration,
+}
+
+impl Metrics {
+    fn format(&self) -> String {
+        let mut metrics = String::new();
+        let mut gauge = |name: &str, help: &str, value: &dyn fmt::Display| {
+            // Writing to a String cannot fail.
+            let _ = writeln!(metrics, "# HELP rrdpit_{} {}", name, help);
+            let _ = writeln!(metrics, "# TYPE rrdpit_{} gauge", name);
+            let _ = writeln!(metrics, "rrdpit_{} {}", name, value);
+        };
+
+        gauge(
+            "serial",
+            "The serial of the current RRDP state.",
+            &self.serial,
+        );
+        gauge(
+            "snapshot_size_bytes",
+            "The size of the current snapshot file.",
+            &self.snapshot_size,
+        );
+        gauge(
+            "delta_publishes",
+            "The number of publishes in the new delta.",
+            &self.publishes,
+        );
+        gauge(
+            "delta_updates",
+            "The number of updates in the new delta.",
+            &self.updates,
+        );
+        gauge(
+            "delta_withdraws",
+            "The number of withdraws in the new delta.",
+            &self.withdraws,
+        );
+        gauge(
+            "deltas",
+            "The number of deltas kept in the notification file.",
+            &self.deltas,
+        );
+        gauge(
+            "deltas_size_bytes",
+            "The total size of the deltas kept in the notification file.",
+            &self.deltas_size,
+        );
+        gauge(
+            "crawl_duration_seconds",
+            "The time it took to read the sources.",
+            &self.crawl_duration.as_secs_f64(),
+        );
+
+        metrics
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use options::Options;
+    use run::run;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use sync::{HttpsUri, RsyncUri};
+
+    #[test]
+    fn write_metrics_file() {
+        const WORK_DIR: &str = "./test-work/write_metrics_file/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let metrics_path = PathBuf::from(WORK_DIR).join("rrdpit.prom");
+
+        let mut options = Options::new(
+            vec![PathBuf::from("./test-resources/source-1/")],
+            PathBuf::from(WORK_DIR).join("target"),
+            RsyncUri::base_uri("rsync://localhost/repo/").unwrap(),
+            HttpsUri::base_uri("https://localhost/rrdp/").unwrap(),
+            false,
+            25,
+        );
+        options.metrics_file = Some(metrics_path.clone());
+        let summary = run(&options).unwrap().unwrap();
+
+        let metrics = fs::read_to_string(&metrics_path).unwrap();
+        let gauges: HashMap<&str, f64> = metrics
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| {
+                let mut parts = line.split(' ');
+                let name = parts.next().unwrap();
+                let value = parts.next().unwrap().parse().unwrap();
+                assert_eq!(None, parts.next());
+                (name, value)
+            })
+            .collect();
+
+        assert_eq!(Some(&1.0), gauges.get("rrdpit_serial"));
+        assert_eq!(
+            Some(&(summary.snapshot_size() as f64)),
+            gauges.get("rrdpit_snapshot_size_bytes")
+        );
+        assert_eq!(Some(&0.0), gauges.get("rrdpit_deltas"));
+        assert!(gauges["rrdpit_crawl_duration_seconds"] >= 0.0);
+
+        // An unchanged run reports the same state, without a new delta.
+        fs::remove_file(&metrics_path).unwrap();
+        assert!(run(&options).unwrap().is_none());
+        let unchanged = fs::read_to_string(&metrics_path).unwrap();
+        let without_duration = |metrics: &str| -> Vec<String> {
+            metrics
+                .lines()
+                .filter(|line| !line.starts_with("rrdpit_crawl_duration_seconds"))
+                .map(String::from)
+                .collect()
+        };
+        assert_eq!(without_duration(&metrics), without_duration(&unchanged));
+    }
+}
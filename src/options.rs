@@ -1,26 +1,367 @@
-use clap::{App, Arg};
-use std::path::PathBuf;
-use sync::{HttpsUri, RsyncUri};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use diff::DiffOptions;
+use glob::Pattern;
+use log::LevelFilter;
+use prune::PruneOptions;
+use repair::RepairOptions;
+use rrdp::{SaveOptions, NOTIFICATION_FILE};
+use serde::Deserialize;
+#[cfg(feature = "server")]
+use serve::ServeOptions;
+use snapshot::SnapshotOptions;
+use stats::StatsOptions;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use sync::{CrawlOptions, FileModes, HashAlg, HttpsUri, RsyncUri, DEFAULT_MAX_DEPTH};
+use uuid::Uuid;
+use verify::VerifyOptions;
+
+//------------ Command -------------------------------------------------------
+
+/// The command to run, as given on the command line.
+pub enum Command {
+    /// Create or update the RRDP files in the target directory.
+    Sync(Box<Options>),
+
+    /// Serve the RRDP files in the target directory over HTTP.
+    #[cfg(feature = "server")]
+    Serve(ServeOptions),
+
+    /// Verify the RRDP files of a remote repository.
+    Verify(VerifyOptions),
+
+    /// Rebuild the notification file from the files in the target directory.
+    Repair(RepairOptions),
+
+    /// Remove the session and serial dirs no longer referenced.
+    Prune(PruneOptions),
+
+    /// Report the size of the repository in the target directory.
+    Stats(StatsOptions),
+
+    /// Show the delta between two source directories.
+    Diff(DiffOptions),
+
+    /// Write a snapshot of the source directory to stdout.
+    Snapshot(SnapshotOptions),
+}
+
+impl Command {
+    pub fn from_args() -> Result<Command, Error> {
+        Self::from_matches(&Options::app().get_matches())
+    }
+
+    /// Parses the command from the given arguments, rather than the actual
+    /// command line arguments. The first argument is the binary name.
+    pub fn from_arg_iter<I, T>(args: I) -> Result<Command, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Options::app()
+            .get_matches_from_safe(args)
+            .map_err(|e| Error::InvalidArguments(e.message))?;
+        Self::from_matches(&matches)
+    }
+
+    fn from_matches(matches: &ArgMatches) -> Result<Command, Error> {
+        #[cfg(feature = "server")]
+        {
+            if let Some(matches) = matches.subcommand_matches("serve") {
+                return Self::serve_from_matches(matches);
+            }
+        }
+        if let Some(matches) = matches.subcommand_matches("verify") {
+            return Self::verify_from_matches(matches);
+        }
+        if let Some(matches) = matches.subcommand_matches("repair") {
+            return Self::repair_from_matches(matches);
+        }
+        if let Some(matches) = matches.subcommand_matches("prune") {
+            return Self::prune_from_matches(matches);
+        }
+        if let Some(matches) = matches.subcommand_matches("stats") {
+            return Self::stats_from_matches(matches);
+        }
+        if let Some(matches) = matches.subcommand_matches("diff") {
+            return Self::diff_from_matches(matches);
+        }
+        if let Some(matches) = matches.subcommand_matches("snapshot") {
+            return Self::snapshot_from_matches(matches);
+        }
+        Options::from_matches(matches).map(|options| Command::Sync(Box::new(options)))
+    }
+
+    #[cfg(feature = "server")]
+    fn serve_from_matches(matches: &ArgMatches) -> Result<Command, Error> {
+        let target = required("target", matches.value_of("target"))?;
+        let target = PathBuf::from(target);
+        if !target.is_dir() {
+            return Err(Error::cannot_read(target));
+        }
+
+        let listen = matches.value_of("listen").unwrap_or("127.0.0.1:3000");
+        let listen = listen
+            .parse()
+            .map_err(|_| Error::InvalidListenAddress(listen.to_string()))?;
+
+        Ok(Command::Serve(ServeOptions { target, listen }))
+    }
+
+    fn verify_from_matches(matches: &ArgMatches) -> Result<Command, Error> {
+        let https = required("https", matches.value_of("https"))?;
+        let https =
+            HttpsUri::base_uri(https).map_err(|_| Error::HttpsBaseUri(https.to_string()))?;
+        Ok(Command::Verify(VerifyOptions { https }))
+    }
+
+    fn repair_from_matches(matches: &ArgMatches) -> Result<Command, Error> {
+        let target = PathBuf::from(required("target", matches.value_of("target"))?);
+        if !target.is_dir() {
+            return Err(Error::cannot_read(target));
+        }
+
+        let https = required("https", matches.value_of("https"))?;
+        let https =
+            HttpsUri::base_uri(https).map_err(|_| Error::HttpsBaseUri(https.to_string()))?;
+        let notification_name = Self::notification_name(matches)?;
+        Ok(Command::Repair(RepairOptions {
+            target,
+            https,
+            notification_name,
+        }))
+    }
+
+    fn prune_from_matches(matches: &ArgMatches) -> Result<Command, Error> {
+        let target = PathBuf::from(required("target", matches.value_of("target"))?);
+        if !target.is_dir() {
+            return Err(Error::cannot_read(target));
+        }
+
+        let max_deltas = required("max_deltas", matches.value_of("max_deltas"))?;
+        let max_deltas = usize::from_str(max_deltas)
+            .map_err(|_| Error::CannotParseNumber(max_deltas.to_string()))?;
+        if max_deltas < 1 {
+            return Err(Error::MaxDeltasMustBeOneOrHigher);
+        }
+
+        let keep_sessions = match matches.value_of("keep_sessions") {
+            Some(keep) => {
+                usize::from_str(keep).map_err(|_| Error::CannotParseNumber(keep.to_string()))?
+            }
+            None => 1,
+        };
+        if keep_sessions < 1 {
+            return Err(Error::KeepSessionsMustBeOneOrHigher);
+        }
+
+        let notification_name = Self::notification_name(matches)?;
+        Ok(Command::Prune(PruneOptions {
+            target,
+            notification_name,
+            max_deltas,
+            keep_sessions,
+        }))
+    }
+
+    fn stats_from_matches(matches: &ArgMatches) -> Result<Command, Error> {
+        let target = PathBuf::from(required("target", matches.value_of("target"))?);
+        if !target.is_dir() {
+            return Err(Error::cannot_read(target));
+        }
+        let notification_name = Self::notification_name(matches)?;
+        let json = matches.is_present("json");
+        Ok(Command::Stats(StatsOptions {
+            target,
+            notification_name,
+            json,
+        }))
+    }
+
+    fn diff_from_matches(matches: &ArgMatches) -> Result<Command, Error> {
+        let old = PathBuf::from(required("old", matches.value_of("old"))?);
+        let new = PathBuf::from(required("new", matches.value_of("new"))?);
+        for dir in &[&old, &new] {
+            if !dir.is_dir() {
+                return Err(Error::cannot_read(dir.to_path_buf()));
+            }
+        }
+
+        let rsync = required("rsync", matches.value_of("rsync"))?;
+        let rsync =
+            RsyncUri::base_uri(rsync).map_err(|_| Error::RsyncBaseUri(rsync.to_string()))?;
+        let rsync_map = Self::rsync_map(matches)?;
+        let xml = matches.is_present("xml");
+        Ok(Command::Diff(DiffOptions {
+            old,
+            new,
+            rsync,
+            rsync_map,
+            xml,
+        }))
+    }
+
+    fn snapshot_from_matches(matches: &ArgMatches) -> Result<Command, Error> {
+        let source = PathBuf::from(required("source", matches.value_of("source"))?);
+        if !source.is_dir() {
+            return Err(Error::cannot_read(source));
+        }
+
+        let rsync = required("rsync", matches.value_of("rsync"))?;
+        let rsync =
+            RsyncUri::base_uri(rsync).map_err(|_| Error::RsyncBaseUri(rsync.to_string()))?;
+        let rsync_map = Self::rsync_map(matches)?;
+        Ok(Command::Snapshot(SnapshotOptions {
+            source,
+            rsync,
+            rsync_map,
+        }))
+    }
+
+    /// Parses the '--notification-name' value, which must be a plain file
+    /// name, or returns the default name.
+    fn notification_name(matches: &ArgMatches) -> Result<String, Error> {
+        match matches.value_of("notification_name") {
+            Some(name)
+                if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) =>
+            {
+                Err(Error::InvalidNotificationName(name.to_string()))
+            }
+            Some(name) => Ok(name.to_string()),
+            None => Ok(NOTIFICATION_FILE.to_string()),
+        }
+    }
+
+    /// Parses the repeated '--rsync-map' values, see 'CrawlOptions::rsync_map'.
+    fn rsync_map(matches: &ArgMatches) -> Result<Vec<(String, RsyncUri)>, Error> {
+        match matches.values_of("rsync_map") {
+            Some(values) => values.map(Options::parse_rsync_map).collect(),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+//------------ Options -------------------------------------------------------
 
 pub struct Options {
-    pub source: PathBuf,
+    pub sources: Vec<PathBuf>,
     pub target: PathBuf,
     pub rsync: RsyncUri,
     pub https: HttpsUri,
     pub clean: bool,
     pub max_deltas: usize,
+    pub gzip: bool,
+    pub threads: usize,
+    pub includes: Vec<Pattern>,
+    pub excludes: Vec<Pattern>,
+    pub follow_symlinks: bool,
+    pub include_hidden: bool,
+    pub max_depth: usize,
+    pub mmap: bool,
+    pub rsync_map: Vec<(String, RsyncUri)>,
+    pub uri_prefixes: Vec<String>,
+    pub file_index: bool,
+    pub max_file_size: Option<u64>,
+    pub fail_on_large_files: bool,
+    pub dry_run: bool,
+    pub output_format: OutputFormat,
+    pub verbosity: u64,
+    pub rsync_out: Option<PathBuf>,
+    pub checksums_file: Option<PathBuf>,
+    pub publish_query: Option<PathBuf>,
+    pub watch: bool,
+    pub metrics_file: Option<PathBuf>,
+    pub hash_alg: HashAlg,
+    pub strict: bool,
+    pub delta_budget: Option<usize>,
+    pub keep_sessions: usize,
+    pub allow_empty: bool,
+    pub pretty: bool,
+    pub session_id: Option<Uuid>,
+    pub serial_start: Option<u64>,
+    pub force_new_session: bool,
+    pub verify: bool,
+    pub max_notification_deltas: Option<usize>,
+    pub hashed_names: bool,
+    pub notification_name: String,
+    pub fsync: bool,
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    pub tmp_dir: Option<PathBuf>,
 }
 
 impl Options {
+    /// Creates options from already parsed values, using defaults for all
+    /// other settings. Unlike 'from_strs' this does not check that the source
+    /// and target directories exist.
+    pub fn new(
+        sources: Vec<PathBuf>,
+        target: PathBuf,
+        rsync: RsyncUri,
+        https: HttpsUri,
+        clean: bool,
+        max_deltas: usize,
+    ) -> Self {
+        Options {
+            sources,
+            target,
+            rsync,
+            https,
+            clean,
+            max_deltas,
+            gzip: false,
+            threads: 1,
+            includes: vec![],
+            excludes: vec![],
+            follow_symlinks: false,
+            include_hidden: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            mmap: false,
+            rsync_map: vec![],
+            uri_prefixes: vec![],
+            file_index: false,
+            max_file_size: None,
+            fail_on_large_files: false,
+            dry_run: false,
+            output_format: OutputFormat::Text,
+            verbosity: 0,
+            rsync_out: None,
+            checksums_file: None,
+            publish_query: None,
+            watch: false,
+            metrics_file: None,
+            hash_alg: HashAlg::Sha256,
+            strict: false,
+            delta_budget: None,
+            keep_sessions: 1,
+            allow_empty: false,
+            pretty: false,
+            session_id: None,
+            serial_start: None,
+            force_new_session: false,
+            verify: false,
+            max_notification_deltas: None,
+            hashed_names: false,
+            notification_name: NOTIFICATION_FILE.to_string(),
+            fsync: false,
+            file_mode: None,
+            dir_mode: None,
+            tmp_dir: None,
+        }
+    }
+
     pub fn from_strs(
-        source: &str,
+        sources: &[&str],
         target: &str,
         rsync: &str,
         https: &str,
         clean: bool,
         max_deltas: &str,
     ) -> Result<Self, Error> {
-        let source = PathBuf::from(source);
+        let sources: Vec<PathBuf> = sources.iter().map(PathBuf::from).collect();
         let target = PathBuf::from(target);
 
         let rsync =
@@ -32,33 +373,127 @@ impl Options {
             .parse::<usize>()
             .map_err(|_| Error::CannotParseNumber(max_deltas.to_string()))?;
 
-        if !source.is_dir() {
-            Err(Error::cannot_read(source))
+        if max_deltas < 1 {
+            Err(Error::MaxDeltasMustBeOneOrHigher)
+        } else if let Some(source) = sources.iter().find(|s| !s.is_dir() && !s.is_file()) {
+            Err(Error::cannot_read(source.clone()))
         } else if !target.is_dir() {
             Err(Error::cannot_read(target))
         } else {
-            Ok(Options {
-                source,
-                target,
-                rsync,
-                https,
-                clean,
-                max_deltas,
-            })
+            Ok(Options::new(
+                sources, target, rsync, https, clean, max_deltas,
+            ))
+        }
+    }
+
+    /// Reads the options from a TOML configuration file. The same validation
+    /// is applied as for options given on the command line.
+    pub fn from_config(path: &Path) -> Result<Self, Error> {
+        let config = ConfigFile::read(path)?;
+
+        let sources = config.sources();
+        if sources.is_empty() {
+            return Err(Error::MissingArgument("source".to_string()));
+        }
+        let target = required("target", config.target.as_deref())?;
+        let rsync = required("rsync", config.rsync.as_deref())?;
+        let https = required("https", config.https.as_deref())?;
+        let clean = config.clean.unwrap_or(false);
+        let max_deltas = config.max_deltas.unwrap_or(25).to_string();
+
+        Self::from_strs(&sources, target, rsync, https, clean, &max_deltas)
+    }
+
+    /// Returns the options for crawling the source directory.
+    pub fn crawl_options(&self) -> CrawlOptions {
+        CrawlOptions {
+            threads: self.threads,
+            includes: self.includes.clone(),
+            excludes: self.excludes.clone(),
+            follow_symlinks: self.follow_symlinks,
+            include_hidden: self.include_hidden,
+            max_depth: self.max_depth,
+            mmap: self.mmap,
+            rsync_map: self.rsync_map.clone(),
+            uri_prefixes: self.uri_prefixes.clone(),
+            max_file_size: self.max_file_size,
+            fail_on_large_files: self.fail_on_large_files,
+            hash_alg: self.hash_alg,
+        }
+    }
+
+    /// Returns the modes for the files and dirs created in the target.
+    pub fn file_modes(&self) -> FileModes {
+        FileModes {
+            file: self.file_mode,
+            dir: self.dir_mode,
+        }
+    }
+
+    /// Returns the log level matching the number of times -v was given.
+    pub fn log_level(&self) -> LevelFilter {
+        match self.verbosity {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+
+    /// Returns the options for saving the repository state.
+    pub fn save_options(&self) -> SaveOptions {
+        SaveOptions {
+            max_deltas: self.max_deltas,
+            clean: self.clean,
+            gzip: self.gzip,
+            hash_alg: self.hash_alg,
+            delta_budget: self.delta_budget,
+            keep_sessions: self.keep_sessions,
+            max_notification_deltas: self.max_notification_deltas,
+            pretty: self.pretty,
+            hashed_names: self.hashed_names,
+            fsync: self.fsync,
         }
     }
 
     pub fn from_args() -> Result<Options, Error> {
-        let matches = App::new("rrdpit")
+        Self::from_matches(&Self::app().get_matches())
+    }
+
+    /// Parses the options from the given arguments, rather than the actual
+    /// command line arguments. The first argument is the binary name.
+    pub fn from_arg_iter<I, T>(args: I) -> Result<Options, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = Self::app()
+            .get_matches_from_safe(args)
+            .map_err(|e| Error::InvalidArguments(e.message))?;
+        Self::from_matches(&matches)
+    }
+
+    fn app<'a, 'b>() -> App<'a, 'b> {
+        let app = App::new("rrdpit")
             .version("0.0.3")
             .about("Dist to RPKI RRDP")
+            .arg(
+                Arg::with_name("config")
+                    .short("c")
+                    .long("config")
+                    .value_name("file")
+                    .help("TOML config file. Command line arguments override its values")
+                    .required(false),
+            )
             .arg(
                 Arg::with_name("source")
                     .short("s")
                     .long("source")
                     .value_name("dir")
-                    .help("source directory")
-                    .required(true),
+                    .help("source directory or tar file. Can be repeated to merge sources [env: RRDPIT_SOURCE=]")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .required(false),
             )
             .arg(
                 Arg::with_name("target")
@@ -66,7 +501,8 @@ impl Options {
                     .long("target")
                     .value_name("dir")
                     .help("target directory")
-                    .required(true),
+                    .env("RRDPIT_TARGET")
+                    .required(false),
             )
             .arg(
                 Arg::with_name("rsync")
@@ -74,7 +510,8 @@ impl Options {
                     .long("rsync")
                     .value_name("uri")
                     .help("base rsync uri")
-                    .required(true),
+                    .env("RRDPIT_RSYNC")
+                    .required(false),
             )
             .arg(
                 Arg::with_name("https")
@@ -82,11 +519,277 @@ impl Options {
                     .long("https")
                     .value_name("uri")
                     .help("base rrdp uri")
-                    .required(true),
+                    .env("RRDPIT_HTTPS")
+                    .required(false),
             )
             .arg(
                 Arg::with_name("clean")
-                    .help("Clean up target dir (handle with care!)")
+                    .long("clean")
+                    .help("Clean up target dir (handle with care!) [env: RRDPIT_CLEAN=]")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("gzip")
+                    .long("gzip")
+                    .help("Also write gzip compressed snapshot and delta files")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("pretty")
+                    .long("pretty")
+                    .help("Wrap base64 in snapshot and delta files over lines (changes hashes)")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("hashed_names")
+                    .long("hashed-names")
+                    .help("Include the hash in snapshot and delta file names, for immutable uris")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("fsync")
+                    .long("fsync")
+                    .help("Sync all written files and dirs to disk, to survive a power loss")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("file_mode")
+                    .long("file-mode")
+                    .value_name("mode")
+                    .help("Octal permissions for created files, e.g. 644. Default: as per umask")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("dir_mode")
+                    .long("dir-mode")
+                    .value_name("mode")
+                    .help("Octal permissions for created dirs, e.g. 755. Default: as per umask")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("tmp_dir")
+                    .long("tmp-dir")
+                    .value_name("dir")
+                    .help("Dir for temporary files of atomic saves. Default: next to each file")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("threads")
+                    .long("threads")
+                    .value_name("number")
+                    .help("The number of threads used to read source files. Default: 1")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("include")
+                    .long("include")
+                    .value_name("glob")
+                    .help("Only include source files matching this pattern. Can be repeated")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("exclude")
+                    .long("exclude")
+                    .value_name("glob")
+                    .help("Exclude source files matching this pattern. Can be repeated")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .required(false),
+            )
+            .arg(rsync_map_arg())
+            .arg(
+                Arg::with_name("uri_prefix")
+                    .long("uri-prefix")
+                    .value_name("uri")
+                    .help("Only publish files with an rsync uri starting with this. Can be repeated")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("follow_symlinks")
+                    .long("follow-symlinks")
+                    .help("Traverse symlinked directories in the source directory")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("include_hidden")
+                    .long("include-hidden")
+                    .help("Include source files and dirs with a name starting with a '.'")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("max_depth")
+                    .long("max-depth")
+                    .value_name("number")
+                    .help("Fail on source dirs nested deeper than this. Default: 64")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("mmap")
+                    .long("mmap")
+                    .help("Memory-map large source files. Only safe if they are not changed meanwhile")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("file_index")
+                    .long("file-index")
+                    .help("Keep an index in the target to skip reading unchanged source files")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("max_file_size")
+                    .long("max-file-size")
+                    .value_name("bytes")
+                    .help("Skip source files larger than this size. Default: unlimited")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("fail_on_large_files")
+                    .long("fail-on-large-files")
+                    .help("Fail, rather than skip, when a file exceeds --max-file-size")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("dry_run")
+                    .long("dry-run")
+                    .help("Show what would be done, without writing or removing anything")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("output_format")
+                    .long("output-format")
+                    .value_name("format")
+                    .possible_values(&["text", "keyvalue", "json"])
+                    .help("The format of the summary printed after a run. Default: text")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("json")
+                    .long("json")
+                    .help("Print the summary as JSON. Same as --output-format json")
+                    .conflicts_with("output_format")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("publish_query")
+                    .long("publish-query")
+                    .value_name("file")
+                    .help("Apply an RFC 8181 query to the current snapshot, instead of using --source")
+                    .conflicts_with("source")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("watch")
+                    .long("watch")
+                    .help("Keep running, and update the target whenever the source changes")
+                    .conflicts_with("publish_query")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("rsync_out")
+                    .long("rsync-out")
+                    .value_name("dir")
+                    .help("Also write the files to this dir, for rsyncd. Cleaned if --clean is set")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("checksums_file")
+                    .long("checksums-file")
+                    .value_name("path")
+                    .help("Write the SHA-256 hashes of all files to this path, for sha256sum -c")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("allow_empty")
+                    .long("allow-empty")
+                    .help("Publish an empty repository if no files are found in the sources")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("keep_sessions")
+                    .long("keep-sessions")
+                    .value_name("number")
+                    .help("The number of session dirs kept by --clean. Default: 1. Minimum: 1")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("max_notification_deltas")
+                    .long("max-notification-deltas")
+                    .value_name("number")
+                    .help("List at most this many of the kept deltas in the notification file")
+                    .required(false),
+            )
+            .arg(notification_name_arg())
+            .arg(
+                Arg::with_name("session_id")
+                    .long("session-id")
+                    .value_name("uuid")
+                    .help("Use this session id for a new session, and refuse to continue another")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("verify")
+                    .long("verify")
+                    .help("Read the target back after saving, and fail if it is inconsistent")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("force_new_session")
+                    .long("force-new-session")
+                    .help("Start a new session, so that relying parties fetch the full snapshot")
+                    .conflicts_with("session_id")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("serial_start")
+                    .long("serial-start")
+                    .value_name("number")
+                    .help("The first serial of a new repository. Default: 1. Minimum: 1")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("delta_budget")
+                    .long("delta-budget")
+                    .value_name("bytes")
+                    .help("Keep deltas up to this total size. Default: the size of the snapshot")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("strict")
+                    .long("strict")
+                    .help("Start a new session if the notification file has unknown attributes or elements")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("hash_alg")
+                    .long("hash-alg")
+                    .value_name("alg")
+                    .possible_values(&["sha256", "sha512"])
+                    .help("Hash algorithm. RRDP uses sha256, others need --experimental-hash-alg. Default: sha256")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("experimental_hash_alg")
+                    .long("experimental-hash-alg")
+                    .help("Allow a --hash-alg other than sha256, which RRDP clients will reject")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("metrics_file")
+                    .long("metrics-file")
+                    .value_name("file")
+                    .help("Write metrics in the Prometheus text format to this file")
+                    .required(false),
+            )
+            .arg(
+                Arg::with_name("verbose")
+                    .short("v")
+                    .long("verbose")
+                    .multiple(true)
+                    .help("Log more details, use -vv for debug output")
                     .required(false),
             )
             .arg(
@@ -95,59 +798,578 @@ impl Options {
                     .long("max_deltas")
                     .value_name("number")
                     .help("Limit the maximum number of deltas kept. Default: 25. Minimum: 1")
+                    .env("RRDPIT_MAX_DELTAS")
                     .required(false),
-            )
-            .get_matches();
+            );
 
-        let source = matches.value_of("source").unwrap();
-        let target = matches.value_of("target").unwrap();
-        let rsync = matches.value_of("rsync").unwrap();
-        let https = matches.value_of("https").unwrap();
-        let max_deltas = matches.value_of("max_deltas").unwrap_or("25");
+        #[cfg(feature = "server")]
+        let app = app.subcommand(
+            SubCommand::with_name("serve")
+                .about("Serve the target directory over HTTP")
+                .arg(
+                    Arg::with_name("target")
+                        .short("t")
+                        .long("target")
+                        .value_name("dir")
+                        .help("target directory")
+                        .env("RRDPIT_TARGET")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("listen")
+                        .short("l")
+                        .long("listen")
+                        .value_name("addr")
+                        .help("Address to listen on. Default: 127.0.0.1:3000")
+                        .required(false),
+                ),
+        );
 
-        let clean = matches.is_present("clean");
+        let app = app.subcommand(
+            SubCommand::with_name("verify")
+                .about("Verify the snapshot and deltas of a remote repository")
+                .arg(
+                    Arg::with_name("https")
+                        .short("h")
+                        .long("https")
+                        .value_name("uri")
+                        .help("base rrdp uri of the remote repository")
+                        .env("RRDPIT_HTTPS")
+                        .required(true),
+                ),
+        );
 
-        Self::from_strs(source, target, rsync, https, clean, max_deltas)
-    }
-}
+        let app = app.subcommand(
+            SubCommand::with_name("repair")
+                .about("Rebuild a lost notification file from the snapshot and delta files")
+                .arg(
+                    Arg::with_name("target")
+                        .short("t")
+                        .long("target")
+                        .value_name("dir")
+                        .help("target directory")
+                        .env("RRDPIT_TARGET")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("https")
+                        .short("h")
+                        .long("https")
+                        .value_name("uri")
+                        .help("base rrdp uri for the notification file")
+                        .env("RRDPIT_HTTPS")
+                        .required(true),
+                )
+                .arg(notification_name_arg()),
+        );
 
-//------------ Error ---------------------------------------------------------
+        let app = app.subcommand(
+            SubCommand::with_name("prune")
+                .about("Remove the session and serial dirs no longer in the notification file")
+                .arg(
+                    Arg::with_name("target")
+                        .short("t")
+                        .long("target")
+                        .value_name("dir")
+                        .help("target directory")
+                        .env("RRDPIT_TARGET")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("max_deltas")
+                        .short("m")
+                        .long("max-deltas")
+                        .alias("max_deltas")
+                        .value_name("number")
+                        .help("Limit the maximum number of deltas kept. Minimum: 1")
+                        .env("RRDPIT_MAX_DELTAS")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("keep_sessions")
+                        .long("keep-sessions")
+                        .value_name("number")
+                        .help("The number of session dirs kept. Default: 1. Minimum: 1")
+                        .required(false),
+                )
+                .arg(notification_name_arg()),
+        );
 
-#[derive(Debug, Display)]
-pub enum Error {
-    #[display(fmt = "Not a directory: {}", _0)]
-    CannotRead(String),
+        let app = app.subcommand(
+            SubCommand::with_name("stats")
+                .about("Report the number of objects and the size of the snapshot and deltas")
+                .arg(
+                    Arg::with_name("target")
+                        .short("t")
+                        .long("target")
+                        .value_name("dir")
+                        .help("target directory")
+                        .env("RRDPIT_TARGET")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print the stats as JSON"),
+                )
+                .arg(notification_name_arg()),
+        );
 
-    #[display(fmt = "Not a directory: {}", _0)]
-    RsyncBaseUri(String),
+        let app = app.subcommand(
+            SubCommand::with_name("diff")
+                .about("Show what would change between two source directories")
+                .arg(
+                    Arg::with_name("old")
+                        .long("old")
+                        .value_name("dir")
+                        .help("source directory with the old content")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .long("new")
+                        .value_name("dir")
+                        .help("source directory with the new content")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("rsync")
+                        .short("r")
+                        .long("rsync")
+                        .value_name("uri")
+                        .help("base rsync uri")
+                        .env("RRDPIT_RSYNC")
+                        .required(true),
+                )
+                .arg(rsync_map_arg())
+                .arg(
+                    Arg::with_name("xml")
+                        .long("xml")
+                        .help("Print the delta XML"),
+                ),
+        );
 
-    #[display(fmt = "Not a directory: {}", _0)]
-    HttpsBaseUri(String),
+        let app = app.subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Write the snapshot XML for a source directory to stdout")
+                .arg(
+                    Arg::with_name("source")
+                        .short("s")
+                        .long("source")
+                        .value_name("dir")
+                        .help("source directory")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("rsync")
+                        .short("r")
+                        .long("rsync")
+                        .value_name("uri")
+                        .help("base rsync uri")
+                        .env("RRDPIT_RSYNC")
+                        .required(true),
+                )
+                .arg(rsync_map_arg()),
+        );
 
-    #[display(fmt = "Cannot parse number: {}", _0)]
-    CannotParseNumber(String),
+        app
+    }
 
-    #[display(fmt = "max_deltas must be at least 1")]
-    MaxDeltasMustBeOneOrHigher,
-}
+    fn from_matches(matches: &ArgMatches) -> Result<Options, Error> {
+        // Values are taken from the command line first, then from the
+        // environment, and finally from the config file, if any.
+        let config = match matches.value_of("config") {
+            Some(path) => ConfigFile::read(Path::new(path))?,
+            None => ConfigFile::default(),
+        };
 
-impl Error {
-    fn cannot_read(source: PathBuf) -> Self {
-        Error::CannotRead(source.to_string_lossy().to_string())
-    }
-}
+        // Note that clap appends the value of an environment variable to
+        // multiple values given on the command line, so we only fall back to
+        // the environment here if no sources were given.
+        let env_source = env::var("RRDPIT_SOURCE").ok();
+        let publish_query = matches.value_of("publish_query").map(PathBuf::from);
+        let sources: Vec<&str> = match matches.values_of("source") {
+            _ if publish_query.is_some() => vec![],
+            Some(values) => values.collect(),
+            None => match env_source.as_ref() {
+                Some(source) => vec![source.as_str()],
+                None => config.sources(),
+            },
+        };
+        if sources.is_empty() && publish_query.is_none() {
+            return Err(Error::MissingArgument("source".to_string()));
+        }
 
-//------------ Tests ---------------------------------------------------------
+        let target = required(
+            "target",
+            matches.value_of("target").or(config.target.as_deref()),
+        )?;
+        let rsync = required(
+            "rsync",
+            matches.value_of("rsync").or(config.rsync.as_deref()),
+        )?;
+        let https = required(
+            "https",
+            matches.value_of("https").or(config.https.as_deref()),
+        )?;
 
-#[cfg(test)]
-pub mod tests {
+        let max_deltas = match matches.value_of("max_deltas") {
+            Some(max_deltas) => max_deltas.to_string(),
+            None => config.max_deltas.unwrap_or(25).to_string(),
+        };
 
-    use super::*;
+        let clean = matches.is_present("clean")
+            || Self::env_flag("RRDPIT_CLEAN")
+            || config.clean.unwrap_or(false);
+
+        let mut options = Self::from_strs(&sources, target, rsync, https, clean, &max_deltas)?;
+        options.gzip = matches.is_present("gzip");
+        options.pretty = matches.is_present("pretty");
+        options.hashed_names = matches.is_present("hashed_names");
+        options.fsync = matches.is_present("fsync");
+        options.dry_run = matches.is_present("dry_run");
+        options.verbosity = matches.occurrences_of("verbose");
+        options.rsync_out = matches.value_of("rsync_out").map(PathBuf::from);
+        options.checksums_file = matches.value_of("checksums_file").map(PathBuf::from);
+        options.publish_query = publish_query;
+        options.watch = matches.is_present("watch");
+        options.metrics_file = matches.value_of("metrics_file").map(PathBuf::from);
+        options.tmp_dir = matches.value_of("tmp_dir").map(PathBuf::from);
+        options.strict = matches.is_present("strict");
+        options.allow_empty = matches.is_present("allow_empty");
+        options.force_new_session = matches.is_present("force_new_session");
+        options.verify = matches.is_present("verify");
+        if let Some(keep) = matches.value_of("keep_sessions") {
+            options.keep_sessions =
+                usize::from_str(keep).map_err(|_| Error::CannotParseNumber(keep.to_string()))?;
+            if options.keep_sessions < 1 {
+                return Err(Error::KeepSessionsMustBeOneOrHigher);
+            }
+        }
+        if let Some(session_id) = matches.value_of("session_id") {
+            let session_id = Uuid::parse_str(session_id)
+                .map_err(|_| Error::InvalidSessionId(session_id.to_string()))?;
+            options.session_id = Some(session_id);
+        }
+        if let Some(start) = matches.value_of("serial_start") {
+            let start =
+                u64::from_str(start).map_err(|_| Error::CannotParseNumber(start.to_string()))?;
+            if start < 1 {
+                return Err(Error::SerialStartMustBeOneOrHigher);
+            }
+            options.serial_start = Some(start);
+        }
+        if let Some(max) = matches.value_of("max_notification_deltas") {
+            let max =
+                usize::from_str(max).map_err(|_| Error::CannotParseNumber(max.to_string()))?;
+            options.max_notification_deltas = Some(max);
+        }
+        options.notification_name = Command::notification_name(matches)?;
+        if let Some(mode) = matches.value_of("file_mode") {
+            options.file_mode = Some(Self::parse_mode(mode)?);
+        }
+        if let Some(mode) = matches.value_of("dir_mode") {
+            options.dir_mode = Some(Self::parse_mode(mode)?);
+        }
+        if let Some(budget) = matches.value_of("delta_budget") {
+            let budget = usize::from_str(budget)
+                .map_err(|_| Error::CannotParseNumber(budget.to_string()))?;
+            options.delta_budget = Some(budget);
+        }
+        if let Some(alg) = matches.value_of("hash_alg") {
+            options.hash_alg =
+                HashAlg::from_str(alg).map_err(|_| Error::InvalidHashAlg(alg.to_string()))?;
+            // RFC 8182 only allows sha256 hashes, also in the delta elements.
+            if options.hash_alg != HashAlg::Sha256 && !matches.is_present("experimental_hash_alg") {
+                return Err(Error::ExperimentalHashAlg(alg.to_string()));
+            }
+        }
+
+        if let Some(format) = matches.value_of("output_format") {
+            options.output_format = OutputFormat::from_str(format)?;
+        } else if matches.is_present("json") {
+            options.output_format = OutputFormat::Json;
+        }
+
+        if let Some(threads) = matches.value_of("threads") {
+            options.threads = threads
+                .parse::<usize>()
+                .map_err(|_| Error::CannotParseNumber(threads.to_string()))?;
+            if options.threads < 1 {
+                return Err(Error::ThreadsMustBeOneOrHigher);
+            }
+        }
+
+        options.follow_symlinks = matches.is_present("follow_symlinks");
+        options.include_hidden = matches.is_present("include_hidden");
+        options.mmap = matches.is_present("mmap");
+        options.file_index = matches.is_present("file_index");
+        options.fail_on_large_files = matches.is_present("fail_on_large_files");
+
+        if let Some(depth) = matches.value_of("max_depth") {
+            options.max_depth =
+                usize::from_str(depth).map_err(|_| Error::CannotParseNumber(depth.to_string()))?;
+        }
+
+        if let Some(size) = matches.value_of("max_file_size") {
+            let size = size
+                .parse::<u64>()
+                .map_err(|_| Error::CannotParseNumber(size.to_string()))?;
+            options.max_file_size = Some(size);
+        }
+
+        options.rsync_map = Command::rsync_map(matches)?;
+
+        if let Some(values) = matches.values_of("uri_prefix") {
+            for prefix in values {
+                if !prefix.starts_with("rsync://") {
+                    return Err(Error::InvalidUriPrefix(prefix.to_string()));
+                }
+                options.uri_prefixes.push(prefix.to_string());
+            }
+        }
+
+        options.includes = Self::patterns(matches, "include")?;
+        options.excludes = Self::patterns(matches, "exclude")?;
+
+        Ok(options)
+    }
+
+    /// Parses a mapping of a top-level source subdir to an rsync base uri,
+    /// e.g. 'module-a=rsync://host/module-a/'.
+    fn parse_rsync_map(value: &str) -> Result<(String, RsyncUri), Error> {
+        let invalid = || Error::InvalidRsyncMap(value.to_string());
+        let (dir, uri) = value.split_once('=').ok_or_else(invalid)?;
+        let dir = dir.strip_suffix('/').unwrap_or(dir);
+        if dir.is_empty() || dir == "." || dir == ".." || dir.contains(['/', '\\']) {
+            return Err(invalid());
+        }
+        let uri = RsyncUri::base_uri(uri).map_err(|_| invalid())?;
+        Ok((dir.to_string(), uri))
+    }
+
+    /// Parses an octal file mode, with or without a leading '0' or '0o'. Only
+    /// the permission bits, and the setuid, setgid and sticky bits, may be set.
+    fn parse_mode(mode: &str) -> Result<u32, Error> {
+        let digits = mode.strip_prefix("0o").unwrap_or(mode);
+        match u32::from_str_radix(digits, 8) {
+            Ok(parsed) if parsed <= 0o7777 && !digits.starts_with('+') => Ok(parsed),
+            _ => Err(Error::InvalidMode(mode.to_string())),
+        }
+    }
+
+    /// Returns whether a boolean environment variable is set to a true value.
+    fn env_flag(name: &str) -> bool {
+        match env::var(name) {
+            Ok(value) => ["1", "true", "yes"].contains(&value.to_lowercase().as_str()),
+            Err(_) => false,
+        }
+    }
+
+    fn patterns(matches: &ArgMatches, name: &str) -> Result<Vec<Pattern>, Error> {
+        matches
+            .values_of(name)
+            .map(|values| {
+                values
+                    .map(|p| Pattern::new(p).map_err(|_| Error::InvalidPattern(p.to_string())))
+                    .collect()
+            })
+            .unwrap_or_else(|| Ok(vec![]))
+    }
+}
+
+fn required<'a>(name: &str, value: Option<&'a str>) -> Result<&'a str, Error> {
+    value.ok_or_else(|| Error::MissingArgument(name.to_string()))
+}
+
+/// The '--notification-name' argument, which the subcommands that work on a
+/// target share with the main command.
+fn notification_name_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("notification_name")
+        .long("notification-name")
+        .value_name("name")
+        .help("The name of the notification file. Default: notification.xml")
+        .required(false)
+}
+
+/// The '--rsync-map' argument, which the subcommands that crawl sources share
+/// with the main command.
+fn rsync_map_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("rsync_map")
+        .long("rsync-map")
+        .value_name("subdir=uri")
+        .help("Use this rsync base uri for a top-level source subdir. Can be repeated")
+        .multiple(true)
+        .number_of_values(1)
+        .required(false)
+}
+
+//------------ OutputFormat --------------------------------------------------
+
+/// The format of the summary printed after a run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// A human readable line.
+    Text,
+
+    /// A single line of space separated key=value pairs.
+    KeyValue,
+
+    /// A single JSON object.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "keyvalue" => Ok(OutputFormat::KeyValue),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(Error::InvalidOutputFormat(s.to_string())),
+        }
+    }
+}
+
+//------------ ConfigFile ----------------------------------------------------
+
+/// The contents of a TOML configuration file. All values are optional, so
+/// that they can be combined with command line arguments.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    source: Option<ConfigSources>,
+    target: Option<String>,
+    rsync: Option<String>,
+    https: Option<String>,
+    clean: Option<bool>,
+    max_deltas: Option<usize>,
+}
+
+/// The source can be a single directory, or a list of directories.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ConfigSources {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl ConfigFile {
+    fn read(path: &Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)
+            .map_err(|_| Error::CannotReadConfig(path.to_string_lossy().to_string()))?;
+        toml::from_str(&content).map_err(|e| Error::InvalidConfig(e.to_string()))
+    }
+
+    fn sources(&self) -> Vec<&str> {
+        match &self.source {
+            None => vec![],
+            Some(ConfigSources::One(source)) => vec![source.as_str()],
+            Some(ConfigSources::Many(sources)) => sources.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+//------------ Error ---------------------------------------------------------
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "Not a directory: {}", _0)]
+    CannotRead(String),
+
+    #[display(fmt = "Not a directory: {}", _0)]
+    RsyncBaseUri(String),
+
+    #[display(fmt = "Not a directory: {}", _0)]
+    HttpsBaseUri(String),
+
+    #[display(fmt = "Cannot parse number: {}", _0)]
+    CannotParseNumber(String),
+
+    #[display(fmt = "{}", _0)]
+    InvalidArguments(String),
+
+    #[display(fmt = "Missing required argument: --{}", _0)]
+    MissingArgument(String),
+
+    #[display(fmt = "Unsupported output format: {}", _0)]
+    InvalidOutputFormat(String),
+
+    #[display(fmt = "Unsupported hash algorithm: {}", _0)]
+    InvalidHashAlg(String),
+
+    #[display(
+        fmt = "Hash algorithm {} is not allowed in RRDP, use --experimental-hash-alg",
+        _0
+    )]
+    ExperimentalHashAlg(String),
+
+    #[display(fmt = "Cannot read config file: {}", _0)]
+    CannotReadConfig(String),
+
+    #[display(fmt = "Invalid config file: {}", _0)]
+    InvalidConfig(String),
+
+    #[display(fmt = "Invalid glob pattern: {}", _0)]
+    InvalidPattern(String),
+
+    #[display(fmt = "Invalid listen address: {}", _0)]
+    InvalidListenAddress(String),
+
+    #[display(fmt = "Invalid session id: {}", _0)]
+    InvalidSessionId(String),
+
+    #[display(fmt = "Notification name must be a plain file name: {}", _0)]
+    InvalidNotificationName(String),
+
+    #[display(fmt = "Invalid octal file mode: {}", _0)]
+    InvalidMode(String),
+
+    #[display(
+        fmt = "Invalid rsync map, expected subdir=rsync://host/module/: {}",
+        _0
+    )]
+    InvalidRsyncMap(String),
+
+    #[display(fmt = "Invalid uri prefix, expected an rsync uri: {}", _0)]
+    InvalidUriPrefix(String),
+
+    #[display(fmt = "max_deltas must be at least 1")]
+    MaxDeltasMustBeOneOrHigher,
+
+    #[display(fmt = "threads must be at least 1")]
+    ThreadsMustBeOneOrHigher,
+
+    #[display(fmt = "keep-sessions must be at least 1")]
+    KeepSessionsMustBeOneOrHigher,
+
+    #[display(fmt = "serial-start must be at least 1")]
+    SerialStartMustBeOneOrHigher,
+}
+
+impl Error {
+    fn cannot_read(source: PathBuf) -> Self {
+        Error::CannotRead(source.to_string_lossy().to_string())
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+pub mod tests {
+
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Guards tests that parse arguments, as these depend on the process wide
+    /// environment variables.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
 
     #[test]
     fn parse_arguments() {
         Options::from_strs(
-            "./test-resources/source-1",
+            &["./test-resources/source-1"],
             "./test-work",
             "rsync://localhost/repo/",
             "https://localhost/repo/",
@@ -156,4 +1378,645 @@ pub mod tests {
         )
         .unwrap();
     }
+
+    #[test]
+    fn parse_include_exclude() {
+        let options = parse(&[
+            "--include",
+            "**/*.cer",
+            "--include",
+            "**/*.roa",
+            "--exclude",
+            "*.tmp",
+        ])
+        .unwrap();
+
+        assert_eq!(2, options.includes.len());
+        assert_eq!(1, options.excludes.len());
+    }
+
+    #[test]
+    fn parse_multiple_sources() {
+        let options = parse(&["--source", "./test-resources/source-2"]).unwrap();
+        assert_eq!(
+            vec![
+                PathBuf::from("./test-resources/source-1"),
+                PathBuf::from("./test-resources/source-2")
+            ],
+            options.sources
+        );
+    }
+
+    #[test]
+    fn parse_output_format() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(OutputFormat::Text, options.output_format);
+
+        let options = parse(&["--output-format", "keyvalue"]).unwrap();
+        assert_eq!(OutputFormat::KeyValue, options.output_format);
+
+        let options = parse(&["--json"]).unwrap();
+        assert_eq!(OutputFormat::Json, options.output_format);
+
+        assert!(parse(&["--output-format", "xml"]).is_err());
+    }
+
+    #[test]
+    fn new_does_not_touch_disk() {
+        let options = Options::new(
+            vec![PathBuf::from("./does-not-exist/source")],
+            PathBuf::from("./does-not-exist/target"),
+            RsyncUri::base_uri("rsync://localhost/repo/").unwrap(),
+            HttpsUri::base_uri("https://localhost/rrdp/").unwrap(),
+            false,
+            25,
+        );
+
+        assert_eq!(
+            vec![PathBuf::from("./does-not-exist/source")],
+            options.sources
+        );
+        assert_eq!(PathBuf::from("./does-not-exist/target"), options.target);
+        assert_eq!(25, options.max_deltas);
+        assert_eq!(1, options.threads);
+    }
+
+    #[test]
+    #[cfg(feature = "server")]
+    fn parse_serve_command() {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let command =
+            Command::from_arg_iter(vec!["rrdpit", "serve", "--target", "./test-work"]).unwrap();
+        match command {
+            Command::Serve(options) => {
+                assert_eq!(PathBuf::from("./test-work"), options.target);
+                assert_eq!("127.0.0.1:3000".parse(), Ok(options.listen));
+            }
+            _ => panic!("Expected serve command"),
+        }
+
+        let res = Command::from_arg_iter(vec![
+            "rrdpit",
+            "serve",
+            "--target",
+            "./test-work",
+            "--listen",
+            "localhost",
+        ]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn parse_verify_command() {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let command = Command::from_arg_iter(vec![
+            "rrdpit",
+            "verify",
+            "--https",
+            "https://example.com/rrdp/",
+        ])
+        .unwrap();
+        match command {
+            Command::Verify(options) => {
+                assert_eq!(HttpsUri::from("https://example.com/rrdp/"), options.https);
+            }
+            _ => panic!("Expected verify command"),
+        }
+
+        let res = Command::from_arg_iter(vec!["rrdpit", "verify", "--https", "http://x/"]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn parse_repair_command() {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        fs::create_dir_all("./test-work").unwrap();
+
+        let command = Command::from_arg_iter(vec![
+            "rrdpit",
+            "repair",
+            "--target",
+            "./test-work",
+            "--https",
+            "https://example.com/rrdp/",
+        ])
+        .unwrap();
+        match command {
+            Command::Repair(options) => {
+                assert_eq!(PathBuf::from("./test-work"), options.target);
+                assert_eq!(HttpsUri::from("https://example.com/rrdp/"), options.https);
+            }
+            _ => panic!("Expected repair command"),
+        }
+    }
+
+    #[test]
+    fn parse_prune_command() {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        fs::create_dir_all("./test-work").unwrap();
+
+        let command = Command::from_arg_iter(vec![
+            "rrdpit",
+            "prune",
+            "--target",
+            "./test-work",
+            "--max-deltas",
+            "5",
+            "--keep-sessions",
+            "2",
+            "--notification-name",
+            "repo-a.xml",
+        ])
+        .unwrap();
+        match command {
+            Command::Prune(options) => {
+                assert_eq!(PathBuf::from("./test-work"), options.target);
+                assert_eq!("repo-a.xml", options.notification_name);
+                assert_eq!(5, options.max_deltas);
+                assert_eq!(2, options.keep_sessions);
+            }
+            _ => panic!("Expected prune command"),
+        }
+
+        let res = Command::from_arg_iter(vec![
+            "rrdpit",
+            "prune",
+            "--target",
+            "./test-work",
+            "--max-deltas",
+            "0",
+        ]);
+        match res {
+            Err(Error::MaxDeltasMustBeOneOrHigher) => {}
+            _ => panic!("Expected MaxDeltasMustBeOneOrHigher"),
+        }
+    }
+
+    #[test]
+    fn parse_stats_command() {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        fs::create_dir_all("./test-work").unwrap();
+
+        let command =
+            Command::from_arg_iter(vec!["rrdpit", "stats", "--target", "./test-work", "--json"])
+                .unwrap();
+        match command {
+            Command::Stats(options) => {
+                assert_eq!(PathBuf::from("./test-work"), options.target);
+                assert_eq!(NOTIFICATION_FILE, options.notification_name);
+                assert!(options.json);
+            }
+            _ => panic!("Expected stats command"),
+        }
+    }
+
+    #[test]
+    fn parse_diff_command() {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let command = Command::from_arg_iter(vec![
+            "rrdpit",
+            "diff",
+            "--old",
+            "./test-resources/source-1",
+            "--new",
+            "./test-resources/source-2",
+            "--rsync",
+            "rsync://localhost/repo/",
+            "--rsync-map",
+            "a=rsync://localhost/a/",
+        ])
+        .unwrap();
+        match command {
+            Command::Diff(options) => {
+                assert_eq!(PathBuf::from("./test-resources/source-1"), options.old);
+                assert_eq!(PathBuf::from("./test-resources/source-2"), options.new);
+                assert_eq!(
+                    vec![("a".to_string(), RsyncUri::from("rsync://localhost/a/"))],
+                    options.rsync_map
+                );
+                assert!(!options.xml);
+            }
+            _ => panic!("Expected diff command"),
+        }
+    }
+
+    #[test]
+    fn parse_snapshot_command() {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let command = Command::from_arg_iter(vec![
+            "rrdpit",
+            "snapshot",
+            "--source",
+            "./test-resources/source-1",
+            "--rsync",
+            "rsync://localhost/repo/",
+        ])
+        .unwrap();
+        match command {
+            Command::Snapshot(options) => {
+                assert_eq!(PathBuf::from("./test-resources/source-1"), options.source);
+                assert_eq!(RsyncUri::from("rsync://localhost/repo/"), options.rsync);
+            }
+            _ => panic!("Expected snapshot command"),
+        }
+    }
+
+    #[test]
+    fn parse_verbosity() {
+        assert_eq!(LevelFilter::Warn, parse(&[]).unwrap().log_level());
+        assert_eq!(LevelFilter::Info, parse(&["-v"]).unwrap().log_level());
+        assert_eq!(LevelFilter::Debug, parse(&["-vv"]).unwrap().log_level());
+        assert_eq!(LevelFilter::Trace, parse(&["-vvv"]).unwrap().log_level());
+    }
+
+    #[test]
+    fn reject_zero_max_deltas() {
+        let res = Options::from_strs(
+            &["./test-resources/source-1"],
+            "./test-work",
+            "rsync://localhost/repo/",
+            "https://localhost/repo/",
+            false,
+            "0",
+        );
+
+        match res {
+            Err(Error::MaxDeltasMustBeOneOrHigher) => {}
+            _ => panic!("Expected MaxDeltasMustBeOneOrHigher"),
+        }
+    }
+
+    fn parse(extra: &[&str]) -> Result<Options, Error> {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        Options::from_arg_iter(args(extra))
+    }
+
+    fn args(extra: &[&str]) -> Vec<String> {
+        let mut args = vec![
+            "rrdpit",
+            "--source",
+            "./test-resources/source-1",
+            "--target",
+            "./test-work",
+            "--rsync",
+            "rsync://localhost/repo/",
+            "--https",
+            "https://localhost/repo/",
+        ];
+        args.extend_from_slice(extra);
+        args.into_iter().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn parse_clean_flag() {
+        let options = parse(&["--clean"]).unwrap();
+        assert!(options.clean);
+
+        let options = parse(&[]).unwrap();
+        assert!(!options.clean);
+    }
+
+    #[test]
+    fn parse_session_id() {
+        let session = "4fcd0d5b-1b0b-4a68-a7b1-b7d7b5f1f0b5";
+        let options = parse(&["--session-id", session]).unwrap();
+        assert_eq!(Some(Uuid::parse_str(session).unwrap()), options.session_id);
+
+        let options = parse(&[]).unwrap();
+        assert_eq!(None, options.session_id);
+
+        match parse(&["--session-id", "not-a-uuid"]) {
+            Err(Error::InvalidSessionId(_)) => {}
+            _ => panic!("Expected InvalidSessionId"),
+        }
+    }
+
+    #[test]
+    fn parse_verify_flag() {
+        assert!(parse(&["--verify"]).unwrap().verify);
+        assert!(!parse(&[]).unwrap().verify);
+    }
+
+    #[test]
+    fn parse_hashed_names() {
+        let options = parse(&["--hashed-names"]).unwrap();
+        assert!(options.hashed_names);
+        assert!(options.save_options().hashed_names);
+        assert!(!parse(&[]).unwrap().hashed_names);
+    }
+
+    #[test]
+    fn parse_hash_alg() {
+        assert_eq!(HashAlg::Sha256, parse(&[]).unwrap().hash_alg);
+        let options = parse(&["--hash-alg", "sha256"]).unwrap();
+        assert_eq!(HashAlg::Sha256, options.save_options().hash_alg);
+
+        match parse(&["--hash-alg", "sha512"]) {
+            Err(Error::ExperimentalHashAlg(alg)) => assert_eq!("sha512", alg),
+            other => panic!("Expected ExperimentalHashAlg, got: {:?}", other.map(|_| ())),
+        }
+
+        let options = parse(&["--hash-alg", "sha512", "--experimental-hash-alg"]).unwrap();
+        assert_eq!(HashAlg::Sha512, options.crawl_options().hash_alg);
+        assert_eq!(HashAlg::Sha512, options.save_options().hash_alg);
+    }
+
+    #[test]
+    fn parse_fsync() {
+        let options = parse(&["--fsync"]).unwrap();
+        assert!(options.fsync);
+        assert!(options.save_options().fsync);
+        assert!(!parse(&[]).unwrap().fsync);
+    }
+
+    #[test]
+    fn parse_file_modes() {
+        let options = parse(&["--file-mode", "644", "--dir-mode", "0o2775"]).unwrap();
+        assert_eq!(Some(0o644), options.file_modes().file);
+        assert_eq!(Some(0o2775), options.file_modes().dir);
+        assert_eq!(FileModes::default(), parse(&[]).unwrap().file_modes());
+
+        for mode in &["", "8", "rw-r--r--", "17777"] {
+            match parse(&["--file-mode", mode]) {
+                Err(Error::InvalidMode(_)) => {}
+                _ => panic!("Expected InvalidMode for: {}", mode),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_include_hidden() {
+        assert!(
+            parse(&["--include-hidden"])
+                .unwrap()
+                .crawl_options()
+                .include_hidden
+        );
+        assert!(!parse(&[]).unwrap().crawl_options().include_hidden);
+    }
+
+    #[test]
+    fn parse_max_depth() {
+        assert_eq!(
+            8,
+            parse(&["--max-depth", "8"])
+                .unwrap()
+                .crawl_options()
+                .max_depth
+        );
+        assert_eq!(DEFAULT_MAX_DEPTH, parse(&[]).unwrap().max_depth);
+        match parse(&["--max-depth", "deep"]) {
+            Err(Error::CannotParseNumber(_)) => {}
+            _ => panic!("Expected CannotParseNumber"),
+        }
+    }
+
+    #[test]
+    fn parse_mmap() {
+        assert!(parse(&["--mmap"]).unwrap().crawl_options().mmap);
+        assert!(!parse(&[]).unwrap().crawl_options().mmap);
+    }
+
+    #[test]
+    fn parse_file_index() {
+        assert!(parse(&["--file-index"]).unwrap().file_index);
+        assert!(!parse(&[]).unwrap().file_index);
+    }
+
+    #[test]
+    fn parse_checksums_file() {
+        let options = parse(&["--checksums-file", "./rsync/SHA256SUMS"]).unwrap();
+        assert_eq!(
+            Some(PathBuf::from("./rsync/SHA256SUMS")),
+            options.checksums_file
+        );
+        assert_eq!(None, parse(&[]).unwrap().checksums_file);
+    }
+
+    #[test]
+    fn parse_rsync_map() {
+        let options = parse(&[
+            "--rsync-map",
+            "module-a/=rsync://host/module-a/",
+            "--rsync-map",
+            "module-b=rsync://host/module-b/",
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![
+                (
+                    "module-a".to_string(),
+                    RsyncUri::from("rsync://host/module-a/")
+                ),
+                (
+                    "module-b".to_string(),
+                    RsyncUri::from("rsync://host/module-b/")
+                ),
+            ],
+            options.crawl_options().rsync_map
+        );
+        assert!(parse(&[]).unwrap().rsync_map.is_empty());
+
+        for map in &[
+            "module-a",
+            "=rsync://host/a/",
+            "a/b=rsync://host/a/",
+            "a=rsync://host/a",
+        ] {
+            match parse(&["--rsync-map", map]) {
+                Err(Error::InvalidRsyncMap(_)) => {}
+                _ => panic!("Expected InvalidRsyncMap for: {}", map),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_uri_prefixes() {
+        let options = parse(&[
+            "--uri-prefix",
+            "rsync://host/repo/a/",
+            "--uri-prefix",
+            "rsync://host/repo/b/",
+        ])
+        .unwrap();
+        assert_eq!(
+            vec!["rsync://host/repo/a/", "rsync://host/repo/b/"],
+            options.crawl_options().uri_prefixes
+        );
+        assert!(parse(&[]).unwrap().uri_prefixes.is_empty());
+
+        match parse(&["--uri-prefix", "https://host/repo/"]) {
+            Err(Error::InvalidUriPrefix(_)) => {}
+            _ => panic!("Expected InvalidUriPrefix"),
+        }
+    }
+
+    #[test]
+    fn parse_tmp_dir() {
+        let options = parse(&["--tmp-dir", "/var/tmp/rrdpit"]).unwrap();
+        assert_eq!(Some(PathBuf::from("/var/tmp/rrdpit")), options.tmp_dir);
+        assert_eq!(None, parse(&[]).unwrap().tmp_dir);
+    }
+
+    #[test]
+    fn parse_force_new_session() {
+        assert!(parse(&["--force-new-session"]).unwrap().force_new_session);
+        assert!(!parse(&[]).unwrap().force_new_session);
+    }
+
+    #[test]
+    fn parse_notification_name() {
+        let options = parse(&["--notification-name", "repo-a.xml"]).unwrap();
+        assert_eq!("repo-a.xml", options.notification_name);
+        assert_eq!("notification.xml", parse(&[]).unwrap().notification_name);
+
+        for name in &["", "..", "a/notification.xml"] {
+            match parse(&["--notification-name", name]) {
+                Err(Error::InvalidNotificationName(_)) => {}
+                _ => panic!("Expected InvalidNotificationName for: {}", name),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_max_notification_deltas() {
+        let options = parse(&["--max-notification-deltas", "3"]).unwrap();
+        assert_eq!(Some(3), options.max_notification_deltas);
+        assert_eq!(Some(3), options.save_options().max_notification_deltas);
+        assert_eq!(None, parse(&[]).unwrap().max_notification_deltas);
+    }
+
+    #[test]
+    fn parse_serial_start() {
+        let options = parse(&["--serial-start", "1000"]).unwrap();
+        assert_eq!(Some(1000), options.serial_start);
+
+        let options = parse(&[]).unwrap();
+        assert_eq!(None, options.serial_start);
+
+        match parse(&["--serial-start", "0"]) {
+            Err(Error::SerialStartMustBeOneOrHigher) => {}
+            _ => panic!("Expected SerialStartMustBeOneOrHigher"),
+        }
+    }
+
+    #[test]
+    fn parse_from_environment() {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        env::set_var("RRDPIT_SOURCE", "./test-resources/source-2");
+        env::set_var("RRDPIT_TARGET", "./test-work");
+        env::set_var("RRDPIT_RSYNC", "rsync://localhost/repo/");
+        env::set_var("RRDPIT_HTTPS", "https://localhost/repo/");
+        env::set_var("RRDPIT_MAX_DELTAS", "10");
+        env::set_var("RRDPIT_CLEAN", "true");
+
+        let from_env = Options::from_arg_iter(vec!["rrdpit"]);
+        let overridden = Options::from_arg_iter(args(&["--max_deltas", "5"]));
+
+        for name in &[
+            "RRDPIT_SOURCE",
+            "RRDPIT_TARGET",
+            "RRDPIT_RSYNC",
+            "RRDPIT_HTTPS",
+            "RRDPIT_MAX_DELTAS",
+            "RRDPIT_CLEAN",
+        ] {
+            env::remove_var(name);
+        }
+
+        let from_env = from_env.unwrap();
+        assert_eq!(
+            vec![PathBuf::from("./test-resources/source-2")],
+            from_env.sources
+        );
+        assert_eq!(PathBuf::from("./test-work"), from_env.target);
+        assert_eq!("rsync://localhost/repo/", from_env.rsync.to_string());
+        assert_eq!("https://localhost/repo/", from_env.https.to_string());
+        assert_eq!(10, from_env.max_deltas);
+        assert!(from_env.clean);
+
+        // Command line arguments take precedence
+        let overridden = overridden.unwrap();
+        assert_eq!(
+            vec![PathBuf::from("./test-resources/source-1")],
+            overridden.sources
+        );
+        assert_eq!(5, overridden.max_deltas);
+    }
+
+    #[test]
+    fn parse_requires_source() {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let res = Options::from_arg_iter(vec![
+            "rrdpit",
+            "--target",
+            "./test-work",
+            "--rsync",
+            "rsync://localhost/repo/",
+            "--https",
+            "https://localhost/repo/",
+        ]);
+        assert!(res.is_err());
+    }
+
+    const CONFIG: &str = r#"
+source = "./test-resources/source-1"
+target = "./test-work"
+rsync = "rsync://localhost/repo/"
+https = "https://localhost/repo/"
+clean = true
+max_deltas = 10
+"#;
+
+    fn save_config(name: &str) -> PathBuf {
+        let path = PathBuf::from(format!("./test-work/{}/rrdpit.toml", name));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, CONFIG).unwrap();
+        path
+    }
+
+    #[test]
+    fn parse_config_file() {
+        let path = save_config("parse_config_file");
+        let options = Options::from_config(&path).unwrap();
+
+        assert_eq!(
+            vec![PathBuf::from("./test-resources/source-1")],
+            options.sources
+        );
+        assert_eq!(PathBuf::from("./test-work"), options.target);
+        assert_eq!("rsync://localhost/repo/", options.rsync.to_string());
+        assert_eq!("https://localhost/repo/", options.https.to_string());
+        assert!(options.clean);
+        assert_eq!(10, options.max_deltas);
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let path = save_config("command_line_overrides_config_file");
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let options = Options::from_arg_iter(vec![
+            "rrdpit",
+            "--config",
+            path.to_str().unwrap(),
+            "--source",
+            "./test-resources/source-2",
+            "--max_deltas",
+            "5",
+        ])
+        .unwrap();
+
+        assert_eq!(
+            vec![PathBuf::from("./test-resources/source-2")],
+            options.sources
+        );
+        assert_eq!("rsync://localhost/repo/", options.rsync.to_string());
+        assert!(options.clean);
+        assert_eq!(5, options.max_deltas);
+    }
 }
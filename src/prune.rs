@@ -0,0 +1,228 @@
+//! Prunes a target directory, i.e. removes the session and serial dirs that
+//! are no longer referenced by its notification file, without saving a new
+//! serial.
+
+use std::io;
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+use lock::{self, TargetLock};
+use rrdp::{self, Notification};
+use storage::FsStorage;
+use sync;
+
+//------------ PruneOptions --------------------------------------------------
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PruneOptions {
+    /// The target directory, containing the notification file.
+    pub target: PathBuf,
+
+    /// The name of the notification file in the target directory.
+    pub notification_name: String,
+
+    /// The maximum number of deltas to keep in the notification file.
+    pub max_deltas: usize,
+
+    /// The number of session dirs kept, including the current session.
+    pub keep_sessions: usize,
+}
+
+//------------ PruneSummary --------------------------------------------------
+
+/// Describes what a prune did.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PruneSummary {
+    session: Uuid,
+    serial: u64,
+    deltas: usize,
+    removed: Vec<PathBuf>,
+}
+
+impl PruneSummary {
+    pub fn session(&self) -> Uuid {
+        self.session
+    }
+
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    /// The number of deltas left in the notification file.
+    pub fn deltas(&self) -> usize {
+        self.deltas
+    }
+
+    pub fn removed(&self) -> &[PathBuf] {
+        &self.removed
+    }
+}
+
+/// Prunes the target dir. If the notification file has more than the
+/// maximum number of deltas, it is rewritten with only the newest ones
+/// first. Then the session dirs other than the current one and the most
+/// recent others are removed, as well as the serial dirs of the current
+/// session that are older than the oldest serial in the notification file.
+///
+/// Nothing referenced by the notification file, as it is after this
+/// returns, is removed.
+pub fn prune(options: &PruneOptions) -> Result<PruneSummary, Error> {
+    let _lock = TargetLock::acquire(&options.target)?;
+    let path = options.target.join(&options.notification_name);
+    let bytes = sync::read(&path).map_err(|_| rrdp::Error::NotificationUnreadable {
+        path: path.to_string_lossy().to_string(),
+    })?;
+    let notification = Notification::from_xml(bytes)?;
+    let session = notification.session();
+    let serial = notification.serial();
+
+    let mut deltas = notification.deltas().clone();
+    if deltas.len() > options.max_deltas {
+        deltas.truncate(options.max_deltas);
+        info!("Keeping the newest {} deltas", deltas.len());
+        let notification =
+            Notification::new(session, serial, notification.snapshot().clone(), deltas);
+        sync::save_atomic(notification.write_xml().as_ref(), &path)?;
+        deltas = notification.deltas().clone();
+    }
+
+    let oldest_serial = deltas.back().map(|d| d.serial()).unwrap_or(serial);
+    let removed = rrdp::clean_dirs(
+        &FsStorage::default(),
+        &options.target,
+        session,
+        Some(oldest_serial),
+        options.keep_sessions,
+        false,
+    )?;
+
+    Ok(PruneSummary {
+        session,
+        serial,
+        deltas: deltas.len(),
+        removed,
+    })
+}
+
+//------------ Error ---------------------------------------------------------
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "{}", _0)]
+    Rrdp(rrdp::Error),
+
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+
+    #[display(fmt = "{}", _0)]
+    Lock(lock::Error),
+}
+
+impl From<rrdp::Error> for Error {
+    fn from(e: rrdp::Error) -> Self {
+        Error::Rrdp(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<lock::Error> for Error {
+    fn from(e: lock::Error) -> Self {
+        Error::Lock(e)
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rrdp::{RepoState, SaveOptions, NOTIFICATION_FILE};
+    use std::fs;
+    use sync::HttpsUri;
+    use test_util::{create_repo, numbered_serials};
+
+    const BASE_URI: &str = "https://localhost/rrdp/";
+
+    #[test]
+    fn prune_unreferenced_dirs() {
+        const WORK_DIR: &str = "./test-work/prune_unreferenced_dirs/";
+        let target = PathBuf::from(WORK_DIR).join("target");
+        let https = HttpsUri::from(BASE_URI);
+        let save_options = SaveOptions {
+            delta_budget: Some(usize::MAX),
+            ..Default::default()
+        };
+        let session = create_repo(&target, &https, numbered_serials(4), &save_options).session();
+        let session_dir = target.join(session.to_string());
+
+        let orphan_session = target.join(Uuid::new_v4().to_string());
+        fs::create_dir_all(orphan_session.join("1")).unwrap();
+        fs::create_dir_all(session_dir.join("0")).unwrap();
+        fs::create_dir_all(session_dir.join("other")).unwrap();
+
+        let mut options = PruneOptions {
+            target: target.clone(),
+            notification_name: NOTIFICATION_FILE.to_string(),
+            max_deltas: 25,
+            keep_sessions: 1,
+        };
+
+        // The deltas for serials 2 to 4 are still referenced, the snapshot
+        // for serial 1 and the orphans are not.
+        let summary = prune(&options).unwrap();
+        assert_eq!(session, summary.session());
+        assert_eq!(4, summary.serial());
+        assert_eq!(3, summary.deltas());
+        let mut removed = summary.removed().to_vec();
+        removed.sort();
+        let mut expected = vec![orphan_session, session_dir.join("0"), session_dir.join("1")];
+        expected.sort();
+        assert_eq!(expected, removed);
+        assert_eq!(
+            3,
+            RepoState::reconstitute(https.clone(), target.clone())
+                .unwrap()
+                .deltas()
+                .len()
+        );
+
+        // With fewer deltas, the notification file is rewritten first.
+        options.max_deltas = 1;
+        let summary = prune(&options).unwrap();
+        assert_eq!(1, summary.deltas());
+        let mut removed = summary.removed().to_vec();
+        removed.sort();
+        assert_eq!(vec![session_dir.join("2"), session_dir.join("3")], removed);
+        assert!(session_dir.join("4/delta.xml").exists());
+        assert!(session_dir.join("4/snapshot.xml").exists());
+        assert!(session_dir.join("other").exists());
+
+        let state = RepoState::reconstitute(https, target).unwrap();
+        assert_eq!(4, state.serial());
+        assert_eq!(1, state.deltas().len());
+    }
+
+    #[test]
+    fn prune_without_notification() {
+        const WORK_DIR: &str = "./test-work/prune_without_notification/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        fs::create_dir_all(WORK_DIR).unwrap();
+
+        let options = PruneOptions {
+            target: PathBuf::from(WORK_DIR),
+            notification_name: NOTIFICATION_FILE.to_string(),
+            max_deltas: 25,
+            keep_sessions: 1,
+        };
+        match prune(&options) {
+            Err(Error::Rrdp(rrdp::Error::NotificationUnreadable { .. })) => {}
+            res => panic!("Expected NotificationUnreadable, got: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,368 @@
+//! Repairs a target directory that lost its notification file, by rebuilding
+//! it from the snapshot and delta files that are still on disk.
+
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use bytes::Bytes;
+use uuid::Uuid;
+
+use lock::{self, TargetLock};
+use rrdp::{self, Delta, DeltaRef, FileRef, Notification, SessionState, Snapshot};
+use storage::FsStorage;
+use sync::{self, HttpsUri};
+
+//------------ RepairOptions -------------------------------------------------
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RepairOptions {
+    /// The target directory, containing the session dirs.
+    pub target: PathBuf,
+
+    /// The base uri of the repository, where the notification file is found.
+    pub https: HttpsUri,
+
+    /// The name of the notification file in the target directory.
+    pub notification_name: String,
+}
+
+//------------ RepairSummary -------------------------------------------------
+
+/// Describes the notification file written by a repair.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RepairSummary {
+    session: Uuid,
+    serial: u64,
+    deltas: usize,
+    excluded: Vec<PathBuf>,
+}
+
+impl RepairSummary {
+    pub fn session(&self) -> Uuid {
+        self.session
+    }
+
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    /// The number of deltas in the notification file.
+    pub fn deltas(&self) -> usize {
+        self.deltas
+    }
+
+    /// The snapshot and delta files that were left out.
+    pub fn excluded(&self) -> &[PathBuf] {
+        &self.excluded
+    }
+}
+
+/// Rebuilds the notification file in the target dir. It refers to the valid
+/// snapshot with the highest serial in the most recently modified session,
+/// and to the contiguous deltas leading up to it.
+///
+/// Files that cannot be parsed, or that are not for the session and serial
+/// of their dir, are excluded. So are deltas that are not contiguous. All
+/// excluded files are logged, and listed in the summary.
+pub fn repair(options: &RepairOptions) -> Result<RepairSummary, Error> {
+    let _lock = TargetLock::acquire(&options.target)?;
+    let mut excluded = vec![];
+
+    for (_, session) in rrdp::session_dirs(&FsStorage::default(), &options.target) {
+        let session_dir = options.target.join(session.to_string());
+        let mut serials = serial_dirs(&session_dir);
+        serials.sort_by(|a, b| b.cmp(a));
+        let mut serials = serials.into_iter();
+
+        // Find the snapshot, excluding higher serials without one.
+        let mut snapshot = None;
+        for serial in serials.by_ref() {
+            let path = find_file(&session_dir, serial, "snapshot");
+            if let Some(bytes) = read_snapshot(&path, session, serial) {
+                snapshot = Some((serial, path, bytes));
+                break;
+            }
+            exclude(&mut excluded, path);
+            exclude(&mut excluded, find_file(&session_dir, serial, "delta"));
+        }
+        let (serial, snapshot_path, snapshot) = match snapshot {
+            Some(snapshot) => snapshot,
+            None => continue,
+        };
+
+        // Take the deltas for this serial and below, as long as they are
+        // contiguous, and exclude any deltas for lower serials after that.
+        let mut deltas = VecDeque::new();
+        let mut next = Some(serial);
+        for delta_serial in Some(serial).into_iter().chain(serials) {
+            let path = find_file(&session_dir, delta_serial, "delta");
+            if next == Some(delta_serial) {
+                if let Some(bytes) = read_delta(&path, session, delta_serial) {
+                    let uri = file_uri(&options.https, &options.target, &path);
+                    deltas.push_back(DeltaRef::new(delta_serial, FileRef::new(uri, &bytes)));
+                    next = delta_serial.checked_sub(1);
+                    continue;
+                }
+                next = None;
+            }
+            exclude(&mut excluded, path);
+        }
+
+        let uri = file_uri(&options.https, &options.target, &snapshot_path);
+        let snapshot_ref = FileRef::new(uri, &snapshot);
+        let deltas_len = deltas.len();
+        let notification = Notification::new(session, serial, snapshot_ref, deltas);
+        sync::save_atomic(
+            notification.write_xml().as_ref(),
+            &options.target.join(&options.notification_name),
+        )?;
+        SessionState::new(session, serial).save(&options.target)?;
+
+        return Ok(RepairSummary {
+            session,
+            serial,
+            deltas: deltas_len,
+            excluded,
+        });
+    }
+
+    Err(Error::NoSnapshot)
+}
+
+/// Returns the serials of the serial dirs in a session dir.
+fn serial_dirs(session_dir: &Path) -> Vec<u64> {
+    fs::read_dir(session_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            if !entry.file_type().ok()?.is_dir() {
+                return None;
+            }
+            rrdp::serial_dir(entry.file_name().to_str()?)
+        })
+        .collect()
+}
+
+/// Returns the path of the snapshot or delta file in a serial dir. This is
+/// the first file with a name including a hash, if there is one, because
+/// such names are used when configured, and the plain name otherwise.
+fn find_file(session_dir: &Path, serial: u64, kind: &str) -> PathBuf {
+    let serial_dir = session_dir.join(serial.to_string());
+    let mut hashed: Vec<_> = fs::read_dir(&serial_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            if name != rrdp::file_name(kind, None) && rrdp::is_file_name(kind, &name) {
+                Some(name)
+            } else {
+                None
+            }
+        })
+        .collect();
+    hashed.sort();
+    match hashed.into_iter().next() {
+        Some(name) => serial_dir.join(name),
+        None => serial_dir.join(rrdp::file_name(kind, None)),
+    }
+}
+
+/// Returns the uri for a file in the target dir.
+fn file_uri(https: &HttpsUri, target: &Path, path: &Path) -> HttpsUri {
+    let rel = path.strip_prefix(target).unwrap_or(path);
+    let rel: Vec<_> = rel
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+    https.resolve(&rel.join("/"))
+}
+
+/// Excludes the file, if it exists.
+fn exclude(excluded: &mut Vec<PathBuf>, path: PathBuf) {
+    if path.exists() {
+        warn!("Excluding: {}", path.display());
+        excluded.push(path);
+    }
+}
+
+fn read(path: &Path) -> Option<Bytes> {
+    let bytes = sync::read(&path.to_path_buf()).ok()?;
+    sync::gunzip_if_compressed(bytes).ok()
+}
+
+/// Reads the snapshot file, if it is for the session and serial.
+fn read_snapshot(path: &Path, session: Uuid, serial: u64) -> Option<Bytes> {
+    let bytes = read(path)?;
+    let snapshot = Snapshot::from_xml(bytes.clone()).ok()?;
+    if snapshot.session() == session && snapshot.serial() == serial {
+        Some(bytes)
+    } else {
+        None
+    }
+}
+
+/// Reads the delta file, if it is for the session and serial.
+fn read_delta(path: &Path, session: Uuid, serial: u64) -> Option<Bytes> {
+    let bytes = read(path)?;
+    let delta = Delta::from_xml(bytes.clone()).ok()?;
+    if *delta.session() == session && delta.serial() == serial {
+        Some(bytes)
+    } else {
+        None
+    }
+}
+
+//------------ Error ---------------------------------------------------------
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "No valid snapshot found to repair from")]
+    NoSnapshot,
+
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+
+    #[display(fmt = "{}", _0)]
+    Lock(lock::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<lock::Error> for Error {
+    fn from(e: lock::Error) -> Self {
+        Error::Lock(e)
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rrdp::{RepoState, SaveOptions, NOTIFICATION_FILE};
+    use test_util::{create_repo, numbered_serials};
+
+    const BASE_URI: &str = "https://localhost/rrdp/";
+
+    #[test]
+    fn repair_lost_notification() {
+        const WORK_DIR: &str = "./test-work/repair_lost_notification/";
+        let target = PathBuf::from(WORK_DIR).join("target");
+        let https = HttpsUri::from(BASE_URI);
+        let session = create_repo(
+            &target,
+            &https,
+            numbered_serials(3),
+            &SaveOptions::default(),
+        )
+        .session();
+        let expected = RepoState::reconstitute(https.clone(), target.clone()).unwrap();
+
+        fs::remove_file(target.join("notification.xml")).unwrap();
+        assert!(RepoState::reconstitute(https.clone(), target.clone()).is_err());
+
+        let options = RepairOptions {
+            target: target.clone(),
+            https: https.clone(),
+            notification_name: NOTIFICATION_FILE.to_string(),
+        };
+        let summary = repair(&options).unwrap();
+        assert_eq!(session, summary.session());
+        assert_eq!(3, summary.serial());
+        assert_eq!(2, summary.deltas());
+        assert!(summary.excluded().is_empty());
+
+        let state = RepoState::reconstitute(https, target).unwrap();
+        assert_eq!(expected, state);
+    }
+
+    #[test]
+    fn repair_with_hashed_names() {
+        const WORK_DIR: &str = "./test-work/repair_with_hashed_names/";
+        let options = SaveOptions {
+            hashed_names: true,
+            ..Default::default()
+        };
+        let target = PathBuf::from(WORK_DIR).join("target");
+        let https = HttpsUri::from(BASE_URI);
+        create_repo(&target, &https, numbered_serials(3), &options);
+        let expected = RepoState::reconstitute(https.clone(), target.clone()).unwrap();
+
+        fs::remove_file(target.join("notification.xml")).unwrap();
+        let options = RepairOptions {
+            target: target.clone(),
+            https: https.clone(),
+            notification_name: NOTIFICATION_FILE.to_string(),
+        };
+        let summary = repair(&options).unwrap();
+        assert_eq!(2, summary.deltas());
+
+        let state = RepoState::reconstitute(https, target).unwrap();
+        assert_eq!(expected, state);
+    }
+
+    #[test]
+    fn repair_excludes_inconsistent_files() {
+        const WORK_DIR: &str = "./test-work/repair_excludes_inconsistent_files/";
+        let target = PathBuf::from(WORK_DIR).join("target");
+        let https = HttpsUri::from(BASE_URI);
+        let session = create_repo(
+            &target,
+            &https,
+            numbered_serials(3),
+            &SaveOptions::default(),
+        )
+        .session();
+        let session_dir = target.join(session.to_string());
+
+        // A broken snapshot for serial 3 means serial 2 is used. A broken
+        // delta for serial 2 means there are no deltas.
+        fs::write(session_dir.join("3/snapshot.xml"), "broken").unwrap();
+        fs::write(session_dir.join("2/delta.xml"), "broken").unwrap();
+        fs::remove_file(target.join("notification.xml")).unwrap();
+
+        let options = RepairOptions {
+            target: target.clone(),
+            https: https.clone(),
+            notification_name: NOTIFICATION_FILE.to_string(),
+        };
+        let summary = repair(&options).unwrap();
+        assert_eq!(2, summary.serial());
+        assert_eq!(0, summary.deltas());
+        assert_eq!(
+            &[
+                session_dir.join("3/snapshot.xml"),
+                session_dir.join("3/delta.xml"),
+                session_dir.join("2/delta.xml"),
+            ],
+            summary.excluded()
+        );
+
+        let state = RepoState::reconstitute(https, target).unwrap();
+        assert_eq!(2, state.serial());
+        assert!(state.deltas().is_empty());
+    }
+
+    #[test]
+    fn repair_without_snapshot() {
+        const WORK_DIR: &str = "./test-work/repair_without_snapshot/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        fs::create_dir_all(WORK_DIR).unwrap();
+
+        let options = RepairOptions {
+            target: PathBuf::from(WORK_DIR),
+            https: HttpsUri::from(BASE_URI),
+            notification_name: NOTIFICATION_FILE.to_string(),
+        };
+        match repair(&options) {
+            Err(Error::NoSnapshot) => {}
+            res => panic!("Expected NoSnapshot, got: {:?}", res),
+        }
+    }
+}
@@ -1,20 +1,36 @@
 //! Data objects used in the (RRDP) repository. I.e. the publish, update, and
 //! withdraw elements, as well as the notification, snapshot and delta file
 //! definitions.
-use std::collections::{HashMap, VecDeque};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
 use std::num::ParseIntError;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::{fmt, io};
+use std::time::SystemTime;
+use std::{fmt, io, slice, vec};
 
 use bytes::Bytes;
+use serde::ser::{SerializeStruct, Serializer};
+#[cfg(feature = "rrdp-serde")]
+use serde::Deserialize;
+use serde::Serialize;
 use uuid::Uuid;
 
-use crate::sync::{self, Base64, CurrentFile, EncodedHash, HttpsUri, RsyncUri};
-use crate::xml::{AttributesError, XmlReader, XmlReaderErr, XmlWriter};
+use crate::storage::{FsStorage, Storage};
+use crate::sync::{
+    self, Base64, CurrentFile, EncodedHash, HashAlg, HashingWriter, HttpsUri, RsyncUri,
+};
+use crate::xml::{
+    Attributes, AttributesError, XmlReader, XmlReaderErr, XmlWriter, DEFAULT_MAX_BYTES,
+};
 
 const VERSION: &str = "1";
 const NS: &str = "http://www.ripe.net/rpki/rrdp";
+const PUBLICATION_VERSION: &str = "4";
+
+/// The default name of the notification file in the base dir.
+pub const NOTIFICATION_FILE: &str = "notification.xml";
 
 //------------ PublishElement ------------------------------------------------
 
@@ -23,6 +39,7 @@ const NS: &str = "http://www.ripe.net/rpki/rrdp";
 /// Note that the difference with the publication protocol is the absence of
 /// the tag.
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "rrdp-serde", derive(Serialize, Deserialize))]
 pub struct PublishElement {
     base64: Base64,
     uri: RsyncUri,
@@ -48,6 +65,7 @@ impl PublishElement {
 /// Note that the difference with the publication protocol is the absence of
 /// the tag.
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "rrdp-serde", derive(Serialize, Deserialize))]
 pub struct UpdateElement {
     uri: RsyncUri,
     hash: EncodedHash,
@@ -73,6 +91,7 @@ impl UpdateElement {
 /// Note that the difference with the publication protocol is the absence of
 /// the tag.
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "rrdp-serde", derive(Serialize, Deserialize))]
 pub struct WithdrawElement {
     uri: RsyncUri,
     hash: EncodedHash,
@@ -89,7 +108,8 @@ impl WithdrawElement {
 
 //------------ Notification --------------------------------------------------
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "rrdp-serde", derive(Serialize, Deserialize))]
 pub struct Notification {
     session: Uuid,
     serial: u64,
@@ -112,6 +132,22 @@ impl Notification {
         }
     }
 
+    pub fn session(&self) -> Uuid {
+        self.session
+    }
+
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    pub fn snapshot(&self) -> &SnapshotRef {
+        &self.snapshot
+    }
+
+    pub fn deltas(&self) -> &VecDeque<DeltaRef> {
+        &self.deltas
+    }
+
     pub fn write_xml(&self) -> Bytes {
         Bytes::from(XmlWriter::encode_vec(|w| {
             let a = [
@@ -124,9 +160,8 @@ impl Notification {
             w.put_element("notification", Some(&a), |w| {
                 {
                     // snapshot ref
-                    let uri = self.snapshot.uri.to_string();
-                    let hash = self.snapshot.hash.to_string();
-                    let a = [("uri", uri.as_str()), ("hash", hash.as_str())];
+                    let hash = self.snapshot.hash.as_str();
+                    let a = [("uri", self.snapshot.uri.as_str()), ("hash", &hash)];
                     w.put_element("snapshot", Some(&a), |w| w.empty())?;
                 }
 
@@ -134,12 +169,11 @@ impl Notification {
                     // delta refs
                     for delta in &self.deltas {
                         let serial = format!("{}", delta.serial);
-                        let uri = delta.file_ref.uri.to_string();
-                        let hash = delta.file_ref.hash.to_string();
+                        let hash = delta.file_ref.hash.as_str();
                         let a = [
                             ("serial", serial.as_ref()),
-                            ("uri", uri.as_str()),
-                            ("hash", hash.as_str()),
+                            ("uri", delta.file_ref.uri.as_str()),
+                            ("hash", &hash),
                         ];
                         w.put_element("delta", Some(&a), |w| w.empty())?;
                     }
@@ -149,6 +183,292 @@ impl Notification {
             })
         }))
     }
+
+    /// Parses a notification file, ignoring unknown attributes and elements.
+    ///
+    /// Note that the sizes of the snapshot and delta files are not part of
+    /// the notification file, so they are zero in the returned references.
+    pub fn from_xml(bytes: Bytes) -> Result<Self, Error> {
+        Self::from_xml_with(bytes, false)
+    }
+
+    /// Parses a notification file. Unknown attributes and elements are an
+    /// error if strict is true, and are ignored otherwise.
+    pub fn from_xml_with(bytes: Bytes, strict: bool) -> Result<Self, Error> {
+        let exhausted = |a: &Attributes| {
+            if strict {
+                a.exhausted().map_err(Error::invalid_xml)
+            } else {
+                Ok(())
+            }
+        };
+
+        XmlReader::decode(bytes.as_ref(), |r| {
+            r.take_named_element("notification", |mut a, r| {
+                let version = a.take_req("version")?;
+                if version != VERSION {
+                    return Err(Error::UnsupportedVersion(version));
+                }
+
+                let session = a.take_req("session_id")?;
+                let session = Uuid::parse_str(&session)?;
+
+                let serial = a.take_req("serial")?;
+                let serial = u64::from_str(&serial)?;
+
+                exhausted(&a)?;
+
+                let mut snapshot = None;
+                let mut deltas = VecDeque::new();
+                while let Some(()) = r.take_opt_element(|t, mut a, r| {
+                    match t.name.as_ref() {
+                        "snapshot" if snapshot.is_none() => {
+                            let uri = a.take_req("uri")?;
+                            let hash = EncodedHash::from_hex(&a.take_req("hash")?)
+                                .map_err(Error::invalid_xml)?;
+                            exhausted(&a)?;
+                            snapshot =
+                                Some(FileRef::without_size(HttpsUri::from(uri.as_str()), hash));
+                        }
+                        "delta" if snapshot.is_some() => {
+                            let serial = a.take_req("serial")?;
+                            let serial = u64::from_str(&serial)?;
+                            let uri = a.take_req("uri")?;
+                            let hash = EncodedHash::from_hex(&a.take_req("hash")?)
+                                .map_err(Error::invalid_xml)?;
+                            exhausted(&a)?;
+                            let file_ref =
+                                FileRef::without_size(HttpsUri::from(uri.as_str()), hash);
+                            deltas.push_back(DeltaRef::new(serial, file_ref));
+                        }
+                        "snapshot" | "delta" => {
+                            return Err(Error::InvalidXml(format!("Unexpected tag: {}", t.name)))
+                        }
+                        _ if !strict => r.skip_content()?,
+                        _ => return Err(Error::InvalidXml(format!("Unexpected tag: {}", t.name))),
+                    }
+                    Ok(Some(()))
+                })? {}
+
+                let snapshot =
+                    snapshot.ok_or_else(|| Error::InvalidXml("Missing snapshot".to_string()))?;
+
+                Ok(Notification {
+                    session,
+                    serial,
+                    snapshot,
+                    deltas,
+                })
+            })
+        })
+    }
+}
+
+//------------ NotificationBuilder -------------------------------------------
+
+/// Builds a notification file from the snapshot and delta files, e.g. for
+/// a mirror that writes these files itself rather than using RepoState. The
+/// hashes and sizes in the references are computed from the file content.
+#[derive(Clone, Debug, Default)]
+pub struct NotificationBuilder {
+    hash_alg: HashAlg,
+    snapshot: Option<SnapshotRef>,
+    deltas: Vec<DeltaRef>,
+}
+
+impl NotificationBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the algorithm used for the hashes, sha256 by default. Set this
+    /// before adding the files.
+    pub fn hash_alg(mut self, alg: HashAlg) -> Self {
+        self.hash_alg = alg;
+        self
+    }
+
+    /// Sets the snapshot file, replacing any snapshot set before.
+    pub fn snapshot(mut self, uri: HttpsUri, bytes: &Bytes) -> Self {
+        self.snapshot = Some(FileRef::new_with(self.hash_alg, uri, bytes));
+        self
+    }
+
+    /// Adds a delta file. Deltas may be added in any order.
+    pub fn add_delta(mut self, serial: u64, uri: HttpsUri, bytes: &Bytes) -> Self {
+        let file_ref = FileRef::new_with(self.hash_alg, uri, bytes);
+        self.deltas.push(DeltaRef::new(serial, file_ref));
+        self
+    }
+
+    /// Returns the notification for the session and serial, listing the
+    /// deltas from the highest serial down, as RepoState does.
+    pub fn build(mut self, session: Uuid, serial: u64) -> Result<Notification, Error> {
+        let snapshot = self.snapshot.ok_or(Error::NoSnapshotRef)?;
+        self.deltas.sort_by_key(|d| Reverse(d.serial));
+        Ok(Notification::new(
+            session,
+            serial,
+            snapshot,
+            self.deltas.into_iter().collect(),
+        ))
+    }
+}
+
+//------------ SaveOptions ---------------------------------------------------
+
+/// Defines how a RepoState is saved to disk.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SaveOptions {
+    /// The maximum number of deltas to keep in the notification file.
+    pub max_deltas: usize,
+
+    /// Clean up unused session dirs and delta dirs for old serials.
+    pub clean: bool,
+
+    /// Also write gzip compressed copies of the snapshot and delta files,
+    /// i.e. 'snapshot.xml.gz' and 'delta.xml.gz'. Note that the hashes and
+    /// sizes in the notification file always refer to the plain files.
+    pub gzip: bool,
+
+    /// The algorithm used for the snapshot and delta hashes in the
+    /// notification file. RFC 8182 only allows sha256.
+    pub hash_alg: HashAlg,
+
+    /// The number of bytes of deltas to keep. Deltas are dropped once the
+    /// deltas before them take up this many bytes. If not set, the size of
+    /// the snapshot is used.
+    pub delta_budget: Option<usize>,
+
+    /// The number of session dirs kept when cleaning, including the current
+    /// session. The other sessions kept are the most recently modified ones.
+    pub keep_sessions: usize,
+
+    /// The maximum number of deltas listed in the notification file, if it
+    /// should be less than the number kept. The delta files for the last
+    /// max_deltas serials are still kept on disk when cleaning. If this is
+    /// zero, the notification file only refers to the snapshot.
+    pub max_notification_deltas: Option<usize>,
+
+    /// Wrap the base64 content of snapshot and delta files over multiple
+    /// lines, for readability. This changes the bytes, and therefore the
+    /// hashes of the files, so it is off by default.
+    pub pretty: bool,
+
+    /// Include the hash of the snapshot and delta files in their names, i.e.
+    /// 'snapshot-{hash}.xml' and 'delta-{hash}.xml', so that their uris
+    /// change whenever their content does, and they can be cached forever.
+    pub hashed_names: bool,
+
+    /// Sync the snapshot and delta files, and the dirs they are in, to stable
+    /// storage before the notification file refers to them, and sync the
+    /// base dir after the notification file is replaced. This makes a save
+    /// survive a power loss, at the cost of waiting for the disk. The
+    /// notification and state files are always synced before they replace
+    /// the previous ones.
+    pub fsync: bool,
+}
+
+impl Default for SaveOptions {
+    fn default() -> Self {
+        SaveOptions {
+            max_deltas: 25,
+            clean: false,
+            gzip: false,
+            hash_alg: HashAlg::Sha256,
+            delta_budget: None,
+            keep_sessions: 1,
+            max_notification_deltas: None,
+            pretty: false,
+            hashed_names: false,
+            fsync: false,
+        }
+    }
+}
+
+//------------ SaveSummary ---------------------------------------------------
+
+/// Describes what saving a RepoState did, or in case of a dry run, would do.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SaveSummary {
+    session: Uuid,
+    serial: u64,
+    snapshot_hash: EncodedHash,
+    snapshot_size: usize,
+    publishes: usize,
+    updates: usize,
+    withdraws: usize,
+    deltas: usize,
+    deltas_size: usize,
+    removed: Vec<PathBuf>,
+}
+
+impl SaveSummary {
+    pub fn session(&self) -> Uuid {
+        self.session
+    }
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+    pub fn snapshot_hash(&self) -> &EncodedHash {
+        &self.snapshot_hash
+    }
+    pub fn snapshot_size(&self) -> usize {
+        self.snapshot_size
+    }
+    pub fn publishes(&self) -> usize {
+        self.publishes
+    }
+    pub fn updates(&self) -> usize {
+        self.updates
+    }
+    pub fn withdraws(&self) -> usize {
+        self.withdraws
+    }
+
+    /// The total number of elements in the new delta, if any.
+    pub fn delta_elements(&self) -> usize {
+        self.publishes + self.updates + self.withdraws
+    }
+
+    /// The number of deltas kept in the notification file.
+    pub fn deltas(&self) -> usize {
+        self.deltas
+    }
+
+    /// The total size of the deltas kept in the notification file.
+    pub fn deltas_size(&self) -> usize {
+        self.deltas_size
+    }
+
+    /// The session and serial directories which were, or would be, removed.
+    pub fn removed(&self) -> &Vec<PathBuf> {
+        &self.removed
+    }
+}
+
+impl Serialize for SaveSummary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let removed: Vec<String> = self
+            .removed
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect();
+
+        let mut s = serializer.serialize_struct("SaveSummary", 11)?;
+        s.serialize_field("session", &self.session.to_string())?;
+        s.serialize_field("serial", &self.serial)?;
+        s.serialize_field("snapshot_hash", &self.snapshot_hash.to_string())?;
+        s.serialize_field("snapshot_size", &self.snapshot_size)?;
+        s.serialize_field("num_deltas", &self.deltas)?;
+        s.serialize_field("deltas_size", &self.deltas_size)?;
+        s.serialize_field("delta_elements", &self.delta_elements())?;
+        s.serialize_field("publishes", &self.publishes)?;
+        s.serialize_field("updates", &self.updates)?;
+        s.serialize_field("withdraws", &self.withdraws)?;
+        s.serialize_field("cleaned", &removed)?;
+        s.end()
+    }
 }
 
 //------------ RepoState ------------------------------------------------------
@@ -164,6 +484,7 @@ impl Notification {
 /// In case the current state cannot be reconstituted this way, a new RepoState,
 /// using a new session id will be used.
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "rrdp-serde", derive(Serialize, Deserialize))]
 pub struct RepoState {
     session: Uuid,
     serial: u64,
@@ -172,6 +493,7 @@ pub struct RepoState {
     deltas: VecDeque<DeltaRef>,
     base_uri: HttpsUri,
     base_dir: PathBuf,
+    notification_name: String,
 }
 
 /// # Data Access
@@ -183,13 +505,24 @@ impl RepoState {
     pub fn serial(&self) -> u64 {
         self.serial
     }
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+    pub fn deltas(&self) -> &VecDeque<DeltaRef> {
+        &self.deltas
+    }
+    pub fn notification_name(&self) -> &str {
+        &self.notification_name
+    }
 }
 
 impl RepoState {
-    /// Creates a new repo state, with a new session id, and serial starting at 1.
+    /// Creates a new repo state without deltas, for the session and serial
+    /// of the snapshot. This is serial 1 for a new session, but may be later
+    /// if a session is continued without its previous state.
     pub fn new(snapshot: Snapshot, base_uri: HttpsUri, base_dir: PathBuf) -> Self {
         let session = snapshot.session;
-        let serial = 1;
+        let serial = snapshot.serial;
 
         let new_delta = None;
         let deltas = VecDeque::new();
@@ -202,184 +535,532 @@ impl RepoState {
             deltas,
             base_uri,
             base_dir,
+            notification_name: NOTIFICATION_FILE.to_string(),
         }
     }
 
+    /// Uses another name for the notification file in the base dir, e.g.
+    /// when the repository is served under another entry point. This does
+    /// not allow several repositories to share a dir, because the session
+    /// dirs and state files in it are not named after the notification file.
+    pub fn with_notification_name(mut self, name: impl Into<String>) -> Self {
+        self.notification_name = name.into();
+        self
+    }
+
     /// Saves a notification file, the snapshot, and the optional new delta to disk.
     ///
     /// If clean is true, this will also delete old sessions and delta/snapshot dirs for
     /// old versions which are no longer referenced in the notification file.
-    pub fn save(mut self, max_deltas: usize, clean: bool) -> Result<(), io::Error> {
+    pub fn save(self, options: &SaveOptions) -> Result<SaveSummary, io::Error> {
+        self.save_to(&FsStorage::default(), options)
+    }
+
+    /// Saves to the given storage rather than the local filesystem. The base
+    /// dir is used as the prefix for all paths in the storage.
+    pub fn save_to<S: Storage>(
+        self,
+        storage: &S,
+        options: &SaveOptions,
+    ) -> Result<SaveSummary, io::Error> {
+        self.process(storage, options, false)
+    }
+
+    /// Determines what 'save' would do, without writing or removing anything.
+    pub fn dry_run(self, options: &SaveOptions) -> Result<SaveSummary, io::Error> {
+        self.dry_run_to(&FsStorage::default(), options)
+    }
+
+    /// Determines what 'save_to' would do for the given storage.
+    pub fn dry_run_to<S: Storage>(
+        self,
+        storage: &S,
+        options: &SaveOptions,
+    ) -> Result<SaveSummary, io::Error> {
+        self.process(storage, options, true)
+    }
+
+    fn process<S: Storage>(
+        mut self,
+        storage: &S,
+        options: &SaveOptions,
+        dry_run: bool,
+    ) -> Result<SaveSummary, io::Error> {
         let serial = self.serial;
         let session = self.session;
 
         // Save new snapshot
-        let snapshot_xml = self.snapshot.write_xml();
-        let snapshot_ref = SnapshotRef::new(self.snapshot_uri(serial), &snapshot_xml);
-        let snapshot_path = self.snapshot_path(serial);
-        sync::save(snapshot_xml.as_ref(), &snapshot_path)?;
+        let snapshot_ref = self.save_snapshot(storage, options, dry_run)?;
 
         // If there is a new delta, save it and add it to top of the list of delta references
         if let Some(delta) = &self.new_delta {
-            let delta_xml = delta.write_xml();
-            let delta_file_ref = FileRef::new(self.delta_uri(serial), &delta_xml);
+            let delta_xml = delta.write_xml_with(options.pretty);
+            let hash = EncodedHash::from_content_with(options.hash_alg, delta_xml.as_ref());
+            let name_hash = Some(&hash).filter(|_| options.hashed_names);
+            let delta_path = self.delta_path(serial, name_hash);
+            let delta_file_ref = FileRef {
+                uri: self.delta_uri(serial, name_hash),
+                size: delta_xml.len(),
+                hash,
+            };
             let delta_ref = DeltaRef::new(serial, delta_file_ref);
-            let delta_path = self.delta_path(serial);
 
-            sync::save(delta_xml.as_ref(), &delta_path)?;
+            if !dry_run {
+                Self::save_xml(storage, &delta_xml, &delta_path, options)?;
+            }
             self.deltas.push_front(delta_ref);
         }
 
-        // First purge deltas in excess of snapshot size
+        // First purge deltas in excess of the budget, by default the snapshot size
         let snapshot_size = snapshot_ref.size();
-        let mut deltas_size = 0;
-        self.deltas.retain(|d| {
-            let add = snapshot_size > deltas_size;
-            deltas_size += d.size();
-            add
-        });
+        Self::retain_deltas(
+            &mut self.deltas,
+            options.delta_budget.unwrap_or(snapshot_size),
+        );
 
         // Truncate any deltas that exceed the max_deltas number
-        self.deltas.truncate(max_deltas);
+        self.deltas.truncate(options.max_deltas);
+
+        // Drop deltas of which the file is gone or changed in size since it
+        // was saved, and all older deltas, so that the notification only lists
+        // deltas relying parties can fetch. The new delta was just written.
+        let new_serial = self.new_delta.as_ref().map(|_| serial);
+        let dangling = self
+            .deltas
+            .iter()
+            .position(|d| Some(d.serial) != new_serial && !self.has_delta_file(storage, d));
+        if let Some(pos) = dangling {
+            warn!(
+                "Delta file for serial {} is missing or changed, dropping it and older deltas",
+                self.deltas[pos].serial
+            );
+            self.deltas.truncate(pos);
+        }
+        let mut last_serial = self.deltas.back().map(|d| d.serial);
+
+        // List fewer deltas in the notification file if so desired. The
+        // unlisted deltas are no longer known after reconstituting, so the
+        // dirs to keep follow from max_deltas instead.
+        if let Some(max) = options.max_notification_deltas {
+            self.deltas.truncate(max);
+            let kept = (options.max_deltas as u64).saturating_sub(1);
+            let oldest = serial.saturating_sub(kept).max(1);
+            last_serial = Some(last_serial.map_or(oldest, |last| last.min(oldest)));
+        }
+        let deltas = self.deltas.len();
+        let deltas_size = self.deltas.iter().map(DeltaRef::size).sum();
+        let snapshot_hash = snapshot_ref.hash().clone();
 
-        let last_serial = self.deltas.back().map(|d| d.serial);
+        // The new files must be durable before the notification refers to them.
+        if !dry_run && options.fsync {
+            self.sync_dirs(storage)?;
+        }
 
         let notification_path = self.notification_path();
         let notification = Notification::new(self.session, self.serial, snapshot_ref, self.deltas);
         let notification_xml = notification.write_xml();
 
-        sync::save(notification_xml.as_ref(), &notification_path)?;
-
-        if clean {
-            // Clean up disk: unused session uuid dirs and unused delta dirs
-            sync::retain_disk(&self.base_dir, |name| name == session.to_string())?;
-
-            if let Some(last_serial) = last_serial {
-                let session_dir = self.base_dir.join(format!("{}/", self.session));
-                sync::retain_disk(&session_dir, |name| {
-                    if let Ok(dir_serial) = u64::from_str(&name) {
-                        dir_serial >= last_serial
-                    } else {
-                        eprintln!("Found dir: {}", &name);
-                        true // keep any other things the user might have added
-                    }
-                })?;
+        if !dry_run {
+            storage.save_atomic(notification_xml.as_ref(), &notification_path)?;
+            SessionState::new(session, serial).save_to(storage, &self.base_dir)?;
+            if options.fsync {
+                storage.sync(&self.base_dir)?;
             }
         }
 
-        Ok(())
-    }
+        let removed = if options.clean {
+            clean_dirs(
+                storage,
+                &self.base_dir,
+                session,
+                last_serial,
+                options.keep_sessions,
+                dry_run,
+            )?
+        } else {
+            vec![]
+        };
 
-    fn notification_path(&self) -> PathBuf {
-        self.base_dir.join(PathBuf::from("notification.xml"))
-    }
+        let (publishes, updates, withdraws) = match &self.new_delta {
+            Some(delta) => (
+                delta.elements.publishes.len(),
+                delta.elements.updates.len(),
+                delta.elements.withdraws.len(),
+            ),
+            None => (0, 0, 0),
+        };
 
-    fn snapshot_uri(&self, serial: u64) -> HttpsUri {
-        self.base_uri.resolve(&self.snapshot_rel(serial))
+        Ok(SaveSummary {
+            session,
+            serial,
+            snapshot_hash,
+            snapshot_size,
+            publishes,
+            updates,
+            withdraws,
+            deltas,
+            deltas_size,
+            removed,
+        })
     }
 
-    fn snapshot_path(&self, serial: u64) -> PathBuf {
-        self.base_dir.join(PathBuf::from(self.snapshot_rel(serial)))
+    /// Returns the names of the session dirs to keep: the current session,
+    /// and the most recently modified other sessions, up to the given total.
+    fn recent_sessions<S: Storage>(
+        storage: &S,
+        base_dir: &Path,
+        current: Uuid,
+        keep: usize,
+    ) -> Vec<String> {
+        let mut sessions = vec![current.to_string()];
+        sessions.extend(
+            session_dirs(storage, base_dir)
+                .into_iter()
+                .filter(|(_, session)| *session != current)
+                .take(keep.saturating_sub(1))
+                .map(|(_, session)| session.to_string()),
+        );
+        sessions
     }
 
-    fn snapshot_rel(&self, serial: u64) -> String {
-        format!("{}/{}/snapshot.xml", &self.session, serial)
+    /// Keeps the newest deltas, as long as the deltas before them take up
+    /// less than the budget in bytes.
+    fn retain_deltas(deltas: &mut VecDeque<DeltaRef>, budget: usize) {
+        let mut deltas_size = 0;
+        deltas.retain(|d| {
+            let add = budget > deltas_size;
+            deltas_size += d.size();
+            add
+        });
     }
 
-    fn delta_uri(&self, serial: u64) -> HttpsUri {
-        self.base_uri.resolve(&self.delta_rel(serial))
+    /// Removes the sub directories of a directory which should not be kept,
+    /// and returns their paths. Plain files, such as the notification file,
+    /// are never removed. In case of a dry run nothing is removed, and the
+    /// directories which would be removed are returned.
+    fn clean<S, P>(
+        storage: &S,
+        dir: &Path,
+        mut keep: P,
+        dry_run: bool,
+    ) -> Result<Vec<PathBuf>, io::Error>
+    where
+        S: Storage,
+        P: FnMut(&str) -> bool,
+    {
+        let mut removed = vec![];
+        for entry in storage.list(dir)? {
+            if !entry.is_dir() || keep(entry.name()) {
+                continue;
+            }
+            let path = dir.join(entry.name());
+            if dry_run {
+                removed.push(path);
+                continue;
+            }
+            match storage.remove(&path) {
+                Ok(()) => {
+                    info!("Removed: {}", path.display());
+                    removed.push(path);
+                }
+                Err(e) => warn!("Could not remove: {}, error: {}", path.display(), e),
+            }
+        }
+        Ok(removed)
     }
 
-    fn delta_path(&self, serial: u64) -> PathBuf {
-        self.base_dir.join(PathBuf::from(self.delta_rel(serial)))
-    }
+    /// Streams the snapshot to disk, and optionally a gzip compressed copy of
+    /// it, while computing its hash and size. In case of a dry run nothing is
+    /// written, but the reference is still computed.
+    ///
+    /// If the hash is part of the file name, it must be known before the file
+    /// is written, so then the snapshot is streamed twice.
+    fn save_snapshot<S: Storage>(
+        &self,
+        storage: &S,
+        options: &SaveOptions,
+        dry_run: bool,
+    ) -> Result<SnapshotRef, io::Error> {
+        let name_hash = if dry_run || options.hashed_names {
+            let mut writer = HashingWriter::new(options.hash_alg, io::sink());
+            self.snapshot.write_xml_to(&mut writer, options.pretty)?;
+            let (_, hash, size) = writer.finish();
+            if dry_run {
+                let hashed = Some(&hash).filter(|_| options.hashed_names);
+                let uri = self.snapshot_uri(self.serial, hashed);
+                return Ok(SnapshotRef { uri, hash, size });
+            }
+            Some(hash)
+        } else {
+            None
+        };
 
-    fn delta_rel(&self, serial: u64) -> String {
-        format!("{}/{}/delta.xml", &self.session, serial)
+        let uri = self.snapshot_uri(self.serial, name_hash.as_ref());
+        let path = self.snapshot_path(self.serial, name_hash.as_ref());
+        debug!("Writing: {}", path.display());
+        let (hash, size) = storage.save_with(&path, |file| {
+            let mut writer = HashingWriter::new(options.hash_alg, file);
+            self.snapshot.write_xml_to(&mut writer, options.pretty)?;
+            writer.flush()?;
+            let (_, hash, size) = writer.finish();
+            Ok((hash, size))
+        })?;
+
+        if options.gzip {
+            storage.gzip(&path, &sync::gzip_path(&path))?;
+        }
+        if options.fsync {
+            Self::sync_file(storage, &path, options.gzip)?;
+        }
+        Ok(SnapshotRef { uri, hash, size })
     }
 
-    pub fn reconstitute(base_uri: HttpsUri, base_dir: PathBuf) -> Result<Self, Error> {
-        let notification_path = base_dir.join("notification.xml");
-        let notification = sync::read(&notification_path).map_err(|_| Error::InvalidRepoState)?;
-
-        XmlReader::decode(notification.as_ref(), |r| {
-            r.take_named_element("notification", |mut a, r| {
-                let version = a.take_req("version")?;
-                if version != "1" {
-                    return Err(Error::InvalidRepoState);
-                }
+    /// Returns whether the file for the delta is still there, with the size
+    /// it had when it was saved or loaded. The file is not hashed again: the
+    /// hash listed in the notification was checked against the content when
+    /// the state was reconstituted, and a new delta was hashed when written.
+    fn has_delta_file<S: Storage>(&self, storage: &S, delta: &DeltaRef) -> bool {
+        let file_ref = &delta.file_ref;
+        match file_path(&self.base_uri, &self.base_dir, &file_ref.uri) {
+            Ok(path) => storage.size(&path).ok() == Some(file_ref.size as u64),
+            Err(_) => false,
+        }
+    }
 
-                let session = a.take_req("session_id")?;
-                let session = Uuid::parse_str(&session)?;
+    /// Saves an XML file, and optionally a gzip compressed copy of it.
+    fn save_xml<S: Storage>(
+        storage: &S,
+        xml: &Bytes,
+        path: &Path,
+        options: &SaveOptions,
+    ) -> Result<(), io::Error> {
+        debug!("Writing: {}", path.display());
+        storage.save(xml.as_ref(), path)?;
+        if options.gzip {
+            storage.save(sync::gzip(xml.as_ref())?.as_ref(), &sync::gzip_path(path))?;
+        }
+        if options.fsync {
+            Self::sync_file(storage, path, options.gzip)?;
+        }
+        Ok(())
+    }
 
-                let serial = a.take_req("serial")?;
-                let serial = u64::from_str(&serial)?;
+    /// Syncs a saved file, and its gzip compressed copy if there is one.
+    fn sync_file<S: Storage>(storage: &S, path: &Path, gzip: bool) -> Result<(), io::Error> {
+        storage.sync(path)?;
+        if gzip {
+            storage.sync(&sync::gzip_path(path))?;
+        }
+        Ok(())
+    }
 
-                a.exhausted().map_err(Error::invalid_xml)?;
+    /// Syncs the serial dir of the current serial, and the session and base
+    /// dirs it is in, so that the new files can be found after a power loss.
+    fn sync_dirs<S: Storage>(&self, storage: &S) -> Result<(), io::Error> {
+        let session_dir = self.base_dir.join(self.session.to_string());
+        storage.sync(&session_dir.join(self.serial.to_string()))?;
+        storage.sync(&session_dir)?;
+        storage.sync(&self.base_dir)
+    }
 
-                let snapshot = r.take_named_element("snapshot", |mut a, _r| {
-                    let uri = a.take_req("uri")?;
-                    let hash = a.take_req("hash")?;
-                    a.exhausted()?;
+    fn notification_path(&self) -> PathBuf {
+        self.base_dir.join(&self.notification_name)
+    }
 
-                    let snapshot_rel = base_uri.relative_to(uri).ok_or(Error::InvalidRepoState)?;
-                    let snapshot_path = base_dir.join(snapshot_rel);
-                    let snapshot =
-                        sync::read(&snapshot_path).map_err(|_| Error::InvalidRepoState)?;
+    fn snapshot_uri(&self, serial: u64, hash: Option<&EncodedHash>) -> HttpsUri {
+        self.base_uri.resolve(&self.snapshot_rel(serial, hash))
+    }
 
-                    let snapshot_hash = EncodedHash::from_content(snapshot.as_ref());
+    fn snapshot_path(&self, serial: u64, hash: Option<&EncodedHash>) -> PathBuf {
+        self.base_dir
+            .join(PathBuf::from(self.snapshot_rel(serial, hash)))
+    }
 
-                    if snapshot_hash.to_string() != hash {
-                        return Err(Error::InvalidRepoState);
-                    }
+    fn snapshot_rel(&self, serial: u64, hash: Option<&EncodedHash>) -> String {
+        format!(
+            "{}/{}/{}",
+            &self.session,
+            serial,
+            file_name("snapshot", hash)
+        )
+    }
 
-                    Snapshot::from_xml(snapshot)
-                })?;
+    fn delta_uri(&self, serial: u64, hash: Option<&EncodedHash>) -> HttpsUri {
+        self.base_uri.resolve(&self.delta_rel(serial, hash))
+    }
 
-                let new_delta = None;
+    fn delta_path(&self, serial: u64, hash: Option<&EncodedHash>) -> PathBuf {
+        self.base_dir
+            .join(PathBuf::from(self.delta_rel(serial, hash)))
+    }
 
-                let mut deltas = VecDeque::new();
+    fn delta_rel(&self, serial: u64, hash: Option<&EncodedHash>) -> String {
+        format!("{}/{}/{}", &self.session, serial, file_name("delta", hash))
+    }
 
-                while let Some(delta) =
-                    r.take_opt_element(|t, mut a, _r| match t.name.as_ref() {
-                        "delta" => {
-                            let serial = a.take_req("serial")?;
-                            let serial = u64::from_str(&serial)?;
+    pub fn reconstitute(base_uri: HttpsUri, base_dir: PathBuf) -> Result<Self, Error> {
+        Self::reconstitute_with(base_uri, base_dir, false)
+    }
 
-                            let uri = a.take_req("uri")?;
-                            let hash = a.take_req("hash")?;
-                            a.exhausted()?;
+    /// Reconstitutes the state from disk. If strict is false, unknown
+    /// attributes and elements in the notification file are ignored, so that
+    /// files written by newer versions or other implementations can be read.
+    ///
+    /// Snapshot and delta files may be stored gzip compressed. They are
+    /// decompressed before their hashes are checked, because the hashes in
+    /// the notification file are always over the uncompressed content.
+    pub fn reconstitute_with(
+        base_uri: HttpsUri,
+        base_dir: PathBuf,
+        strict: bool,
+    ) -> Result<Self, Error> {
+        Self::reconstitute_from(&FsStorage::default(), base_uri, base_dir, strict)
+    }
 
-                            let rel = base_uri.relative_to(uri).ok_or(Error::InvalidRepoState)?;
+    /// Reconstitutes the state from the given storage rather than the local
+    /// filesystem.
+    pub fn reconstitute_from<S: Storage>(
+        storage: &S,
+        base_uri: HttpsUri,
+        base_dir: PathBuf,
+        strict: bool,
+    ) -> Result<Self, Error> {
+        Self::reconstitute_named(storage, base_uri, base_dir, NOTIFICATION_FILE, strict)
+    }
 
-                            let uri = base_uri.resolve(&rel);
-                            let path = base_dir.join(rel);
+    /// Reconstitutes the state starting with the notification file with the
+    /// given name, rather than the default.
+    pub fn reconstitute_named<S: Storage>(
+        storage: &S,
+        base_uri: HttpsUri,
+        base_dir: PathBuf,
+        notification_name: &str,
+        strict: bool,
+    ) -> Result<Self, Error> {
+        let (base, dir) = (base_uri.clone(), base_dir.clone());
+        Self::load_named(
+            base_uri,
+            base_dir,
+            notification_name,
+            strict,
+            |kind, uri| read_file(storage, &base, &dir, kind, uri),
+        )
+    }
 
-                            let file = sync::read(&path).map_err(|_| Error::InvalidRepoState)?;
-                            let file_ref = FileRef::new(uri, &file);
+    /// Loads the state starting with the notification file under the base
+    /// uri. The notification file, and the snapshot and delta files it refers
+    /// to, are read using the given closure. Their hashes must match, and the
+    /// snapshot must be for the session and serial of the notification file.
+    ///
+    /// The deltas must have contiguous serials, down from the serial of the
+    /// notification file. If there is a gap, the deltas after it cannot be
+    /// used to catch up. They are dropped, so that the next save lists a
+    /// contiguous chain again, or in strict mode this is an error.
+    ///
+    /// The closure returns files as stored. Snapshot and delta files that are
+    /// gzip compressed are decompressed before their hashes are checked, but
+    /// the size of a delta is that of the file as stored.
+    pub fn load<F, E>(
+        base_uri: HttpsUri,
+        base_dir: PathBuf,
+        strict: bool,
+        read: F,
+    ) -> Result<Self, E>
+    where
+        F: FnMut(FileKind, &HttpsUri) -> Result<Bytes, E>,
+        E: From<Error>,
+    {
+        Self::load_named(base_uri, base_dir, NOTIFICATION_FILE, strict, read)
+    }
 
-                            if file_ref.hash().to_string() != hash {
-                                return Err(Error::InvalidRepoState);
-                            }
+    /// Loads the state starting with the notification file with the given
+    /// name under the base uri.
+    pub fn load_named<F, E>(
+        base_uri: HttpsUri,
+        base_dir: PathBuf,
+        notification_name: &str,
+        strict: bool,
+        mut read: F,
+    ) -> Result<Self, E>
+    where
+        F: FnMut(FileKind, &HttpsUri) -> Result<Bytes, E>,
+        E: From<Error>,
+    {
+        let notification = read(FileKind::Notification, &base_uri.resolve(notification_name))?;
+        let notification = Notification::from_xml_with(notification, strict)?;
+
+        let snapshot_uri = notification.snapshot.uri;
+        let expected = notification.snapshot.hash;
+        let alg = expected.alg();
+        let snapshot = decompress(&snapshot_uri, read(FileKind::Snapshot, &snapshot_uri)?)?;
+        let found = EncodedHash::from_content_with(alg, snapshot.as_ref());
+        if found != expected {
+            return Err(Error::SnapshotHashMismatch {
+                uri: snapshot_uri,
+                expected: expected.to_string(),
+                found: found.to_string(),
+            }
+            .into());
+        }
+        let snapshot = Snapshot::from_xml_with(alg, snapshot)?;
+        if snapshot.session != notification.session || snapshot.serial != notification.serial {
+            return Err(Error::SnapshotMismatch.into());
+        }
 
-                            Ok(Some(DeltaRef::new(serial, file_ref)))
-                        }
-                        _ => Err(Error::InvalidXml(format!("Unexpected tag: {}", t.name))),
-                    })?
-                {
-                    deltas.push_back(delta)
+        let mut listed: Vec<_> = notification.deltas.into_iter().collect();
+        listed.sort_by_key(|d| Reverse(d.serial));
+
+        let mut deltas = VecDeque::new();
+        let mut next = Some(notification.serial);
+        for delta in listed {
+            if next != Some(delta.serial) {
+                let expected = next.unwrap_or(0);
+                if strict {
+                    return Err(Error::DeltaGap {
+                        expected,
+                        found: delta.serial,
+                    }
+                    .into());
                 }
+                warn!(
+                    "Expected delta for serial {}, found {}, dropping it and older deltas",
+                    expected, delta.serial
+                );
+                break;
+            }
+            next = delta.serial.checked_sub(1);
+
+            // The hash is over the uncompressed content, but the size is that
+            // of the file as stored.
+            let expected = delta.file_ref.hash;
+            let file = read(FileKind::Delta, &delta.file_ref.uri)?;
+            let size = file.len();
+            let file = decompress(&delta.file_ref.uri, file)?;
+            let file_ref = FileRef {
+                hash: EncodedHash::from_content_with(expected.alg(), file.as_ref()),
+                uri: delta.file_ref.uri,
+                size,
+            };
+            if file_ref.hash != expected {
+                return Err(Error::DeltaHashMismatch {
+                    found: file_ref.hash.to_string(),
+                    uri: file_ref.uri,
+                    expected: expected.to_string(),
+                }
+                .into());
+            }
+            deltas.push_back(DeltaRef::new(delta.serial, file_ref));
+        }
 
-                Ok(RepoState {
-                    session,
-                    serial,
-                    snapshot,
-                    new_delta,
-                    deltas,
-                    base_uri,
-                    base_dir,
-                })
-            })
+        Ok(RepoState {
+            session: notification.session,
+            serial: notification.serial,
+            snapshot,
+            new_delta: None,
+            deltas,
+            base_uri,
+            base_dir,
+            notification_name: notification_name.to_string(),
         })
     }
 
@@ -393,7 +1074,11 @@ impl RepoState {
         }
 
         // Must be the next snapshot for this state.
-        if new_snapshot.serial != self.serial + 1 || new_snapshot.session != self.session {
+        let next = self
+            .serial
+            .checked_add(1)
+            .ok_or(Error::SerialOverflow(self.serial))?;
+        if new_snapshot.serial != next || new_snapshot.session != self.session {
             return Err(Error::InvalidDelta);
         }
 
@@ -402,29 +1087,303 @@ impl RepoState {
         if !delta.is_empty() {
             self.snapshot = new_snapshot;
             self.new_delta = Some(delta);
-            self.serial += 1;
+            self.serial = next;
         }
 
         Ok(())
     }
 }
 
-//------------ FileRef -------------------------------------------------------
+//------------ Session and serial dirs ---------------------------------------
 
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct FileRef {
-    uri: HttpsUri,
-    hash: EncodedHash,
-    size: usize,
+/// Cleans up the base dir: removes unused session uuid dirs, keeping the
+/// given session and the most recent others up to keep_sessions in total,
+/// and removes the serial dirs in the session before the oldest serial that
+/// is still used. Returns the removed paths, or the paths that would be
+/// removed in case of a dry run.
+pub(crate) fn clean_dirs<S: Storage>(
+    storage: &S,
+    base_dir: &Path,
+    session: Uuid,
+    oldest_serial: Option<u64>,
+    keep_sessions: usize,
+    dry_run: bool,
+) -> Result<Vec<PathBuf>, io::Error> {
+    let mut removed = vec![];
+
+    let sessions = RepoState::recent_sessions(storage, base_dir, session, keep_sessions);
+    let keep_session = |name: &str| sessions.iter().any(|s| s == name);
+    removed.append(&mut RepoState::clean(
+        storage,
+        base_dir,
+        keep_session,
+        dry_run,
+    )?);
+
+    if let Some(oldest_serial) = oldest_serial {
+        let session_dir = base_dir.join(format!("{}/", session));
+        let keep_serial = |name: &str| {
+            if let Some(dir_serial) = serial_dir(name) {
+                dir_serial >= oldest_serial
+            } else {
+                warn!("Retaining unexpected dir: {}", name);
+                true // keep any other things the user might have added
+            }
+        };
+        removed.append(&mut RepoState::clean(
+            storage,
+            &session_dir,
+            keep_serial,
+            dry_run,
+        )?);
+    }
+
+    Ok(removed)
 }
 
-impl FileRef {
-    pub fn new(uri: HttpsUri, bytes: &Bytes) -> Self {
-        let hash = EncodedHash::from_content(bytes.as_ref());
-        let size = bytes.len();
+/// Returns the session dirs in the base dir, most recently modified first.
+pub(crate) fn session_dirs<S: Storage>(storage: &S, base_dir: &Path) -> Vec<(SystemTime, Uuid)> {
+    let mut sessions: Vec<_> = storage
+        .list(base_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            if !entry.is_dir() {
+                return None;
+            }
+            let session = Uuid::parse_str(entry.name()).ok()?;
+            Some((entry.modified(), session))
+        })
+        .collect();
+    sessions.sort_by(|a, b| b.cmp(a));
+    sessions
+}
+
+/// Returns the name of a snapshot or delta file in a serial dir, with the
+/// hash of its content if given.
+pub(crate) fn file_name(kind: &str, hash: Option<&EncodedHash>) -> String {
+    match hash {
+        Some(hash) => format!("{}-{}.xml", kind, hash),
+        None => format!("{}.xml", kind),
+    }
+}
+
+/// Returns whether the name is that of a snapshot or delta file in a serial
+/// dir, with or without a hash.
+pub(crate) fn is_file_name(kind: &str, name: &str) -> bool {
+    match name
+        .strip_prefix(kind)
+        .and_then(|name| name.strip_suffix(".xml"))
+    {
+        Some("") => true,
+        Some(hash) => hash
+            .strip_prefix('-')
+            .is_some_and(|hash| EncodedHash::from_hex(hash).is_ok()),
+        None => false,
+    }
+}
+
+/// Returns the serial for the name of a serial dir in a session dir.
+pub(crate) fn serial_dir(name: &str) -> Option<u64> {
+    u64::from_str(name).ok()
+}
+
+//------------ SessionState --------------------------------------------------
+
+/// The name of the file next to the notification file that keeps the session
+/// and serial.
+const STATE_FILE: &str = "state.json";
+
+/// The session and serial of the last saved state. These are also kept in a
+/// separate file, so that the session can continue with the next serial if
+/// the notification file is lost or corrupted.
+///
+/// It may also keep a fingerprint of the sources the state was saved for,
+/// so that nothing needs to be done if they did not change.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SessionState {
+    session: Uuid,
+    serial: u64,
+    fingerprint: Option<EncodedHash>,
+}
+
+impl SessionState {
+    pub fn new(session: Uuid, serial: u64) -> Self {
+        SessionState {
+            session,
+            serial,
+            fingerprint: None,
+        }
+    }
+
+    pub fn with_fingerprint(mut self, fingerprint: EncodedHash) -> Self {
+        self.fingerprint = Some(fingerprint);
+        self
+    }
+
+    pub fn session(&self) -> Uuid {
+        self.session
+    }
+
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    pub fn fingerprint(&self) -> Option<&EncodedHash> {
+        self.fingerprint.as_ref()
+    }
+
+    /// Loads the session state from the base dir, if it is there and can
+    /// be parsed.
+    pub fn load(base_dir: &Path) -> Option<Self> {
+        Self::load_from(&FsStorage::default(), base_dir)
+    }
+
+    /// Loads the session state from the base dir in the given storage.
+    pub fn load_from<S: Storage>(storage: &S, base_dir: &Path) -> Option<Self> {
+        let bytes = storage.read(&base_dir.join(STATE_FILE)).ok()?;
+        let value: serde_json::Value = serde_json::from_slice(bytes.as_ref()).ok()?;
+        let session = Uuid::parse_str(value["session"].as_str()?).ok()?;
+        let serial = value["serial"].as_u64()?;
+        let fingerprint = value["fingerprint"]
+            .as_str()
+            .and_then(|hash| EncodedHash::from_hex(hash).ok());
+        Some(SessionState {
+            session,
+            serial,
+            fingerprint,
+        })
+    }
+
+    /// Recovers the session state from the dirs in the base dir, i.e. the
+    /// highest serial dir in the most recently modified session dir.
+    pub fn recover(base_dir: &Path) -> Option<Self> {
+        Self::recover_from(&FsStorage::default(), base_dir)
+    }
+
+    /// Recovers the session state from the dirs in the base dir in the given
+    /// storage.
+    pub fn recover_from<S: Storage>(storage: &S, base_dir: &Path) -> Option<Self> {
+        session_dirs(storage, base_dir)
+            .into_iter()
+            .find_map(|(_, session)| {
+                let serial = storage
+                    .list(&base_dir.join(session.to_string()))
+                    .ok()?
+                    .into_iter()
+                    .filter(|entry| entry.is_dir())
+                    .filter_map(|entry| serial_dir(entry.name()))
+                    .max()?;
+                Some(SessionState::new(session, serial))
+            })
+    }
+
+    /// Saves the session state in the base dir.
+    pub fn save(&self, base_dir: &Path) -> Result<(), io::Error> {
+        self.save_to(&FsStorage::default(), base_dir)
+    }
+
+    /// Saves the session state in the base dir in the given storage.
+    pub fn save_to<S: Storage>(&self, storage: &S, base_dir: &Path) -> Result<(), io::Error> {
+        let mut json = serde_json::json!({
+            "session": self.session.to_string(),
+            "serial": self.serial,
+        });
+        if let Some(fingerprint) = &self.fingerprint {
+            json["fingerprint"] = fingerprint.to_string().into();
+        }
+        storage.save_atomic(json.to_string().as_bytes(), &base_dir.join(STATE_FILE))
+    }
+}
+
+//------------ FileKind ------------------------------------------------------
+
+/// Reads a file of the given kind from the base dir in the storage, for a
+/// uri under the base uri. The content is returned as stored, so snapshot
+/// and delta files may still be compressed, see `decompress`. Files larger
+/// than the maximum size of an XML document are not read at all.
+pub(crate) fn read_file<S: Storage>(
+    storage: &S,
+    base_uri: &HttpsUri,
+    base_dir: &Path,
+    kind: FileKind,
+    uri: &HttpsUri,
+) -> Result<Bytes, Error> {
+    let path = file_path(base_uri, base_dir, uri)?;
+    let read_error = |error: io::Error| {
+        let path = path.to_string_lossy().to_string();
+        if error.kind() != io::ErrorKind::NotFound {
+            return Error::ReadFailed { path, error };
+        }
+        match kind {
+            FileKind::Notification => Error::NotificationUnreadable { path },
+            FileKind::Snapshot => Error::SnapshotFileMissing { path },
+            FileKind::Delta => Error::DeltaFileMissing { path },
+        }
+    };
+
+    let size = storage.size(&path).map_err(read_error)?;
+    if size > DEFAULT_MAX_BYTES {
+        return Err(Error::FileTooLarge {
+            path: path.to_string_lossy().to_string(),
+            size,
+            max: DEFAULT_MAX_BYTES,
+        });
+    }
+    storage.read(&path).map_err(read_error)
+}
+
+/// Returns the path in the base dir for a uri under the base uri.
+fn file_path(base_uri: &HttpsUri, base_dir: &Path, uri: &HttpsUri) -> Result<PathBuf, Error> {
+    let rel = base_uri
+        .relative_to(uri.to_string())
+        .ok_or_else(|| Error::UriOutsideBase(uri.clone()))?;
+    Ok(base_dir.join(rel))
+}
+
+/// Decompresses the content of a snapshot or delta file if it is gzip
+/// compressed, up to the maximum size of an XML document.
+fn decompress(uri: &HttpsUri, content: Bytes) -> Result<Bytes, Error> {
+    sync::gunzip_if_compressed_with(content, Some(DEFAULT_MAX_BYTES))
+        .map_err(|e| Error::InvalidXml(format!("{}: {}", uri, e)))
+}
+
+/// The kind of file read when loading a repository state.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileKind {
+    Notification,
+    Snapshot,
+    Delta,
+}
+
+//------------ FileRef -------------------------------------------------------
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "rrdp-serde", derive(Serialize, Deserialize))]
+pub struct FileRef {
+    uri: HttpsUri,
+    hash: EncodedHash,
+    size: usize,
+}
+
+impl FileRef {
+    pub fn new(uri: HttpsUri, bytes: &Bytes) -> Self {
+        Self::new_with(HashAlg::Sha256, uri, bytes)
+    }
+
+    pub fn new_with(alg: HashAlg, uri: HttpsUri, bytes: &Bytes) -> Self {
+        let hash = EncodedHash::from_content_with(alg, bytes.as_ref());
+        let size = bytes.len();
 
         FileRef { uri, hash, size }
     }
+
+    /// Creates a reference for a file that was not read, such as one found
+    /// in a notification file. Its size is zero.
+    fn without_size(uri: HttpsUri, hash: EncodedHash) -> Self {
+        FileRef { uri, hash, size: 0 }
+    }
+
     pub fn uri(&self) -> &HttpsUri {
         &self.uri
     }
@@ -441,6 +1400,7 @@ impl FileRef {
 pub type SnapshotRef = FileRef;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "rrdp-serde", derive(Serialize, Deserialize))]
 pub struct DeltaRef {
     serial: u64,
     file_ref: FileRef,
@@ -470,6 +1430,7 @@ impl AsRef<FileRef> for DeltaRef {
 
 /// A structure to contain the RRDP snapshot data.
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "rrdp-serde", derive(Serialize, Deserialize))]
 pub struct Snapshot {
     session: Uuid,
     serial: u64,
@@ -477,7 +1438,11 @@ pub struct Snapshot {
 }
 
 impl Snapshot {
-    pub fn new(session: Uuid, serial: u64, current_objects: Vec<CurrentFile>) -> Self {
+    /// Creates a new snapshot. The current objects are sorted by uri, so that
+    /// the snapshot XML does not depend on the order in which files happened
+    /// to be found on disk.
+    pub fn new(session: Uuid, serial: u64, mut current_objects: Vec<CurrentFile>) -> Self {
+        current_objects.sort_by(|a, b| a.uri().cmp(b.uri()));
         Snapshot {
             session,
             serial,
@@ -485,8 +1450,21 @@ impl Snapshot {
         }
     }
 
+    pub fn session(&self) -> Uuid {
+        self.session
+    }
+
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    /// Returns the delta from this snapshot to the new snapshot. Publishes
+    /// and updates reuse the encoded content of the new snapshot, rather
+    /// than copying it, so both refer to the same buffer for every file.
     pub fn to(&self, new_snapshot: &Snapshot) -> Result<Delta, Error> {
-        if self.serial != new_snapshot.serial - 1 || self.session != new_snapshot.session {
+        if self.serial.checked_add(1) != Some(new_snapshot.serial)
+            || self.session != new_snapshot.session
+        {
             return Err(Error::InvalidDelta);
         }
 
@@ -505,7 +1483,8 @@ impl Snapshot {
         for (uri, old_file) in old_files.into_iter() {
             match new_files.remove(uri) {
                 Some(new_file) => {
-                    if new_file != old_file {
+                    // Compare the content, the hashes may use another algorithm.
+                    if new_file.base64() != old_file.base64() {
                         updates.push(UpdateElement {
                             uri: uri.clone(),
                             hash: old_file.hash().clone(),
@@ -527,6 +1506,10 @@ impl Snapshot {
             })
         }
 
+        publishes.sort_by(|a, b| a.uri.cmp(&b.uri));
+        updates.sort_by(|a, b| a.uri.cmp(&b.uri));
+        withdraws.sort_by(|a, b| a.uri.cmp(&b.uri));
+
         let elements = DeltaElements {
             publishes,
             updates,
@@ -548,48 +1531,153 @@ impl Snapshot {
         self.current_objects.is_empty()
     }
 
-    pub fn write_xml(&self) -> Bytes {
-        Bytes::from(XmlWriter::encode_vec(|w| {
-            let a = [
-                ("xmlns", NS),
-                ("version", VERSION),
-                ("session_id", &format!("{}", self.session)),
-                ("serial", &format!("{}", self.serial)),
-            ];
+    /// Returns the hash algorithm used for the current objects.
+    fn hash_alg(&self) -> HashAlg {
+        self.current_objects
+            .first()
+            .map(|f| f.hash().alg())
+            .unwrap_or_default()
+    }
+
+    /// Returns the current objects, sorted by uri.
+    pub fn current_objects(&self) -> &[CurrentFile] {
+        &self.current_objects
+    }
+
+    /// Iterates over the current objects, sorted by uri.
+    pub fn iter(&self) -> slice::Iter<'_, CurrentFile> {
+        self.current_objects.iter()
+    }
+
+    /// Applies publish, update and withdraw elements to this snapshot, and
+    /// returns the snapshot for the next serial. New objects may not exist
+    /// yet, and updated or withdrawn objects must exist with the given hash.
+    pub fn apply_elements(&self, elements: &DeltaElements) -> Result<Snapshot, Error> {
+        let alg = self.hash_alg();
+        let mut files: HashMap<RsyncUri, CurrentFile> = self
+            .current_objects
+            .iter()
+            .map(|f| (f.uri().clone(), f.clone()))
+            .collect();
 
-            w.put_element("snapshot", Some(&a), |w| {
-                for el in &self.current_objects {
-                    let uri = el.uri().to_string();
-                    let b64 = el.base64().to_string();
-                    let atr = [("uri", uri.as_ref())];
-                    w.put_element("publish", Some(&atr), |w| w.put_text(&b64))?;
+        for withdraw in &elements.withdraws {
+            match files.get(&withdraw.uri) {
+                Some(file) if file.has_hash(&withdraw.hash) => {
+                    files.remove(&withdraw.uri);
                 }
-                Ok(())
-            })
-        }))
+                _ => return Err(Error::NoObjectForUriAndHash(withdraw.uri.to_string())),
+            }
+        }
+
+        for update in &elements.updates {
+            match files.get(&update.uri) {
+                Some(file) if file.has_hash(&update.hash) => {
+                    let content = update.base64.decode()?;
+                    files.insert(
+                        update.uri.clone(),
+                        CurrentFile::new_with(alg, update.uri.clone(), &content),
+                    );
+                }
+                _ => return Err(Error::NoObjectForUriAndHash(update.uri.to_string())),
+            }
+        }
+
+        for publish in &elements.publishes {
+            if files.contains_key(&publish.uri) {
+                return Err(Error::ObjectAlreadyExists(publish.uri.to_string()));
+            }
+            let content = publish.base64.decode()?;
+            files.insert(
+                publish.uri.clone(),
+                CurrentFile::new_with(alg, publish.uri.clone(), &content),
+            );
+        }
+
+        let serial = self
+            .serial
+            .checked_add(1)
+            .ok_or(Error::SerialOverflow(self.serial))?;
+        Ok(Snapshot::new(
+            self.session,
+            serial,
+            files.into_values().collect(),
+        ))
+    }
+
+    /// Applies a delta to this snapshot, and returns the resulting snapshot.
+    /// This is the inverse of 'to'. The delta must be for the same session
+    /// and the next serial.
+    pub fn apply_delta(&self, delta: &Delta) -> Result<Snapshot, Error> {
+        if delta.session != self.session || self.serial.checked_add(1) != Some(delta.serial) {
+            return Err(Error::InvalidDelta);
+        }
+        self.apply_elements(&delta.elements)
+    }
+
+    pub fn write_xml(&self) -> Bytes {
+        self.write_xml_with(false)
+    }
+
+    /// Writes the XML, wrapping the base64 content over multiple lines if
+    /// pretty is true.
+    pub fn write_xml_with(&self, pretty: bool) -> Bytes {
+        Bytes::from(XmlWriter::encode_vec_with(pretty, |w| self.put_xml(w)))
+    }
+
+    /// Streams the XML to the writer, rather than building it in memory.
+    pub fn write_xml_to<W: io::Write>(&self, w: W, pretty: bool) -> Result<(), io::Error> {
+        XmlWriter::encode_to_with(w, pretty, |w| self.put_xml(w))
     }
 
+    fn put_xml<W: io::Write>(&self, w: &mut XmlWriter<W>) -> Result<(), io::Error> {
+        let a = [
+            ("xmlns", NS),
+            ("version", VERSION),
+            ("session_id", &format!("{}", self.session)),
+            ("serial", &format!("{}", self.serial)),
+        ];
+
+        w.put_element("snapshot", Some(&a), |w| {
+            for el in &self.current_objects {
+                let atr = [("uri", el.uri().as_str())];
+                w.put_element("publish", Some(&atr), |w| {
+                    w.put_wrapped_text(&el.base64().as_str())
+                })?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Parses a snapshot. Any input, however malformed, results in an error
+    /// rather than a panic, see the fuzz target in 'fuzz/'.
     pub fn from_xml(bytes: Bytes) -> Result<Self, Error> {
+        Self::from_xml_with(HashAlg::Sha256, bytes)
+    }
+
+    /// Parses a snapshot, hashing the objects using the given algorithm.
+    pub fn from_xml_with(alg: HashAlg, bytes: Bytes) -> Result<Self, Error> {
         XmlReader::decode(bytes.as_ref(), |r| {
             r.take_named_element("snapshot", |mut a, r| {
-                let _version = a.take_req("version")?;
+                let version = a.take_req("version")?;
                 let session = a.take_req("session_id")?;
                 let session = Uuid::from_str(&session)?;
                 let serial = a.take_req("serial")?;
                 let serial = u64::from_str(serial.as_str())?;
                 a.exhausted()?;
+                check_version_and_serial(&version, serial)?;
 
                 let mut files = vec![];
                 while let Some(file) = r.take_opt_element(|t, mut a, r| match t.name.as_ref() {
                     "publish" => {
                         let uri = a.take_req("uri")?;
-                        let uri = RsyncUri::from(uri.as_str());
+                        let uri = RsyncUri::parse(&uri)
+                            .map_err(|_| Error::InvalidRsyncUri(uri.clone()))?;
                         a.exhausted()?;
 
-                        let base64 = r.take_chars()?;
-                        let content = base64::decode(&base64)?;
+                        // Real world snapshots may wrap the base64 over lines.
+                        let content = r.take_bytes_std()?;
 
-                        Ok(Some(CurrentFile::new(uri, &content)))
+                        Ok(Some(CurrentFile::new_with(alg, uri, &content)))
                     }
                     _ => Err(Error::InvalidXml(format!("Unexpected tag: {}", t.name))),
                 })? {
@@ -602,10 +1690,29 @@ impl Snapshot {
     }
 }
 
+impl IntoIterator for Snapshot {
+    type Item = CurrentFile;
+    type IntoIter = vec::IntoIter<CurrentFile>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.current_objects.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Snapshot {
+    type Item = &'a CurrentFile;
+    type IntoIter = slice::Iter<'a, CurrentFile>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.current_objects.iter()
+    }
+}
+
 //------------ DeltaElements -------------------------------------------------
 
 /// Defines the elements for an RRDP delta.
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "rrdp-serde", derive(Serialize, Deserialize))]
 pub struct DeltaElements {
     publishes: Vec<PublishElement>,
     updates: Vec<UpdateElement>,
@@ -642,12 +1749,47 @@ impl DeltaElements {
     pub fn withdraws(&self) -> &Vec<WithdrawElement> {
         &self.withdraws
     }
+
+    /// Returns all elements in a single list, sorted by uri. This is the
+    /// order in which they are written to a delta file.
+    pub fn sorted(&self) -> Vec<DeltaElement<'_>> {
+        let mut elements: Vec<_> = self
+            .publishes
+            .iter()
+            .map(DeltaElement::Publish)
+            .chain(self.updates.iter().map(DeltaElement::Update))
+            .chain(self.withdraws.iter().map(DeltaElement::Withdraw))
+            .collect();
+        elements.sort_by(|a, b| a.uri().cmp(b.uri()));
+        elements
+    }
+}
+
+//------------ DeltaElement --------------------------------------------------
+
+/// A reference to a single element of a delta.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeltaElement<'a> {
+    Publish(&'a PublishElement),
+    Update(&'a UpdateElement),
+    Withdraw(&'a WithdrawElement),
+}
+
+impl DeltaElement<'_> {
+    pub fn uri(&self) -> &RsyncUri {
+        match self {
+            DeltaElement::Publish(el) => &el.uri,
+            DeltaElement::Update(el) => &el.uri,
+            DeltaElement::Withdraw(el) => &el.uri,
+        }
+    }
 }
 
 //------------ Delta ---------------------------------------------------------
 
 /// Defines an RRDP delta.
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "rrdp-serde", derive(Serialize, Deserialize))]
 pub struct Delta {
     session: Uuid,
     serial: u64,
@@ -691,7 +1833,13 @@ impl Delta {
     }
 
     pub fn write_xml(&self) -> Bytes {
-        Bytes::from(XmlWriter::encode_vec(|w| {
+        self.write_xml_with(false)
+    }
+
+    /// Writes the XML, wrapping the base64 content over multiple lines if
+    /// pretty is true.
+    pub fn write_xml_with(&self, pretty: bool) -> Bytes {
+        Bytes::from(XmlWriter::encode_vec_with(pretty, |w| {
             let a = [
                 ("xmlns", NS),
                 ("version", VERSION),
@@ -699,34 +1847,195 @@ impl Delta {
                 ("serial", &format!("{}", self.serial)),
             ];
 
+            // The elements are written in a single pass sorted by uri, so
+            // the order does not depend on their kind.
             w.put_element("delta", Some(&a), |w| {
-                for el in &self.elements.publishes {
-                    let uri = el.uri.to_string();
-                    let b64 = el.base64.to_string();
-                    let atr = [("uri", uri.as_ref())];
-                    w.put_element("publish", Some(&atr), |w| w.put_text(&b64))?;
+                for el in self.elements.sorted() {
+                    let uri = el.uri().as_str();
+                    match el {
+                        DeltaElement::Publish(el) => {
+                            let atr = [("uri", uri)];
+                            w.put_element("publish", Some(&atr), |w| {
+                                w.put_wrapped_text(&el.base64.as_str())
+                            })?;
+                        }
+                        DeltaElement::Update(el) => {
+                            let hash = el.hash.as_str();
+                            let atr = [("uri", uri), ("hash", &hash)];
+                            w.put_element("publish", Some(&atr), |w| {
+                                w.put_wrapped_text(&el.base64.as_str())
+                            })?;
+                        }
+                        DeltaElement::Withdraw(el) => {
+                            let hash = el.hash.as_str();
+                            let atr = [("uri", uri), ("hash", &hash)];
+                            w.put_element("withdraw", Some(&atr), |w| w.empty())?;
+                        }
+                    }
                 }
 
-                for el in &self.elements.updates {
-                    let uri = el.uri.to_string();
-                    let b64 = el.base64.to_string();
-                    let hash = el.hash.to_string();
-                    let atr = [("uri", uri.as_ref()), ("hash", hash.as_ref())];
-                    w.put_element("publish", Some(&atr), |w| w.put_text(&b64))?;
-                }
+                Ok(())
+            })
+        }))
+    }
+
+    /// Parses a delta. Publish elements with a hash are updates. A uri may
+    /// only appear once.
+    pub fn from_xml(bytes: Bytes) -> Result<Self, Error> {
+        XmlReader::decode(bytes.as_ref(), |r| {
+            r.take_named_element("delta", |mut a, r| {
+                let version = a.take_req("version")?;
+                let session = a.take_req("session_id")?;
+                let session = Uuid::from_str(&session)?;
+                let serial = a.take_req("serial")?;
+                let serial = u64::from_str(serial.as_str())?;
+                a.exhausted()?;
+                check_version_and_serial(&version, serial)?;
+
+                let mut publishes = vec![];
+                let mut updates = vec![];
+                let mut withdraws = vec![];
+                let mut uris = HashSet::new();
+
+                while let Some(()) = r.take_opt_element(|t, mut a, r| {
+                    let uri = a.take_req("uri")?;
+                    let uri =
+                        RsyncUri::parse(&uri).map_err(|_| Error::InvalidRsyncUri(uri.clone()))?;
+                    let hash = a
+                        .take_opt("hash")
+                        .map(|hash| EncodedHash::from_hex(&hash))
+                        .transpose()
+                        .map_err(Error::invalid_xml)?;
+                    a.exhausted()?;
+                    if !uris.insert(uri.clone()) {
+                        return Err(Error::InvalidXml(format!("Duplicate uri: {}", uri)));
+                    }
+
+                    match (t.name.as_ref(), hash) {
+                        ("publish", hash) => {
+                            let base64 = Base64::from_content(&r.take_bytes_std()?);
+                            match hash {
+                                None => publishes.push(PublishElement { base64, uri }),
+                                Some(hash) => updates.push(UpdateElement { uri, hash, base64 }),
+                            }
+                        }
+                        ("withdraw", Some(hash)) => withdraws.push(WithdrawElement { uri, hash }),
+                        ("withdraw", None) => {
+                            return Err(Error::InvalidXml("Withdraw without hash".to_string()))
+                        }
+                        _ => return Err(Error::InvalidXml(format!("Unexpected tag: {}", t.name))),
+                    }
+                    Ok(Some(()))
+                })? {}
+
+                Ok(Delta::new(
+                    session,
+                    serial,
+                    DeltaElements {
+                        publishes,
+                        updates,
+                        withdraws,
+                    },
+                ))
+            })
+        })
+    }
+}
+
+//------------ PublishQuery --------------------------------------------------
+
+/// A query message as defined in the publication protocol (RFC 8181), with the
+/// publish and withdraw elements a CA wants to apply to its repository. The
+/// list query is not supported.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PublishQuery {
+    elements: DeltaElements,
+}
+
+impl PublishQuery {
+    pub fn elements(&self) -> &DeltaElements {
+        &self.elements
+    }
 
-                for el in &self.elements.withdraws {
-                    let uri = el.uri.to_string();
-                    let hash = el.hash.to_string();
+    pub fn from_xml(bytes: Bytes) -> Result<Self, Error> {
+        let mut publishes = vec![];
+        let mut updates = vec![];
+        let mut withdraws = vec![];
 
-                    let atr = [("uri", uri.as_ref()), ("hash", hash.as_ref())];
-                    w.put_element("withdraw", Some(&atr), |w| w.empty())?;
+        XmlReader::decode(bytes.as_ref(), |r| {
+            r.take_named_element("msg", |mut a, r| {
+                if a.take_req("version")? != PUBLICATION_VERSION {
+                    return Err(Error::InvalidXml("Unsupported version".to_string()));
                 }
+                if a.take_req("type")? != "query" {
+                    return Err(Error::InvalidXml("Expected query".to_string()));
+                }
+                a.exhausted()?;
+
+                while let Some(()) = r.take_opt_element(|t, mut a, r| {
+                    let _tag = a.take_opt("tag");
+                    let uri = a.take_req("uri")?;
+                    let uri =
+                        RsyncUri::parse(&uri).map_err(|_| Error::InvalidRsyncUri(uri.clone()))?;
+
+                    match t.name.as_ref() {
+                        "publish" => {
+                            let hash = a.take_opt("hash");
+                            a.exhausted()?;
+
+                            let content = base64::decode(r.take_chars()?.trim())?;
+                            let base64 = Base64::from_content(&content);
+
+                            match hash {
+                                None => publishes.push(PublishElement { base64, uri }),
+                                Some(hash) => updates.push(UpdateElement {
+                                    uri,
+                                    hash: parse_hash(&hash)?,
+                                    base64,
+                                }),
+                            }
+                        }
+                        "withdraw" => {
+                            let hash = parse_hash(&a.take_req("hash")?)?;
+                            a.exhausted()?;
+                            withdraws.push(WithdrawElement { uri, hash });
+                        }
+                        _ => return Err(Error::InvalidXml(format!("Unexpected tag: {}", t.name))),
+                    }
+                    Ok(Some(()))
+                })? {}
 
                 Ok(())
             })
-        }))
+        })?;
+
+        Ok(PublishQuery {
+            elements: DeltaElements {
+                publishes,
+                updates,
+                withdraws,
+            },
+        })
+    }
+}
+
+/// Checks the version and serial of a snapshot or delta file: RRDP only has
+/// version 1, and serials start at 1.
+fn check_version_and_serial(version: &str, serial: u64) -> Result<(), Error> {
+    if version != "1" {
+        return Err(Error::InvalidXml(format!(
+            "Unsupported version: {}",
+            version
+        )));
     }
+    if serial == 0 {
+        return Err(Error::InvalidXml("Serial must be at least 1".to_string()));
+    }
+    Ok(())
+}
+
+fn parse_hash(hash: &str) -> Result<EncodedHash, Error> {
+    EncodedHash::from_hex(&hash.to_ascii_lowercase()).map_err(Error::invalid_xml)
 }
 
 //------------ Error ---------------------------------------------------------
@@ -735,17 +2044,96 @@ pub enum Error {
     #[display(fmt = "Invalid XML: {}", _0)]
     InvalidXml(String),
 
+    #[display(fmt = "Invalid rsync uri: {}", _0)]
+    InvalidRsyncUri(String),
+
+    #[display(fmt = "Object already exists: {}", _0)]
+    ObjectAlreadyExists(String),
+
+    #[display(fmt = "No object matching uri and hash: {}", _0)]
+    NoObjectForUriAndHash(String),
+
     #[display(fmt = "Invalid delta for current session and serial")]
     InvalidDelta,
 
-    #[display(fmt = "No valid repo state found on disk")]
-    InvalidRepoState,
+    #[display(fmt = "Serial {} cannot be incremented", _0)]
+    SerialOverflow(u64),
+
+    #[display(fmt = "Cannot read notification file: {}", path)]
+    NotificationUnreadable { path: String },
+
+    #[display(fmt = "Cannot read {}: {}", path, error)]
+    ReadFailed { path: String, error: io::Error },
+
+    #[display(
+        fmt = "File {} is {} bytes, more than the maximum of {}",
+        path,
+        size,
+        max
+    )]
+    FileTooLarge { path: String, size: u64, max: u64 },
+
+    #[display(fmt = "Unsupported notification version: {}", _0)]
+    UnsupportedVersion(String),
+
+    #[display(fmt = "Uri not under the base uri: {}", _0)]
+    UriOutsideBase(HttpsUri),
+
+    #[display(fmt = "Snapshot file missing: {}", path)]
+    SnapshotFileMissing { path: String },
+
+    #[display(fmt = "Delta file missing: {}", path)]
+    DeltaFileMissing { path: String },
+
+    #[display(
+        fmt = "Hash of snapshot {} does not match, expected: {}, found: {}",
+        uri,
+        expected,
+        found
+    )]
+    SnapshotHashMismatch {
+        uri: HttpsUri,
+        expected: String,
+        found: String,
+    },
+
+    #[display(
+        fmt = "Hash of delta {} does not match, expected: {}, found: {}",
+        uri,
+        expected,
+        found
+    )]
+    DeltaHashMismatch {
+        uri: HttpsUri,
+        expected: String,
+        found: String,
+    },
+
+    #[display(fmt = "Notification has no snapshot")]
+    NoSnapshotRef,
+
+    #[display(fmt = "Snapshot does not match session and serial in notification")]
+    SnapshotMismatch,
+
+    #[display(
+        fmt = "Deltas are not contiguous, expected serial {}, found: {}",
+        expected,
+        found
+    )]
+    DeltaGap { expected: u64, found: u64 },
 }
 
 impl Error {
     fn invalid_xml(e: impl fmt::Display) -> Self {
         Error::InvalidXml(e.to_string())
     }
+
+    /// Returns whether a file exists but could not be read, e.g. for lack of
+    /// permissions. Unlike missing or invalid files, this may be transient,
+    /// and says nothing about whether the repository is valid.
+    pub fn is_read_failure(&self) -> bool {
+        matches!(self, Error::ReadFailed { .. })
+    }
 }
 
 impl From<XmlReaderErr> for Error {
@@ -784,7 +2172,15 @@ impl From<uuid::parser::ParseError> for Error {
 mod tests {
     use super::*;
     use rrdp::Snapshot;
+    use std::fs;
+    use std::io::Read;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+    use storage::{DirEntry, MemStorage};
     use sync;
+    #[cfg(feature = "rrdp-serde")]
+    use test_util;
 
     const SOURCE_1: &str = "./test-resources/source-1/";
     const SOURCE_2: &str = "./test-resources/source-2/";
@@ -815,19 +2211,551 @@ mod tests {
         Snapshot::new(session, serial, files)
     }
 
-    #[test]
-    fn save_and_reload_snapshot() {
-        let snapshot = snapshot_source_1();
+    /// A logger which keeps all records, so that tests can check what was
+    /// logged. Note that records from all tests end up here.
+    struct CapturingLogger(Mutex<Vec<(log::Level, String)>>);
 
-        let xml = snapshot.write_xml();
-        let target = PathBuf::from("./test-work/snapshot.xml");
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
 
-        sync::save(xml.as_ref(), &target).unwrap();
+        fn log(&self, record: &log::Record) {
+            let mut records = self.0.lock().unwrap();
+            records.push((record.level(), record.args().to_string()));
+        }
 
-        let bytes = sync::read(&target).unwrap();
-        let loaded_snapshot = Snapshot::from_xml(bytes).unwrap();
+        fn flush(&self) {}
+    }
 
-        assert_eq!(snapshot, loaded_snapshot);
+    static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+
+    fn capture_logs() {
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(log::LevelFilter::Trace);
+        }
+    }
+
+    fn logged(level: log::Level, msg: &str) -> bool {
+        let records = LOGGER.0.lock().unwrap();
+        records.iter().any(|(l, m)| *l == level && m == msg)
+    }
+
+    fn clean_options(max_deltas: usize) -> SaveOptions {
+        SaveOptions {
+            max_deltas,
+            clean: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn save_and_reload_snapshot() {
+        let snapshot = snapshot_source_1();
+
+        let xml = snapshot.write_xml();
+        let target = PathBuf::from("./test-work/snapshot.xml");
+
+        sync::save(xml.as_ref(), &target).unwrap();
+
+        let bytes = sync::read(&target).unwrap();
+        let loaded_snapshot = Snapshot::from_xml(bytes).unwrap();
+
+        assert_eq!(snapshot, loaded_snapshot);
+    }
+
+    #[test]
+    fn snapshot_current_objects() {
+        let base_dir = PathBuf::from(SOURCE_3);
+        let rsync_base = RsyncUri::base_uri(RSYNC_BASE).unwrap();
+        let files = sync::crawl_disk(&base_dir, &rsync_base).unwrap();
+
+        let mut reversed = files.clone();
+        reversed.reverse();
+        let snapshot = Snapshot::new(Uuid::new_v4(), 1, reversed);
+
+        assert_eq!(files.as_slice(), snapshot.current_objects());
+    }
+
+    #[test]
+    fn iterate_snapshot() {
+        let base_dir = PathBuf::from(SOURCE_3);
+        let rsync_base = RsyncUri::base_uri(RSYNC_BASE).unwrap();
+        let mut files = sync::crawl_disk(&base_dir, &rsync_base).unwrap();
+        files.reverse();
+        let snapshot = Snapshot::new(Uuid::new_v4(), 1, files.clone());
+
+        let mut expected: Vec<_> = files.iter().map(|f| f.uri().clone()).collect();
+        expected.sort();
+
+        let mut found = vec![];
+        for file in &snapshot {
+            found.push(file.uri().clone());
+        }
+        assert_eq!(expected, found);
+
+        let found: Vec<_> = snapshot.iter().map(|f| f.uri().clone()).collect();
+        assert_eq!(expected, found);
+
+        let found: Vec<_> = snapshot.into_iter().map(|f| f.uri().clone()).collect();
+        assert_eq!(expected, found);
+    }
+
+    #[test]
+    #[cfg(feature = "rrdp-serde")]
+    fn snapshot_json_round_trip() {
+        let snapshot = snapshot_source_1();
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let loaded: Snapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot, loaded);
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let first = &value["current_objects"][0];
+        assert_eq!(RSYNC_FILE1, first["uri"]);
+        assert_eq!(
+            snapshot.current_objects()[0].hash().to_string(),
+            first["hash"]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "rrdp-serde")]
+    fn repo_state_json_round_trip() {
+        const WORK_DIR: &str = "./test-work/repo_state_json_round_trip/";
+        let https = HttpsUri::base_uri("https://localhost/rrdp/").unwrap();
+        test_util::create_repo(
+            Path::new(WORK_DIR),
+            &https,
+            test_util::source_serials(),
+            &SaveOptions::default(),
+        );
+        let state = RepoState::reconstitute(https, WORK_DIR.into()).unwrap();
+        assert_eq!(1, state.deltas().len());
+
+        let json = serde_json::to_string(&state).unwrap();
+        let loaded: RepoState = serde_json::from_str(&json).unwrap();
+        assert_eq!(state, loaded);
+    }
+
+    #[test]
+    #[cfg(feature = "rrdp-serde")]
+    fn reject_invalid_hash_in_json() {
+        let snapshot = snapshot_source_1();
+        let mut value = serde_json::to_value(&snapshot).unwrap();
+        value["current_objects"][0]["hash"] = "not a hash".into();
+        assert!(serde_json::from_value::<Snapshot>(value).is_err());
+    }
+
+    fn publish_query(elements: &str) -> PublishQuery {
+        let xml = format!(
+            "<msg xmlns=\"http://www.hactrn.net/uris/rpki/publication-spec/\" \
+             version=\"4\" type=\"query\">{}</msg>",
+            elements
+        );
+        PublishQuery::from_xml(Bytes::from(xml)).unwrap()
+    }
+
+    #[test]
+    fn apply_publish_query() {
+        let snapshot = snapshot_source_1();
+        let file1 = &snapshot.current_objects()[0];
+        let file3 = &snapshot.current_objects()[2];
+
+        let query = publish_query(&format!(
+            "<publish tag=\"a\" uri=\"{}\">{}</publish>\
+             <publish tag=\"b\" uri=\"{}\" hash=\"{}\">{}</publish>\
+             <withdraw tag=\"c\" uri=\"{}\" hash=\"{}\"/>",
+            RSYNC_FILE4,
+            base64::encode("new"),
+            RSYNC_FILE1,
+            file1.hash(),
+            base64::encode("updated"),
+            RSYNC_FILE3,
+            file3.hash()
+        ));
+
+        let next = snapshot.apply_elements(query.elements()).unwrap();
+        assert_eq!(snapshot.serial + 1, next.serial);
+
+        let delta = snapshot.to(&next).unwrap();
+        let (publishes, updates, withdraws) = delta.elements().clone().unwrap();
+
+        assert_eq!(1, publishes.len());
+        assert_eq!(&RsyncUri::from(RSYNC_FILE4), publishes[0].uri());
+        assert_eq!(&Base64::from_content(b"new"), publishes[0].base64());
+
+        assert_eq!(1, updates.len());
+        assert_eq!(&RsyncUri::from(RSYNC_FILE1), updates[0].uri());
+        assert_eq!(file1.hash(), updates[0].hash());
+        assert_eq!(&Base64::from_content(b"updated"), updates[0].base64());
+
+        assert_eq!(1, withdraws.len());
+        assert_eq!(&RsyncUri::from(RSYNC_FILE3), withdraws[0].uri());
+    }
+
+    #[test]
+    fn reject_publish_query_with_wrong_hash() {
+        let snapshot = snapshot_source_1();
+        let wrong_hash = EncodedHash::from_content(b"wrong");
+
+        let query = publish_query(&format!(
+            "<withdraw tag=\"c\" uri=\"{}\" hash=\"{}\"/>",
+            RSYNC_FILE3, wrong_hash
+        ));
+        match snapshot.apply_elements(query.elements()) {
+            Err(Error::NoObjectForUriAndHash(uri)) => assert_eq!(RSYNC_FILE3, uri),
+            _ => panic!("Expected NoObjectForUriAndHash error"),
+        }
+
+        let query = publish_query(&format!(
+            "<publish tag=\"a\" uri=\"{}\">{}</publish>",
+            RSYNC_FILE1,
+            base64::encode("new")
+        ));
+        match snapshot.apply_elements(query.elements()) {
+            Err(Error::ObjectAlreadyExists(uri)) => assert_eq!(RSYNC_FILE1, uri),
+            _ => panic!("Expected ObjectAlreadyExists error"),
+        }
+    }
+
+    #[test]
+    fn apply_delta_to_snapshot() {
+        let session = Uuid::new_v4();
+        let snapshot_1 = snapshot_from_src(session, 1, SOURCE_1);
+        let snapshot_2 = snapshot_from_src(session, 2, SOURCE_2);
+        let delta = snapshot_1.to(&snapshot_2).unwrap();
+
+        assert_eq!(snapshot_2, snapshot_1.apply_delta(&delta).unwrap());
+
+        // The delta only applies to the snapshot it was made for.
+        match snapshot_2.apply_delta(&delta) {
+            Err(Error::InvalidDelta) => {}
+            res => panic!("Expected InvalidDelta, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn delta_shares_content_with_snapshot() {
+        let session = Uuid::new_v4();
+        let snapshot_1 = snapshot_from_src(session, 1, SOURCE_1);
+        let snapshot_2 = snapshot_from_src(session, 2, SOURCE_2);
+        let delta = snapshot_1.to(&snapshot_2).unwrap();
+
+        let elements = delta.elements();
+        assert!(!elements.publishes().is_empty());
+        assert!(!elements.updates().is_empty());
+
+        let published = elements.publishes().iter().map(|p| (p.uri(), p.base64()));
+        let updated = elements.updates().iter().map(|u| (u.uri(), u.base64()));
+        for (uri, base64) in published.chain(updated) {
+            let file = snapshot_2
+                .current_objects()
+                .iter()
+                .find(|f| f.uri() == uri)
+                .unwrap();
+            assert_eq!(file.base64().as_bytes(), base64.as_bytes());
+            assert_eq!(
+                file.base64().as_bytes().as_ptr(),
+                base64.as_bytes().as_ptr()
+            );
+        }
+    }
+
+    #[test]
+    fn refuse_to_overflow_serial() {
+        let session = Uuid::new_v4();
+        let snapshot = snapshot_from_src(session, u64::MAX, SOURCE_1);
+        let mut state = RepoState::new(
+            snapshot.clone(),
+            HttpsUri::from("https://localhost/rrdp/"),
+            PathBuf::from("./test-work/refuse_to_overflow_serial/"),
+        );
+
+        let next = snapshot_from_src(session, 0, SOURCE_2);
+        match state.apply(next.clone()) {
+            Err(Error::SerialOverflow(serial)) => assert_eq!(u64::MAX, serial),
+            res => panic!("Expected SerialOverflow, got: {:?}", res),
+        }
+        match snapshot.to(&next) {
+            Err(Error::InvalidDelta) => {}
+            res => panic!("Expected InvalidDelta, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn delta_xml_round_trip() {
+        let session = Uuid::new_v4();
+        let snapshot = snapshot_source_1();
+        let file1 = &snapshot.current_objects()[0];
+        let file3 = &snapshot.current_objects()[2];
+
+        let query = publish_query(&format!(
+            "<publish tag=\"a\" uri=\"{}\">{}</publish>\
+             <publish tag=\"b\" uri=\"{}\" hash=\"{}\">{}</publish>\
+             <withdraw tag=\"c\" uri=\"{}\" hash=\"{}\"/>",
+            RSYNC_FILE4,
+            base64::encode("new"),
+            RSYNC_FILE1,
+            file1.hash(),
+            base64::encode("updated"),
+            RSYNC_FILE3,
+            file3.hash()
+        ));
+        let delta = Delta::new(session, 2, query.elements().clone());
+
+        assert_eq!(delta, Delta::from_xml(delta.write_xml()).unwrap());
+        assert_eq!(delta, Delta::from_xml(delta.write_xml_with(true)).unwrap());
+    }
+
+    #[test]
+    fn write_delta_elements_sorted_by_uri() {
+        let session = Uuid::new_v4();
+        let file = |name: &str, content: &str| {
+            CurrentFile::new(
+                RsyncUri::from(format!("{}{}", RSYNC_BASE, name).as_str()),
+                content.as_bytes(),
+            )
+        };
+        let old = Snapshot::new(
+            session,
+            1,
+            vec![
+                file("a", "a"),
+                file("b", "b"),
+                file("c", "c"),
+                file("e", "e"),
+            ],
+        );
+        let new = Snapshot::new(
+            session,
+            2,
+            vec![
+                file("a", "a2"),
+                file("c", "c"),
+                file("d", "d"),
+                file("f", "f"),
+            ],
+        );
+        let delta = old.to(&new).unwrap();
+
+        let elements: Vec<_> = delta
+            .elements()
+            .sorted()
+            .into_iter()
+            .map(|el| match el {
+                DeltaElement::Publish(el) => format!("publish {}", el.uri()),
+                DeltaElement::Update(el) => format!("update {}", el.uri()),
+                DeltaElement::Withdraw(el) => format!("withdraw {}", el.uri()),
+            })
+            .collect();
+        let expected: Vec<_> = [
+            "update a",
+            "withdraw b",
+            "publish d",
+            "withdraw e",
+            "publish f",
+        ]
+        .iter()
+        .map(|el| el.replace(' ', &format!(" {}", RSYNC_BASE)))
+        .collect();
+        assert_eq!(expected, elements);
+
+        // The XML has the same single stream of elements, each uri once.
+        let xml = String::from_utf8(delta.write_xml().to_vec()).unwrap();
+        let uris: Vec<_> = xml
+            .split("uri=\"")
+            .skip(1)
+            .map(|rest| &rest[..rest.find('"').unwrap()])
+            .collect();
+        let expected: Vec<_> = ["a", "b", "d", "e", "f"]
+            .iter()
+            .map(|name| format!("{}{}", RSYNC_BASE, name))
+            .collect();
+        assert_eq!(expected, uris);
+        assert_eq!(delta, Delta::from_xml(delta.write_xml()).unwrap());
+    }
+
+    #[test]
+    fn reject_delta_with_duplicate_uri() {
+        let xml = format!(
+            "<delta xmlns=\"{}\" version=\"1\" session_id=\"{}\" serial=\"2\">\
+             <publish uri=\"{}\">{}</publish>\
+             <withdraw uri=\"{}\" hash=\"{}\"/>\
+             </delta>",
+            NS,
+            Uuid::new_v4(),
+            RSYNC_FILE1,
+            base64::encode("new"),
+            RSYNC_FILE1,
+            EncodedHash::from_content(b"old"),
+        );
+        match Delta::from_xml(Bytes::from(xml)) {
+            Err(Error::InvalidXml(msg)) => assert!(msg.contains("Duplicate uri")),
+            res => panic!("Expected InvalidXml, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn reject_delta_with_wrong_hash() {
+        let snapshot = snapshot_source_1();
+        let wrong_hash = EncodedHash::from_content(b"wrong");
+
+        let query = publish_query(&format!(
+            "<withdraw tag=\"c\" uri=\"{}\" hash=\"{}\"/>",
+            RSYNC_FILE3, wrong_hash
+        ));
+        let delta = Delta::new(
+            snapshot.session,
+            snapshot.serial + 1,
+            query.elements().clone(),
+        );
+        match snapshot.apply_delta(&delta) {
+            Err(Error::NoObjectForUriAndHash(uri)) => assert_eq!(RSYNC_FILE3, uri),
+            res => panic!("Expected NoObjectForUriAndHash, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn reject_invalid_uri_in_snapshot_xml() {
+        let xml = format!(
+            "<snapshot xmlns=\"{}\" version=\"1\" session_id=\"{}\" serial=\"1\">\
+             <publish uri=\"http://localhost/repo/file.cer\">AAAA</publish>\
+             </snapshot>",
+            NS,
+            Uuid::new_v4()
+        );
+
+        match Snapshot::from_xml(Bytes::from(xml)) {
+            Err(Error::InvalidRsyncUri(uri)) => {
+                assert_eq!("http://localhost/repo/file.cer", uri)
+            }
+            _ => panic!("Expected InvalidRsyncUri error"),
+        }
+    }
+
+    #[test]
+    fn reject_publish_without_valid_content_in_snapshot_xml() {
+        let xml = |content: &str| {
+            format!(
+                "<snapshot xmlns=\"{}\" version=\"1\" session_id=\"{}\" serial=\"1\">\
+                 <publish uri=\"rsync://localhost/repo/file.cer\">{}</publish>\
+                 </snapshot>",
+                NS,
+                Uuid::new_v4(),
+                content
+            )
+        };
+
+        let empty = format!(
+            "<snapshot xmlns=\"{}\" version=\"1\" session_id=\"{}\" serial=\"1\">\
+             <publish uri=\"rsync://localhost/repo/file.cer\"/>\
+             </snapshot>",
+            NS,
+            Uuid::new_v4()
+        );
+        for xml in [empty, xml(""), xml(" \n\t "), xml("AAECA"), xml("AAEC!wQ=")] {
+            match Snapshot::from_xml(Bytes::from(xml.clone())) {
+                Err(Error::InvalidXml(_)) => {}
+                res => panic!("Expected InvalidXml for {}, got: {:?}", xml, res),
+            }
+        }
+
+        // Truncating a valid snapshot anywhere never panics.
+        let valid = snapshot_source_1().write_xml();
+        for len in 0..valid.len() {
+            assert!(Snapshot::from_xml(valid.slice_to(len)).is_err());
+        }
+    }
+
+    #[test]
+    fn snapshot_xml_is_reproducible() {
+        let session = Uuid::new_v4();
+        let snapshot_1 = snapshot_from_src(session, 1, SOURCE_3);
+        let snapshot_2 = snapshot_from_src(session, 1, SOURCE_3);
+
+        let mut reversed = snapshot_2.current_objects.clone();
+        reversed.reverse();
+        let snapshot_3 = Snapshot::new(session, 1, reversed);
+
+        assert_eq!(snapshot_1.write_xml(), snapshot_2.write_xml());
+        assert_eq!(snapshot_1.write_xml(), snapshot_3.write_xml());
+    }
+
+    #[test]
+    fn parse_snapshot_with_bom_or_declaration() {
+        let snapshot = snapshot_from_src(Uuid::new_v4(), 1, SOURCE_3);
+        let xml = snapshot.write_xml();
+
+        let mut with_bom = b"\xef\xbb\xbf".to_vec();
+        with_bom.extend_from_slice(xml.as_ref());
+
+        let mut with_declaration =
+            b"<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n".to_vec();
+        with_declaration.extend_from_slice(xml.as_ref());
+
+        let mut with_both = b"\xef\xbb\xbf<?xml version='1.0'?>".to_vec();
+        with_both.extend_from_slice(xml.as_ref());
+
+        for bytes in [with_bom, with_declaration, with_both] {
+            assert_eq!(snapshot, Snapshot::from_xml(Bytes::from(bytes)).unwrap());
+        }
+    }
+
+    #[test]
+    fn reject_snapshot_with_other_version() {
+        let xml = snapshot_source_1().write_xml();
+        let text = String::from_utf8_lossy(xml.as_ref()).replace("version=\"1\"", "version=\"2\"");
+        match Snapshot::from_xml(Bytes::from(text)) {
+            Err(Error::InvalidXml(msg)) => assert!(msg.contains("version")),
+            res => panic!("Expected InvalidXml, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn reject_snapshot_with_serial_zero() {
+        let xml = snapshot_from_src(Uuid::new_v4(), 0, SOURCE_1).write_xml();
+        match Snapshot::from_xml(xml) {
+            Err(Error::InvalidXml(msg)) => assert!(msg.contains("Serial")),
+            res => panic!("Expected InvalidXml, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn escape_special_characters_in_uris() {
+        let uri = RsyncUri::from("rsync://localhost/repo/a&b<c>\"d'.cer");
+        let objects = vec![CurrentFile::new(uri.clone(), b"content")];
+        let snapshot = Snapshot::new(Uuid::new_v4(), 1, objects);
+
+        let xml = snapshot.write_xml();
+        let text = String::from_utf8_lossy(xml.as_ref());
+        assert!(text.contains("a&amp;b&lt;c&gt;&quot;d"));
+
+        let parsed = Snapshot::from_xml(xml).unwrap();
+        assert_eq!(&uri, parsed.current_objects()[0].uri());
+        assert_eq!(snapshot, parsed);
+    }
+
+    #[test]
+    fn stream_snapshot_xml() {
+        let snapshot = snapshot_from_src(Uuid::new_v4(), 1, SOURCE_3);
+        for &pretty in &[false, true] {
+            let mut streamed = vec![];
+            snapshot.write_xml_to(&mut streamed, pretty).unwrap();
+            assert_eq!(snapshot.write_xml_with(pretty), Bytes::from(streamed));
+        }
+    }
+
+    #[test]
+    fn pretty_snapshot_xml_parses_back() {
+        let uri = RsyncUri::from("rsync://localhost/repo/large.cer");
+        let mut objects = snapshot_source_1().current_objects;
+        objects.push(CurrentFile::new(uri, &[7; 200]));
+        let snapshot = Snapshot::new(Uuid::new_v4(), 1, objects);
+
+        let pretty = snapshot.write_xml_with(true);
+        assert_ne!(snapshot.write_xml(), pretty);
+        assert_eq!(snapshot, Snapshot::from_xml(pretty).unwrap());
     }
 
     #[test]
@@ -846,77 +2774,1057 @@ mod tests {
         assert_eq!(1, publishes.len());
         assert_eq!(
             &RsyncUri::from(RSYNC_FILE4),
-            publishes.get(0).unwrap().uri()
+            publishes.first().unwrap().uri()
         );
 
         assert_eq!(1, updates.len());
-        assert_eq!(&RsyncUri::from(RSYNC_FILE1), updates.get(0).unwrap().uri());
+        assert_eq!(&RsyncUri::from(RSYNC_FILE1), updates.first().unwrap().uri());
 
         assert_eq!(1, withdraws.len());
         assert_eq!(
             &RsyncUri::from(RSYNC_FILE3),
-            withdraws.get(0).unwrap().uri()
+            withdraws.first().unwrap().uri()
         );
     }
 
-    #[test]
-    fn save_and_reload_current_state() {
+    /// Returns the serial dirs for the session in the storage.
+    fn stored_serials<S: Storage>(storage: &S, base_dir: &str, session: Uuid) -> Vec<u64> {
+        let dir = PathBuf::from(base_dir).join(session.to_string());
+        let mut serials: Vec<_> = storage
+            .list(&dir)
+            .unwrap()
+            .into_iter()
+            .filter_map(|entry| serial_dir(entry.name()))
+            .collect();
+        serials.sort();
+        serials
+    }
+
+    fn save_and_reload<S: Storage>(storage: &S, base_dir: &str) {
+        let https = HttpsUri::from("https://localhost/rrdp/");
+        let reconstitute =
+            || RepoState::reconstitute_from(storage, https.clone(), base_dir.into(), false);
         let snapshot_1 = snapshot_source_1();
 
-        let state = RepoState::new(
-            snapshot_1,
-            HttpsUri::from("https://localhost/rrdp/"),
-            PathBuf::from("./test-work/"),
-        );
-        let target_dir_1 = PathBuf::from(format!("./test-work/{}/1", state.session));
+        let state = RepoState::new(snapshot_1, https.clone(), PathBuf::from(base_dir));
+        let session = state.session;
 
-        state.clone().save(25, true).unwrap();
+        state.clone().save_to(storage, &clean_options(25)).unwrap();
 
-        let mut loaded_state = RepoState::reconstitute(
-            HttpsUri::from("https://localhost/rrdp/"),
-            PathBuf::from("./test-work/"),
-        )
-        .unwrap();
+        let mut loaded_state = reconstitute().unwrap();
 
         assert_eq!(state, loaded_state);
 
         let snapshot_2 = snapshot_from_src(loaded_state.session, loaded_state.serial + 1, SOURCE_2);
-        let target_dir_2 = PathBuf::from(format!("./test-work/{}/2", state.session));
 
         loaded_state.apply(snapshot_2).unwrap();
-        loaded_state.save(25, true).unwrap();
+        loaded_state.save_to(storage, &clean_options(25)).unwrap();
 
-        let mut state = RepoState::reconstitute(
-            HttpsUri::from("https://localhost/rrdp/"),
-            PathBuf::from("./test-work/"),
-        )
-        .unwrap();
-        let target_dir_3 = PathBuf::from(format!("./test-work/{}/3", state.session));
+        let mut state = reconstitute().unwrap();
 
         let snapshot_3 = snapshot_from_src(state.session, state.serial + 1, SOURCE_3);
         state.apply(snapshot_3).unwrap();
-        state.save(25, true).unwrap();
+        state.save_to(storage, &clean_options(25)).unwrap();
 
-        assert!(!target_dir_1.exists()); // dir 1 should be cleaned up (too much space)
-        assert!(target_dir_3.exists());
+        // dir 1 should be cleaned up (too much space)
+        assert_eq!(vec![2, 3], stored_serials(storage, base_dir, session));
 
         // Applying a zero delta should be a no-op, so the new target dir should not exist
         // Furthermore, delta 2 should be removed if we limit the max_deltas to 1. I.e.
         // we will only have target dir 3 remaining.
-        let mut state = RepoState::reconstitute(
-            HttpsUri::from("https://localhost/rrdp/"),
-            PathBuf::from("./test-work/"),
+        let mut state = reconstitute().unwrap();
+
+        let snapshot_4 = snapshot_from_src(state.session, state.serial + 1, SOURCE_3);
+        state.apply(snapshot_4).unwrap();
+        state.save_to(storage, &clean_options(1)).unwrap();
+
+        assert_eq!(vec![3], stored_serials(storage, base_dir, session));
+    }
+
+    #[test]
+    fn save_and_reload_current_state() {
+        save_and_reload(
+            &FsStorage::default(),
+            "./test-work/save_and_reload_current_state/",
+        );
+    }
+
+    #[test]
+    fn save_and_reload_in_memory() {
+        save_and_reload(&MemStorage::default(), "./rrdp/");
+    }
+
+    /// Keeps files in memory, and records the paths that were synced.
+    #[derive(Default)]
+    struct SyncRecorder {
+        inner: MemStorage,
+        synced: Mutex<Vec<PathBuf>>,
+    }
+
+    impl Storage for SyncRecorder {
+        fn read(&self, path: &Path) -> Result<Bytes, io::Error> {
+            self.inner.read(path)
+        }
+
+        fn save(&self, content: &[u8], path: &Path) -> Result<(), io::Error> {
+            self.inner.save(content, path)
+        }
+
+        fn sync(&self, path: &Path) -> Result<(), io::Error> {
+            assert!(
+                self.inner.get(path).is_some() || self.inner.list(path).is_ok(),
+                "Synced before saved: {}",
+                path.display()
+            );
+            self.synced.lock().unwrap().push(path.to_path_buf());
+            Ok(())
+        }
+
+        fn list(&self, dir: &Path) -> Result<Vec<DirEntry>, io::Error> {
+            self.inner.list(dir)
+        }
+
+        fn remove(&self, path: &Path) -> Result<(), io::Error> {
+            self.inner.remove(path)
+        }
+    }
+
+    #[test]
+    fn save_with_fsync() {
+        let https = HttpsUri::from("https://localhost/rrdp/");
+        let base_dir = PathBuf::from("./rrdp/");
+        let session = Uuid::new_v4();
+        let options = SaveOptions {
+            gzip: true,
+            fsync: true,
+            ..Default::default()
+        };
+
+        let storage = SyncRecorder::default();
+        RepoState::new(
+            snapshot_from_src(session, 1, SOURCE_1),
+            https.clone(),
+            base_dir.clone(),
         )
+        .save_to(&storage, &SaveOptions::default())
         .unwrap();
+        assert!(storage.synced.lock().unwrap().is_empty());
+
+        let mut state =
+            RepoState::reconstitute_from(&storage, https, base_dir.clone(), false).unwrap();
+        state
+            .apply(snapshot_from_src(session, 2, SOURCE_2))
+            .unwrap();
+        state.save_to(&storage, &options).unwrap();
+
+        let session_dir = base_dir.join(session.to_string());
+        let serial_dir = session_dir.join("2");
+        let expected = vec![
+            serial_dir.join("snapshot.xml"),
+            serial_dir.join("snapshot.xml.gz"),
+            serial_dir.join("delta.xml"),
+            serial_dir.join("delta.xml.gz"),
+            serial_dir,
+            session_dir,
+            base_dir.clone(),
+            base_dir,
+        ];
+        assert_eq!(expected, *storage.synced.lock().unwrap());
+    }
 
-        let target_dir_4 = PathBuf::from(format!("./test-work/{}/4", state.session));
+    #[test]
+    fn save_and_reconstitute_with_notification_name() {
+        let storage = MemStorage::new();
+        let https = HttpsUri::from("https://localhost/rrdp/");
+        let base_dir = PathBuf::from("./rrdp/");
+
+        let state = RepoState::new(snapshot_source_1(), https.clone(), base_dir.clone());
+        assert_eq!(NOTIFICATION_FILE, state.notification_name());
+        let state = state.with_notification_name("repo-a.xml");
+        state
+            .clone()
+            .save_to(&storage, &SaveOptions::default())
+            .unwrap();
+
+        assert!(storage.get(&base_dir.join("repo-a.xml")).is_some());
+        assert!(storage.get(&base_dir.join(NOTIFICATION_FILE)).is_none());
+        assert!(
+            RepoState::reconstitute_from(&storage, https.clone(), base_dir.clone(), false).is_err()
+        );
 
-        let snapshot_4 = snapshot_from_src(state.session, state.serial + 1, SOURCE_3);
-        state.apply(snapshot_4).unwrap();
-        state.save(1, true).unwrap();
+        let loaded =
+            RepoState::reconstitute_named(&storage, https, base_dir, "repo-a.xml", false).unwrap();
+        assert_eq!(state, loaded);
+    }
+
+    #[test]
+    fn drop_dangling_delta_refs() {
+        let storage = MemStorage::new();
+        let https = HttpsUri::from("https://localhost/rrdp/");
+        let base_dir = PathBuf::from("./rrdp/");
+        let session = Uuid::new_v4();
+        let options = SaveOptions {
+            delta_budget: Some(usize::MAX),
+            ..Default::default()
+        };
+        let reconstitute = || {
+            RepoState::reconstitute_from(&storage, https.clone(), base_dir.clone(), false).unwrap()
+        };
+
+        let sources = [SOURCE_1, SOURCE_2, SOURCE_3, SOURCE_1];
+        RepoState::new(
+            snapshot_from_src(session, 1, sources[0]),
+            https.clone(),
+            base_dir.clone(),
+        )
+        .save_to(&storage, &options)
+        .unwrap();
+        for (serial, source) in (2..).zip(&sources[1..3]) {
+            let mut state = reconstitute();
+            state
+                .apply(snapshot_from_src(session, serial, source))
+                .unwrap();
+            state.save_to(&storage, &options).unwrap();
+        }
+        assert_eq!(2, reconstitute().deltas().len());
+
+        // The delta for serial 2 is gone, so only the deltas after it can
+        // be listed.
+        let mut state = reconstitute();
+        storage
+            .remove(&base_dir.join(format!("{}/2/delta.xml", session)))
+            .unwrap();
+        state
+            .apply(snapshot_from_src(session, 4, sources[3]))
+            .unwrap();
+        let summary = state.save_to(&storage, &options).unwrap();
+        assert_eq!(2, summary.deltas());
+
+        let state = reconstitute();
+        let serials: Vec<_> = state.deltas().iter().map(DeltaRef::serial).collect();
+        assert_eq!(vec![4, 3], serials);
+
+        // The delta for serial 3 changed in size, so it is dropped as well.
+        let mut state = reconstitute();
+        storage
+            .save(b"", &base_dir.join(format!("{}/3/delta.xml", session)))
+            .unwrap();
+        state
+            .apply(snapshot_from_src(session, 5, SOURCE_2))
+            .unwrap();
+        state.save_to(&storage, &options).unwrap();
+
+        let state = reconstitute();
+        let serials: Vec<_> = state.deltas().iter().map(DeltaRef::serial).collect();
+        assert_eq!(vec![5, 4], serials);
+    }
+
+    fn save_and_reload_sha512<S: Storage>(storage: &S, base_dir: &str) {
+        let https = HttpsUri::from("https://localhost/rrdp/");
+        let options = SaveOptions {
+            hash_alg: HashAlg::Sha512,
+            ..SaveOptions::default()
+        };
+
+        let state = RepoState::new(snapshot_source_1(), https.clone(), base_dir.into());
+        let summary = state.save_to(storage, &options).unwrap();
+        assert_eq!(128, summary.snapshot_hash().to_string().len());
+
+        let mut state =
+            RepoState::reconstitute_from(storage, https.clone(), base_dir.into(), false).unwrap();
+        let snapshot_2 = snapshot_from_src(state.session, state.serial + 1, SOURCE_2);
+        state.apply(snapshot_2).unwrap();
+        state.save_to(storage, &options).unwrap();
+
+        let state = RepoState::reconstitute_from(storage, https, base_dir.into(), false).unwrap();
+        assert_eq!(2, state.serial());
+        assert_eq!(1, state.deltas().len());
+        assert_eq!(HashAlg::Sha512, state.deltas()[0].as_ref().hash().alg());
+    }
+
+    #[test]
+    fn save_and_reload_with_sha512() {
+        const WORK_DIR: &str = "./test-work/save_and_reload_with_sha512/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        save_and_reload_sha512(&FsStorage::default(), WORK_DIR);
+    }
+
+    #[test]
+    fn save_and_reload_with_sha512_in_memory() {
+        save_and_reload_sha512(&MemStorage::default(), "./rrdp/");
+    }
+
+    #[test]
+    fn save_session_state() {
+        const WORK_DIR: &str = "./test-work/save_session_state/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let https = HttpsUri::from("https://localhost/rrdp/");
+        assert_eq!(None, SessionState::load(Path::new(WORK_DIR)));
+
+        let state = RepoState::new(snapshot_source_1(), https, WORK_DIR.into());
+        let session = state.session;
+        state.save(&SaveOptions::default()).unwrap();
+
+        let last = SessionState::load(Path::new(WORK_DIR)).unwrap();
+        assert_eq!(session, last.session());
+        assert_eq!(1, last.serial());
+        assert_eq!(None, last.fingerprint());
+
+        let fingerprint = EncodedHash::from_content(b"sources");
+        let state = SessionState::new(session, 1).with_fingerprint(fingerprint.clone());
+        state.save(Path::new(WORK_DIR)).unwrap();
+        assert_eq!(Some(state), SessionState::load(Path::new(WORK_DIR)));
+
+        fs::write(PathBuf::from(WORK_DIR).join(STATE_FILE), "{}").unwrap();
+        assert_eq!(None, SessionState::load(Path::new(WORK_DIR)));
+    }
+
+    #[test]
+    fn recover_session_state_from_dirs() {
+        const WORK_DIR: &str = "./test-work/recover_session_state_from_dirs/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        assert_eq!(None, SessionState::recover(Path::new(WORK_DIR)));
+
+        let old_session = Uuid::new_v4();
+        let session = Uuid::new_v4();
+        let dir = |session: Uuid, serial: &str| {
+            let dir = PathBuf::from(WORK_DIR)
+                .join(session.to_string())
+                .join(serial);
+            fs::create_dir_all(dir).unwrap();
+        };
+        dir(old_session, "40");
+        thread::sleep(Duration::from_millis(10));
+        for serial in &["7", "12", "9", "unexpected"] {
+            dir(session, serial);
+        }
+        sync::save(
+            b"",
+            &PathBuf::from(WORK_DIR).join(format!("{}/100", session)),
+        )
+        .unwrap();
+
+        let recovered = SessionState::recover(Path::new(WORK_DIR)).unwrap();
+        assert_eq!(session, recovered.session());
+        assert_eq!(12, recovered.serial());
+    }
+
+    #[test]
+    fn notification_xml_round_trip() {
+        let https = HttpsUri::from("https://localhost/rrdp/");
+        let snapshot = FileRef::new(https.resolve("s/3/snapshot.xml"), &Bytes::from("3"));
+        let deltas = (2..=3)
+            .rev()
+            .map(|serial| {
+                let uri = https.resolve(&format!("s/{}/delta.xml", serial));
+                DeltaRef::new(serial, FileRef::new(uri, &Bytes::from("delta")))
+            })
+            .collect();
+        let notification = Notification::new(Uuid::new_v4(), 3, snapshot.clone(), deltas);
+
+        let xml = notification.write_xml();
+        let parsed = Notification::from_xml(xml.clone()).unwrap();
+        assert_eq!(xml, parsed.write_xml());
+
+        assert_eq!(notification.session(), parsed.session());
+        assert_eq!(3, parsed.serial());
+        assert_eq!(snapshot.uri(), parsed.snapshot().uri());
+        assert_eq!(snapshot.hash(), parsed.snapshot().hash());
+        assert_eq!(
+            vec![3, 2],
+            parsed
+                .deltas()
+                .iter()
+                .map(DeltaRef::serial)
+                .collect::<Vec<_>>()
+        );
+        for (delta, found) in notification.deltas().iter().zip(parsed.deltas()) {
+            assert_eq!(delta.as_ref().uri(), found.as_ref().uri());
+            assert_eq!(delta.as_ref().hash(), found.as_ref().hash());
+        }
+    }
+
+    #[test]
+    fn build_notification() {
+        let https = HttpsUri::from("https://localhost/rrdp/");
+        let session = Uuid::new_v4();
+        let snapshot_uri = https.resolve("s/3/snapshot.xml");
+        let delta_uri = |serial| https.resolve(&format!("s/{}/delta.xml", serial));
+        let delta = |serial| Bytes::from(format!("delta {}", serial));
+
+        let snapshot = FileRef::new(snapshot_uri.clone(), &Bytes::from("3"));
+        let deltas = (2..=3)
+            .rev()
+            .map(|serial| DeltaRef::new(serial, FileRef::new(delta_uri(serial), &delta(serial))))
+            .collect();
+        let expected = Notification::new(session, 3, snapshot, deltas);
+
+        let built = NotificationBuilder::new()
+            .add_delta(2, delta_uri(2), &delta(2))
+            .snapshot(snapshot_uri.clone(), &Bytes::from("3"))
+            .add_delta(3, delta_uri(3), &delta(3))
+            .build(session, 3)
+            .unwrap();
+        assert_eq!(expected, built);
+        assert_eq!(expected.write_xml(), built.write_xml());
+
+        let sha512 = NotificationBuilder::new()
+            .hash_alg(HashAlg::Sha512)
+            .snapshot(snapshot_uri, &Bytes::from("3"))
+            .build(session, 3)
+            .unwrap();
+        assert_eq!(HashAlg::Sha512, sha512.snapshot().hash().alg());
+
+        match NotificationBuilder::new().build(session, 3) {
+            Err(Error::NoSnapshotRef) => {}
+            res => panic!("Expected NoSnapshotRef, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn reconstitute_with_delta_gap() {
+        let storage = MemStorage::new();
+        let https = HttpsUri::from("https://localhost/rrdp/");
+        let base_dir = PathBuf::from("./rrdp/");
+        let session = Uuid::new_v4();
+        let options = SaveOptions {
+            delta_budget: Some(usize::MAX),
+            ..Default::default()
+        };
+        let reconstitute = |strict| {
+            RepoState::reconstitute_from(&storage, https.clone(), base_dir.clone(), strict)
+        };
+
+        let sources = [SOURCE_1, SOURCE_2, SOURCE_3, SOURCE_1, SOURCE_2];
+        RepoState::new(
+            snapshot_from_src(session, 1, sources[0]),
+            https.clone(),
+            base_dir.clone(),
+        )
+        .save_to(&storage, &options)
+        .unwrap();
+        for (serial, source) in (2..).zip(&sources[1..4]) {
+            let mut state = reconstitute(false).unwrap();
+            state
+                .apply(snapshot_from_src(session, serial, source))
+                .unwrap();
+            state.save_to(&storage, &options).unwrap();
+        }
+
+        // Leave out the delta for serial 3 from the notification file.
+        let path = base_dir.join(NOTIFICATION_FILE);
+        let notification = Notification::from_xml(storage.get(&path).unwrap()).unwrap();
+        let deltas = notification
+            .deltas()
+            .iter()
+            .filter(|d| d.serial() != 3)
+            .cloned()
+            .collect();
+        let notification = Notification::new(session, 4, notification.snapshot().clone(), deltas);
+        storage
+            .save(notification.write_xml().as_ref(), &path)
+            .unwrap();
+
+        match reconstitute(true) {
+            Err(Error::DeltaGap {
+                expected: 3,
+                found: 2,
+            }) => {}
+            res => panic!("Expected DeltaGap, got: {:?}", res.map(|s| s.serial())),
+        }
+
+        let mut state = reconstitute(false).unwrap();
+        let serials: Vec<_> = state.deltas().iter().map(DeltaRef::serial).collect();
+        assert_eq!(vec![4], serials);
+
+        state
+            .apply(snapshot_from_src(session, 5, sources[4]))
+            .unwrap();
+        state.save_to(&storage, &options).unwrap();
+        let state = reconstitute(true).unwrap();
+        let serials: Vec<_> = state.deltas().iter().map(DeltaRef::serial).collect();
+        assert_eq!(vec![5, 4], serials);
+    }
+
+    #[test]
+    fn reconstitute_unreadable_or_corrupt_notification() {
+        const WORK_DIR: &str = "./test-work/reconstitute_unreadable_or_corrupt_notification/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let https = HttpsUri::from("https://localhost/rrdp/");
+        let reconstitute = || RepoState::reconstitute(https.clone(), WORK_DIR.into());
+
+        let state = RepoState::new(snapshot_source_1(), https.clone(), WORK_DIR.into());
+        state.save(&SaveOptions::default()).unwrap();
+        let path = PathBuf::from(WORK_DIR).join("notification.xml");
+
+        // A directory exists, but cannot be read as a file.
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+        match reconstitute() {
+            Err(e @ Error::ReadFailed { .. }) => assert!(e.is_read_failure()),
+            res => panic!("Expected ReadFailed, got: {:?}", res.map(|s| s.serial())),
+        }
+
+        fs::remove_dir(&path).unwrap();
+        fs::write(&path, "broken").unwrap();
+        match reconstitute() {
+            Err(e @ Error::InvalidXml(_)) => assert!(!e.is_read_failure()),
+            res => panic!("Expected InvalidXml, got: {:?}", res.map(|s| s.serial())),
+        }
+
+        fs::remove_file(&path).unwrap();
+        match reconstitute() {
+            Err(e @ Error::NotificationUnreadable { .. }) => assert!(!e.is_read_failure()),
+            res => panic!(
+                "Expected NotificationUnreadable, got: {:?}",
+                res.map(|s| s.serial())
+            ),
+        }
+    }
+
+    #[test]
+    fn reconstitute_notification_with_unknown_content() {
+        const WORK_DIR: &str = "./test-work/reconstitute_notification_with_unknown_content/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let https = HttpsUri::from("https://localhost/rrdp/");
+
+        let state = RepoState::new(snapshot_source_1(), https.clone(), WORK_DIR.into());
+        state.clone().save(&SaveOptions::default()).unwrap();
+
+        let path = PathBuf::from(WORK_DIR).join("notification.xml");
+        let notification = fs::read_to_string(&path).unwrap();
+
+        let extra_attribute = notification.replace("<notification ", "<notification extra=\"x\" ");
+        let extra_element = notification.replace(
+            "</notification>",
+            "<extension kind=\"x\"><nested>text</nested></extension></notification>",
+        );
+
+        for xml in &[extra_attribute, extra_element] {
+            assert_ne!(&notification, xml);
+            fs::write(&path, xml).unwrap();
+
+            assert!(RepoState::reconstitute_with(https.clone(), WORK_DIR.into(), true).is_err());
+            let loaded = RepoState::reconstitute_with(https.clone(), WORK_DIR.into(), false);
+            assert_eq!(state, loaded.unwrap());
+        }
+    }
+
+    #[test]
+    fn reconstitute_reports_corrupt_files() {
+        const WORK_DIR: &str = "./test-work/reconstitute_reports_corrupt_files/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let https = HttpsUri::from("https://localhost/rrdp/");
+
+        let state = RepoState::new(snapshot_source_1(), https.clone(), WORK_DIR.into());
+        state.save(&SaveOptions::default()).unwrap();
+        let mut state = RepoState::reconstitute(https.clone(), WORK_DIR.into()).unwrap();
+        let snapshot_2 = snapshot_from_src(state.session, state.serial + 1, SOURCE_2);
+        state.apply(snapshot_2).unwrap();
+        let session = state.session;
+        state.save(&SaveOptions::default()).unwrap();
+
+        let session_dir = PathBuf::from(WORK_DIR).join(session.to_string());
+        let snapshot_path = session_dir.join("2").join("snapshot.xml");
+        let delta_path = session_dir.join("2").join("delta.xml");
+
+        let snapshot = fs::read(&snapshot_path).unwrap();
+        fs::write(&snapshot_path, b"corrupt").unwrap();
+        match RepoState::reconstitute(https.clone(), WORK_DIR.into()) {
+            Err(Error::SnapshotHashMismatch {
+                uri,
+                expected,
+                found,
+            }) => {
+                assert!(uri.to_string().ends_with("/2/snapshot.xml"));
+                assert_ne!(expected, found);
+            }
+            res => panic!("Expected snapshot hash mismatch, got: {:?}", res),
+        }
+        fs::write(&snapshot_path, snapshot).unwrap();
+
+        fs::write(&delta_path, b"corrupt").unwrap();
+        match RepoState::reconstitute(https.clone(), WORK_DIR.into()) {
+            Err(Error::DeltaHashMismatch { uri, .. }) => {
+                assert!(uri.to_string().ends_with("/2/delta.xml"))
+            }
+            res => panic!("Expected delta hash mismatch, got: {:?}", res),
+        }
+
+        fs::remove_file(&delta_path).unwrap();
+        match RepoState::reconstitute(https.clone(), WORK_DIR.into()) {
+            Err(Error::DeltaFileMissing { path }) => {
+                assert_eq!(delta_path.to_string_lossy(), path)
+            }
+            res => panic!("Expected missing delta, got: {:?}", res),
+        }
+
+        fs::remove_file(PathBuf::from(WORK_DIR).join("notification.xml")).unwrap();
+        match RepoState::reconstitute(https, WORK_DIR.into()) {
+            Err(Error::NotificationUnreadable { .. }) => {}
+            res => panic!("Expected unreadable notification, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn reconstitute_from_gzipped_files() {
+        const WORK_DIR: &str = "./test-work/reconstitute_from_gzipped_files/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let https = HttpsUri::from("https://localhost/rrdp/");
+
+        let state = RepoState::new(snapshot_source_1(), https.clone(), WORK_DIR.into());
+        state.save(&SaveOptions::default()).unwrap();
+        let mut state = RepoState::reconstitute(https.clone(), WORK_DIR.into()).unwrap();
+        let snapshot_2 = snapshot_from_src(state.session, state.serial + 1, SOURCE_2);
+        state.apply(snapshot_2).unwrap();
+        state.clone().save(&SaveOptions::default()).unwrap();
+        let expected = RepoState::reconstitute(https.clone(), WORK_DIR.into()).unwrap();
+
+        // Replace the snapshot and delta with compressed versions in place.
+        let serial_dir = PathBuf::from(WORK_DIR)
+            .join(state.session.to_string())
+            .join("2");
+        for name in &["snapshot.xml", "delta.xml"] {
+            let path = serial_dir.join(name);
+            let compressed = sync::gzip(sync::read(&path).unwrap().as_ref()).unwrap();
+            fs::write(&path, compressed).unwrap();
+        }
+
+        let state = RepoState::reconstitute(https, WORK_DIR.into()).unwrap();
+        assert_eq!(expected.snapshot(), state.snapshot());
+        assert_eq!(expected.deltas().len(), state.deltas().len());
+        let delta = &state.deltas()[0];
+        assert_eq!(expected.deltas()[0].as_ref().hash(), delta.as_ref().hash());
+        assert_eq!(
+            fs::metadata(serial_dir.join("delta.xml")).unwrap().len() as usize,
+            delta.size()
+        );
+        assert!(delta.size() < expected.deltas()[0].size());
+    }
+
+    #[test]
+    fn reject_too_large_snapshot_before_reading() {
+        const WORK_DIR: &str = "./test-work/reject_too_large_snapshot_before_reading/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let https = HttpsUri::from("https://localhost/rrdp/");
+
+        let state = RepoState::new(snapshot_source_1(), https.clone(), WORK_DIR.into());
+        state.clone().save(&SaveOptions::default()).unwrap();
+
+        // A sparse file takes no space, but is too large to be read.
+        let path = PathBuf::from(WORK_DIR)
+            .join(state.session.to_string())
+            .join("1/snapshot.xml");
+        fs::File::create(&path)
+            .unwrap()
+            .set_len(DEFAULT_MAX_BYTES + 1)
+            .unwrap();
+
+        match RepoState::reconstitute(https, WORK_DIR.into()) {
+            Err(Error::FileTooLarge { size, max, .. }) => {
+                assert_eq!(DEFAULT_MAX_BYTES + 1, size);
+                assert_eq!(DEFAULT_MAX_BYTES, max);
+            }
+            res => panic!("Expected file too large, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn retain_deltas_within_budget() {
+        let deltas = || -> VecDeque<DeltaRef> {
+            (1..=5)
+                .rev()
+                .map(|serial| {
+                    let uri =
+                        HttpsUri::from(format!("https://localhost/rrdp/{}.xml", serial).as_str());
+                    DeltaRef::new(serial, FileRef::new(uri, &Bytes::from(vec![0; 100])))
+                })
+                .collect()
+        };
+        let retained = |budget| {
+            let mut deltas = deltas();
+            RepoState::retain_deltas(&mut deltas, budget);
+            deltas.iter().map(DeltaRef::serial).collect::<Vec<_>>()
+        };
+
+        assert_eq!(Vec::<u64>::new(), retained(0));
+        assert_eq!(vec![5], retained(100));
+        assert_eq!(vec![5, 4, 3], retained(250));
+        assert_eq!(vec![5, 4, 3, 2, 1], retained(10_000));
+    }
+
+    #[test]
+    fn limit_deltas_in_notification() {
+        const WORK_DIR: &str = "./test-work/limit_deltas_in_notification/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let https = HttpsUri::from("https://localhost/rrdp/");
+        let session = Uuid::new_v4();
+        let options = SaveOptions {
+            delta_budget: Some(usize::MAX),
+            max_notification_deltas: Some(2),
+            ..clean_options(4)
+        };
+
+        let snapshot = |serial: u64| {
+            let objects = (1..=serial)
+                .map(|i| {
+                    let uri = RsyncUri::from(format!("rsync://localhost/repo/{}.cer", i).as_str());
+                    CurrentFile::new(uri, format!("{}", i).as_bytes())
+                })
+                .collect();
+            Snapshot::new(session, serial, objects)
+        };
+        RepoState::new(snapshot(1), https.clone(), WORK_DIR.into())
+            .save(&options)
+            .unwrap();
+        for serial in 2..=6 {
+            let mut state = RepoState::reconstitute(https.clone(), WORK_DIR.into()).unwrap();
+            state.apply(snapshot(serial)).unwrap();
+            let summary = state.save(&options).unwrap();
+            assert!(summary.deltas() <= 2);
+        }
+
+        let state = RepoState::reconstitute(https, WORK_DIR.into()).unwrap();
+        let listed: Vec<u64> = state.deltas().iter().map(DeltaRef::serial).collect();
+        assert_eq!(vec![6, 5], listed);
+
+        // The delta files for the last four serials are kept.
+        let session_dir = PathBuf::from(WORK_DIR).join(session.to_string());
+        for serial in 3..=6 {
+            assert!(session_dir.join(format!("{}/delta.xml", serial)).exists());
+        }
+        assert!(!session_dir.join("2").exists());
+        assert!(!session_dir.join("1").exists());
+    }
+
+    #[test]
+    fn clean_keeps_recent_sessions() {
+        const WORK_DIR: &str = "./test-work/clean_keeps_recent_sessions/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let https = HttpsUri::from("https://localhost/rrdp/");
+
+        let save_session = |keep_sessions| {
+            // Make sure that the session dirs differ in modification time.
+            thread::sleep(Duration::from_millis(10));
+            let state = RepoState::new(snapshot_source_1(), https.clone(), WORK_DIR.into());
+            let options = SaveOptions {
+                keep_sessions,
+                ..clean_options(25)
+            };
+            state.save(&options).unwrap().session()
+        };
+        let session_dir = |session: Uuid| PathBuf::from(WORK_DIR).join(session.to_string());
+
+        let first = save_session(1);
+        let second = save_session(2);
+        assert!(session_dir(first).exists());
+        assert!(session_dir(second).exists());
+
+        let third = save_session(2);
+        assert!(!session_dir(first).exists());
+        assert!(session_dir(second).exists());
+        assert!(session_dir(third).exists());
+
+        let fourth = save_session(1);
+        assert!(!session_dir(second).exists());
+        assert!(!session_dir(third).exists());
+        assert!(session_dir(fourth).exists());
+    }
+
+    #[test]
+    fn clean_keeps_recent_sessions_in_memory() {
+        let storage = MemStorage::new();
+        let https = HttpsUri::from("https://localhost/rrdp/");
+        let base_dir = PathBuf::from("./rrdp/");
+
+        // The older session has the larger Uuid, so that keeping sessions by
+        // Uuid rather than by modification time would keep the wrong one.
+        let sessions = [
+            Uuid::parse_str("ffffffff-ffff-4fff-bfff-ffffffffffff").unwrap(),
+            Uuid::parse_str("00000000-0000-4000-8000-000000000001").unwrap(),
+            Uuid::new_v4(),
+        ];
+        let options = SaveOptions {
+            keep_sessions: 2,
+            ..clean_options(25)
+        };
+        for session in &sessions {
+            let snapshot = snapshot_from_src(*session, 1, SOURCE_1);
+            RepoState::new(snapshot, https.clone(), base_dir.clone())
+                .save_to(&storage, &options)
+                .unwrap();
+        }
+
+        let mut kept: Vec<_> = session_dirs(&storage, &base_dir)
+            .into_iter()
+            .map(|(_, session)| session)
+            .collect();
+        kept.sort();
+        let mut expected = vec![sessions[1], sessions[2]];
+        expected.sort();
+        assert_eq!(expected, kept);
+    }
+
+    #[test]
+    fn save_gzip_copies() {
+        const WORK_DIR: &str = "./test-work/save_gzip_copies/";
+        let session = Uuid::new_v4();
+        let snapshot_1 = snapshot_from_src(session, 1, SOURCE_1);
+        let snapshot_2 = snapshot_from_src(session, 2, SOURCE_2);
+
+        let mut state = RepoState::new(
+            snapshot_1,
+            HttpsUri::from("https://localhost/rrdp/"),
+            PathBuf::from(WORK_DIR),
+        );
+        state.apply(snapshot_2).unwrap();
+
+        let options = SaveOptions {
+            gzip: true,
+            ..Default::default()
+        };
+        state.save(&options).unwrap();
+
+        for name in &["snapshot.xml", "delta.xml"] {
+            let path = PathBuf::from(format!("{}{}/2/{}", WORK_DIR, session, name));
+            let plain = sync::read(&path).unwrap();
+            let compressed = sync::read(&sync::gzip_path(&path)).unwrap();
+
+            let mut decompressed = vec![];
+            flate2::read::GzDecoder::new(compressed.as_ref())
+                .read_to_end(&mut decompressed)
+                .unwrap();
+
+            assert_eq!(plain.as_ref(), decompressed.as_slice());
+        }
+
+        // The notification must refer to the uncompressed snapshot
+        let reloaded =
+            RepoState::reconstitute(HttpsUri::from("https://localhost/rrdp/"), WORK_DIR.into())
+                .unwrap();
+        assert_eq!(2, reloaded.serial());
+    }
+
+    #[test]
+    fn save_with_hashed_names() {
+        const WORK_DIR: &str = "./test-work/save_with_hashed_names/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let https = HttpsUri::from("https://localhost/rrdp/");
+        let session = Uuid::new_v4();
+        let options = SaveOptions {
+            hashed_names: true,
+            ..Default::default()
+        };
+
+        let mut state = RepoState::new(
+            snapshot_from_src(session, 1, SOURCE_1),
+            https.clone(),
+            WORK_DIR.into(),
+        );
+        state
+            .apply(snapshot_from_src(session, 2, SOURCE_2))
+            .unwrap();
+        let summary = state.save(&options).unwrap();
+
+        let serial_dir = PathBuf::from(format!("{}{}/2", WORK_DIR, session));
+        let snapshot_name = format!("snapshot-{}.xml", summary.snapshot_hash());
+        assert!(serial_dir.join(&snapshot_name).exists());
+        assert!(!serial_dir.join("snapshot.xml").exists());
+        assert!(!serial_dir.join("delta.xml").exists());
+
+        let state = RepoState::reconstitute(https, WORK_DIR.into()).unwrap();
+        assert_eq!(2, state.serial());
+        let delta = state.deltas()[0].as_ref();
+        assert!(delta
+            .uri()
+            .to_string()
+            .ends_with(&format!("/2/delta-{}.xml", delta.hash())));
+        assert!(is_file_name("snapshot", &snapshot_name));
+        assert!(is_file_name("delta", "delta.xml"));
+        assert!(!is_file_name("delta", "delta-x.xml"));
+    }
+
+    fn list_dir(dir: &Path) -> Vec<PathBuf> {
+        let mut found = vec![];
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                found.append(&mut list_dir(&path));
+            }
+            found.push(path);
+        }
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn dry_run_leaves_target_unchanged() {
+        const WORK_DIR: &str = "./test-work/dry_run_leaves_target_unchanged/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+
+        let base_uri = HttpsUri::from("https://localhost/rrdp/");
+        let session = Uuid::new_v4();
+
+        // Leave an old session behind, so that clean has something to do
+        let old_session = snapshot_from_src(Uuid::new_v4(), 1, SOURCE_1);
+        RepoState::new(old_session, base_uri.clone(), WORK_DIR.into())
+            .save(&SaveOptions::default())
+            .unwrap();
+
+        let snapshot_1 = snapshot_from_src(session, 1, SOURCE_1);
+        RepoState::new(snapshot_1, base_uri.clone(), WORK_DIR.into())
+            .save(&SaveOptions::default())
+            .unwrap();
+
+        let before = list_dir(Path::new(WORK_DIR));
+        let notification_before = sync::read(&PathBuf::from(WORK_DIR).join("notification.xml"));
+
+        let mut state = RepoState::reconstitute(base_uri, WORK_DIR.into()).unwrap();
+        state
+            .apply(snapshot_from_src(session, 2, SOURCE_2))
+            .unwrap();
+        let summary = state.dry_run(&clean_options(25)).unwrap();
+
+        assert_eq!(before, list_dir(Path::new(WORK_DIR)));
+        assert_eq!(
+            notification_before.unwrap(),
+            sync::read(&PathBuf::from(WORK_DIR).join("notification.xml")).unwrap()
+        );
+
+        assert_eq!(session, summary.session());
+        assert_eq!(2, summary.serial());
+        assert_eq!(1, summary.publishes());
+        assert_eq!(1, summary.updates());
+        assert_eq!(1, summary.withdraws());
+
+        // The old session, and serial 1 of the current session would be removed
+        assert_eq!(2, summary.removed().len());
+        assert!(summary
+            .removed()
+            .contains(&PathBuf::from(WORK_DIR).join(format!("{}/1", session))));
+    }
+
+    #[test]
+    fn save_returns_summary() {
+        const WORK_DIR: &str = "./test-work/save_returns_summary/";
+        let base_uri = HttpsUri::from("https://localhost/rrdp/");
+        let session = Uuid::new_v4();
+
+        let snapshot_1 = snapshot_from_src(session, 1, SOURCE_1);
+        let summary = RepoState::new(snapshot_1, base_uri.clone(), WORK_DIR.into())
+            .save(&SaveOptions::default())
+            .unwrap();
+
+        assert_eq!(session, summary.session());
+        assert_eq!(1, summary.serial());
+        assert_eq!(0, summary.delta_elements());
+        assert_eq!(0, summary.deltas());
+
+        let mut state = RepoState::reconstitute(base_uri, WORK_DIR.into()).unwrap();
+        state
+            .apply(snapshot_from_src(session, 2, SOURCE_2))
+            .unwrap();
+        let summary = state.save(&SaveOptions::default()).unwrap();
+
+        assert_eq!(session, summary.session());
+        assert_eq!(2, summary.serial());
+        assert_eq!(3, summary.delta_elements());
+        assert_eq!(1, summary.deltas());
+        assert!(summary.removed().is_empty());
+    }
+
+    #[test]
+    fn summary_as_json() {
+        const WORK_DIR: &str = "./test-work/summary_as_json/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let base_uri = HttpsUri::from("https://localhost/rrdp/");
+        let session = Uuid::new_v4();
+
+        let snapshot_1 = snapshot_from_src(session, 1, SOURCE_1);
+        RepoState::new(snapshot_1, base_uri.clone(), WORK_DIR.into())
+            .save(&SaveOptions::default())
+            .unwrap();
+
+        let mut state = RepoState::reconstitute(base_uri, WORK_DIR.into()).unwrap();
+        state
+            .apply(snapshot_from_src(session, 2, SOURCE_2))
+            .unwrap();
+        let summary = state.save(&clean_options(25)).unwrap();
+
+        let json = serde_json::to_string(&summary).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        let snapshot_path = PathBuf::from(format!("{}{}/2/snapshot.xml", WORK_DIR, session));
+        let snapshot = sync::read(&snapshot_path).unwrap();
+
+        assert_eq!(session.to_string(), value["session"]);
+        assert_eq!(2, value["serial"]);
+        assert_eq!(
+            EncodedHash::from_content(snapshot.as_ref()).to_string(),
+            value["snapshot_hash"]
+        );
+        assert_eq!(snapshot.len(), value["snapshot_size"]);
+        assert_eq!(1, value["num_deltas"]);
+        assert_eq!(3, value["delta_elements"]);
+        assert_eq!(
+            vec![format!("{}{}/1", WORK_DIR, session)],
+            serde_json::from_value::<Vec<String>>(value["cleaned"].clone()).unwrap()
+        );
+    }
+
+    #[test]
+    fn clean_logs_retained_and_removed_dirs() {
+        const WORK_DIR: &str = "./test-work/clean_logs_retained_and_removed_dirs/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        capture_logs();
+
+        let base_uri = HttpsUri::from("https://localhost/rrdp/");
+        let session = Uuid::new_v4();
+
+        let snapshot_1 = snapshot_from_src(session, 1, SOURCE_1);
+        RepoState::new(snapshot_1, base_uri.clone(), WORK_DIR.into())
+            .save(&SaveOptions::default())
+            .unwrap();
+
+        let unexpected = format!("{}{}/unexpected_dir", WORK_DIR, session);
+        fs::create_dir_all(&unexpected).unwrap();
+
+        let mut state = RepoState::reconstitute(base_uri.clone(), WORK_DIR.into()).unwrap();
+        state
+            .apply(snapshot_from_src(session, 2, SOURCE_2))
+            .unwrap();
+        state.save(&clean_options(1)).unwrap();
+
+        assert!(PathBuf::from(&unexpected).is_dir());
+        assert!(logged(
+            log::Level::Warn,
+            "Retaining unexpected dir: unexpected_dir"
+        ));
+        assert!(logged(
+            log::Level::Info,
+            &format!("Removed: {}{}/1", WORK_DIR, session)
+        ));
+    }
+
+    #[test]
+    fn clean_keeps_notification_file() {
+        const WORK_DIR: &str = "./test-work/clean_keeps_notification_file/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        capture_logs();
+
+        let base_uri = HttpsUri::from("https://localhost/rrdp/");
+        for _ in 0..2 {
+            RepoState::new(snapshot_source_1(), base_uri.clone(), WORK_DIR.into())
+                .save(&clean_options(25))
+                .unwrap();
+        }
 
-        assert!(!target_dir_2.exists());
-        assert!(target_dir_3.exists());
-        assert!(!target_dir_4.exists());
+        let notification = format!("{}notification.xml", WORK_DIR);
+        assert!(PathBuf::from(&notification).is_file());
+        let removal = format!("Could not remove: {}", notification);
+        let records = LOGGER.0.lock().unwrap();
+        assert!(!records.iter().any(|(_, m)| m.starts_with(&removal)));
+        drop(records);
+        RepoState::reconstitute(base_uri, WORK_DIR.into()).unwrap();
     }
 }
@@ -0,0 +1,939 @@
+//! Runs rrdpit, i.e. crawls the sources, derives the next repository state
+//! and saves it to the target directory.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use index::{FileIndex, INDEX_FILE};
+use lock::{self, TargetLock};
+use metrics::{write_metrics, write_unchanged_metrics};
+use options::Options;
+use rrdp::{self, PublishQuery, RepoState, SaveSummary, SessionState, Snapshot};
+use stats::{self, StatsOptions};
+use storage::{FsStorage, Storage};
+use sync::{self, crawl_sources_indexed, mirror, CurrentFile, EncodedHash};
+
+/// Updates the RRDP files in the target directory for the current content
+/// of the sources, or the publish query. Nothing is written in a dry run,
+/// but the summary still reflects what would have been done. Metrics are
+/// written after the target was saved, if a metrics file is set.
+///
+/// Returns None if the sources did not change since they were last saved.
+/// The target is left as it is then, but the rsync mirror, checksums and
+/// metrics are still written, so that they are up to date as well.
+pub fn run(options: &Options) -> Result<Option<SaveSummary>, Error> {
+    if let Some(path) = other_notification(options) {
+        let path = path.to_string_lossy().to_string();
+        return Err(Error::OtherNotification(path));
+    }
+
+    // Another run against the same target could interleave its saving and
+    // cleaning with this one, or change the state the file index is based
+    // on. A dry run writes nothing, so it need not lock.
+    let _lock = if options.dry_run {
+        None
+    } else {
+        options.file_modes().create_dirs(&options.target)?;
+        Some(TargetLock::acquire(&options.target)?)
+    };
+    let storage = FsStorage::with_modes(options.file_modes()).with_tmp_dir(options.tmp_dir.clone());
+
+    // The file index takes the content of unchanged files from the current
+    // state, so that is reconstituted before the crawl if it is used, and
+    // else only if the sources changed.
+    let use_index = options.file_index && options.publish_query.is_none();
+    let mut reconstituted = if use_index {
+        Some(reconstitute(options, &storage)?)
+    } else {
+        None
+    };
+    let index = reconstituted
+        .as_ref()
+        .map(|state| load_index(options, &storage, state.as_ref()));
+
+    // Crawl the sources first, so that nothing else needs to be done if
+    // they did not change since the last run.
+    let started = Instant::now();
+    let files = match &options.publish_query {
+        Some(_) => None,
+        None => {
+            let files = crawl_sources_indexed(
+                &options.sources,
+                &options.rsync,
+                &options.crawl_options(),
+                index.as_ref(),
+            )?;
+            if files.is_empty() && !options.allow_empty {
+                return Err(Error::EmptySources);
+            }
+            Some(files)
+        }
+    };
+    let crawl_duration = started.elapsed();
+
+    let fingerprint = files.as_ref().map(|files| fingerprint(options, files));
+    if let (Some(files), Some(fingerprint)) = (&files, &fingerprint) {
+        if is_unchanged(options, fingerprint) {
+            info!("No changes");
+            if !options.dry_run {
+                write_unchanged(options, &storage, files, crawl_duration)?;
+            }
+            return Ok(None);
+        }
+    }
+
+    let mut state = match reconstituted.take() {
+        Some(state) => state,
+        None => reconstitute(options, &storage)?,
+    };
+
+    // The serial cannot be incremented beyond its maximum, so roll over to a
+    // new session instead, as RRDP allows.
+    let rollover = state
+        .as_ref()
+        .is_some_and(|state| state.serial() == u64::MAX);
+    if rollover {
+        warn!("Serial cannot be incremented, rolling over to a new session");
+    }
+
+    // Forcing a new session makes relying parties fetch a full snapshot. The
+    // previous state is only used for the content a publish query applies to.
+    let previous = if options.force_new_session || rollover {
+        state.take()
+    } else {
+        None
+    };
+
+    // A pinned session must not fork the history of another session.
+    if let (Some(state), Some(session_id)) = (&state, options.session_id) {
+        if state.session() != session_id {
+            return Err(Error::SessionMismatch {
+                expected: session_id,
+                found: state.session(),
+            });
+        }
+    }
+
+    // A serial start is for continuing the serials of another generator, it
+    // must not take the serial back.
+    if let (Some(state), Some(start)) = (&state, options.serial_start) {
+        if state.serial() >= start {
+            return Err(Error::SerialStartConflict {
+                start,
+                serial: state.serial(),
+            });
+        }
+    }
+
+    // If the state cannot be reconstituted, continue the last session if it
+    // is known, so that relying parties only need to fetch a new snapshot.
+    // It is known from the state file, or else from the dirs on disk. A
+    // pinned session is used instead of a new one, or any other last one.
+    let (session, serial) = match &state {
+        Some(state) => (state.session(), state.serial() + 1),
+        None if rollover => (Uuid::new_v4(), 1),
+        None if options.force_new_session => (
+            options.session_id.unwrap_or_else(Uuid::new_v4),
+            options.serial_start.unwrap_or(1),
+        ),
+        None => match SessionState::load(&options.target)
+            .or_else(|| SessionState::recover(&options.target))
+            .filter(|last| options.session_id.is_none_or(|id| id == last.session()))
+            .filter(|last| last.serial() < u64::MAX)
+        {
+            Some(last) => {
+                warn!(
+                    "Cannot reconstitute state, continuing session {} from serial {}",
+                    last.session(),
+                    last.serial()
+                );
+                (last.session(), last.serial() + 1)
+            }
+            None => (
+                options.session_id.unwrap_or_else(Uuid::new_v4),
+                options.serial_start.unwrap_or(1),
+            ),
+        },
+    };
+
+    let snapshot = match &options.publish_query {
+        Some(path) => {
+            let query = PublishQuery::from_xml(sync::read(path)?)?;
+            let current = match (&state, &previous) {
+                (Some(state), _) => state.snapshot().clone(),
+                (None, Some(previous)) => Snapshot::new(
+                    session,
+                    serial - 1,
+                    previous.snapshot().current_objects().to_vec(),
+                ),
+                (None, None) => Snapshot::new(session, serial - 1, vec![]),
+            };
+            current.apply_elements(query.elements())?
+        }
+        None => Snapshot::new(session, serial, files.unwrap_or_default()),
+    };
+
+    if let Some(rsync_out) = &options.rsync_out {
+        if !options.dry_run {
+            // The mirror was last written for the snapshot of the state.
+            let mirrored = state
+                .as_ref()
+                .or(previous.as_ref())
+                .map_or(&[][..], |state| state.snapshot().current_objects());
+            mirror(
+                &storage,
+                snapshot.current_objects(),
+                mirrored,
+                &options.rsync,
+                &options.rsync_map,
+                rsync_out,
+                options.clean,
+            )?;
+        }
+    }
+
+    let state = match state {
+        Some(mut state) => {
+            state.apply(snapshot)?;
+            state
+        }
+        None => RepoState::new(snapshot, options.https.clone(), options.target.clone())
+            .with_notification_name(options.notification_name.clone()),
+    };
+
+    if options.dry_run {
+        return Ok(Some(state.dry_run_to(&storage, &options.save_options())?));
+    }
+
+    let expected = if options.verify {
+        Some(state.snapshot().clone())
+    } else {
+        None
+    };
+
+    // The checksums are for the files in the saved target, so they are only
+    // written once it was saved, but saving consumes the state.
+    let checksums = match &options.checksums_file {
+        Some(path) => {
+            let objects = state.snapshot().current_objects();
+            let checksums = sync::format_checksums(objects, &options.rsync, &options.rsync_map)?;
+            Some((path, checksums))
+        }
+        None => None,
+    };
+
+    let summary = state.save_to(&storage, &options.save_options())?;
+    if let Some(expected) = expected {
+        verify_saved(options, &expected, &summary)?;
+    }
+    if let Some((path, checksums)) = checksums {
+        storage.save_atomic(checksums.as_bytes(), path)?;
+    }
+    if let Some(fingerprint) = fingerprint {
+        SessionState::new(summary.session(), summary.serial())
+            .with_fingerprint(fingerprint)
+            .save_to(&storage, &options.target)?;
+    }
+    if let Some(index) = &index {
+        index.save_to(&storage, &options.target.join(INDEX_FILE))?;
+    }
+    if let Some(path) = &options.metrics_file {
+        write_metrics(path, &summary, crawl_duration)?;
+    }
+    Ok(Some(summary))
+}
+
+/// Reconstitutes the state in the target. A repository that is missing or
+/// invalid is replaced by a new one, so then there is no state, but one that
+/// cannot be read may be fine, so that is an error instead.
+fn reconstitute(options: &Options, storage: &FsStorage) -> Result<Option<RepoState>, Error> {
+    match RepoState::reconstitute_named(
+        storage,
+        options.https.clone(),
+        options.target.clone(),
+        &options.notification_name,
+        options.strict,
+    ) {
+        Ok(state) => Ok(Some(state)),
+        Err(e) if e.is_read_failure() => Err(e.into()),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Loads the file index from the target, with the content of the current
+/// snapshot. If there is no snapshot, the index is of no use, and all files
+/// are read.
+fn load_index(options: &Options, storage: &FsStorage, state: Option<&RepoState>) -> FileIndex {
+    let previous = match state {
+        Some(state) => state.snapshot().current_objects(),
+        None => &[],
+    };
+    FileIndex::load_from(storage, &options.target.join(INDEX_FILE), previous)
+}
+
+/// Writes the rsync mirror, checksums and metrics for sources that did not
+/// change. The metrics are taken from the target as it is.
+fn write_unchanged(
+    options: &Options,
+    storage: &FsStorage,
+    files: &[CurrentFile],
+    crawl_duration: Duration,
+) -> Result<(), Error> {
+    if let Some(path) = &options.checksums_file {
+        sync::write_checksums(storage, files, &options.rsync, &options.rsync_map, path)?;
+    }
+    if let Some(rsync_out) = &options.rsync_out {
+        // The sources did not change since they were last saved, and then
+        // mirrored, so the files were mirrored before as they are.
+        mirror(
+            storage,
+            files,
+            files,
+            &options.rsync,
+            &options.rsync_map,
+            rsync_out,
+            options.clean,
+        )?;
+    }
+    if let Some(path) = &options.metrics_file {
+        let stats = stats::stats(&StatsOptions {
+            target: options.target.clone(),
+            notification_name: options.notification_name.clone(),
+            json: false,
+        })?;
+        write_unchanged_metrics(path, &stats, crawl_duration)?;
+    }
+    Ok(())
+}
+
+/// Returns a fingerprint of the crawled files, i.e. a hash over their sorted
+/// uris and hashes, the https base uri they are published under, and the
+/// options that affect the files saved for them.
+fn fingerprint(options: &Options, files: &[CurrentFile]) -> EncodedHash {
+    let mut files: Vec<&CurrentFile> = files.iter().collect();
+    files.sort_by(|a, b| a.uri().cmp(b.uri()));
+
+    let save = options.save_options();
+    let mut content = format!(
+        "{}\nmax_deltas={} clean={} gzip={} hash_alg={:?} delta_budget={:?} \
+         keep_sessions={} max_notification_deltas={:?} pretty={} hashed_names={}\n",
+        options.https,
+        save.max_deltas,
+        save.clean,
+        save.gzip,
+        save.hash_alg,
+        save.delta_budget,
+        save.keep_sessions,
+        save.max_notification_deltas,
+        save.pretty,
+        save.hashed_names,
+    );
+    for file in files {
+        content.push_str(&format!("{} {}\n", file.uri(), file.hash()));
+    }
+    EncodedHash::from_content(content.as_bytes())
+}
+
+/// Returns the path of a notification file in the target other than the one
+/// with the configured name, if there is one. A target holds a single
+/// repository, because the session dirs and state files in it are shared.
+fn other_notification(options: &Options) -> Option<PathBuf> {
+    fs::read_dir(&options.target)
+        .ok()?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_file()))
+        .map(|entry| entry.path())
+        .find(|path| {
+            path.extension() == Some(OsStr::new("xml"))
+                && path.file_name() != Some(OsStr::new(&options.notification_name))
+        })
+}
+
+/// Returns whether the target was last saved for the same sources, and can
+/// be left as it is. It cannot if a new session is forced, the notification
+/// file is gone, or the last state conflicts with the session id or serial
+/// start.
+fn is_unchanged(options: &Options, fingerprint: &EncodedHash) -> bool {
+    if options.force_new_session || !options.target.join(&options.notification_name).is_file() {
+        return false;
+    }
+    match SessionState::load(&options.target) {
+        Some(last) => {
+            last.fingerprint() == Some(fingerprint)
+                && options.session_id.is_none_or(|id| id == last.session())
+                && options
+                    .serial_start
+                    .is_none_or(|start| last.serial() < start)
+        }
+        None => false,
+    }
+}
+
+/// Reads back the saved target, which checks the snapshot and delta files
+/// against the hashes in the notification file, and checks that it has the
+/// expected snapshot and number of deltas.
+fn verify_saved(
+    options: &Options,
+    expected: &Snapshot,
+    summary: &SaveSummary,
+) -> Result<(), Error> {
+    let state = RepoState::reconstitute_named(
+        &FsStorage::default(),
+        options.https.clone(),
+        options.target.clone(),
+        &options.notification_name,
+        false,
+    )
+    .map_err(|e| Error::VerifyFailed(e.to_string()))?;
+    if state.session() != summary.session() || state.serial() != summary.serial() {
+        return Err(Error::VerifyFailed(format!(
+            "found session {} serial {}",
+            state.session(),
+            state.serial()
+        )));
+    }
+    if state.snapshot() != expected {
+        return Err(Error::VerifyFailed("snapshot content differs".to_string()));
+    }
+    if state.deltas().len() != summary.deltas() {
+        return Err(Error::VerifyFailed(format!(
+            "found {} deltas instead of {}",
+            state.deltas().len(),
+            summary.deltas()
+        )));
+    }
+    Ok(())
+}
+
+//------------ Error ---------------------------------------------------------
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "{}", _0)]
+    Sync(sync::Error),
+
+    #[display(fmt = "{}", _0)]
+    Rrdp(rrdp::Error),
+
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+
+    #[display(fmt = "{}", _0)]
+    Lock(lock::Error),
+
+    #[display(fmt = "{}", _0)]
+    Stats(stats::Error),
+
+    #[display(fmt = "Cannot watch sources: {}", _0)]
+    Watch(String),
+
+    #[display(fmt = "No files found in the sources, use --allow-empty to publish nothing")]
+    EmptySources,
+
+    #[display(
+        fmt = "Session {} in the target does not match session id {}",
+        found,
+        expected
+    )]
+    SessionMismatch { expected: Uuid, found: Uuid },
+
+    #[display(
+        fmt = "Serial start {} is not above the current serial {}",
+        start,
+        serial
+    )]
+    SerialStartConflict { start: u64, serial: u64 },
+
+    #[display(fmt = "Verification of the saved repository failed: {}", _0)]
+    VerifyFailed(String),
+
+    #[display(fmt = "Target already holds another notification file: {}", _0)]
+    OtherNotification(String),
+}
+
+impl From<sync::Error> for Error {
+    fn from(e: sync::Error) -> Self {
+        Error::Sync(e)
+    }
+}
+
+impl From<rrdp::Error> for Error {
+    fn from(e: rrdp::Error) -> Self {
+        Error::Rrdp(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<lock::Error> for Error {
+    fn from(e: lock::Error) -> Self {
+        Error::Lock(e)
+    }
+}
+
+impl From<stats::Error> for Error {
+    fn from(e: stats::Error) -> Self {
+        Error::Stats(e)
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use sync::{HttpsUri, RsyncUri};
+
+    fn empty_source_options(work_dir: &str) -> Options {
+        let _ = fs::remove_dir_all(work_dir);
+        let source = PathBuf::from(work_dir).join("source");
+        fs::create_dir_all(&source).unwrap();
+
+        Options::new(
+            vec![source],
+            PathBuf::from(work_dir).join("target"),
+            RsyncUri::base_uri("rsync://localhost/repo/").unwrap(),
+            HttpsUri::base_uri("https://localhost/rrdp/").unwrap(),
+            false,
+            25,
+        )
+    }
+
+    #[test]
+    fn refuse_empty_sources() {
+        let options = empty_source_options("./test-work/refuse_empty_sources/");
+        match run(&options) {
+            Err(Error::EmptySources) => {}
+            res => panic!(
+                "Expected EmptySources, got: {:?}",
+                res.map(|s| s.map(|s| s.serial()))
+            ),
+        }
+        assert!(!options.target.join("notification.xml").exists());
+    }
+
+    #[test]
+    fn allow_empty_sources() {
+        let mut options = empty_source_options("./test-work/allow_empty_sources/");
+        options.allow_empty = true;
+        let summary = run(&options).unwrap().unwrap();
+        assert_eq!(1, summary.serial());
+        assert!(options.target.join("notification.xml").exists());
+    }
+
+    #[test]
+    fn run_with_notification_name() {
+        let work_dir = PathBuf::from("./test-work/run_with_notification_name/");
+        let _ = fs::remove_dir_all(&work_dir);
+        let source = work_dir.join("source");
+        sync::save(b"one", &source.join("one.cer")).unwrap();
+
+        let mut options = Options::new(
+            vec![source.clone()],
+            work_dir.join("target"),
+            RsyncUri::base_uri("rsync://localhost/repo/").unwrap(),
+            HttpsUri::base_uri("https://localhost/rrdp/").unwrap(),
+            false,
+            25,
+        );
+        options.notification_name = "repo-a.xml".to_string();
+        options.verify = true;
+        let first = run(&options).unwrap().unwrap();
+        assert!(options.target.join("repo-a.xml").is_file());
+        assert!(!options.target.join("notification.xml").exists());
+        assert!(run(&options).unwrap().is_none());
+
+        sync::save(b"two", &source.join("two.cer")).unwrap();
+        let second = run(&options).unwrap().unwrap();
+        assert_eq!(first.session(), second.session());
+        assert_eq!(2, second.serial());
+        assert_eq!(1, second.deltas());
+
+        // Another repository cannot be published in the same target.
+        options.notification_name = "repo-b.xml".to_string();
+        match run(&options) {
+            Err(Error::OtherNotification(path)) => assert!(path.ends_with("repo-a.xml")),
+            res => panic!(
+                "Expected OtherNotification, got: {:?}",
+                res.map(|s| s.map(|s| s.serial()))
+            ),
+        }
+        assert!(!options.target.join("repo-b.xml").exists());
+    }
+
+    #[test]
+    fn continue_session_without_notification() {
+        let work_dir = PathBuf::from("./test-work/continue_session_without_notification/");
+        let _ = fs::remove_dir_all(&work_dir);
+        let source = work_dir.join("source");
+        sync::save(b"one", &source.join("one.cer")).unwrap();
+
+        let options = Options::new(
+            vec![source.clone()],
+            work_dir.join("target"),
+            RsyncUri::base_uri("rsync://localhost/repo/").unwrap(),
+            HttpsUri::base_uri("https://localhost/rrdp/").unwrap(),
+            false,
+            25,
+        );
+        let first = run(&options).unwrap().unwrap();
+        assert_eq!(1, first.serial());
+
+        fs::remove_file(options.target.join("notification.xml")).unwrap();
+        sync::save(b"two", &source.join("two.cer")).unwrap();
+        let second = run(&options).unwrap().unwrap();
+        assert_eq!(first.session(), second.session());
+        assert_eq!(2, second.serial());
+
+        // Without the state file, the serial is recovered from the dirs.
+        fs::remove_file(options.target.join("notification.xml")).unwrap();
+        fs::remove_file(options.target.join("state.json")).unwrap();
+        let third = run(&options).unwrap().unwrap();
+        assert_eq!(first.session(), third.session());
+        assert_eq!(3, third.serial());
+
+        // Without any of these, a new session is started.
+        fs::remove_dir_all(&options.target).unwrap();
+        let fourth = run(&options).unwrap().unwrap();
+        assert_ne!(first.session(), fourth.session());
+        assert_eq!(1, fourth.serial());
+    }
+
+    #[test]
+    fn stop_on_unreadable_notification() {
+        let options = empty_source_options("./test-work/stop_on_unreadable_notification/");
+        sync::save(b"one", &options.sources[0].join("one.cer")).unwrap();
+        let first = run(&options).unwrap().unwrap();
+        sync::save(b"two", &options.sources[0].join("two.cer")).unwrap();
+
+        // The repository may be fine, so it must not be replaced.
+        let path = options.target.join("notification.xml");
+        fs::remove_file(&path).unwrap();
+        fs::create_dir(&path).unwrap();
+        match run(&options) {
+            Err(Error::Rrdp(rrdp::Error::ReadFailed { .. })) => {}
+            res => panic!(
+                "Expected ReadFailed, got: {:?}",
+                res.map(|s| s.map(|s| s.serial()))
+            ),
+        }
+        assert!(path.is_dir());
+
+        // A corrupt notification file is replaced, continuing the session.
+        fs::remove_dir(&path).unwrap();
+        fs::write(&path, "broken").unwrap();
+        let second = run(&options).unwrap().unwrap();
+        assert_eq!(first.session(), second.session());
+        assert_eq!(2, second.serial());
+    }
+
+    #[test]
+    fn refuse_locked_target() {
+        let mut options = empty_source_options("./test-work/refuse_locked_target/");
+        options.allow_empty = true;
+
+        let lock = TargetLock::acquire(&options.target).unwrap();
+        match run(&options) {
+            Err(Error::Lock(lock::Error::Locked(_))) => {}
+            res => panic!(
+                "Expected Locked, got: {:?}",
+                res.map(|s| s.map(|s| s.serial()))
+            ),
+        }
+        assert!(!options.target.join("notification.xml").exists());
+
+        drop(lock);
+        assert_eq!(1, run(&options).unwrap().unwrap().serial());
+    }
+
+    #[test]
+    fn skip_unchanged_sources() {
+        let mut options = empty_source_options("./test-work/skip_unchanged_sources/");
+        sync::save(b"one", &options.sources[0].join("one.cer")).unwrap();
+        let first = run(&options).unwrap().unwrap();
+
+        let modified = |name: &str| {
+            fs::metadata(options.target.join(name))
+                .unwrap()
+                .modified()
+                .unwrap()
+        };
+        let notification = modified("notification.xml");
+        let state = modified("state.json");
+
+        assert_eq!(None, run(&options).unwrap());
+        assert_eq!(notification, modified("notification.xml"));
+        assert_eq!(state, modified("state.json"));
+
+        // Any change in the sources means a new serial.
+        sync::save(b"two", &options.sources[0].join("two.cer")).unwrap();
+        let second = run(&options).unwrap().unwrap();
+        assert_eq!(first.session(), second.session());
+        assert_eq!(2, second.serial());
+
+        // A change in how the target is saved means it is saved again.
+        options.gzip = true;
+        let third = run(&options).unwrap().unwrap();
+        let snapshot = format!("{}/{}/snapshot.xml.gz", third.session(), third.serial());
+        assert!(options.target.join(snapshot).is_file());
+        assert_eq!(None, run(&options).unwrap());
+    }
+
+    #[test]
+    fn run_with_file_index() {
+        let mut options = empty_source_options("./test-work/run_with_file_index/");
+        options.file_index = true;
+        options.clean = true;
+        sync::save(b"one", &options.sources[0].join("one.cer")).unwrap();
+        run(&options).unwrap().unwrap();
+        assert!(options.target.join(INDEX_FILE).is_file());
+
+        sync::save(b"two", &options.sources[0].join("two.cer")).unwrap();
+        let summary = run(&options).unwrap().unwrap();
+        assert_eq!(2, summary.serial());
+
+        let state = RepoState::reconstitute(options.https.clone(), options.target.clone()).unwrap();
+        let expected = crawl_sources_indexed(
+            &options.sources,
+            &options.rsync,
+            &options.crawl_options(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(expected.as_slice(), state.snapshot().current_objects());
+        assert!(options.target.join(INDEX_FILE).is_file());
+    }
+
+    #[test]
+    fn write_checksums_file() {
+        let mut options = empty_source_options("./test-work/write_checksums_file/");
+        let path = PathBuf::from("./test-work/write_checksums_file/SHA256SUMS");
+        options.checksums_file = Some(path.clone());
+        sync::save(b"one", &options.sources[0].join("one.cer")).unwrap();
+        run(&options).unwrap().unwrap();
+
+        assert_eq!(
+            format!("{}  one.cer\n", EncodedHash::from_content(b"one")),
+            fs::read_to_string(&path).unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_checksums_file_with_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut options = empty_source_options("./test-work/write_checksums_file_with_mode/");
+        let path = PathBuf::from("./test-work/write_checksums_file_with_mode/SHA256SUMS");
+        options.checksums_file = Some(path.clone());
+        options.file_mode = Some(0o640);
+        sync::save(b"one", &options.sources[0].join("one.cer")).unwrap();
+        let mode = || fs::metadata(&path).unwrap().permissions().mode() & 0o7777;
+
+        run(&options).unwrap().unwrap();
+        assert_eq!(0o640, mode());
+
+        // Also when the sources did not change.
+        fs::remove_file(&path).unwrap();
+        assert!(run(&options).unwrap().is_none());
+        assert_eq!(0o640, mode());
+    }
+
+    #[test]
+    fn mirror_with_rsync_map() {
+        const WORK_DIR: &str = "./test-work/mirror_with_rsync_map/";
+        let mut options = empty_source_options(WORK_DIR);
+        let rsync_out = PathBuf::from(WORK_DIR).join("rsync");
+        let checksums = PathBuf::from(WORK_DIR).join("SHA256SUMS");
+        options.rsync_out = Some(rsync_out.clone());
+        options.checksums_file = Some(checksums.clone());
+        options.rsync_map = vec![(
+            "module".to_string(),
+            RsyncUri::base_uri("rsync://localhost/module/").unwrap(),
+        )];
+        sync::save(b"one", &options.sources[0].join("one.cer")).unwrap();
+        sync::save(b"two", &options.sources[0].join("module/two.cer")).unwrap();
+        run(&options).unwrap().unwrap();
+
+        assert_eq!(
+            b"one".to_vec(),
+            fs::read(rsync_out.join("one.cer")).unwrap()
+        );
+        assert_eq!(
+            b"two".to_vec(),
+            fs::read(rsync_out.join("module/two.cer")).unwrap()
+        );
+        assert_eq!(
+            format!(
+                "{}  module/two.cer\n{}  one.cer\n",
+                EncodedHash::from_content(b"two"),
+                EncodedHash::from_content(b"one")
+            ),
+            fs::read_to_string(&checksums).unwrap()
+        );
+    }
+
+    #[test]
+    fn pin_session_id() {
+        let mut options = empty_source_options("./test-work/pin_session_id/");
+        options.allow_empty = true;
+        let session = Uuid::new_v4();
+        options.session_id = Some(session);
+
+        let first = run(&options).unwrap().unwrap();
+        assert_eq!(session, first.session());
+        assert_eq!(1, first.serial());
+
+        // The pinned session is continued.
+        sync::save(b"one", &options.sources[0].join("one.cer")).unwrap();
+        let second = run(&options).unwrap().unwrap();
+        assert_eq!(session, second.session());
+        assert_eq!(2, second.serial());
+    }
+
+    #[test]
+    fn force_new_session() {
+        let mut options = empty_source_options("./test-work/force_new_session/");
+        sync::save(b"one", &options.sources[0].join("one.cer")).unwrap();
+        let first = run(&options).unwrap().unwrap();
+        sync::save(b"two", &options.sources[0].join("two.cer")).unwrap();
+        let second = run(&options).unwrap().unwrap();
+        assert_eq!(first.session(), second.session());
+        assert_eq!(2, second.serial());
+
+        options.force_new_session = true;
+        options.clean = true;
+        let forced = run(&options).unwrap().unwrap();
+        assert_ne!(first.session(), forced.session());
+        assert_eq!(1, forced.serial());
+        assert!(!options.target.join(first.session().to_string()).exists());
+
+        let state = RepoState::reconstitute(options.https.clone(), options.target.clone()).unwrap();
+        assert_eq!(forced.session(), state.session());
+        assert_eq!(2, state.snapshot().len());
+        assert!(state.deltas().is_empty());
+    }
+
+    #[test]
+    fn verify_after_save() {
+        let mut options = empty_source_options("./test-work/verify_after_save/");
+        options.verify = true;
+        sync::save(b"one", &options.sources[0].join("one.cer")).unwrap();
+        run(&options).unwrap().unwrap();
+        sync::save(b"two", &options.sources[0].join("two.cer")).unwrap();
+        let summary = run(&options).unwrap().unwrap();
+        assert_eq!(2, summary.serial());
+    }
+
+    #[test]
+    fn verify_detects_tampered_files() {
+        let options = empty_source_options("./test-work/verify_detects_tampered_files/");
+        sync::save(b"one", &options.sources[0].join("one.cer")).unwrap();
+        let summary = run(&options).unwrap().unwrap();
+        let state = RepoState::reconstitute(options.https.clone(), options.target.clone()).unwrap();
+        verify_saved(&options, state.snapshot(), &summary).unwrap();
+
+        let path = options
+            .target
+            .join(format!("{}/1/snapshot.xml", summary.session()));
+        let tampered = fs::read_to_string(&path).unwrap().replace("b25l", "dHdv");
+        fs::write(&path, tampered).unwrap();
+        match verify_saved(&options, state.snapshot(), &summary) {
+            Err(Error::VerifyFailed(_)) => {}
+            res => panic!("Expected VerifyFailed, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn roll_over_session_at_max_serial() {
+        let mut options = empty_source_options("./test-work/roll_over_session_at_max_serial/");
+        options.clean = true;
+        options.serial_start = Some(u64::MAX);
+        sync::save(b"one", &options.sources[0].join("one.cer")).unwrap();
+        let first = run(&options).unwrap().unwrap();
+        assert_eq!(u64::MAX, first.serial());
+
+        options.serial_start = None;
+        sync::save(b"two", &options.sources[0].join("two.cer")).unwrap();
+        let second = run(&options).unwrap().unwrap();
+        assert_ne!(first.session(), second.session());
+        assert_eq!(1, second.serial());
+        assert!(!options.target.join(first.session().to_string()).exists());
+
+        let state = RepoState::reconstitute(options.https.clone(), options.target.clone()).unwrap();
+        assert_eq!(second.session(), state.session());
+        assert_eq!(2, state.snapshot().len());
+
+        // Also when the state is lost, a new session is started.
+        fs::remove_dir_all(&options.target).unwrap();
+        SessionState::new(first.session(), u64::MAX)
+            .save(&options.target)
+            .unwrap();
+        let third = run(&options).unwrap().unwrap();
+        assert_ne!(first.session(), third.session());
+        assert_eq!(1, third.serial());
+    }
+
+    #[test]
+    fn start_at_serial() {
+        let mut options = empty_source_options("./test-work/start_at_serial/");
+        options.allow_empty = true;
+        options.serial_start = Some(1000);
+
+        let summary = run(&options).unwrap().unwrap();
+        assert_eq!(1000, summary.serial());
+        let state = RepoState::reconstitute(options.https.clone(), options.target.clone()).unwrap();
+        assert_eq!(1000, state.serial());
+    }
+
+    #[test]
+    fn refuse_serial_start_at_or_below_current() {
+        let mut options =
+            empty_source_options("./test-work/refuse_serial_start_at_or_below_current/");
+        options.allow_empty = true;
+        options.serial_start = Some(5);
+        run(&options).unwrap().unwrap();
+
+        match run(&options) {
+            Err(Error::SerialStartConflict { start, serial }) => {
+                assert_eq!(5, start);
+                assert_eq!(5, serial);
+            }
+            res => panic!(
+                "Expected SerialStartConflict, got: {:?}",
+                res.map(|s| s.map(|s| s.serial()))
+            ),
+        }
+    }
+
+    #[test]
+    fn refuse_other_session_id() {
+        let mut options = empty_source_options("./test-work/refuse_other_session_id/");
+        options.allow_empty = true;
+        let existing = run(&options).unwrap().unwrap();
+
+        let pinned = Uuid::new_v4();
+        options.session_id = Some(pinned);
+        match run(&options) {
+            Err(Error::SessionMismatch { expected, found }) => {
+                assert_eq!(pinned, expected);
+                assert_eq!(existing.session(), found);
+            }
+            res => panic!(
+                "Expected SessionMismatch, got: {:?}",
+                res.map(|s| s.map(|s| s.serial()))
+            ),
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! A minimal HTTP server for the files in the target directory.
+//!
+//! This is meant for testing and small deployments. For anything serious
+//! use an http server of your preferred flavour.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The default number of connections handled at the same time. Further
+/// connections wait in the listen backlog until one is done.
+const MAX_CONNECTIONS: usize = 64;
+
+/// The default time to wait for a client to send its request, or to take
+/// the response.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+//------------ ServeOptions --------------------------------------------------
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ServeOptions {
+    /// The directory with the RRDP files to serve.
+    pub target: PathBuf,
+
+    /// The address to listen on.
+    pub listen: SocketAddr,
+}
+
+//------------ Server --------------------------------------------------------
+
+/// Serves the files in a target directory over HTTP/1.1. Every connection is
+/// handled in its own thread and closed after a single request. Only a
+/// limited number of connections is handled at the same time, and clients
+/// that are too slow to send their request or take the response are
+/// dropped.
+pub struct Server {
+    listener: TcpListener,
+    target: PathBuf,
+    max_connections: usize,
+    timeout: Duration,
+}
+
+impl Server {
+    pub fn bind(options: &ServeOptions) -> Result<Self, io::Error> {
+        let listener = TcpListener::bind(options.listen)?;
+        Ok(Server {
+            listener,
+            target: options.target.clone(),
+            max_connections: MAX_CONNECTIONS,
+            timeout: TIMEOUT,
+        })
+    }
+
+    /// Returns a server that handles at most this many connections at the
+    /// same time.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections.max(1);
+        self
+    }
+
+    /// Returns a server that waits at most this long for a client to send
+    /// its request, or to take the response.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr, io::Error> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections until the listener fails.
+    pub fn run(self) -> Result<(), io::Error> {
+        let slots = Arc::new(Slots::new(self.max_connections));
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            stream.set_read_timeout(Some(self.timeout))?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            let slot = Slots::acquire(&slots);
+            let target = self.target.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle(stream, &target) {
+                    warn!("Error handling request: {}", e);
+                }
+                drop(slot);
+            });
+        }
+        Ok(())
+    }
+}
+
+//------------ Slots ---------------------------------------------------------
+
+/// Counts the connections being handled, so that no more than the maximum
+/// are handled at the same time.
+struct Slots {
+    max: usize,
+    active: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Slots {
+    fn new(max: usize) -> Self {
+        Slots {
+            max,
+            active: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Waits until fewer than the maximum connections are handled, and takes
+    /// a slot for one more. The slot is given back when it is dropped.
+    fn acquire(slots: &Arc<Slots>) -> Slot {
+        let mut active = slots.active.lock().expect("slots lock poisoned");
+        while *active >= slots.max {
+            active = slots.freed.wait(active).expect("slots lock poisoned");
+        }
+        *active += 1;
+        Slot(slots.clone())
+    }
+}
+
+/// A connection being handled.
+struct Slot(Arc<Slots>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut active = self.0.active.lock().expect("slots lock poisoned");
+        *active -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+fn handle(stream: TcpStream, target: &Path) -> Result<(), io::Error> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Skip the headers, we do not need any of them.
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) if method == "GET" || method == "HEAD" => {
+            let mut response = respond(target, path);
+            if method == "HEAD" {
+                response.body = vec![];
+            }
+            response
+        }
+        (Some(_), Some(_)) => Response::error("405 Method Not Allowed"),
+        _ => Response::error("400 Bad Request"),
+    };
+
+    debug!("{} -> {}", request_line.trim(), response.status);
+    response.write_to(stream)
+}
+
+fn respond(target: &Path, path: &str) -> Response {
+    let path = path.split('?').next().unwrap_or("");
+    let rel = match path.strip_prefix('/') {
+        Some(rel) => rel,
+        None => return Response::error("400 Bad Request"),
+    };
+
+    // Do not allow escaping the target dir, or serving hidden files such as
+    // the temporary files used when saving.
+    if rel
+        .split('/')
+        .any(|segment| segment.is_empty() || segment.starts_with('.') || segment.contains('\\'))
+    {
+        return Response::error("404 Not Found");
+    }
+
+    let full_path = target.join(rel);
+    match fs::read(&full_path) {
+        Ok(body) if full_path.is_file() => Response {
+            status: "200 OK",
+            content_type: content_type(rel),
+            cache_control: cache_control(rel),
+            body,
+        },
+        _ => Response::error("404 Not Found"),
+    }
+}
+
+fn content_type(rel: &str) -> &'static str {
+    if rel.ends_with(".xml") {
+        "application/xml"
+    } else if rel.ends_with(".gz") {
+        "application/gzip"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// The notification file changes on every run, but snapshot and delta files
+/// never change once written, as new files get a new serial.
+fn cache_control(rel: &str) -> &'static str {
+    if rel == "notification.xml" {
+        "no-cache"
+    } else {
+        "public, max-age=31536000, immutable"
+    }
+}
+
+//------------ Response ------------------------------------------------------
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    cache_control: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn error(status: &'static str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain",
+            cache_control: "no-cache",
+            body: status.as_bytes().to_vec(),
+        }
+    }
+
+    fn write_to(self, mut stream: TcpStream) -> Result<(), io::Error> {
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\n\
+             Content-Type: {}\r\n\
+             Content-Length: {}\r\n\
+             Cache-Control: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.status,
+            self.content_type,
+            self.body.len(),
+            self.cache_control
+        )?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rrdp::{RepoState, SaveOptions, Snapshot};
+    use std::io::Read;
+    use sync::{self, HttpsUri, RsyncUri};
+    use uuid::Uuid;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serve_generated_repo() {
+        const WORK_DIR: &str = "./test-work/serve_generated_repo/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let files =
+            sync::crawl_disk(&PathBuf::from("./test-resources/source-1/"), &rsync_base).unwrap();
+        let session = Uuid::new_v4();
+        let snapshot = Snapshot::new(session, 1, files);
+        RepoState::new(
+            snapshot,
+            HttpsUri::from("https://localhost/rrdp/"),
+            WORK_DIR.into(),
+        )
+        .save(&SaveOptions::default())
+        .unwrap();
+
+        let server = Server::bind(&ServeOptions {
+            target: PathBuf::from(WORK_DIR),
+            listen: "127.0.0.1:0".parse().unwrap(),
+        })
+        .unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let notification = fs::read_to_string(format!("{}notification.xml", WORK_DIR)).unwrap();
+        let response = get(addr, "/notification.xml");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/xml\r\n"));
+        assert!(response.contains("Cache-Control: no-cache\r\n"));
+        assert!(response.ends_with(&format!("\r\n\r\n{}", notification)));
+
+        let response = get(addr, &format!("/{}/1/snapshot.xml", session));
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Cache-Control: public, max-age=31536000, immutable\r\n"));
+
+        let response = get(addr, "/../Cargo.toml");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let response = get(addr, "/missing.xml");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn drop_idle_connections() {
+        const WORK_DIR: &str = "./test-work/drop_idle_connections/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        sync::save(b"n", &PathBuf::from(WORK_DIR).join("notification.xml")).unwrap();
+
+        let server = Server::bind(&ServeOptions {
+            target: PathBuf::from(WORK_DIR),
+            listen: "127.0.0.1:0".parse().unwrap(),
+        })
+        .unwrap()
+        .with_max_connections(1)
+        .with_timeout(Duration::from_millis(200));
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // An idle client takes the only slot, so the next request is only
+        // handled once the idle connection has timed out.
+        let mut idle = TcpStream::connect(addr).unwrap();
+        let response = get(addr, "/notification.xml");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nn"));
+
+        let mut rest = vec![];
+        assert_eq!(0, idle.read_to_end(&mut rest).unwrap());
+    }
+}
@@ -0,0 +1,89 @@
+//! Writes a single snapshot for a source directory, without a notification
+//! file, deltas or any state, e.g. to pipe it into another tool.
+
+use std::io;
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+use rrdp::Snapshot;
+use sync::{self, crawl_disk_with, CrawlOptions, RsyncUri};
+
+//------------ SnapshotOptions -----------------------------------------------
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SnapshotOptions {
+    /// The source directory.
+    pub source: PathBuf,
+
+    /// The base rsync uri for the source directory.
+    pub rsync: RsyncUri,
+
+    /// Rsync base uris for top-level subdirs, see 'CrawlOptions::rsync_map'.
+    pub rsync_map: Vec<(String, RsyncUri)>,
+}
+
+/// Crawls the source directory, and returns a snapshot of it for a new
+/// session, with serial 1.
+pub fn snapshot(options: &SnapshotOptions) -> Result<Snapshot, Error> {
+    let crawl_options = CrawlOptions {
+        rsync_map: options.rsync_map.clone(),
+        ..Default::default()
+    };
+    let files = crawl_disk_with(&options.source, &options.rsync, &crawl_options)?;
+    Ok(Snapshot::new(Uuid::new_v4(), 1, files))
+}
+
+/// Writes the snapshot XML for the source directory. These are the exact
+/// bytes that would be saved as 'snapshot.xml' in the target directory.
+pub fn write_snapshot<W: io::Write>(options: &SnapshotOptions, mut w: W) -> Result<(), Error> {
+    snapshot(options)?.write_xml_to(&mut w, false)?;
+    w.flush()?;
+    Ok(())
+}
+
+//------------ Error ---------------------------------------------------------
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "{}", _0)]
+    Sync(sync::Error),
+
+    #[display(fmt = "{}", _0)]
+    Io(io::Error),
+}
+
+impl From<sync::Error> for Error {
+    fn from(e: sync::Error) -> Self {
+        Error::Sync(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_snapshot_xml() {
+        let options = SnapshotOptions {
+            source: PathBuf::from("./test-resources/source-1/"),
+            rsync: RsyncUri::base_uri("rsync://localhost/repo/").unwrap(),
+            rsync_map: vec![],
+        };
+        let mut xml = vec![];
+        write_snapshot(&options, &mut xml).unwrap();
+
+        let snapshot = Snapshot::from_xml(xml.clone().into()).unwrap();
+        assert_eq!(1, snapshot.serial());
+        assert_eq!(3, snapshot.len());
+        assert_eq!(xml, snapshot.write_xml().to_vec());
+    }
+}
@@ -0,0 +1,193 @@
+//! Reports the size of the repository in a target directory, i.e. the number
+//! of objects and the sizes of the snapshot and deltas.
+
+use std::path::{Path, PathBuf};
+
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use uuid::Uuid;
+
+use rrdp::{self, FileKind, Notification, RepoState};
+use storage::FsStorage;
+use sync::{self, HttpsUri};
+
+//------------ StatsOptions --------------------------------------------------
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StatsOptions {
+    /// The target directory, containing the notification file.
+    pub target: PathBuf,
+
+    /// The name of the notification file in the target directory.
+    pub notification_name: String,
+
+    /// Print the stats as JSON.
+    pub json: bool,
+}
+
+//------------ Stats ---------------------------------------------------------
+
+/// The size of a repository.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Stats {
+    session: Uuid,
+    serial: u64,
+    objects: usize,
+    snapshot_size: usize,
+    deltas: usize,
+    deltas_size: usize,
+}
+
+impl Stats {
+    pub fn session(&self) -> Uuid {
+        self.session
+    }
+
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    /// The number of objects in the snapshot.
+    pub fn objects(&self) -> usize {
+        self.objects
+    }
+
+    /// The size of the uncompressed snapshot file in bytes.
+    pub fn snapshot_size(&self) -> usize {
+        self.snapshot_size
+    }
+
+    /// The number of deltas in the notification file.
+    pub fn deltas(&self) -> usize {
+        self.deltas
+    }
+
+    /// The total size of the uncompressed delta files in bytes.
+    pub fn deltas_size(&self) -> usize {
+        self.deltas_size
+    }
+}
+
+impl Serialize for Stats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Stats", 6)?;
+        s.serialize_field("session", &self.session.to_string())?;
+        s.serialize_field("serial", &self.serial)?;
+        s.serialize_field("objects", &self.objects)?;
+        s.serialize_field("snapshot_size", &self.snapshot_size)?;
+        s.serialize_field("num_deltas", &self.deltas)?;
+        s.serialize_field("deltas_size", &self.deltas_size)?;
+        s.end()
+    }
+}
+
+/// Reconstitutes the state in the target dir, and returns its stats. The
+/// base uri is derived from the snapshot uri in the notification file, so
+/// that it need not be given.
+pub fn stats(options: &StatsOptions) -> Result<Stats, Error> {
+    let base_uri = base_uri(&options.target, &options.notification_name)?;
+
+    let mut snapshot_size = 0;
+    let state = RepoState::load_named(
+        base_uri.clone(),
+        options.target.clone(),
+        &options.notification_name,
+        false,
+        |kind, uri| {
+            let bytes =
+                rrdp::read_file(&FsStorage::default(), &base_uri, &options.target, kind, uri)?;
+            if kind == FileKind::Snapshot {
+                snapshot_size = bytes.len();
+            }
+            Ok::<_, rrdp::Error>(bytes)
+        },
+    )?;
+
+    Ok(Stats {
+        session: state.session(),
+        serial: state.serial(),
+        objects: state.snapshot().len(),
+        snapshot_size,
+        deltas: state.deltas().len(),
+        deltas_size: state.deltas().iter().map(|d| d.size()).sum(),
+    })
+}
+
+/// Derives the base uri from the snapshot uri in the notification file,
+/// which rrdpit always puts in '{session}/{serial}/'.
+fn base_uri(target: &Path, notification_name: &str) -> Result<HttpsUri, Error> {
+    let path = target.join(notification_name);
+    let bytes = sync::read(&path).map_err(|_| rrdp::Error::NotificationUnreadable {
+        path: path.to_string_lossy().to_string(),
+    })?;
+    let notification = Notification::from_xml(bytes)?;
+
+    let uri = notification.snapshot().uri().to_string();
+    let rel = format!("{}/{}/", notification.session(), notification.serial());
+    uri.rsplit_once('/')
+        .and_then(|(dir, _)| format!("{}/", dir).strip_suffix(&rel).map(String::from))
+        .and_then(|base| HttpsUri::base_uri(&base).ok())
+        .ok_or(Error::UnexpectedSnapshotUri(uri))
+}
+
+//------------ Error ---------------------------------------------------------
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "{}", _0)]
+    Rrdp(rrdp::Error),
+
+    #[display(fmt = "Cannot derive the base uri from snapshot uri: {}", _0)]
+    UnexpectedSnapshotUri(String),
+}
+
+impl From<rrdp::Error> for Error {
+    fn from(e: rrdp::Error) -> Self {
+        Error::Rrdp(e)
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rrdp::{SaveOptions, NOTIFICATION_FILE};
+    use std::fs;
+    use test_util::{create_repo, source_serials};
+
+    #[test]
+    fn report_repo_stats() {
+        const WORK_DIR: &str = "./test-work/report_repo_stats/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let https = HttpsUri::base_uri("https://localhost/rrdp/").unwrap();
+        let target = PathBuf::from(WORK_DIR).join("target");
+        let serials = source_serials();
+        let objects = serials[1].len();
+        let summary = create_repo(&target, &https, serials, &SaveOptions::default());
+        let session = summary.session();
+
+        let stats = stats(&StatsOptions {
+            target: target.clone(),
+            notification_name: NOTIFICATION_FILE.to_string(),
+            json: false,
+        })
+        .unwrap();
+        assert_eq!(session, stats.session());
+        assert_eq!(2, stats.serial());
+        assert_eq!(objects, stats.objects());
+        assert_eq!(summary.snapshot_size(), stats.snapshot_size());
+        assert_eq!(
+            fs::metadata(target.join(format!("{}/2/snapshot.xml", session)))
+                .unwrap()
+                .len() as usize,
+            stats.snapshot_size()
+        );
+        assert_eq!(1, stats.deltas());
+        assert_eq!(summary.deltas_size(), stats.deltas_size());
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(objects, json["objects"].as_u64().unwrap() as usize);
+        assert_eq!(1, json["num_deltas"].as_u64().unwrap());
+    }
+}
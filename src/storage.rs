@@ -0,0 +1,396 @@
+//! Defines where the repository is stored. By default this is the local
+//! filesystem, but other backends, such as an object store, can be used by
+//! implementing the `Storage` trait.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+
+use sync::{self, Error, FileModes};
+
+//------------ Storage -------------------------------------------------------
+
+/// A backend in which the files of a repository are saved, and from which
+/// they are read again. Files are identified by their path. Directories
+/// are implied by the paths of the files in them, so backends without a
+/// notion of directories, such as object stores, need not create them.
+pub trait Storage {
+    /// Reads the file at the path.
+    fn read(&self, path: &Path) -> Result<Bytes, io::Error>;
+
+    /// Returns the size in bytes of the file at the path. The default reads
+    /// the file, backends which can tell the size without reading it should
+    /// override this.
+    fn size(&self, path: &Path) -> Result<u64, io::Error> {
+        self.read(path).map(|content| content.len() as u64)
+    }
+
+    /// Saves the content to the file at the path, replacing it if it exists.
+    fn save(&self, content: &[u8], path: &Path) -> Result<(), io::Error>;
+
+    /// Saves the content so that readers see either the old or the new file,
+    /// but never a partially written one. Backends where every save is
+    /// atomic can rely on the default.
+    fn save_atomic(&self, content: &[u8], path: &Path) -> Result<(), io::Error> {
+        self.save(content, path)
+    }
+
+    /// Saves the content written by the closure to the file at the path.
+    /// The default collects the content in memory first, backends which
+    /// can stream it should override this.
+    fn save_with<T, F>(&self, path: &Path, write: F) -> Result<T, io::Error>
+    where
+        F: FnOnce(&mut dyn Write) -> Result<T, io::Error>,
+    {
+        let mut content = Vec::new();
+        let res = write(&mut content)?;
+        self.save(&content, path)?;
+        Ok(res)
+    }
+
+    /// Saves a gzip compressed copy of the source file to the target path.
+    fn gzip(&self, source: &Path, target: &Path) -> Result<(), io::Error> {
+        let content = self.read(source)?;
+        self.save(sync::gzip(content.as_ref())?.as_ref(), target)
+    }
+
+    /// Makes the file or directory at the path durable, so that it survives
+    /// a power loss. The default does nothing, for backends where saving is
+    /// durable, or where durability is out of our hands.
+    fn sync(&self, path: &Path) -> Result<(), io::Error> {
+        let _ = path;
+        Ok(())
+    }
+
+    /// Lists the entries directly in a directory.
+    fn list(&self, dir: &Path) -> Result<Vec<DirEntry>, io::Error>;
+
+    /// Removes the file or directory at the path, including everything in
+    /// it.
+    fn remove(&self, path: &Path) -> Result<(), io::Error>;
+}
+
+//------------ DirEntry ------------------------------------------------------
+
+/// An entry in a directory listed by a storage backend.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry {
+    name: String,
+    is_dir: bool,
+    modified: SystemTime,
+}
+
+impl DirEntry {
+    pub fn new(name: String, is_dir: bool, modified: SystemTime) -> Self {
+        DirEntry {
+            name,
+            is_dir,
+            modified,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+}
+
+//------------ FsStorage -----------------------------------------------------
+
+/// Stores the repository on the local filesystem.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FsStorage {
+    modes: FileModes,
+    tmp_dir: Option<PathBuf>,
+}
+
+impl FsStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns storage that gives created files and dirs the modes.
+    pub fn with_modes(modes: FileModes) -> Self {
+        FsStorage {
+            modes,
+            tmp_dir: None,
+        }
+    }
+
+    /// Returns storage that writes the temporary files of atomic saves in
+    /// the tmp dir, rather than next to the saved file.
+    pub fn with_tmp_dir(mut self, tmp_dir: Option<PathBuf>) -> Self {
+        self.tmp_dir = tmp_dir;
+        self
+    }
+}
+
+impl Storage for FsStorage {
+    fn read(&self, path: &Path) -> Result<Bytes, io::Error> {
+        sync::read(&path.to_path_buf())
+    }
+
+    fn size(&self, path: &Path) -> Result<u64, io::Error> {
+        fs::metadata(path).map(|metadata| metadata.len())
+    }
+
+    fn save(&self, content: &[u8], path: &Path) -> Result<(), io::Error> {
+        self.modes.save(content, path)
+    }
+
+    fn save_atomic(&self, content: &[u8], path: &Path) -> Result<(), io::Error> {
+        self.modes
+            .save_atomic_in(content, path, self.tmp_dir.as_deref())
+    }
+
+    fn save_with<T, F>(&self, path: &Path, write: F) -> Result<T, io::Error>
+    where
+        F: FnOnce(&mut dyn Write) -> Result<T, io::Error>,
+    {
+        let mut file = BufWriter::new(self.modes.create_file(path)?);
+        let res = write(&mut file)?;
+        file.flush()?;
+        Ok(res)
+    }
+
+    fn gzip(&self, source: &Path, target: &Path) -> Result<(), io::Error> {
+        self.modes.gzip_file(source, target)
+    }
+
+    fn sync(&self, path: &Path) -> Result<(), io::Error> {
+        sync::sync_path(path)
+    }
+
+    /// Lists the entries in the directory. Entries with names which are not
+    /// valid UTF-8 are skipped, they are never written by rrdpit.
+    fn list(&self, dir: &Path) -> Result<Vec<DirEntry>, io::Error> {
+        let mut res = vec![];
+        for entry in fs::read_dir(dir).map_err(|_| Error::cannot_read(dir))? {
+            let entry = entry.map_err(|_| Error::cannot_read(dir))?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let metadata = entry.metadata()?;
+            let modified = metadata.modified()?;
+            res.push(DirEntry::new(name, entry.path().is_dir(), modified));
+        }
+        Ok(res)
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), io::Error> {
+        if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        }
+    }
+}
+
+//------------ MemStorage ----------------------------------------------------
+
+/// Keeps the repository in memory, e.g. to save and reconstitute it in tests
+/// without touching the disk, or to serve it from an embedding application.
+///
+/// Directories are implied by the paths of the files. Modification times are
+/// not wall clock times, but count the saves since the Unix epoch, so that
+/// they keep the order in which files were written. A directory is listed
+/// as modified when the most recent file in it was.
+#[derive(Debug, Default)]
+pub struct MemStorage {
+    files: Mutex<HashMap<PathBuf, MemFile>>,
+    saves: AtomicU64,
+}
+
+/// The content of a file in memory, and when it was saved.
+#[derive(Clone, Debug)]
+struct MemFile {
+    content: Bytes,
+    modified: SystemTime,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the content of the file at the path, if there is one.
+    pub fn get(&self, path: &Path) -> Option<Bytes> {
+        let files = self.files.lock().unwrap();
+        files.get(path).map(|file| file.content.clone())
+    }
+
+    /// Returns a copy of all files, by path.
+    pub fn files(&self) -> HashMap<PathBuf, Bytes> {
+        let files = self.files.lock().unwrap();
+        files
+            .iter()
+            .map(|(path, file)| (path.clone(), file.content.clone()))
+            .collect()
+    }
+
+    /// Returns the number of files.
+    pub fn len(&self) -> usize {
+        self.files.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Storage for MemStorage {
+    fn read(&self, path: &Path) -> Result<Bytes, io::Error> {
+        self.get(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, Error::cannot_read(path)))
+    }
+
+    fn save(&self, content: &[u8], path: &Path) -> Result<(), io::Error> {
+        let saves = self.saves.fetch_add(1, Ordering::SeqCst) + 1;
+        let file = MemFile {
+            content: Bytes::from(content),
+            modified: UNIX_EPOCH + Duration::from_secs(saves),
+        };
+        let mut files = self.files.lock().unwrap();
+        files.insert(path.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn list(&self, dir: &Path) -> Result<Vec<DirEntry>, io::Error> {
+        let files = self.files.lock().unwrap();
+        let mut res: Vec<DirEntry> = vec![];
+        for (path, file) in files.iter() {
+            let mut components = match path.strip_prefix(dir) {
+                Ok(rel) => rel.components(),
+                Err(_) => continue,
+            };
+            let name = match components.next() {
+                Some(name) => name.as_os_str().to_string_lossy().to_string(),
+                None => continue,
+            };
+            let is_dir = components.next().is_some();
+            match res.iter_mut().find(|e| e.name == name) {
+                Some(entry) => entry.modified = entry.modified.max(file.modified),
+                None => res.push(DirEntry::new(name, is_dir, file.modified)),
+            }
+        }
+        if res.is_empty() {
+            return Err(Error::cannot_read(dir).into());
+        }
+        Ok(res)
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), io::Error> {
+        let mut files = self.files.lock().unwrap();
+        files.retain(|file, _| !file.starts_with(path));
+        Ok(())
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(storage: &impl Storage, dir: &Path) -> Vec<(String, bool)> {
+        let mut names: Vec<_> = storage
+            .list(dir)
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.name().to_string(), e.is_dir()))
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn save_list_and_remove(storage: &impl Storage, base: &Path) {
+        storage.save(b"1", &base.join("a/1/file.xml")).unwrap();
+        storage
+            .save_atomic(b"2", &base.join("a/2/file.xml"))
+            .unwrap();
+        storage
+            .save_with(&base.join("notification.xml"), |w| w.write_all(b"n"))
+            .unwrap();
+        storage
+            .gzip(&base.join("a/1/file.xml"), &base.join("a/1/file.xml.gz"))
+            .unwrap();
+        storage.sync(&base.join("a/1/file.xml")).unwrap();
+        storage.sync(&base.join("a/1")).unwrap();
+
+        assert_eq!(
+            vec![
+                ("a".to_string(), true),
+                ("notification.xml".to_string(), false)
+            ],
+            names(storage, base)
+        );
+        assert_eq!(
+            Bytes::from("n"),
+            storage.read(&base.join("notification.xml")).unwrap()
+        );
+        let gzipped = storage.read(&base.join("a/1/file.xml.gz")).unwrap();
+        assert_eq!(
+            Bytes::from("1"),
+            sync::gunzip_if_compressed(gzipped).unwrap()
+        );
+
+        storage.remove(&base.join("a/1")).unwrap();
+        assert_eq!(
+            vec![("2".to_string(), true)],
+            names(storage, &base.join("a"))
+        );
+        assert!(storage.read(&base.join("a/1/file.xml")).is_err());
+        assert!(storage.list(&base.join("a/1")).is_err());
+    }
+
+    #[test]
+    fn fs_storage() {
+        const WORK_DIR: &str = "./test-work/fs_storage/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        save_list_and_remove(&FsStorage::default(), Path::new(WORK_DIR));
+    }
+
+    #[test]
+    fn fs_storage_with_tmp_dir() {
+        const WORK_DIR: &str = "./test-work/fs_storage_with_tmp_dir/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let base = Path::new(WORK_DIR);
+        let tmp_dir = base.join("tmp");
+        fs::create_dir_all(&tmp_dir).unwrap();
+
+        let storage = FsStorage::new().with_tmp_dir(Some(tmp_dir.clone()));
+        let path = base.join("target/notification.xml");
+        storage.save_atomic(b"first", &path).unwrap();
+        storage.save_atomic(b"second", &path).unwrap();
+
+        assert_eq!(Bytes::from("second"), storage.read(&path).unwrap());
+        assert_eq!(0, fs::read_dir(&tmp_dir).unwrap().count());
+        assert_eq!(1, fs::read_dir(base.join("target")).unwrap().count());
+    }
+
+    #[test]
+    fn mem_storage() {
+        let storage = MemStorage::new();
+        save_list_and_remove(&storage, Path::new("./mem/"));
+        assert_eq!(2, storage.len());
+        assert_eq!(
+            Some(Bytes::from("2")),
+            storage.get(Path::new("./mem/a/2/file.xml"))
+        );
+        assert!(!Path::new("./mem/").exists());
+    }
+}
@@ -1,11 +1,27 @@
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
-use std::str::from_utf8_unchecked;
-use std::{fmt, fs, io};
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Read, Write};
+use std::path::{self, Component, Path, PathBuf};
+use std::str::FromStr;
+use std::{fmt, fs, io, thread};
 
+use base64::write::EncoderWriter;
 use bytes::Bytes;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use glob::Pattern;
+use index::FileIndex;
+use memmap2::Mmap;
 use ring::digest;
+#[cfg(feature = "rrdp-serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use storage::{FsStorage, Storage};
 
 //------------ RsyncUri -----------------------------------------------------
 
@@ -14,6 +30,30 @@ use ring::digest;
 pub struct RsyncUri(String);
 
 impl RsyncUri {
+    /// Parses an rsync uri, e.g. taken from RRDP XML. The uri must use the
+    /// rsync scheme, have a host and a module, and may not contain any
+    /// whitespace or control characters.
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let rest = s.strip_prefix("rsync://").ok_or(Error::InvalidRsyncUri)?;
+        let mut parts = rest.splitn(2, '/');
+        let host = parts.next().unwrap_or("");
+        let module = parts.next().unwrap_or("");
+
+        if host.is_empty()
+            || module.is_empty()
+            || module.starts_with('/')
+            || s.chars().any(|c| c.is_whitespace() || c.is_control())
+        {
+            Err(Error::InvalidRsyncUri)
+        } else {
+            Ok(RsyncUri(s.to_string()))
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
     pub fn base_uri(s: &str) -> Result<Self, Error> {
         if s.starts_with("rsync://") && s.ends_with('/') {
             Ok(RsyncUri(s.to_string()))
@@ -25,6 +65,12 @@ impl RsyncUri {
     fn resolve(&self, s: &str) -> Self {
         RsyncUri(format!("{}{}", self.0, s))
     }
+
+    /// Returns the part of this uri after the given base uri, if this uri
+    /// starts with the base uri.
+    pub fn relative_to(&self, base: &RsyncUri) -> Option<&str> {
+        self.0.strip_prefix(&base.0)
+    }
 }
 
 impl From<&str> for RsyncUri {
@@ -33,6 +79,21 @@ impl From<&str> for RsyncUri {
     }
 }
 
+#[cfg(feature = "rrdp-serde")]
+impl Serialize for RsyncUri {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "rrdp-serde")]
+impl<'de> Deserialize<'de> for RsyncUri {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        RsyncUri::parse(&s).map_err(de::Error::custom)
+    }
+}
+
 //------------ HttpsUri -----------------------------------------------------
 
 #[derive(Clone, Debug, Display, Eq, Hash, PartialEq)]
@@ -40,6 +101,10 @@ impl From<&str> for RsyncUri {
 pub struct HttpsUri(String);
 
 impl HttpsUri {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
     pub fn base_uri(s: &str) -> Result<Self, Error> {
         if s.starts_with("https://") && s.ends_with('/') {
             Ok(HttpsUri(s.to_string()))
@@ -48,8 +113,19 @@ impl HttpsUri {
         }
     }
 
+    /// Resolves a path relative to this uri, which is treated as a base
+    /// uri whether or not it ends with a slash. Leading slashes in the path
+    /// are ignored, and an absolute https uri is returned as is.
     pub fn resolve(&self, s: &str) -> Self {
-        HttpsUri(format!("{}{}", self.0, s))
+        let uri = if s.starts_with("https://") {
+            s.to_string()
+        } else {
+            let base = self.0.trim_end_matches('/');
+            let rel = s.trim_start_matches('/');
+            format!("{}/{}", base, rel)
+        };
+        debug_assert!(uri.starts_with("https://"), "Not an https uri: {}", uri);
+        HttpsUri(uri)
     }
 
     pub fn relative_to(&self, mut uri: String) -> Option<String> {
@@ -67,6 +143,25 @@ impl From<&str> for HttpsUri {
     }
 }
 
+#[cfg(feature = "rrdp-serde")]
+impl Serialize for HttpsUri {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "rrdp-serde")]
+impl<'de> Deserialize<'de> for HttpsUri {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        if s.starts_with("https://") {
+            Ok(HttpsUri(s))
+        } else {
+            Err(de::Error::custom(format!("Invalid https uri: {}", s)))
+        }
+    }
+}
+
 //------------ Base64 --------------------------------------------------------
 
 /// This type contains a base64 encoded structure. The publication protocol
@@ -85,28 +180,155 @@ impl Base64 {
     pub fn from_b64_str(s: &str) -> Self {
         Base64(Bytes::from(s))
     }
+
+    /// Decodes the base64 to the original content.
+    pub fn decode(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        base64::decode(self.0.as_ref())
+    }
+
+    /// Returns the length of the decoded content, without decoding it.
+    pub fn decoded_len(&self) -> usize {
+        let bytes = self.as_bytes();
+        let padding = bytes.iter().rev().take_while(|&&b| b == b'=').count();
+        (bytes.len() / 4 * 3).saturating_sub(padding)
+    }
+
+    /// Returns the encoded bytes. Clones of this value share them.
+    pub fn as_bytes(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+
+    /// Returns the base64 as text, e.g. for writing XML. This borrows, and
+    /// only allocates if the content is not valid UTF-8. Base64 is always
+    /// ASCII, but this type does not guarantee it.
+    pub fn as_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.0.as_ref())
+    }
 }
 
 impl fmt::Display for Base64 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let s = unsafe { from_utf8_unchecked(self.0.as_ref()) };
-        s.fmt(f)
+        self.as_str().fmt(f)
+    }
+}
+
+#[cfg(feature = "rrdp-serde")]
+impl Serialize for Base64 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "rrdp-serde")]
+impl<'de> Deserialize<'de> for Base64 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Ok(Base64::from_b64_str(&s))
+    }
+}
+
+//------------ HashAlg -------------------------------------------------------
+
+/// The algorithm used for hashes. RRDP uses SHA-256, the others are only
+/// supported for experimentation.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum HashAlg {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl HashAlg {
+    /// Returns the algorithm for a hex encoded hash of the given length.
+    pub fn from_hex_len(len: usize) -> Option<Self> {
+        match len {
+            64 => Some(HashAlg::Sha256),
+            128 => Some(HashAlg::Sha512),
+            _ => None,
+        }
+    }
+
+    fn digest_alg(self) -> &'static digest::Algorithm {
+        match self {
+            HashAlg::Sha256 => &digest::SHA256,
+            HashAlg::Sha512 => &digest::SHA512,
+        }
+    }
+}
+
+impl FromStr for HashAlg {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        match s {
+            "sha256" => Ok(HashAlg::Sha256),
+            "sha512" => Ok(HashAlg::Sha512),
+            _ => Err(Error::UnsupportedHashAlg(s.to_string())),
+        }
     }
 }
 
 //------------ EncodedHash ---------------------------------------------------
 
-/// This type contains a hex encoded sha256 hash.
+/// This type contains a hex encoded hash, by default using sha256.
 ///
-/// Note that we store this in a Bytes for cheap cloning.
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub struct EncodedHash(Bytes);
+/// Note that we store this in a Bytes for cheap cloning. Hashes are compared
+/// in constant time, because they decide whether content is trusted.
+#[derive(Clone, Debug)]
+pub struct EncodedHash {
+    alg: HashAlg,
+    hex: Bytes,
+}
 
 impl EncodedHash {
     pub fn from_content(content: &[u8]) -> Self {
-        let sha256 = Self::sha256(content);
-        let hex = hex::encode(sha256);
-        EncodedHash(Bytes::from(hex))
+        Self::from_content_with(HashAlg::Sha256, content)
+    }
+
+    pub fn from_content_with(alg: HashAlg, content: &[u8]) -> Self {
+        Self::from_digest(alg, digest::digest(alg.digest_alg(), content).as_ref())
+    }
+
+    /// Parses a lowercase hex encoded hash. The algorithm is implied by its
+    /// length.
+    pub fn from_hex(hex: &str) -> Result<Self, Error> {
+        let alg =
+            HashAlg::from_hex_len(hex.len()).ok_or_else(|| Error::InvalidHash(hex.to_string()))?;
+        if !hex
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        {
+            return Err(Error::InvalidHash(hex.to_string()));
+        }
+        Ok(EncodedHash {
+            alg,
+            hex: Bytes::from(hex),
+        })
+    }
+
+    fn from_digest(alg: HashAlg, digest: &[u8]) -> Self {
+        let hex = hex::encode(digest);
+        EncodedHash {
+            alg,
+            hex: Bytes::from(hex),
+        }
+    }
+
+    pub fn alg(&self) -> HashAlg {
+        self.alg
+    }
+
+    /// Returns the hex encoding as text, e.g. for writing XML. This borrows,
+    /// and only allocates if the content is not valid UTF-8. Hex is always
+    /// ASCII, but this type does not guarantee it.
+    pub fn as_str(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.hex.as_ref())
+    }
+
+    /// Compares the hex encoding of this hash to the given string, in time
+    /// that depends only on their lengths.
+    pub fn eq_str(&self, other: &str) -> bool {
+        constant_time_eq(self.hex.as_ref(), other.as_bytes())
     }
 
     pub fn sha256(object: &[u8]) -> Bytes {
@@ -116,14 +338,103 @@ impl EncodedHash {
 
 impl fmt::Display for EncodedHash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let s = unsafe { from_utf8_unchecked(self.0.as_ref()) };
-        s.fmt(f)
+        self.as_str().fmt(f)
+    }
+}
+
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0, |diff, (left, right)| diff | (left ^ right))
+            == 0
+}
+
+impl PartialEq for EncodedHash {
+    fn eq(&self, other: &Self) -> bool {
+        self.alg == other.alg && constant_time_eq(self.hex.as_ref(), other.hex.as_ref())
+    }
+}
+
+impl Eq for EncodedHash {}
+
+impl Hash for EncodedHash {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.alg.hash(state);
+        self.hex.hash(state);
+    }
+}
+
+impl<'a> PartialEq<&'a str> for EncodedHash {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.eq_str(other)
+    }
+}
+
+#[cfg(feature = "rrdp-serde")]
+impl Serialize for EncodedHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "rrdp-serde")]
+impl<'de> Deserialize<'de> for EncodedHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        EncodedHash::from_hex(&s).map_err(de::Error::custom)
+    }
+}
+
+//------------ HashingWriter -------------------------------------------------
+
+/// Passes everything written on to another writer, while computing the hash
+/// and size of what was written.
+pub struct HashingWriter<W> {
+    inner: W,
+    alg: HashAlg,
+    context: digest::Context,
+    size: usize,
+}
+
+impl<W> HashingWriter<W> {
+    pub fn new(alg: HashAlg, inner: W) -> Self {
+        HashingWriter {
+            inner,
+            alg,
+            context: digest::Context::new(alg.digest_alg()),
+            size: 0,
+        }
+    }
+
+    /// Returns the inner writer, and the hash and size of what was written.
+    pub fn finish(self) -> (W, EncodedHash, usize) {
+        let hash = EncodedHash::from_digest(self.alg, self.context.finish().as_ref());
+        (self.inner, hash, self.size)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.context.update(&buf[..written]);
+        self.size += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
 //------------ CurrentFile ---------------------------------------------------
 
+/// The minimum size of a file to memory-map it, see 'CurrentFile::from_mmap'.
+pub const MMAP_MIN_SIZE: u64 = 64 * 1024;
+
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "rrdp-serde", derive(Serialize, Deserialize))]
 pub struct CurrentFile {
     /// The full uri for this file.
     uri: RsyncUri,
@@ -131,17 +442,75 @@ pub struct CurrentFile {
     /// The base64 encoded content of a file.
     base64: Base64,
 
-    /// The hex encoded sha-256 hash of the file.
+    /// The hex encoded hash of the file, sha-256 unless set otherwise.
     hash: EncodedHash,
 }
 
 impl CurrentFile {
     pub fn new(uri: RsyncUri, content: &[u8]) -> Self {
+        Self::new_with(HashAlg::Sha256, uri, content)
+    }
+
+    pub fn new_with(alg: HashAlg, uri: RsyncUri, content: &[u8]) -> Self {
         let base64 = Base64::from_content(content);
-        let hash = EncodedHash::from_content(content);
+        let hash = EncodedHash::from_content_with(alg, content);
         CurrentFile { uri, base64, hash }
     }
 
+    /// Creates a CurrentFile from content that is known to have the hash.
+    pub(crate) fn from_parts(uri: RsyncUri, base64: Base64, hash: EncodedHash) -> Self {
+        CurrentFile { uri, base64, hash }
+    }
+
+    /// Creates a new CurrentFile by reading the file at the given path in
+    /// chunks. The content is hashed and base64 encoded on the fly, so that
+    /// only the encoded form is held in memory rather than the whole file as
+    /// well.
+    pub fn from_path(alg: HashAlg, uri: RsyncUri, path: &Path) -> Result<Self, io::Error> {
+        let mut file = File::open(path)?;
+        let mut context = digest::Context::new(alg.digest_alg());
+        let mut base64 = Vec::new();
+
+        {
+            let mut encoder = EncoderWriter::new(&mut base64, base64::STANDARD);
+            let mut buf = [0; 64 * 1024];
+            loop {
+                let len = match file.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => len,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                context.update(&buf[..len]);
+                encoder.write_all(&buf[..len])?;
+            }
+            encoder.finish()?;
+        }
+
+        let base64 = Base64(Bytes::from(base64));
+        let hash = EncodedHash::from_digest(alg, context.finish().as_ref());
+        Ok(CurrentFile { uri, base64, hash })
+    }
+
+    /// Creates a new CurrentFile by memory-mapping the file at the given
+    /// path, so that the content is hashed and base64 encoded without first
+    /// copying it. Files smaller than 'MMAP_MIN_SIZE' are read in chunks, as
+    /// with 'from_path', because mapping them costs more than it saves.
+    ///
+    /// A mapped file must not be truncated while it is read, or the process
+    /// gets a SIGBUS and dies. This is only safe if nothing else modifies
+    /// the source files during a run, and is therefore opt-in.
+    pub fn from_mmap(alg: HashAlg, uri: RsyncUri, path: &Path) -> Result<Self, io::Error> {
+        let file = File::open(path)?;
+        if file.metadata()?.len() < MMAP_MIN_SIZE {
+            return Self::from_path(alg, uri, path);
+        }
+        // Safety: the mapping is only read, and the caller accepts the risk
+        // of the file being truncated, as documented above.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Self::new_with(alg, uri, &map))
+    }
+
     pub fn uri(&self) -> &RsyncUri {
         &self.uri
     }
@@ -151,25 +520,291 @@ impl CurrentFile {
     pub fn hash(&self) -> &EncodedHash {
         &self.hash
     }
+
+    /// Returns whether the content of this file matches the hash, which may
+    /// use another algorithm than the hash of this file.
+    pub fn has_hash(&self, hash: &EncodedHash) -> bool {
+        if self.hash.alg == hash.alg {
+            &self.hash == hash
+        } else {
+            match self.base64.decode() {
+                Ok(content) => &EncodedHash::from_content_with(hash.alg, &content) == hash,
+                Err(_) => false,
+            }
+        }
+    }
+}
+
+//------------ FileModes -----------------------------------------------------
+
+/// The permissions given to created files and dirs, as Unix modes, e.g.
+/// 0o644. If a mode is not set, it follows from the umask of the process as
+/// usual. Modes are ignored on other platforms.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FileModes {
+    /// The mode of created files.
+    pub file: Option<u32>,
+
+    /// The mode of created dirs. Dirs that already exist are left as is.
+    pub dir: Option<u32>,
+}
+
+impl FileModes {
+    /// Creates a file, creating parent dirs as needed.
+    pub fn create_file(&self, path: &Path) -> Result<File, io::Error> {
+        if !path.exists() {
+            if let Some(parent) = path.parent() {
+                self.create_dirs(parent)?;
+            }
+        }
+        let file = File::create(path)?;
+        if let Some(mode) = self.file {
+            set_mode(path, mode)?;
+        }
+        Ok(file)
+    }
+
+    /// Creates a dir, and any missing parent dirs.
+    pub fn create_dirs(&self, dir: &Path) -> Result<(), io::Error> {
+        let mode = match self.dir {
+            Some(mode) => mode,
+            None => return fs::create_dir_all(dir),
+        };
+        let missing: Vec<_> = dir
+            .ancestors()
+            .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+            .collect();
+        fs::create_dir_all(dir)?;
+        for dir in missing {
+            set_mode(dir, mode)?;
+        }
+        Ok(())
+    }
+
+    /// Saves a file, see 'save'.
+    pub fn save(&self, content: &[u8], full_path: &Path) -> Result<(), io::Error> {
+        let mut f = self.create_file(full_path)?;
+        f.write_all(content)?;
+        Ok(())
+    }
+
+    /// Saves a file atomically, see 'save_atomic'. The temporary file gets
+    /// the mode, so the file has it as soon as it appears.
+    pub fn save_atomic(&self, content: &[u8], full_path: &Path) -> Result<(), io::Error> {
+        self.save_atomic_in(content, full_path, None)
+    }
+
+    /// Saves a file atomically, writing the temporary file in the tmp dir
+    /// rather than next to the file, if given.
+    ///
+    /// If the tmp dir is on another filesystem, the file cannot be renamed
+    /// into place. It is then copied to a temporary file next to the final
+    /// path first, so that the last step is still an atomic rename.
+    pub fn save_atomic_in(
+        &self,
+        content: &[u8],
+        full_path: &Path,
+        tmp_dir: Option<&Path>,
+    ) -> Result<(), io::Error> {
+        let tmp_path = match tmp_dir {
+            Some(dir) => {
+                if let Some(parent) = full_path.parent() {
+                    self.create_dirs(parent)?;
+                }
+                tmp_path_in(dir, full_path)?
+            }
+            None => tmp_path(full_path)?,
+        };
+        let mut f = self.create_file(&tmp_path)?;
+        f.write_all(content)?;
+        f.sync_all()?;
+        drop(f);
+        let renamed = fs::rename(&tmp_path, full_path);
+        self.finish_atomic(content, &tmp_path, full_path, renamed)
+    }
+
+    /// Handles the outcome of renaming the temporary file to the final path.
+    ///
+    /// If the rename failed with EXDEV, the temporary file was on another
+    /// filesystem, as can happen with bind mounts or overlay filesystems in
+    /// containers. The content is then written to a temporary file next to
+    /// the final path and renamed there, which is atomic again. This is not
+    /// tried if the temporary file already was next to the final path.
+    fn finish_atomic(
+        &self,
+        content: &[u8],
+        tmp_path: &Path,
+        full_path: &Path,
+        renamed: Result<(), io::Error>,
+    ) -> Result<(), io::Error> {
+        let res = match renamed {
+            Ok(()) => return Ok(()),
+            Err(ref e) if is_cross_device(e) && tmp_path.parent() != full_path.parent() => {
+                debug!(
+                    "Cannot rename across filesystems, saving in place: {}",
+                    full_path.display()
+                );
+                self.save_atomic_in(content, full_path, None)
+            }
+            Err(e) => Err(e),
+        };
+        let _ = fs::remove_file(tmp_path);
+        res
+    }
+
+    /// Writes a gzip compressed copy of a file, see 'gzip_file'.
+    pub fn gzip_file(&self, source: &Path, target: &Path) -> Result<(), io::Error> {
+        let mut source = File::open(source).map_err(|_| Error::cannot_read(source))?;
+        let mut encoder = GzEncoder::new(
+            BufWriter::new(self.create_file(target)?),
+            Compression::default(),
+        );
+        io::copy(&mut source, &mut encoder)?;
+        encoder.finish()?.flush()
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), io::Error> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<(), io::Error> {
+    Ok(())
+}
+
+//------------ CrawlOptions --------------------------------------------------
+
+/// The default maximum depth of directories below a source directory.
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+
+/// Defines how a source directory is crawled.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CrawlOptions {
+    /// The number of threads used to read and hash files.
+    pub threads: usize,
+
+    /// Only files matching any of these patterns are included. If there are
+    /// no patterns, then all files are included.
+    pub includes: Vec<Pattern>,
+
+    /// Files matching any of these patterns are excluded, even if they match
+    /// an include pattern.
+    pub excludes: Vec<Pattern>,
+
+    /// Traverse symlinked directories. If false, symlinked directories are
+    /// skipped. Symlinked files are always followed.
+    pub follow_symlinks: bool,
+
+    /// Include hidden files and dirs, i.e. those with a name starting with a
+    /// '.' character. These are skipped by default.
+    pub include_hidden: bool,
+
+    /// The maximum depth of directories below the base directory. Crawling
+    /// a deeper directory is an error, rather than skipping it, so that its
+    /// files are not withdrawn by accident.
+    pub max_depth: usize,
+
+    /// Memory-map large files to read them, see 'CurrentFile::from_mmap'.
+    pub mmap: bool,
+
+    /// Rsync base uris for top-level subdirectories, used instead of the
+    /// rsync base uri of the crawl for the files in them. This way, e.g.
+    /// 'module-a/' and 'module-b/' can be published as separate modules.
+    /// Files in other subdirectories use the rsync base uri of the crawl.
+    pub rsync_map: Vec<(String, RsyncUri)>,
+
+    /// Only files with an rsync uri starting with any of these prefixes are
+    /// included. If there are no prefixes, then all files are included.
+    pub uri_prefixes: Vec<String>,
+
+    /// The maximum size in bytes of a file. Larger files are skipped with a
+    /// warning, or result in an error if 'fail_on_large_files' is set.
+    pub max_file_size: Option<u64>,
+
+    /// Fail the crawl, rather than skip files, when a file is larger than
+    /// 'max_file_size'.
+    pub fail_on_large_files: bool,
+
+    /// The algorithm used to hash the files. These hashes end up in the
+    /// delta elements, where RFC 8182 only allows sha256.
+    pub hash_alg: HashAlg,
+}
+
+impl CrawlOptions {
+    /// Returns whether a file should be included, based on its rsync uri.
+    fn includes_uri(&self, uri: &RsyncUri) -> bool {
+        self.uri_prefixes.is_empty()
+            || self
+                .uri_prefixes
+                .iter()
+                .any(|prefix| uri.as_str().starts_with(prefix.as_str()))
+    }
+
+    /// Returns the uri for a file, based on its path relative to the base
+    /// directory, using the mapped rsync base uri for its top-level
+    /// subdirectory, if there is one.
+    fn uri_for(&self, rsync_base: &RsyncUri, rel: &str) -> RsyncUri {
+        if let Some((top, rest)) = rel.split_once('/') {
+            if let Some((_, base)) = self.rsync_map.iter().find(|(dir, _)| dir == top) {
+                return base.resolve(&encode_path(rest));
+            }
+        }
+        rsync_base.resolve(&encode_path(rel))
+    }
+
+    /// Returns whether a file should be included, based on its path relative
+    /// to the base directory.
+    fn includes_path(&self, rel: &str) -> bool {
+        if self.excludes.iter().any(|p| p.matches(rel)) {
+            false
+        } else {
+            self.includes.is_empty() || self.includes.iter().any(|p| p.matches(rel))
+        }
+    }
+}
+
+impl Default for CrawlOptions {
+    fn default() -> Self {
+        CrawlOptions {
+            threads: 1,
+            includes: vec![],
+            excludes: vec![],
+            follow_symlinks: false,
+            include_hidden: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            mmap: false,
+            rsync_map: vec![],
+            uri_prefixes: vec![],
+            max_file_size: None,
+            fail_on_large_files: false,
+            hash_alg: HashAlg::Sha256,
+        }
+    }
 }
 
 //------------ CurrentFile ---------------------------------------------------
 
 /// Reads a file to Bytes
 pub fn read(path: &PathBuf) -> Result<Bytes, io::Error> {
-    let mut f = File::open(path).map_err(|_| Error::cannot_read(path))?;
-    let mut bytes = Vec::new();
+    // Keep the kind of error, so that a missing file can be told apart from
+    // one that cannot be read.
+    let mut f = File::open(path).map_err(|e| io::Error::new(e.kind(), Error::cannot_read(path)))?;
+
+    // Reserve the whole file up front, so that it is read with as few calls
+    // as possible. A BufReader would not save any calls here, it would only
+    // add a copy. The length is just a hint, the file may still change.
+    let len = f.metadata().map(|m| m.len() as usize).unwrap_or(0);
+    let mut bytes = Vec::with_capacity(len);
     f.read_to_end(&mut bytes)?;
     Ok(Bytes::from(bytes))
 }
 
-fn create_file_with_path(path: &Path) -> Result<File, io::Error> {
-    if !path.exists() {
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)?;
-        }
-    }
-    File::create(path)
+/// Creates a file, creating parent dirs as needed.
+pub fn create_file_with_path(path: &Path) -> Result<File, io::Error> {
+    FileModes::default().create_file(path)
 }
 
 /// Derive the path for this file.
@@ -181,125 +816,962 @@ pub fn file_path(base_path: &Path, file_name: &str) -> PathBuf {
 
 /// Saves a file, creating parent dirs as needed
 pub fn save(content: &[u8], full_path: &Path) -> Result<(), io::Error> {
-    let mut f = create_file_with_path(full_path)?;
-    f.write_all(content)?;
-    Ok(())
+    FileModes::default().save(content, full_path)
 }
 
-fn recurse_disk(
-    base_path: &PathBuf,
-    path: &PathBuf,
-    rsync_base: &RsyncUri,
-) -> Result<Vec<CurrentFile>, Error> {
-    let mut res = Vec::new();
-
-    for entry in fs::read_dir(path).map_err(|_| Error::cannot_read(path))? {
-        let entry = entry.map_err(|_| Error::cannot_read(path))?;
-        let path = entry.path();
-        if entry
-            .file_name()
-            .to_str()
-            .map(|name| name.starts_with('.'))
-            .unwrap_or(true)
-        {
-            // this is a hidden file / directory (by convention) so skip it
-        } else if path.is_dir() {
-            let mut other = recurse_disk(base_path, &path, rsync_base)?;
-            res.append(&mut other);
-        } else {
-            let uri = derive_uri(base_path, &path, rsync_base)?;
-            let content = read(&path).map_err(|_| Error::cannot_read(&path))?;
-            let current_file = CurrentFile::new(uri, &content);
+/// Saves a file atomically, creating parent dirs as needed.
+///
+/// The content is first written to a temporary file in the same directory,
+/// which is then renamed to the final path. Renaming is atomic on the same
+/// filesystem, so readers will see either the old or the new file, but never
+/// a partially written one. The temporary file is synced to stable storage
+/// before it is renamed, otherwise a power loss could leave an empty file.
+pub fn save_atomic(content: &[u8], full_path: &Path) -> Result<(), io::Error> {
+    FileModes::default().save_atomic(content, full_path)
+}
 
-            res.push(current_file);
-        }
+/// Flushes the file or directory at the path to stable storage. Syncing a
+/// directory makes the creation and renaming of files in it durable. This
+/// is only possible on Unix, elsewhere directories are skipped.
+pub fn sync_path(path: &Path) -> Result<(), io::Error> {
+    if cfg!(not(unix)) && path.is_dir() {
+        return Ok(());
     }
+    File::open(path)?.sync_all()
+}
 
-    Ok(res)
+/// Derive a temporary path for a file, i.e. a hidden file in the same
+/// directory.
+fn tmp_path(full_path: &Path) -> Result<PathBuf, io::Error> {
+    let file_name = full_path
+        .file_name()
+        .ok_or_else(|| Error::cannot_read(full_path))?;
+
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+
+    Ok(full_path.with_file_name(tmp_name))
 }
 
-fn derive_uri(base_path: &Path, path: &Path, rsync_base: &RsyncUri) -> Result<RsyncUri, Error> {
-    let rel_path = derive_relative_path(base_path, path)?;
-    Ok(rsync_base.resolve(&rel_path))
+/// Derive a temporary path for a file in another directory. The process id
+/// is included, so that runs for different targets can share the directory.
+fn tmp_path_in(tmp_dir: &Path, full_path: &Path) -> Result<PathBuf, io::Error> {
+    let file_name = full_path
+        .file_name()
+        .ok_or_else(|| Error::cannot_read(full_path))?;
+
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+
+    Ok(tmp_dir.join(tmp_name))
 }
 
-fn derive_relative_path(base_path: &Path, path: &Path) -> Result<String, Error> {
-    let base_str = base_path.to_string_lossy().to_string();
-    let mut path_str = path.to_string_lossy().to_string();
+/// Returns whether a rename failed because the paths are on different
+/// filesystems, i.e. EXDEV.
+fn is_cross_device(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::CrossesDevices
+}
 
-    if !path_str.starts_with(&base_str) {
-        Err(Error::OutsideJail(path_str, base_str))
-    } else {
-        let base_len = base_str.len();
-        let rel = path_str.split_off(base_len);
-        Ok(rel)
-    }
+/// Compresses the content using gzip
+pub fn gzip(content: &[u8]) -> Result<Bytes, io::Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(content)?;
+    Ok(Bytes::from(encoder.finish()?))
 }
 
-pub fn crawl_disk(base_path: &PathBuf, rsync_base: &RsyncUri) -> Result<Vec<CurrentFile>, Error> {
-    recurse_disk(base_path, base_path, rsync_base)
+/// Writes a gzip compressed copy of the source file to the target path,
+/// without reading the whole file into memory.
+pub fn gzip_file(source: &Path, target: &Path) -> Result<(), io::Error> {
+    FileModes::default().gzip_file(source, target)
 }
 
-/// Cleans up a directory, i.e. it retains any files and/or disks for which the
-/// predicate function returns 'true'
-pub fn retain_disk<P>(base_path: &PathBuf, keep: P) -> Result<(), Error>
-where
-    P: Copy + FnOnce(String) -> bool,
-{
-    for entry in fs::read_dir(base_path).map_err(|_| Error::cannot_read(base_path))? {
-        let entry = entry.map_err(|_| Error::cannot_read(base_path))?;
-        let rel = derive_relative_path(base_path, &entry.path())?;
+/// Decompresses the content if it starts with the gzip magic bytes, and
+/// returns it unchanged otherwise.
+pub fn gunzip_if_compressed(content: Bytes) -> Result<Bytes, io::Error> {
+    gunzip_if_compressed_with(content, None)
+}
 
-        if !keep(rel) {
-            let _res = fs::remove_dir_all(entry.path());
+/// Decompresses the content if it starts with the gzip magic bytes, but
+/// fails rather than decompress more than the maximum number of bytes, if
+/// any. Content that is not compressed is returned unchanged.
+pub fn gunzip_if_compressed_with(
+    content: Bytes,
+    max_bytes: Option<u64>,
+) -> Result<Bytes, io::Error> {
+    if !content.starts_with(&[0x1f, 0x8b]) {
+        return Ok(content);
+    }
+    let limit = max_bytes.map_or(u64::MAX, |max| max.saturating_add(1));
+    let mut decompressed = Vec::new();
+    GzDecoder::new(content.as_ref())
+        .take(limit)
+        .read_to_end(&mut decompressed)?;
+    if let Some(max) = max_bytes {
+        if decompressed.len() as u64 > max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("decompressed content exceeds {} bytes", max),
+            ));
         }
     }
-
-    Ok(())
+    Ok(Bytes::from(decompressed))
 }
 
-//------------ Error ---------------------------------------------------------
-#[derive(Debug, Display)]
-pub enum Error {
-    #[display(fmt = "Invalid rsync uri")]
-    InvalidRsyncUri,
+/// Derive the path for the gzip compressed version of a file, i.e. the same
+/// path with '.gz' appended.
+pub fn gzip_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(".gz");
+    PathBuf::from(path)
+}
 
-    #[display(fmt = "rsync base uri must start with rsync:// end with slash")]
-    InvalidRsyncBase,
+/// Walks the directory tree below the base path, and returns the uris and
+/// paths of the files to publish, in the order of a depth first walk by name.
+///
+/// This uses an explicit stack rather than recursion, so that deep trees do
+/// not exhaust the call stack. Entries are pushed in reverse name order, so
+/// that a directory is walked completely before its next sibling.
+fn walk_disk(
+    base_path: &Path,
+    rsync_base: &RsyncUri,
+    options: &CrawlOptions,
+    visited: &mut HashSet<PathBuf>,
+) -> Result<Vec<(RsyncUri, PathBuf)>, Error> {
+    let mut res = Vec::new();
+    let mut stack = read_dir_sorted(base_path, 0)?;
 
-    #[display(fmt = "https base uri must start with https:// end with slash")]
-    InvalidHttpsBase,
+    // Without following symlinks, the files published must be inside the
+    // base path. Following them is an explicit choice to publish files from
+    // elsewhere, so then there is no jail.
+    let jail = if options.follow_symlinks {
+        None
+    } else {
+        Some(canonicalize(base_path)?)
+    };
 
-    #[display(fmt = "Cannot read: {}", _0)]
-    CannotRead(String),
+    while let Some((entry, depth)) = stack.pop() {
+        let path = entry.path();
+        // Names need only be valid UTF-8 if the file is published, which
+        // derive_uri checks. Hidden and excluded files are skipped first.
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        // The type comes with the entry on most platforms, only symlinks
+        // need another stat to find out what they point to.
+        let file_type = entry.file_type().map_err(|_| Error::cannot_read(&path))?;
+        let is_symlink = file_type.is_symlink();
+        if name.starts_with('.') && !options.include_hidden {
+            // this is a hidden file / directory (by convention) so skip it
+        } else if file_type.is_dir() || (is_symlink && path.is_dir()) {
+            if is_symlink && !options.follow_symlinks {
+                warn!("Skipping symlinked directory: {}", path.display());
+            } else if options.follow_symlinks && !visit(&path, visited)? {
+                warn!("Skipping already visited directory: {}", path.display());
+            } else if depth >= options.max_depth {
+                return Err(Error::too_deep(&path, options.max_depth));
+            } else {
+                debug!("Crawling directory: {}", path.display());
+                stack.append(&mut read_dir_sorted(&path, depth + 1)?);
+            }
+        } else if !options.includes_path(&filter_relative_path(base_path, &path)?) {
+            // excluded, or not included
+        } else if !is_regular_file(&path, file_type)? {
+            // Reading a FIFO, socket or device could block, or never end.
+            warn!("Skipping special file: {}", path.display());
+        } else {
+            if let Some(jail) = &jail {
+                check_jail(jail, base_path, &entry)?;
+            }
+            let uri = derive_uri(base_path, &path, rsync_base, options)?;
+            if options.includes_uri(&uri) && check_size(&path, options)? {
+                res.push((uri, path));
+            }
+        }
+    }
 
-    #[display(fmt = "Unsupported characters: {}", _0)]
-    UnsupportedFileName(String),
+    Ok(res)
+}
 
-    #[display(fmt = "File: {} outside of jail: {}", _0, _1)]
-    OutsideJail(String, String),
+/// Reads the entries of a directory at the given depth, in reverse name
+/// order. Sorting makes the walk independent of the order in which the file
+/// system happens to return them.
+fn read_dir_sorted(dir: &Path, depth: usize) -> Result<Vec<(fs::DirEntry, usize)>, Error> {
+    let mut entries = fs::read_dir(dir)
+        .map_err(|_| Error::cannot_read(dir))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::cannot_read(dir))?;
+    entries.sort_by_key(|entry| Reverse(entry.file_name()));
+    Ok(entries.into_iter().map(|entry| (entry, depth)).collect())
 }
 
-impl Error {
-    fn cannot_read(path: &Path) -> Error {
-        let str = path.to_string_lossy().to_string();
-        Error::CannotRead(str)
+/// Returns whether the entry of the given type is a regular file. Only a
+/// symlink needs another stat, to find out what it points to.
+fn is_regular_file(path: &Path, file_type: fs::FileType) -> Result<bool, Error> {
+    if !file_type.is_symlink() {
+        return Ok(file_type.is_file());
     }
+    fs::metadata(path)
+        .map(|metadata| metadata.is_file())
+        .map_err(|_| Error::cannot_read(path))
 }
 
-impl std::error::Error for Error {}
+/// Checks the size of a file against the configured maximum. Returns false
+/// if the file should be skipped, or an error if the crawl should fail.
+fn check_size(path: &Path, options: &CrawlOptions) -> Result<bool, Error> {
+    let limit = match options.max_file_size {
+        Some(limit) => limit,
+        None => return Ok(true),
+    };
 
-impl From<Error> for io::Error {
-    fn from(e: Error) -> Self {
-        io::Error::new(io::ErrorKind::Other, e)
+    let size = fs::metadata(path)
+        .map_err(|_| Error::cannot_read(path))?
+        .len();
+
+    check_len(path, size, limit, options)
+}
+
+fn check_len(path: &Path, size: u64, limit: u64, options: &CrawlOptions) -> Result<bool, Error> {
+    if size <= limit {
+        Ok(true)
+    } else if options.fail_on_large_files {
+        Err(Error::file_too_large(path, size, limit))
+    } else {
+        warn!(
+            "Skipping file: {} of {} bytes, exceeding the limit of {} bytes",
+            path.display(),
+            size,
+            limit
+        );
+        Ok(false)
     }
 }
 
-//------------ Tests ---------------------------------------------------------
-//
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Marks a directory as visited, using its canonical path. Returns false if
+/// the directory was visited before, which can happen when symlinks are
+/// followed.
+fn visit(path: &Path, visited: &mut HashSet<PathBuf>) -> Result<bool, Error> {
+    let canonical = fs::canonicalize(path).map_err(|_| Error::cannot_read(path))?;
+    Ok(visited.insert(canonical))
+}
+
+/// Reads and hashes the files found on disk, using the number of threads
+/// and hash algorithm from the options.
+fn read_files(
+    files: Vec<(RsyncUri, PathBuf)>,
+    options: &CrawlOptions,
+    index: Option<&FileIndex>,
+) -> Result<Vec<CurrentFile>, Error> {
+    let (threads, alg, mmap) = (options.threads, options.hash_alg, options.mmap);
+    if threads <= 1 || files.len() <= 1 {
+        return files
+            .into_iter()
+            .map(|(uri, path)| read_current_file(alg, mmap, index, uri, &path))
+            .collect();
+    }
+
+    let chunk_size = files.len().div_ceil(threads);
+
+    thread::scope(|scope| {
+        let workers: Vec<_> = files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|(uri, path)| read_current_file(alg, mmap, index, uri.clone(), path))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+            })
+            .collect();
+
+        let mut res = Vec::with_capacity(files.len());
+        for worker in workers {
+            let mut found = worker.join().expect("crawl worker panicked")?;
+            res.append(&mut found);
+        }
+        Ok(res)
+    })
+}
+
+fn read_current_file(
+    alg: HashAlg,
+    mmap: bool,
+    index: Option<&FileIndex>,
+    uri: RsyncUri,
+    path: &Path,
+) -> Result<CurrentFile, Error> {
+    let read = |uri| {
+        if mmap {
+            CurrentFile::from_mmap(alg, uri, path)
+        } else {
+            CurrentFile::from_path(alg, uri, path)
+        }
+    };
+    let res = match index {
+        Some(index) => index.read(alg, uri, path, read),
+        None => read(uri),
+    };
+    res.map_err(|_| Error::cannot_read(path))
+}
+
+fn derive_uri(
+    base_path: &Path,
+    path: &Path,
+    rsync_base: &RsyncUri,
+    options: &CrawlOptions,
+) -> Result<RsyncUri, Error> {
+    let rel_path = derive_relative_path(base_path, path)?;
+    if !is_supported_path(&rel_path) {
+        return Err(Error::unsupported_file_name(path));
+    }
+    Ok(options.uri_for(rsync_base, &rel_path))
+}
+
+/// Returns whether a relative path can be published. Control characters,
+/// such as newlines, could be percent-encoded, but they are not valid in
+/// rsync uris and most likely a mistake, so such files are rejected.
+fn is_supported_path(rel: &str) -> bool {
+    !rel.chars().any(char::is_control)
+}
+
+/// Characters which must be percent-encoded in a uri path segment as per
+/// RFC 3986. Non-ASCII characters are always encoded.
+const SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// Percent-encodes each segment of a '/' separated relative path.
+fn encode_path(rel: &str) -> String {
+    rel.split('/')
+        .map(|segment| utf8_percent_encode(segment, SEGMENT).to_string())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Derives the '/' separated path of a file relative to the base path, from
+/// the path under which the file was found rather than its physical path, so
+/// that a symlinked file or directory is published under the link's name.
+///
+/// Both paths are normalized first, so that e.g. './source', 'source/' and
+/// 'other/../source' are the same base. This is done lexically, without
+/// touching the file system. Whether the file itself is inside the jail is
+/// checked by 'check_jail'.
+fn derive_relative_path(base_path: &Path, path: &Path) -> Result<String, Error> {
+    // A lossy conversion would give a uri for an object that does not
+    // exist.
+    let rel = relative_path(base_path, path)?;
+    let rel = rel
+        .to_str()
+        .ok_or_else(|| Error::unsupported_file_name(path))?;
+    Ok(with_uri_separators(rel, path::MAIN_SEPARATOR))
+}
+
+/// Derives the relative path like 'derive_relative_path', but replaces
+/// invalid UTF-8 rather than rejecting it. This is only for matching the
+/// include and exclude patterns, so that a file which is skipped anyway
+/// need not have a valid name.
+fn filter_relative_path(base_path: &Path, path: &Path) -> Result<String, Error> {
+    let rel = relative_path(base_path, path)?;
+    Ok(with_uri_separators(
+        &rel.to_string_lossy(),
+        path::MAIN_SEPARATOR,
+    ))
+}
+
+/// Returns the path relative to the base path, which it must be inside of.
+fn relative_path(base_path: &Path, path: &Path) -> Result<PathBuf, Error> {
+    match normalize(path).strip_prefix(normalize(base_path)) {
+        Ok(rel) if rel.components().next().is_some() => Ok(rel.to_path_buf()),
+        _ => Err(Error::OutsideJail(
+            path.to_string_lossy().to_string(),
+            base_path.to_string_lossy().to_string(),
+        )),
+    }
+}
+
+/// Removes '.' components from a path, and resolves '..' components against
+/// the preceding component, without following symlinks.
+fn normalize(path: &Path) -> PathBuf {
+    let mut res = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match res.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    res.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => res.push(component),
+            },
+            _ => res.push(component),
+        }
+    }
+    res
+}
+
+/// Checks that a symlinked file found below the base path points to a file
+/// inside the canonical base path, so that it cannot be used to publish
+/// arbitrary files. Other entries are inside by construction, because
+/// symlinked directories are not walked when the jail applies.
+fn check_jail(jail: &Path, base_path: &Path, entry: &fs::DirEntry) -> Result<(), Error> {
+    let path = entry.path();
+    let is_symlink = entry
+        .file_type()
+        .map_err(|_| Error::cannot_read(&path))?
+        .is_symlink();
+    if is_symlink && !canonicalize(&path)?.starts_with(jail) {
+        return Err(Error::OutsideJail(
+            path.to_string_lossy().to_string(),
+            base_path.to_string_lossy().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the canonical form of a path, treating an empty path, e.g. the
+/// parent of a relative file name, as the current dir.
+fn canonicalize(path: &Path) -> Result<PathBuf, Error> {
+    let path = if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    };
+    fs::canonicalize(path).map_err(|_| Error::cannot_read(path))
+}
+
+/// Replaces the platform's path separator with the '/' used in uris. This is
+/// a no-op on unix.
+fn with_uri_separators(rel: &str, separator: char) -> String {
+    rel.replace(separator, "/")
+}
+
+pub fn crawl_disk(base_path: &Path, rsync_base: &RsyncUri) -> Result<Vec<CurrentFile>, Error> {
+    crawl_disk_with(base_path, rsync_base, &CrawlOptions::default())
+}
+
+/// Crawls multiple source directories, and merges the results. A source which
+/// is a file is read as a tar archive. The same rsync uri may not be derived
+/// from more than one source.
+pub fn crawl_sources(
+    sources: &[PathBuf],
+    rsync_base: &RsyncUri,
+    options: &CrawlOptions,
+) -> Result<Vec<CurrentFile>, Error> {
+    crawl_sources_indexed(sources, rsync_base, options, None)
+}
+
+/// Crawls multiple sources like 'crawl_sources', taking unchanged files in
+/// source directories from the index if one is given.
+pub fn crawl_sources_indexed(
+    sources: &[PathBuf],
+    rsync_base: &RsyncUri,
+    options: &CrawlOptions,
+    index: Option<&FileIndex>,
+) -> Result<Vec<CurrentFile>, Error> {
+    let mut origins: HashMap<RsyncUri, &PathBuf> = HashMap::new();
+    let mut res = vec![];
+
+    for source in sources {
+        let files = if source.is_file() {
+            let reader = File::open(source).map_err(|_| Error::cannot_read(source))?;
+            crawl_tar_with(reader, rsync_base, options)?
+        } else {
+            crawl_disk_indexed(source, rsync_base, options, index)?
+        };
+
+        for file in files {
+            if let Some(other) = origins.insert(file.uri.clone(), source) {
+                return Err(Error::duplicate_uri(&file.uri, other, source));
+            }
+            res.push(file);
+        }
+    }
+
+    res.sort_by(|a, b| a.uri.cmp(&b.uri));
+    Ok(res)
+}
+
+/// Crawls a tar archive, rather than a directory on disk. The rsync uri of
+/// each file is derived from its path in the archive. Anything but regular
+/// files, and hidden files and directories, are skipped.
+pub fn crawl_tar<R: Read>(reader: R, rsync_base: &RsyncUri) -> Result<Vec<CurrentFile>, Error> {
+    crawl_tar_with(reader, rsync_base, &CrawlOptions::default())
+}
+
+/// Crawls a tar archive using the include, exclude and size options. Note
+/// that the symlink and thread options do not apply to archives.
+pub fn crawl_tar_with<R: Read>(
+    reader: R,
+    rsync_base: &RsyncUri,
+    options: &CrawlOptions,
+) -> Result<Vec<CurrentFile>, Error> {
+    let mut archive = tar::Archive::new(reader);
+    let mut res = vec![];
+    let mut paths = vec![];
+
+    for entry in archive.entries().map_err(Error::invalid_tar)? {
+        let mut entry = entry.map_err(Error::invalid_tar)?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path().map_err(Error::invalid_tar)?.into_owned();
+        let rel = match tar_relative_path(&path, options.include_hidden)? {
+            Some(rel) => rel,
+            None => continue, // hidden
+        };
+
+        if !options.includes_path(&rel) {
+            continue;
+        }
+        if path.to_str().is_none() {
+            return Err(Error::unsupported_file_name(&path));
+        }
+        if !is_supported_path(&rel) {
+            return Err(Error::UnsupportedFileName(rel));
+        }
+        let uri = options.uri_for(rsync_base, &rel);
+        if !options.includes_uri(&uri) {
+            continue;
+        }
+        if let Some(limit) = options.max_file_size {
+            if !check_len(&path, entry.size(), limit, options)? {
+                continue;
+            }
+        }
+
+        let mut content = Vec::with_capacity(entry.size() as usize);
+        entry
+            .read_to_end(&mut content)
+            .map_err(Error::invalid_tar)?;
+
+        res.push(CurrentFile::new_with(
+            options.hash_alg,
+            uri.clone(),
+            &content,
+        ));
+        paths.push((uri, path));
+    }
+
+    paths.sort_by(|a, b| a.0.cmp(&b.0));
+    check_unique_uris(&paths)?;
+
+    res.sort_by(|a, b| a.uri.cmp(&b.uri));
+    Ok(res)
+}
+
+/// Derives the '/' separated relative path for a path in a tar archive, or
+/// None if the path is hidden. Paths may not leave the archive root. Invalid
+/// UTF-8 is replaced, so the result is only fit for matching the include and
+/// exclude patterns, as with 'filter_relative_path'.
+fn tar_relative_path(path: &Path, include_hidden: bool) -> Result<Option<String>, Error> {
+    let mut segments = vec![];
+    for component in path.components() {
+        match component {
+            path::Component::CurDir => {}
+            path::Component::Normal(name) => {
+                let name = name.to_string_lossy();
+                if name.starts_with('.') && !include_hidden {
+                    return Ok(None);
+                }
+                segments.push(name.to_string());
+            }
+            _ => {
+                return Err(Error::OutsideJail(
+                    path.to_string_lossy().to_string(),
+                    "tar archive".to_string(),
+                ))
+            }
+        }
+    }
+    Ok(Some(segments.join("/")))
+}
+
+/// Crawls the disk using the given options. The resulting files are sorted by
+/// uri, so that the result does not depend on the order in which files were
+/// found or processed.
+pub fn crawl_disk_with(
+    base_path: &Path,
+    rsync_base: &RsyncUri,
+    options: &CrawlOptions,
+) -> Result<Vec<CurrentFile>, Error> {
+    crawl_disk_indexed(base_path, rsync_base, options, None)
+}
+
+fn crawl_disk_indexed(
+    base_path: &Path,
+    rsync_base: &RsyncUri,
+    options: &CrawlOptions,
+    index: Option<&FileIndex>,
+) -> Result<Vec<CurrentFile>, Error> {
+    let mut visited = HashSet::new();
+    visit(base_path, &mut visited)?;
+
+    let mut files = walk_disk(base_path, rsync_base, options, &mut visited)?;
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    check_unique_uris(&files)?;
+
+    read_files(files, options, index)
+}
+
+/// Verifies that no two files map to the same rsync uri, which could happen
+/// on a case insensitive file system, or if names can only be converted to
+/// UTF-8 lossily. Expects the files to be sorted by uri.
+fn check_unique_uris(files: &[(RsyncUri, PathBuf)]) -> Result<(), Error> {
+    for pair in files.windows(2) {
+        let ((uri, first), (other, second)) = (&pair[0], &pair[1]);
+        if uri == other {
+            return Err(Error::duplicate_uri(uri, first, second));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the files to a directory, so that they can be served by rsyncd. The
+/// path of each file is derived from its uri, relative to the rsync base uri.
+/// Files with a uri under a mapped rsync base uri, see 'CrawlOptions', are
+/// written in the subdir it is mapped for, so that the directory has the
+/// same layout as the source. Files are only written if their content
+/// changed, and then atomically through the storage, so that they get its
+/// modes and use its tmp dir.
+///
+/// To tell whether the content changed without reading every file, a file
+/// of which the size differs is always written, and one of which the size
+/// matches is taken to be unchanged if it was mirrored before with the same
+/// hash. The previous files are those the mirror was last written for,
+/// typically the previous snapshot. Other files are read and hashed.
+///
+/// If clean is set, then any other files in the directory are removed, as
+/// well as any directories which are left empty. Hidden files are left alone.
+/// Returns the paths of the removed files.
+pub fn mirror(
+    storage: &FsStorage,
+    files: &[CurrentFile],
+    previous: &[CurrentFile],
+    rsync_base: &RsyncUri,
+    rsync_map: &[(String, RsyncUri)],
+    out: &Path,
+    clean: bool,
+) -> Result<Vec<PathBuf>, io::Error> {
+    let previous: HashMap<&RsyncUri, &EncodedHash> = previous
+        .iter()
+        .map(|file| (file.uri(), file.hash()))
+        .collect();
+    let mut expected = HashSet::new();
+
+    for file in files {
+        let path = mirror_path(file.uri(), rsync_base, rsync_map, out)?;
+
+        if !is_mirrored(&path, file, previous.get(file.uri()).copied()) {
+            let content = file
+                .base64()
+                .decode()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            debug!("Writing: {}", path.display());
+            storage.save_atomic(&content, &path)?;
+        }
+
+        expected.insert(path);
+    }
+
+    let mut removed = vec![];
+    if clean && out.is_dir() {
+        remove_stale(out, &expected, &mut removed)?;
+    }
+    Ok(removed)
+}
+
+/// Returns whether the file at the path in the mirror has the content of the
+/// file, see 'mirror'. The previous hash is that of the file when the mirror
+/// was last written, if it was in there.
+fn is_mirrored(path: &Path, file: &CurrentFile, previous: Option<&EncodedHash>) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) if metadata.len() == file.base64().decoded_len() as u64 => {}
+        _ => return false,
+    }
+    if previous == Some(file.hash()) {
+        return true;
+    }
+    fs::read(path)
+        .map(|content| EncodedHash::from_content_with(file.hash().alg(), &content) == *file.hash())
+        .unwrap_or(false)
+}
+
+/// Derives the path for a file in the rsync mirror from its uri.
+fn mirror_path(
+    uri: &RsyncUri,
+    rsync_base: &RsyncUri,
+    rsync_map: &[(String, RsyncUri)],
+    out: &Path,
+) -> Result<PathBuf, Error> {
+    let mut path = out.to_path_buf();
+    for segment in mirror_segments(uri, rsync_base, rsync_map)? {
+        path.push(segment);
+    }
+    Ok(path)
+}
+
+/// Returns the decoded segments of the path of a uri relative to the rsync
+/// base uri, or to a mapped rsync base uri prefixed with the subdir it is
+/// mapped for. Segments that would lead outside of the mirror are refused.
+fn mirror_segments(
+    uri: &RsyncUri,
+    rsync_base: &RsyncUri,
+    rsync_map: &[(String, RsyncUri)],
+) -> Result<Vec<String>, Error> {
+    let outside = || Error::OutsideJail(uri.to_string(), rsync_base.to_string());
+
+    let mut segments = vec![];
+    let mapped = rsync_map
+        .iter()
+        .find_map(|(dir, base)| Some((dir, uri.relative_to(base)?)));
+    let rel = match mapped {
+        Some((dir, rel)) => {
+            segments.push(dir.clone());
+            rel
+        }
+        None => uri.relative_to(rsync_base).ok_or_else(outside)?,
+    };
+
+    for segment in rel.split('/') {
+        let segment = percent_decode_str(segment)
+            .decode_utf8()
+            .map_err(|_| Error::UnsupportedFileName(uri.to_string()))?;
+        if segment.is_empty() || segment == "." || segment == ".." {
+            return Err(outside());
+        }
+        segments.push(segment.into_owned());
+    }
+    Ok(segments)
+}
+
+/// Writes the SHA-256 hash of each file with its path in the rsync mirror,
+/// see 'mirror', in the format of 'sha256sum'. This allows checking an rsync
+/// mirror with 'sha256sum -c'. The file is replaced atomically through the
+/// storage.
+pub fn write_checksums<S: Storage>(
+    storage: &S,
+    files: &[CurrentFile],
+    rsync_base: &RsyncUri,
+    rsync_map: &[(String, RsyncUri)],
+    path: &Path,
+) -> Result<(), io::Error> {
+    storage.save_atomic(
+        format_checksums(files, rsync_base, rsync_map)?.as_bytes(),
+        path,
+    )
+}
+
+/// Formats the checksums of the files, see 'write_checksums'. The lines are
+/// sorted by path, so that the same files always give the same result.
+pub fn format_checksums(
+    files: &[CurrentFile],
+    rsync_base: &RsyncUri,
+    rsync_map: &[(String, RsyncUri)],
+) -> Result<String, Error> {
+    let mut lines = Vec::with_capacity(files.len());
+    for file in files {
+        let path = mirror_segments(file.uri(), rsync_base, rsync_map)?.join("/");
+        let hash = if file.hash().alg() == HashAlg::Sha256 {
+            file.hash().clone()
+        } else {
+            let content = file
+                .base64()
+                .decode()
+                .map_err(|_| Error::InvalidContent(file.uri().to_string()))?;
+            EncodedHash::from_content_with(HashAlg::Sha256, &content)
+        };
+        lines.push((path, hash));
+    }
+    lines.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut res = String::new();
+    for (path, hash) in lines {
+        // Like sha256sum, escape backslashes and newlines in the path, and
+        // mark the line as escaped with a leading backslash.
+        if path.contains(['\\', '\n']) {
+            let path = path.replace('\\', "\\\\").replace('\n', "\\n");
+            res.push_str(&format!("\\{}  {}\n", hash, path));
+        } else {
+            res.push_str(&format!("{}  {}\n", hash, path));
+        }
+    }
+    Ok(res)
+}
+
+/// Removes all non-hidden files in a directory which are not expected, and
+/// then the directory itself if it is left empty.
+fn remove_stale(
+    dir: &Path,
+    expected: &HashSet<PathBuf>,
+    removed: &mut Vec<PathBuf>,
+) -> Result<(), io::Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        if entry.file_type()?.is_dir() {
+            remove_stale(&path, expected, removed)?;
+            if fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path)?;
+            }
+        } else if !expected.contains(&path) {
+            info!("Removed: {}", path.display());
+            fs::remove_file(&path)?;
+            removed.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Cleans up a directory, i.e. it retains any sub directories for which the
+/// predicate function returns 'true'. Plain files, such as the notification
+/// file, are never removed. Returns the paths which were removed.
+pub fn retain_disk<P>(base_path: &Path, keep: P) -> Result<Vec<PathBuf>, Error>
+where
+    P: FnMut(&str) -> bool,
+{
+    let storage = FsStorage::default();
+    let mut removed = vec![];
+
+    for path in unretained(base_path, keep)? {
+        match storage.remove(&path) {
+            Ok(()) => {
+                info!("Removed: {}", path.display());
+                removed.push(path);
+            }
+            Err(e) => warn!("Could not remove: {}, error: {}", path.display(), e),
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Returns the sub directories of a directory for which the predicate
+/// function returns 'false', i.e. the paths which would be removed by
+/// 'retain_disk'.
+pub fn unretained<P>(base_path: &Path, mut keep: P) -> Result<Vec<PathBuf>, Error>
+where
+    P: FnMut(&str) -> bool,
+{
+    let entries = FsStorage::default()
+        .list(base_path)
+        .map_err(|_| Error::cannot_read(base_path))?;
+    Ok(entries
+        .into_iter()
+        .filter(|entry| entry.is_dir() && !keep(entry.name()))
+        .map(|entry| base_path.join(entry.name()))
+        .collect())
+}
+
+//------------ Error ---------------------------------------------------------
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "Invalid rsync uri")]
+    InvalidRsyncUri,
+
+    #[display(fmt = "rsync base uri must start with rsync:// end with slash")]
+    InvalidRsyncBase,
+
+    #[display(fmt = "https base uri must start with https:// end with slash")]
+    InvalidHttpsBase,
+
+    #[display(fmt = "Cannot read: {}", _0)]
+    CannotRead(String),
+
+    #[display(fmt = "Unsupported characters: {}", _0)]
+    UnsupportedFileName(String),
+
+    #[display(fmt = "File: {} outside of jail: {}", _0, _1)]
+    OutsideJail(String, String),
+
+    #[display(fmt = "File: {} of {} bytes exceeds limit of {} bytes", _0, _1, _2)]
+    FileTooLarge(String, u64, u64),
+
+    #[display(fmt = "Directory: {} exceeds the maximum depth of {}", _0, _1)]
+    TooDeep(String, usize),
+
+    #[display(fmt = "Invalid tar archive: {}", _0)]
+    InvalidTar(String),
+
+    #[display(fmt = "Uri: {} found for both: {} and: {}", _0, _1, _2)]
+    DuplicateUri(String, String, String),
+
+    #[display(fmt = "Unsupported hash algorithm: {}", _0)]
+    UnsupportedHashAlg(String),
+
+    #[display(fmt = "Invalid hash: {}", _0)]
+    InvalidHash(String),
+
+    #[display(fmt = "Invalid base64 content for: {}", _0)]
+    InvalidContent(String),
+}
+
+impl Error {
+    fn invalid_tar(e: io::Error) -> Error {
+        Error::InvalidTar(e.to_string())
+    }
+
+    fn duplicate_uri(uri: &RsyncUri, first: &Path, second: &Path) -> Error {
+        Error::DuplicateUri(
+            uri.to_string(),
+            first.to_string_lossy().to_string(),
+            second.to_string_lossy().to_string(),
+        )
+    }
+
+    fn file_too_large(path: &Path, size: u64, limit: u64) -> Error {
+        let str = path.to_string_lossy().to_string();
+        Error::FileTooLarge(str, size, limit)
+    }
+
+    fn too_deep(path: &Path, max_depth: usize) -> Error {
+        let str = path.to_string_lossy().to_string();
+        Error::TooDeep(str, max_depth)
+    }
+
+    fn unsupported_file_name(path: &Path) -> Error {
+        let str = path.to_string_lossy().to_string();
+        Error::UnsupportedFileName(str)
+    }
+
+    pub(crate) fn cannot_read(path: &Path) -> Error {
+        let str = path.to_string_lossy().to_string();
+        Error::CannotRead(str)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        io::Error::other(e)
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+//
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
     fn should_scan_disk() {
@@ -328,4 +1800,1276 @@ mod tests {
 
         assert_eq!(expected, found);
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_create_with_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        const WORK_DIR: &str = "./test-work/should_create_with_modes/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        fs::create_dir_all(WORK_DIR).unwrap();
+        fs::set_permissions(WORK_DIR, fs::Permissions::from_mode(0o700)).unwrap();
+
+        let modes = FileModes {
+            file: Some(0o640),
+            dir: Some(0o750),
+        };
+        let base = Path::new(WORK_DIR);
+        modes.save(b"content", &base.join("a/b/file.xml")).unwrap();
+        modes
+            .save_atomic(b"content", &base.join("a/notification.xml"))
+            .unwrap();
+
+        let mode =
+            |path: &str| fs::metadata(base.join(path)).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(0o640, mode("a/b/file.xml"));
+        assert_eq!(0o640, mode("a/notification.xml"));
+        assert_eq!(0o750, mode("a"));
+        assert_eq!(0o750, mode("a/b"));
+
+        // Existing dirs are left as they are.
+        assert_eq!(0o700, mode(""));
+    }
+
+    #[test]
+    fn should_read_unchanged() {
+        const WORK_DIR: &str = "./test-work/should_read_unchanged/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let base = PathBuf::from(WORK_DIR);
+
+        let large: Vec<u8> = (0..3_000_001).map(|i| (i % 251) as u8).collect();
+        for (name, content) in &[
+            ("empty", &b""[..]),
+            ("small", b"content"),
+            ("large", &large),
+        ] {
+            let path = base.join(name);
+            save(content, &path).unwrap();
+            assert_eq!(Bytes::from(*content), read(&path).unwrap());
+        }
+    }
+
+    #[test]
+    fn should_save_atomic() {
+        let path = PathBuf::from("./test-work/should_save_atomic/notification.xml");
+
+        save_atomic(b"first", &path).unwrap();
+        save_atomic(b"second", &path).unwrap();
+
+        assert_eq!(read(&path).unwrap(), Bytes::from("second"));
+        assert!(!tmp_path(&path).unwrap().exists());
+    }
+
+    #[test]
+    fn should_save_atomic_across_devices() {
+        const WORK_DIR: &str = "./test-work/should_save_atomic_across_devices/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let base = Path::new(WORK_DIR);
+        let path = base.join("target/notification.xml");
+        save(b"old", &path).unwrap();
+
+        // Pretend that the tmp dir is on another filesystem, by failing the
+        // rename with EXDEV.
+        let modes = FileModes::default();
+        let staged = tmp_path_in(&base.join("tmp"), &path).unwrap();
+        modes.save(b"new content", &staged).unwrap();
+        let exdev = io::Error::from(io::ErrorKind::CrossesDevices);
+        modes
+            .finish_atomic(b"new content", &staged, &path, Err(exdev))
+            .unwrap();
+
+        assert_eq!(Bytes::from("new content"), read(&path).unwrap());
+        assert!(!staged.exists());
+        assert!(!tmp_path(&path).unwrap().exists());
+
+        // Other errors are not hidden, nor is EXDEV when the temporary file
+        // was next to the final path already.
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(modes
+            .finish_atomic(b"x", &staged, &path, Err(denied))
+            .is_err());
+        let next_to = tmp_path(&path).unwrap();
+        let exdev = io::Error::from(io::ErrorKind::CrossesDevices);
+        assert!(modes
+            .finish_atomic(b"x", &next_to, &path, Err(exdev))
+            .is_err());
+        assert_eq!(Bytes::from("new content"), read(&path).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_skip_special_files() {
+        use std::process::Command;
+
+        const WORK_DIR: &str = "./test-work/should_skip_special_files/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let base_dir = PathBuf::from(WORK_DIR);
+        save(b"content", &base_dir.join("file.txt")).unwrap();
+        let status = Command::new("mkfifo")
+            .arg(base_dir.join("pipe"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let files = crawl_disk(&base_dir, &rsync_base).unwrap();
+
+        assert_eq!(1, files.len());
+        assert_eq!("rsync://localhost/repo/file.txt", files[0].uri().as_str());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_filter_before_checking_file_type() {
+        use std::os::unix::fs::symlink;
+
+        const WORK_DIR: &str = "./test-work/should_filter_before_checking_file_type/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let base_dir = PathBuf::from(WORK_DIR);
+        save(b"content", &base_dir.join("file.cer")).unwrap();
+        symlink("missing.cer", base_dir.join("dangling.tmp")).unwrap();
+
+        // The dangling symlink cannot be read, unless it is excluded.
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        match crawl_disk(&base_dir, &rsync_base) {
+            Err(Error::CannotRead(path)) => assert!(path.ends_with("dangling.tmp")),
+            res => panic!("Expected CannotRead, got: {:?}", res),
+        }
+
+        let options = CrawlOptions {
+            excludes: vec![Pattern::new("*.tmp").unwrap()],
+            ..Default::default()
+        };
+        let files = crawl_disk_with(&base_dir, &rsync_base, &options).unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!("rsync://localhost/repo/file.cer", files[0].uri().as_str());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_reject_non_utf8_file_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        const WORK_DIR: &str = "./test-work/should_reject_non_utf8_file_names/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let base_dir = PathBuf::from(WORK_DIR);
+        let path = base_dir.join(OsStr::from_bytes(b"invalid-\xff.cer"));
+        save(b"content", &path).unwrap();
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        match crawl_disk(&base_dir, &rsync_base) {
+            Err(Error::UnsupportedFileName(name)) => {
+                assert!(name.ends_with("invalid-\u{fffd}.cer"))
+            }
+            res => panic!("Expected UnsupportedFileName, got: {:?}", res),
+        }
+        match derive_uri(&base_dir, &path, &rsync_base, &CrawlOptions::default()) {
+            Err(Error::UnsupportedFileName(_)) => {}
+            res => panic!("Expected UnsupportedFileName, got: {:?}", res),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_skip_non_utf8_file_names_which_are_not_published() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        const WORK_DIR: &str =
+            "./test-work/should_skip_non_utf8_file_names_which_are_not_published/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let base_dir = PathBuf::from(WORK_DIR);
+        save(b"content", &base_dir.join("file.cer")).unwrap();
+        save(
+            b"hidden",
+            &base_dir.join(OsStr::from_bytes(b".hidden-\xff")),
+        )
+        .unwrap();
+        save(
+            b"excluded",
+            &base_dir.join(OsStr::from_bytes(b"excluded-\xff.tmp")),
+        )
+        .unwrap();
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let options = CrawlOptions {
+            excludes: vec![Pattern::new("*.tmp").unwrap()],
+            ..Default::default()
+        };
+        let files = crawl_disk_with(&base_dir, &rsync_base, &options).unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!("rsync://localhost/repo/file.cer", files[0].uri().as_str());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_reject_non_utf8_file_names_in_tar() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut builder = tar::Builder::new(vec![]);
+        for name in &[&b"file.cer"[..], b"invalid-\xff.cer", b".hidden-\xff"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(7);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, OsStr::from_bytes(name), &b"content"[..])
+                .unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        match crawl_tar(tar.as_slice(), &rsync_base) {
+            Err(Error::UnsupportedFileName(name)) => {
+                assert!(name.ends_with("invalid-\u{fffd}.cer"))
+            }
+            res => panic!("Expected UnsupportedFileName, got: {:?}", res),
+        }
+
+        let options = CrawlOptions {
+            excludes: vec![Pattern::new("invalid-*").unwrap()],
+            ..Default::default()
+        };
+        let files = crawl_tar_with(tar.as_slice(), &rsync_base, &options).unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!("rsync://localhost/repo/file.cer", files[0].uri().as_str());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_reject_control_chars_in_file_names() {
+        const WORK_DIR: &str = "./test-work/should_reject_control_chars_in_file_names/";
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+
+        for name in &["new\nline.cer", "control-\u{1}.cer"] {
+            let _ = fs::remove_dir_all(WORK_DIR);
+            let base_dir = PathBuf::from(WORK_DIR);
+            let path = base_dir.join(name);
+            save(b"content", &path).unwrap();
+
+            match crawl_disk(&base_dir, &rsync_base) {
+                Err(Error::UnsupportedFileName(found)) => assert!(found.ends_with(name)),
+                res => panic!("Expected UnsupportedFileName, got: {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn format_sha256sum_checksums() {
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let files = vec![
+            CurrentFile::new(RsyncUri::from("rsync://localhost/repo/ca/b.roa"), b"b"),
+            CurrentFile::new(RsyncUri::from("rsync://localhost/repo/a%20b.cer"), b"a"),
+            CurrentFile::new_with(
+                HashAlg::Sha512,
+                RsyncUri::from("rsync://localhost/repo/c.crl"),
+                b"c",
+            ),
+        ];
+
+        let checksums = format_checksums(&files, &rsync_base, &[]).unwrap();
+        let lines: Vec<_> = checksums.lines().collect();
+        assert_eq!(
+            vec![
+                format!("{}  a b.cer", EncodedHash::from_content(b"a")),
+                format!("{}  c.crl", EncodedHash::from_content(b"c")),
+                format!("{}  ca/b.roa", EncodedHash::from_content(b"b")),
+            ],
+            lines
+        );
+
+        let escaped = [CurrentFile::new(
+            RsyncUri::from("rsync://localhost/repo/a%5Cb.cer"),
+            b"a",
+        )];
+        assert_eq!(
+            format!("\\{}  a\\\\b.cer\n", EncodedHash::from_content(b"a")),
+            format_checksums(&escaped, &rsync_base, &[]).unwrap()
+        );
+
+        let outside = [CurrentFile::new(
+            RsyncUri::from("rsync://other/a.cer"),
+            b"a",
+        )];
+        assert!(format_checksums(&outside, &rsync_base, &[]).is_err());
+
+        let invalid = [CurrentFile::from_parts(
+            RsyncUri::from("rsync://localhost/repo/a.cer"),
+            Base64::from_b64_str("not base64!"),
+            EncodedHash::from_content_with(HashAlg::Sha512, b"a"),
+        )];
+        match format_checksums(&invalid, &rsync_base, &[]) {
+            Err(Error::InvalidContent(uri)) => assert!(uri.ends_with("a.cer")),
+            res => panic!("Expected InvalidContent, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn crawl_with_rsync_map() {
+        const WORK_DIR: &str = "./test-work/crawl_with_rsync_map/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let base_dir = PathBuf::from(WORK_DIR).join("source");
+        for name in &[
+            "module-a/a.cer",
+            "module-b/sub/b.cer",
+            "other/c.cer",
+            "d.cer",
+        ] {
+            save(name.as_bytes(), &base_dir.join(name)).unwrap();
+        }
+
+        let rsync_base = RsyncUri::base_uri("rsync://host/default/").unwrap();
+        let options = CrawlOptions {
+            rsync_map: vec![
+                (
+                    "module-a".to_string(),
+                    RsyncUri::base_uri("rsync://host/module-a/").unwrap(),
+                ),
+                (
+                    "module-b".to_string(),
+                    RsyncUri::base_uri("rsync://other-host/b/").unwrap(),
+                ),
+            ],
+            ..Default::default()
+        };
+        let files = crawl_disk_with(&base_dir, &rsync_base, &options).unwrap();
+        let found: Vec<String> = files.iter().map(|f| f.uri.to_string()).collect();
+
+        assert_eq!(
+            vec![
+                "rsync://host/default/d.cer",
+                "rsync://host/default/other/c.cer",
+                "rsync://host/module-a/a.cer",
+                "rsync://other-host/b/sub/b.cer",
+            ],
+            found
+        );
+
+        // The mirror and checksums have the same layout as the source.
+        let out = PathBuf::from(WORK_DIR).join("out");
+        mirror(
+            &FsStorage::default(),
+            &files,
+            &[],
+            &rsync_base,
+            &options.rsync_map,
+            &out,
+            true,
+        )
+        .unwrap();
+        assert_eq!(files, crawl_disk_with(&out, &rsync_base, &options).unwrap());
+        let checksums = format_checksums(&files, &rsync_base, &options.rsync_map).unwrap();
+        let paths: Vec<_> = checksums
+            .lines()
+            .map(|line| line.split_once("  ").unwrap().1)
+            .collect();
+        assert_eq!(
+            vec![
+                "d.cer",
+                "module-a/a.cer",
+                "module-b/sub/b.cer",
+                "other/c.cer"
+            ],
+            paths
+        );
+    }
+
+    #[test]
+    fn crawl_with_uri_prefixes() {
+        const WORK_DIR: &str = "./test-work/crawl_with_uri_prefixes/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let base_dir = PathBuf::from(WORK_DIR);
+        for name in &["repo-a/a.cer", "repo-a/sub/b.roa", "repo-b/c.cer", "d.cer"] {
+            save(name.as_bytes(), &base_dir.join(name)).unwrap();
+        }
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let crawl = |prefixes: &[&str]| -> Vec<String> {
+            let options = CrawlOptions {
+                uri_prefixes: prefixes.iter().map(|p| p.to_string()).collect(),
+                ..Default::default()
+            };
+            crawl_disk_with(&base_dir, &rsync_base, &options)
+                .unwrap()
+                .into_iter()
+                .map(|f| f.uri.to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            vec![
+                "rsync://localhost/repo/repo-a/a.cer",
+                "rsync://localhost/repo/repo-a/sub/b.roa",
+            ],
+            crawl(&["rsync://localhost/repo/repo-a/"])
+        );
+        assert_eq!(
+            vec![
+                "rsync://localhost/repo/d.cer",
+                "rsync://localhost/repo/repo-b/c.cer",
+            ],
+            crawl(&[
+                "rsync://localhost/repo/repo-b/",
+                "rsync://localhost/repo/d."
+            ])
+        );
+        assert_eq!(4, crawl(&[]).len());
+    }
+
+    #[test]
+    fn parallel_crawl_equals_serial_crawl() {
+        let base_dir = PathBuf::from("./test-resources/");
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+
+        let serial = crawl_disk_with(&base_dir, &rsync_base, &CrawlOptions::default()).unwrap();
+        let options = CrawlOptions {
+            threads: 4,
+            ..Default::default()
+        };
+        let parallel = crawl_disk_with(&base_dir, &rsync_base, &options).unwrap();
+
+        assert_eq!(10, serial.len());
+        assert_eq!(serial, parallel);
+    }
+
+    #[test]
+    fn current_file_from_path_equals_from_content() {
+        let path = PathBuf::from("./test-work/current_file_from_path/large.bin");
+        let content: Vec<u8> = (0..3_000_001).map(|i| (i % 251) as u8).collect();
+        save(&content, &path).unwrap();
+
+        let uri = RsyncUri::from("rsync://localhost/repo/large.bin");
+        let streamed = CurrentFile::from_path(HashAlg::Sha256, uri.clone(), &path).unwrap();
+        let one_shot = CurrentFile::new(uri, &content);
+
+        assert_eq!(one_shot, streamed);
+    }
+
+    #[test]
+    fn current_file_from_mmap_equals_from_path() {
+        const WORK_DIR: &str = "./test-work/current_file_from_mmap/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let base = PathBuf::from(WORK_DIR);
+        let uri = RsyncUri::from("rsync://localhost/repo/file.bin");
+
+        // Both a file that is mapped, and a small one that is read instead.
+        for len in &[3_000_001, MMAP_MIN_SIZE as usize - 1] {
+            let path = base.join(format!("{}.bin", len));
+            let content: Vec<u8> = (0..*len).map(|i| (i % 251) as u8).collect();
+            save(&content, &path).unwrap();
+
+            for alg in &[HashAlg::Sha256, HashAlg::Sha512] {
+                let mapped = CurrentFile::from_mmap(*alg, uri.clone(), &path).unwrap();
+                let streamed = CurrentFile::from_path(*alg, uri.clone(), &path).unwrap();
+                assert_eq!(streamed, mapped);
+            }
+        }
+    }
+
+    fn crawl_filtered(includes: &[&str], excludes: &[&str]) -> Vec<String> {
+        let base_dir = PathBuf::from("./test-work/crawl_filtered/");
+        for name in &["a.cer", "b.tmp", "sub/c.cer", "sub/d.tmp", "sub/e.roa"] {
+            save(b"content", &base_dir.join(name)).unwrap();
+        }
+
+        let options = CrawlOptions {
+            includes: includes.iter().map(|p| Pattern::new(p).unwrap()).collect(),
+            excludes: excludes.iter().map(|p| Pattern::new(p).unwrap()).collect(),
+            ..Default::default()
+        };
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        crawl_disk_with(&base_dir, &rsync_base, &options)
+            .unwrap()
+            .into_iter()
+            .map(|f| f.uri.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn should_exclude_tmp_files() {
+        let found = crawl_filtered(&[], &["*.tmp"]);
+        assert_eq!(
+            vec![
+                "rsync://localhost/repo/a.cer",
+                "rsync://localhost/repo/sub/c.cer",
+                "rsync://localhost/repo/sub/e.roa",
+            ],
+            found
+        );
+    }
+
+    #[test]
+    fn should_include_cer_files() {
+        let found = crawl_filtered(&["**/*.cer"], &[]);
+        assert_eq!(
+            vec![
+                "rsync://localhost/repo/a.cer",
+                "rsync://localhost/repo/sub/c.cer",
+            ],
+            found
+        );
+    }
+
+    #[test]
+    fn exclude_takes_precedence_over_include() {
+        let found = crawl_filtered(&["**/*.cer"], &["sub/*"]);
+        assert_eq!(vec!["rsync://localhost/repo/a.cer"], found);
+    }
+
+    #[test]
+    fn should_include_hidden_files_if_asked() {
+        const WORK_DIR: &str = "./test-work/should_include_hidden_files_if_asked/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let base_dir = PathBuf::from(WORK_DIR);
+        save(b"content", &base_dir.join("file.cer")).unwrap();
+        save(b"hidden", &base_dir.join(".hidden.cer")).unwrap();
+        save(b"known", &base_dir.join(".well-known/file.cer")).unwrap();
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let crawl = |include_hidden| -> Vec<String> {
+            let options = CrawlOptions {
+                include_hidden,
+                ..Default::default()
+            };
+            crawl_disk_with(&base_dir, &rsync_base, &options)
+                .unwrap()
+                .into_iter()
+                .map(|f| f.uri.to_string())
+                .collect()
+        };
+
+        assert_eq!(vec!["rsync://localhost/repo/file.cer"], crawl(false));
+        assert_eq!(
+            vec![
+                "rsync://localhost/repo/.hidden.cer",
+                "rsync://localhost/repo/.well-known/file.cer",
+                "rsync://localhost/repo/file.cer",
+            ],
+            crawl(true)
+        );
+        assert_eq!(
+            Some(".file.txt".to_string()),
+            tar_relative_path(Path::new(".file.txt"), true).unwrap()
+        );
+    }
+
+    #[test]
+    fn should_enforce_max_depth() {
+        const WORK_DIR: &str = "./test-work/should_enforce_max_depth/";
+        let _ = fs::remove_dir_all(WORK_DIR);
+        let base_dir = PathBuf::from(WORK_DIR);
+        save(b"content", &base_dir.join("a/b/c/file.cer")).unwrap();
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let crawl = |max_depth| {
+            let options = CrawlOptions {
+                max_depth,
+                ..Default::default()
+            };
+            crawl_disk_with(&base_dir, &rsync_base, &options)
+        };
+
+        assert_eq!(1, crawl(3).unwrap().len());
+        match crawl(2) {
+            Err(Error::TooDeep(path, 2)) => assert!(path.ends_with("c")),
+            res => panic!("Expected TooDeep, got: {:?}", res),
+        }
+    }
+
+    #[cfg(unix)]
+    fn crawl_symlinks(name: &str, follow_symlinks: bool) -> Vec<String> {
+        use std::os::unix::fs::symlink;
+
+        let base_dir = PathBuf::from(format!("./test-work/{}/", name));
+        let _ = fs::remove_dir_all(&base_dir);
+        save(b"content", &base_dir.join("dir/file.cer")).unwrap();
+        symlink("dir/file.cer", base_dir.join("link.cer")).unwrap();
+        symlink("..", base_dir.join("dir/loop")).unwrap();
+
+        let options = CrawlOptions {
+            follow_symlinks,
+            ..Default::default()
+        };
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        crawl_disk_with(&base_dir, &rsync_base, &options)
+            .unwrap()
+            .into_iter()
+            .map(|f| f.uri.to_string())
+            .collect()
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn should_skip_symlinked_dirs_by_default() {
+        let found = crawl_symlinks("should_skip_symlinked_dirs", false);
+        assert_eq!(
+            vec![
+                "rsync://localhost/repo/dir/file.cer",
+                "rsync://localhost/repo/link.cer",
+            ],
+            found
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn should_follow_symlinks_without_looping() {
+        let found = crawl_symlinks("should_follow_symlinks", true);
+        assert_eq!(
+            vec![
+                "rsync://localhost/repo/dir/file.cer",
+                "rsync://localhost/repo/link.cer",
+            ],
+            found
+        );
+    }
+
+    fn crawl_limited(name: &str, fail_on_large_files: bool) -> Result<Vec<CurrentFile>, Error> {
+        let base_dir = PathBuf::from(format!("./test-work/{}/", name));
+        save(&[0; 10], &base_dir.join("small.cer")).unwrap();
+        save(&[0; 11], &base_dir.join("large.cer")).unwrap();
+
+        let options = CrawlOptions {
+            max_file_size: Some(10),
+            fail_on_large_files,
+            ..Default::default()
+        };
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        crawl_disk_with(&base_dir, &rsync_base, &options)
+    }
+
+    #[test]
+    fn should_skip_large_files() {
+        let files = crawl_limited("should_skip_large_files", false).unwrap();
+        assert_eq!(1, files.len());
+        assert_eq!(
+            &RsyncUri::from("rsync://localhost/repo/small.cer"),
+            files[0].uri()
+        );
+    }
+
+    #[test]
+    fn should_fail_on_large_files() {
+        match crawl_limited("should_fail_on_large_files", true) {
+            Err(Error::FileTooLarge(_, 11, 10)) => {}
+            _ => panic!("Expected FileTooLarge error"),
+        }
+    }
+
+    #[test]
+    fn should_derive_relative_path_with_forward_slashes() {
+        let base_dir = PathBuf::from("./test-work/should_derive_forward_slashes/");
+        let path = base_dir.join("sub").join("dir").join("file.cer");
+        save(b"content", &path).unwrap();
+
+        assert_eq!(
+            "sub/dir/file.cer",
+            derive_relative_path(&base_dir, &path).unwrap()
+        );
+        assert_eq!(
+            "sub/dir/file.cer",
+            with_uri_separators("sub\\dir\\file.cer", '\\')
+        );
+        assert_eq!(
+            "sub/dir/file.cer",
+            with_uri_separators("sub/dir/file.cer", '/')
+        );
+    }
+
+    #[test]
+    fn should_derive_relative_path_regardless_of_prefix() {
+        let path = Path::new("./test-resources/source-1/file1.txt");
+
+        for base in &[
+            "./test-resources/source-1/",
+            "./test-resources/source-1",
+            "test-resources/source-1/",
+            "test-resources/source-1",
+        ] {
+            assert_eq!(
+                "file1.txt",
+                derive_relative_path(Path::new(base), path).unwrap()
+            );
+        }
+
+        let path = Path::new("test-resources/source-1/file1.txt");
+        assert_eq!(
+            "file1.txt",
+            derive_relative_path(Path::new("./test-resources/source-1/"), path).unwrap()
+        );
+    }
+
+    #[test]
+    fn should_derive_relative_path_with_parent_dirs() {
+        let base_dir = Path::new("./test-resources/source-1/");
+
+        let path = Path::new("./test-resources/source-2/../source-1/file1.txt");
+        assert_eq!("file1.txt", derive_relative_path(base_dir, path).unwrap());
+
+        let path = Path::new("./test-resources/source-1/../source-2/file1.txt");
+        match derive_relative_path(base_dir, path) {
+            Err(Error::OutsideJail(_, _)) => {}
+            _ => panic!("Expected OutsideJail error"),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn should_reject_symlinks_escaping_jail() {
+        use std::os::unix::fs::symlink;
+
+        let base_dir = PathBuf::from("./test-work/should_reject_symlinks_escaping_jail/");
+        let _ = fs::remove_dir_all(&base_dir);
+        save(b"content", &base_dir.join("file.cer")).unwrap();
+
+        let outside = fs::canonicalize("./test-resources/source-1/file1.txt").unwrap();
+        symlink(outside, base_dir.join("escape.cer")).unwrap();
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        match crawl_disk(&base_dir, &rsync_base) {
+            Err(Error::OutsideJail(path, _)) => assert!(path.ends_with("escape.cer")),
+            _ => panic!("Expected OutsideJail error"),
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn should_publish_symlinks_outside_tree_under_link_name() {
+        use std::os::unix::fs::symlink;
+
+        let base_dir = PathBuf::from("./test-work/should_publish_symlinks_outside_tree/");
+        let _ = fs::remove_dir_all(&base_dir);
+        save(b"content", &base_dir.join("file.cer")).unwrap();
+
+        let outside = fs::canonicalize("./test-resources/source-1/").unwrap();
+        symlink(outside, base_dir.join("linked")).unwrap();
+        symlink("file.cer", base_dir.join("alias.cer")).unwrap();
+
+        let options = CrawlOptions {
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let found: Vec<_> = crawl_disk_with(&base_dir, &rsync_base, &options)
+            .unwrap()
+            .into_iter()
+            .map(|f| f.uri.to_string())
+            .collect();
+
+        assert!(found.contains(&"rsync://localhost/repo/alias.cer".to_string()));
+        assert!(found.contains(&"rsync://localhost/repo/linked/file1.txt".to_string()));
+    }
+
+    #[test]
+    fn should_percent_encode_unsafe_characters() {
+        use percent_encoding::percent_decode_str;
+
+        let base_dir = PathBuf::from("./test-work/should_percent_encode/");
+        let _ = fs::remove_dir_all(&base_dir);
+        save(b"space", &base_dir.join("sub dir/a file.cer")).unwrap();
+        save(b"hash", &base_dir.join("a#b.cer")).unwrap();
+        save(b"safe", &base_dir.join("a-b_c~d.cer")).unwrap();
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let found: Vec<String> = crawl_disk(&base_dir, &rsync_base)
+            .unwrap()
+            .into_iter()
+            .map(|f| f.uri.to_string())
+            .collect();
+
+        assert_eq!(
+            vec![
+                "rsync://localhost/repo/a%23b.cer",
+                "rsync://localhost/repo/a-b_c~d.cer",
+                "rsync://localhost/repo/sub%20dir/a%20file.cer",
+            ],
+            found
+        );
+
+        assert_eq!(
+            "sub dir/a file.cer",
+            percent_decode_str(&encode_path("sub dir/a file.cer"))
+                .decode_utf8()
+                .unwrap()
+        );
+        assert_eq!(
+            "a#b.cer",
+            percent_decode_str(&encode_path("a#b.cer"))
+                .decode_utf8()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn hash_with_either_algorithm() {
+        let sha256 = EncodedHash::from_content_with(HashAlg::Sha256, b"content");
+        let sha512 = EncodedHash::from_content_with(HashAlg::Sha512, b"content");
+        assert_eq!(64, sha256.to_string().len());
+        assert_eq!(128, sha512.to_string().len());
+        assert_eq!(EncodedHash::from_content(b"content"), sha256);
+
+        assert_eq!(Some(HashAlg::Sha256), HashAlg::from_hex_len(64));
+        assert_eq!(Some(HashAlg::Sha512), HashAlg::from_hex_len(128));
+        assert_eq!(None, HashAlg::from_hex_len(40));
+
+        let uri = RsyncUri::from("rsync://localhost/repo/a.cer");
+        let file = CurrentFile::new_with(HashAlg::Sha512, uri, b"content");
+        assert_eq!(&sha512, file.hash());
+        assert!(file.has_hash(&sha256));
+        assert!(!file.has_hash(&EncodedHash::from_content(b"other")));
+    }
+
+    #[test]
+    fn compare_hash_to_str() {
+        let hash = EncodedHash::from_content(b"content");
+        let hex = hash.to_string();
+        assert!(hash.eq_str(&hex));
+        assert!(hash == hex.as_str());
+
+        let mut other = hex.clone().into_bytes();
+        other[63] = if other[63] == b'0' { b'1' } else { b'0' };
+        let other = String::from_utf8(other).unwrap();
+        assert!(!hash.eq_str(&other));
+        assert!(hash != other.as_str());
+
+        assert!(!hash.eq_str(&hex[..63]));
+        assert!(!hash.eq_str(&format!("{}0", hex)));
+        assert!(!hash.eq_str(""));
+    }
+
+    #[test]
+    fn should_limit_decompressed_size() {
+        let content = Bytes::from(vec![b'x'; 100]);
+        let compressed = gzip(content.as_ref()).unwrap();
+        assert!(compressed.len() < 100);
+
+        let limited = |max| gunzip_if_compressed_with(compressed.clone(), max);
+        assert_eq!(content, limited(None).unwrap());
+        assert_eq!(content, limited(Some(100)).unwrap());
+        assert_eq!(
+            io::ErrorKind::InvalidData,
+            limited(Some(99)).unwrap_err().kind()
+        );
+
+        // Uncompressed content is returned as is, whatever its size.
+        assert_eq!(
+            content,
+            gunzip_if_compressed_with(content.clone(), Some(10)).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_hex_hash() {
+        let hash = EncodedHash::from_content(b"content");
+        let hex = hash.to_string();
+        assert_eq!(hash, EncodedHash::from_hex(&hex).unwrap());
+
+        let sha512 = EncodedHash::from_content_with(HashAlg::Sha512, b"content");
+        let parsed = EncodedHash::from_hex(&sha512.to_string()).unwrap();
+        assert_eq!(HashAlg::Sha512, parsed.alg());
+
+        let invalid = |hex: &str| match EncodedHash::from_hex(hex) {
+            Err(Error::InvalidHash(found)) => assert_eq!(hex, found),
+            res => panic!("Expected invalid hash, got: {:?}", res),
+        };
+        invalid(&hex.to_uppercase());
+        invalid(&hex[..62]);
+        invalid(&format!("g{}", &hex[1..]));
+    }
+
+    #[test]
+    fn should_display_invalid_utf8_safely() {
+        let base64 = Base64::from_b64_str("AAAA");
+        assert_eq!("AAAA", base64.to_string());
+        assert!(matches!(base64.as_str(), Cow::Borrowed("AAAA")));
+
+        let base64 = Base64(Bytes::from(&b"AA\xffA"[..]));
+        assert_eq!("AA\u{fffd}A", base64.to_string());
+
+        let hash = EncodedHash {
+            alg: HashAlg::Sha256,
+            hex: Bytes::from(&b"ab\xfe"[..]),
+        };
+        assert_eq!("ab\u{fffd}", hash.to_string());
+    }
+
+    #[test]
+    fn should_resolve_https_uri() {
+        for base in &["https://localhost/rrdp/", "https://localhost/rrdp"] {
+            let base = HttpsUri::from(*base);
+            for rel in &["session/1/snapshot.xml", "/session/1/snapshot.xml"] {
+                assert_eq!(
+                    HttpsUri::from("https://localhost/rrdp/session/1/snapshot.xml"),
+                    base.resolve(rel)
+                );
+            }
+            assert_eq!(
+                HttpsUri::from("https://other/snapshot.xml"),
+                base.resolve("https://other/snapshot.xml")
+            );
+        }
+    }
+
+    #[test]
+    fn should_parse_rsync_uri() {
+        let uri = RsyncUri::parse("rsync://localhost/repo/sub/file.cer").unwrap();
+        assert_eq!("rsync://localhost/repo/sub/file.cer", uri.to_string());
+
+        for invalid in &[
+            "https://localhost/repo/file.cer",
+            "rsync://",
+            "rsync:///repo/file.cer",
+            "rsync://localhost",
+            "rsync://localhost/",
+            "rsync://localhost//file.cer",
+            "rsync://localhost/repo/a file.cer",
+            "garbage",
+        ] {
+            match RsyncUri::parse(invalid) {
+                Err(Error::InvalidRsyncUri) => {}
+                _ => panic!("Expected InvalidRsyncUri for: {}", invalid),
+            }
+        }
+    }
+
+    #[test]
+    fn should_crawl_tar() {
+        let source = PathBuf::from("./test-resources/source-3/");
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+
+        let mut builder = tar::Builder::new(vec![]);
+        builder.append_dir_all(".", &source).unwrap();
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(6);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "./.hidden/file.txt", &b"hidden"[..])
+            .unwrap();
+        let tar = builder.into_inner().unwrap();
+
+        let from_tar = crawl_tar(tar.as_slice(), &rsync_base).unwrap();
+        let from_disk = crawl_disk(&source, &rsync_base).unwrap();
+        assert_eq!(from_disk, from_tar);
+    }
+
+    #[test]
+    fn should_reject_tar_paths_outside_root() {
+        assert_eq!(
+            Some("sub/file.txt".to_string()),
+            tar_relative_path(Path::new("./sub/file.txt"), false).unwrap()
+        );
+        assert_eq!(
+            None,
+            tar_relative_path(Path::new("sub/.file.txt"), false).unwrap()
+        );
+        assert!(tar_relative_path(Path::new("../file.txt"), false).is_err());
+        assert!(tar_relative_path(Path::new("/etc/file.txt"), false).is_err());
+    }
+
+    #[test]
+    fn should_mirror_files() {
+        let base_dir = PathBuf::from("./test-work/should_mirror_files/");
+        let source = base_dir.join("source");
+        let out = base_dir.join("out");
+        let _ = fs::remove_dir_all(&base_dir);
+
+        save(b"a", &source.join("a.cer")).unwrap();
+        save(b"b", &source.join("sub dir/b.cer")).unwrap();
+        save(b"stale", &out.join("old/stale.cer")).unwrap();
+        save(b"hidden", &out.join(".hidden")).unwrap();
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let files = crawl_disk(&source, &rsync_base).unwrap();
+
+        let removed = mirror(
+            &FsStorage::default(),
+            &files,
+            &[],
+            &rsync_base,
+            &[],
+            &out,
+            true,
+        )
+        .unwrap();
+        assert_eq!(vec![out.join("old/stale.cer")], removed);
+        assert!(!out.join("old").exists());
+        assert!(out.join(".hidden").exists());
+
+        let mirrored = crawl_disk(&out, &rsync_base).unwrap();
+        assert_eq!(files, mirrored);
+        for (file, mirrored) in files.iter().zip(mirrored.iter()) {
+            assert_eq!(file.hash(), mirrored.hash());
+        }
+
+        // Nothing is removed without clean
+        save(b"stale", &out.join("stale.cer")).unwrap();
+        assert!(mirror(
+            &FsStorage::default(),
+            &files,
+            &[],
+            &rsync_base,
+            &[],
+            &out,
+            false
+        )
+        .unwrap()
+        .is_empty());
+        assert!(out.join("stale.cer").exists());
+    }
+
+    #[test]
+    fn should_mirror_only_changed_files() {
+        let out = PathBuf::from("./test-work/should_mirror_only_changed_files/");
+        let _ = fs::remove_dir_all(&out);
+        let storage = FsStorage::default();
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let uri = RsyncUri::from("rsync://localhost/repo/a.cer");
+        let files = vec![CurrentFile::new(uri.clone(), b"abc")];
+        let mirror = |previous: &[CurrentFile]| {
+            mirror(&storage, &files, previous, &rsync_base, &[], &out, false).unwrap();
+            fs::read(out.join("a.cer")).unwrap()
+        };
+        assert_eq!(b"abc".to_vec(), mirror(&[]));
+
+        // A file of the same size that was mirrored with the same hash
+        // before is not read, so a change of its content goes unnoticed.
+        save(b"xyz", &out.join("a.cer")).unwrap();
+        assert_eq!(b"xyz".to_vec(), mirror(&files));
+
+        // Without a previous hash it is read, and written if it differs.
+        assert_eq!(b"abc".to_vec(), mirror(&[]));
+
+        // A file of which the size differs is always written.
+        save(b"abcd", &out.join("a.cer")).unwrap();
+        assert_eq!(b"abc".to_vec(), mirror(&files));
+    }
+
+    #[test]
+    fn base64_decoded_len() {
+        for len in 0..8 {
+            let content = vec![7; len];
+            assert_eq!(len, Base64::from_content(&content).decoded_len());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn should_mirror_files_with_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let base_dir = PathBuf::from("./test-work/should_mirror_files_with_modes/");
+        let out = base_dir.join("out");
+        let tmp_dir = base_dir.join("tmp");
+        let _ = fs::remove_dir_all(&base_dir);
+        fs::create_dir_all(&tmp_dir).unwrap();
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let files = vec![CurrentFile::new(
+            RsyncUri::from("rsync://localhost/repo/sub/a.cer"),
+            b"a",
+        )];
+        let storage = FsStorage::with_modes(FileModes {
+            file: Some(0o640),
+            dir: Some(0o750),
+        })
+        .with_tmp_dir(Some(tmp_dir.clone()));
+        mirror(&storage, &files, &[], &rsync_base, &[], &out, false).unwrap();
+
+        let mode = |path: PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(0o640, mode(out.join("sub/a.cer")));
+        assert_eq!(0o750, mode(out.join("sub")));
+        assert_eq!(0, fs::read_dir(&tmp_dir).unwrap().count());
+    }
+
+    #[test]
+    fn should_reject_mirror_paths_outside_base() {
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let out = Path::new("./test-work/out");
+
+        for uri in &[
+            "rsync://other/repo/a.cer",
+            "rsync://localhost/repo/../a.cer",
+            "rsync://localhost/repo/%2E%2E/a.cer",
+            "rsync://localhost/repo/sub//a.cer",
+        ] {
+            assert!(mirror_path(&RsyncUri::from(*uri), &rsync_base, &[], out).is_err());
+        }
+    }
+
+    #[test]
+    fn should_reject_duplicate_uris() {
+        // Two distinct files may map to the same uri, e.g. on a case
+        // insensitive file system, which we cannot set up here.
+        let base_dir = PathBuf::from("./test-work/should_reject_duplicate_uris/");
+        let upper = base_dir.join("FILE.cer");
+        let lower = base_dir.join("file.cer");
+        save(b"upper", &upper).unwrap();
+        save(b"lower", &lower).unwrap();
+
+        let uri = RsyncUri::from("rsync://localhost/repo/file.cer");
+        let files = vec![(uri.clone(), upper.clone()), (uri, lower.clone())];
+
+        match check_unique_uris(&files) {
+            Err(Error::DuplicateUri(uri, first, second)) => {
+                assert_eq!("rsync://localhost/repo/file.cer", uri);
+                assert_eq!(upper.to_string_lossy(), first);
+                assert_eq!(lower.to_string_lossy(), second);
+            }
+            _ => panic!("Expected DuplicateUri error"),
+        }
+    }
+
+    #[test]
+    fn should_reject_duplicate_uris_in_sources() {
+        let sources = vec![
+            PathBuf::from("./test-resources/source-1/"),
+            PathBuf::from("./test-resources/source-2/"),
+        ];
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+
+        match crawl_sources(&sources, &rsync_base, &CrawlOptions::default()) {
+            Err(Error::DuplicateUri(uri, _, _)) => {
+                assert_eq!("rsync://localhost/repo/file1.txt", uri)
+            }
+            _ => panic!("Expected DuplicateUri error"),
+        }
+    }
+
+    #[test]
+    fn should_merge_sources() {
+        let base_dir = PathBuf::from("./test-work/should_merge_sources/");
+        save(b"a", &base_dir.join("a/a.cer")).unwrap();
+        save(b"b", &base_dir.join("b/sub/b.cer")).unwrap();
+
+        let sources = vec![base_dir.join("a/"), base_dir.join("b/")];
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+
+        let found: Vec<String> = crawl_sources(&sources, &rsync_base, &CrawlOptions::default())
+            .unwrap()
+            .into_iter()
+            .map(|f| f.uri.to_string())
+            .collect();
+
+        assert_eq!(
+            vec![
+                "rsync://localhost/repo/a.cer",
+                "rsync://localhost/repo/sub/b.cer",
+            ],
+            found
+        );
+    }
+
+    #[test]
+    fn retain_disk_with_stateful_predicate() {
+        let work_dir = PathBuf::from("./test-work/retain_disk_with_stateful_predicate/");
+        let _ = fs::remove_dir_all(&work_dir);
+        for dir in &["a", "b", "c"] {
+            fs::create_dir_all(work_dir.join(dir)).unwrap();
+        }
+        save(b"file", &work_dir.join("file.xml")).unwrap();
+
+        let mut kept = 0;
+        let removed = retain_disk(&work_dir, |name| {
+            let keep = name != "b";
+            if keep {
+                kept += 1;
+            }
+            keep
+        })
+        .unwrap();
+
+        assert_eq!(2, kept);
+        assert_eq!(vec![work_dir.join("b")], removed);
+        assert!(work_dir.join("file.xml").is_file());
+    }
+
+    #[test]
+    fn walk_disk_in_name_order() {
+        let base = PathBuf::from("./test-work/walk_disk_in_name_order/");
+        let _ = fs::remove_dir_all(&base);
+        for name in &[
+            "b.cer",
+            "a/y.roa",
+            "a.cer",
+            "c/z/1.crl",
+            "a/x.roa",
+            "c/0.mft",
+        ] {
+            save(name.as_bytes(), &base.join(name)).unwrap();
+        }
+
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        let crawl = || -> Vec<String> {
+            let options = CrawlOptions::default();
+            walk_disk(&base, &rsync_base, &options, &mut HashSet::new())
+                .unwrap()
+                .into_iter()
+                .map(|(uri, _)| uri.to_string())
+                .collect()
+        };
+
+        let first = crawl();
+        assert_eq!(first, crawl());
+        assert_eq!(
+            vec![
+                "rsync://localhost/repo/a/x.roa",
+                "rsync://localhost/repo/a/y.roa",
+                "rsync://localhost/repo/a.cer",
+                "rsync://localhost/repo/b.cer",
+                "rsync://localhost/repo/c/0.mft",
+                "rsync://localhost/repo/c/z/1.crl",
+            ],
+            first
+        );
+    }
+
+    /// The depth first walk by name that 'walk_disk' replaced, as a
+    /// reference for its output.
+    fn walk_recursively(base: &Path, dir: &Path, rsync_base: &RsyncUri, res: &mut Vec<String>) {
+        let mut entries: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap()).collect();
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            } else if path.is_dir() {
+                walk_recursively(base, &path, rsync_base, res);
+            } else {
+                let options = CrawlOptions::default();
+                let uri = derive_uri(base, &path, rsync_base, &options).unwrap();
+                res.push(uri.to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn iterative_walk_equals_recursive_walk() {
+        let rsync_base = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+        for dir in &[
+            "./test-resources/",
+            "./test-resources/source-1/",
+            "./test-resources/source-2/",
+            "./test-resources/source-3/",
+        ] {
+            let base = PathBuf::from(dir);
+            let mut expected = vec![];
+            walk_recursively(&base, &base, &rsync_base, &mut expected);
+
+            let options = CrawlOptions::default();
+            let found: Vec<_> = walk_disk(&base, &rsync_base, &options, &mut HashSet::new())
+                .unwrap()
+                .into_iter()
+                .map(|(uri, _)| uri.to_string())
+                .collect();
+
+            assert!(!found.is_empty());
+            assert_eq!(expected, found);
+        }
+    }
 }
@@ -0,0 +1,62 @@
+//! Fixtures shared by the tests of several modules.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use rrdp::{RepoState, SaveOptions, SaveSummary, Snapshot};
+use sync::{self, CurrentFile, HttpsUri, RsyncUri};
+
+/// Saves a repository for a new session in the target dir, with a serial for
+/// each of the given sets of files, in order. Anything in the target dir is
+/// removed first. Returns the summary of saving the last serial.
+pub fn create_repo(
+    target: &Path,
+    https: &HttpsUri,
+    serials: Vec<Vec<CurrentFile>>,
+    options: &SaveOptions,
+) -> SaveSummary {
+    let _ = fs::remove_dir_all(target);
+    let session = Uuid::new_v4();
+
+    let mut summary = None;
+    for (serial, files) in (1..).zip(serials) {
+        let snapshot = Snapshot::new(session, serial, files);
+        let state = match RepoState::reconstitute(https.clone(), target.to_path_buf()) {
+            Ok(mut state) => {
+                state.apply(snapshot).unwrap();
+                state
+            }
+            Err(_) => RepoState::new(snapshot, https.clone(), target.to_path_buf()),
+        };
+        summary = Some(state.save(options).unwrap());
+    }
+    summary.expect("no serials to save")
+}
+
+/// Returns the files for serials 1 to the last, where each serial adds a
+/// file named after it, i.e. serial 2 has '1.cer' and '2.cer'.
+pub fn numbered_serials(last: u64) -> Vec<Vec<CurrentFile>> {
+    (1..=last)
+        .map(|serial| {
+            (1..=serial)
+                .map(|n| {
+                    let uri =
+                        RsyncUri::parse(&format!("rsync://localhost/repo/{}.cer", n)).unwrap();
+                    CurrentFile::new(uri, n.to_string().as_bytes())
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Returns the files for serial 1 and 2, the content of the 'source-1' and
+/// 'source-2' test resources.
+pub fn source_serials() -> Vec<Vec<CurrentFile>> {
+    let rsync = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+    ["./test-resources/source-1/", "./test-resources/source-2/"]
+        .iter()
+        .map(|dir| sync::crawl_disk(&PathBuf::from(dir), &rsync).unwrap())
+        .collect()
+}
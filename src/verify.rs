@@ -0,0 +1,214 @@
+//! Verifies a remote repository, i.e. fetches its notification file and
+//! checks that the snapshot and deltas it refers to match their hashes.
+
+use std::io::Read;
+use std::path::PathBuf;
+use std::{error, fmt};
+
+use bytes::Bytes;
+
+use rrdp::{self, RepoState};
+use sync::HttpsUri;
+
+//------------ VerifyOptions -------------------------------------------------
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerifyOptions {
+    /// The base uri of the repository, where notification.xml is found.
+    pub https: HttpsUri,
+}
+
+/// Fetches the notification file under the base uri, and the snapshot and
+/// deltas it refers to, and returns the state of the remote repository if
+/// they are all consistent.
+pub fn verify(options: &VerifyOptions) -> Result<RepoState, Error> {
+    verify_with(&options.https, fetch)
+}
+
+/// Verifies the repository under the base uri, using the given closure to
+/// fetch files. The returned state has no base directory, it is not meant
+/// to be saved.
+pub fn verify_with<F>(https: &HttpsUri, mut fetch: F) -> Result<RepoState, Error>
+where
+    F: FnMut(&HttpsUri) -> Result<Bytes, Error>,
+{
+    RepoState::load(https.clone(), PathBuf::new(), false, |_, uri| fetch(uri))
+}
+
+fn fetch(uri: &HttpsUri) -> Result<Bytes, Error> {
+    let response = ureq::get(&uri.to_string()).call().map_err(|e| match e {
+        ureq::Error::Status(status, _) => Error::fetch(uri, format!("HTTP status {}", status)),
+        ureq::Error::Transport(e) => match error::Error::source(&e) {
+            Some(source) => Error::fetch(uri, format!("{}: {}", e.kind(), source)),
+            None => Error::fetch(uri, e.kind()),
+        },
+    })?;
+    let mut body = vec![];
+    response
+        .into_reader()
+        .read_to_end(&mut body)
+        .map_err(|e| Error::fetch(uri, e))?;
+    Ok(Bytes::from(body))
+}
+
+//------------ Error ---------------------------------------------------------
+
+#[derive(Debug, Display)]
+pub enum Error {
+    #[display(fmt = "Cannot fetch {}: {}", _0, _1)]
+    Fetch(HttpsUri, String),
+
+    #[display(fmt = "{}", _0)]
+    Rrdp(rrdp::Error),
+}
+
+impl Error {
+    fn fetch(uri: &HttpsUri, e: impl fmt::Display) -> Self {
+        Error::Fetch(uri.clone(), e.to_string())
+    }
+}
+
+impl From<rrdp::Error> for Error {
+    fn from(e: rrdp::Error) -> Self {
+        Error::Rrdp(e)
+    }
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rrdp::SaveOptions;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::path::Path;
+    use std::thread;
+    use sync;
+    use test_util::{self, source_serials};
+
+    const BASE_URI: &str = "https://localhost/rrdp/";
+
+    /// Creates a repository with a snapshot for serial 2, and a delta.
+    fn create_repo(work_dir: &str) -> RepoState {
+        let https = HttpsUri::base_uri(BASE_URI).unwrap();
+        test_util::create_repo(
+            Path::new(work_dir),
+            &https,
+            source_serials(),
+            &SaveOptions::default(),
+        );
+        RepoState::reconstitute(https, work_dir.into()).unwrap()
+    }
+
+    /// Serves the files in the work dir as if they were under the base uri.
+    fn serve(work_dir: &str) -> impl FnMut(&HttpsUri) -> Result<Bytes, Error> + '_ {
+        move |uri| {
+            let rel = HttpsUri::from(BASE_URI)
+                .relative_to(uri.to_string())
+                .ok_or_else(|| Error::fetch(uri, "not found"))?;
+            sync::read(&PathBuf::from(work_dir).join(rel)).map_err(|e| Error::fetch(uri, e))
+        }
+    }
+
+    /// Serves the files in the work dir over HTTP on a local port, and
+    /// returns the address. Each connection is closed after one request.
+    fn listen(work_dir: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut header = String::new();
+                    if reader.read_line(&mut header).unwrap() == 0 || header.trim().is_empty() {
+                        break;
+                    }
+                }
+
+                let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+                let (status, body) = match fs::read(PathBuf::from(work_dir).join(&path[1..])) {
+                    Ok(body) => ("200 OK", body),
+                    Err(_) => ("404 Not Found", vec![]),
+                };
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                let mut stream = &stream;
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn verify_known_good_repo() {
+        const WORK_DIR: &str = "./test-work/verify_known_good_repo/";
+        let local = create_repo(WORK_DIR);
+
+        let remote = verify_with(&HttpsUri::from(BASE_URI), serve(WORK_DIR)).unwrap();
+        assert_eq!(local.session(), remote.session());
+        assert_eq!(2, remote.serial());
+        assert_eq!(local.snapshot(), remote.snapshot());
+        assert_eq!(1, remote.deltas().len());
+    }
+
+    #[test]
+    fn reject_tampered_repo() {
+        const WORK_DIR: &str = "./test-work/reject_tampered_repo/";
+        let local = create_repo(WORK_DIR);
+
+        // Point the notification at the snapshot of the previous serial.
+        let notification_path = PathBuf::from(WORK_DIR).join("notification.xml");
+        let notification = fs::read_to_string(&notification_path).unwrap();
+        let tampered = notification.replace(
+            &format!("{}/2/snapshot.xml", local.session()),
+            &format!("{}/1/snapshot.xml", local.session()),
+        );
+        assert_ne!(notification, tampered);
+        fs::write(&notification_path, tampered).unwrap();
+
+        match verify_with(&HttpsUri::from(BASE_URI), serve(WORK_DIR)) {
+            Err(Error::Rrdp(rrdp::Error::SnapshotHashMismatch { uri, .. })) => {
+                assert!(uri.to_string().ends_with("/1/snapshot.xml"))
+            }
+            res => panic!("Expected hash mismatch, got: {:?}", res.map(|s| s.serial())),
+        }
+    }
+
+    #[test]
+    fn verify_over_http() {
+        const WORK_DIR: &str = "./test-work/verify_over_http/";
+        let local = create_repo(WORK_DIR);
+        let base = format!("http://{}/", listen(WORK_DIR));
+
+        // The repository refers to https uris, fetch those from the local
+        // server instead.
+        let over_http = |uri: &HttpsUri| {
+            let rel = HttpsUri::from(BASE_URI)
+                .relative_to(uri.to_string())
+                .unwrap();
+            fetch(&HttpsUri::from(format!("{}{}", base, rel).as_str()))
+        };
+        let remote = verify_with(&HttpsUri::from(BASE_URI), over_http).unwrap();
+        assert_eq!(local.session(), remote.session());
+        assert_eq!(local.snapshot(), remote.snapshot());
+        assert_eq!(1, remote.deltas().len());
+
+        let missing = HttpsUri::from(format!("{}missing.xml", base).as_str());
+        match fetch(&missing) {
+            Err(Error::Fetch(uri, msg)) => {
+                assert_eq!(missing, uri);
+                assert_eq!("HTTP status 404", msg);
+            }
+            res => panic!("Expected not found, got: {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Regenerates the repository whenever the sources change.
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::time::Duration;
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+
+use options::Options;
+use rrdp::SaveSummary;
+use run::{run, Error};
+
+/// The time to wait for changes to settle down, before regenerating.
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// Runs once, and then again whenever the sources change. A run only starts
+/// once no changes were seen for the debounce period, so that bulk writes do
+/// not cause many new serials.
+///
+/// The result of every run is passed to the report closure, which is None if
+/// the sources did not actually change. Watching stops when it returns false.
+pub fn watch<F>(options: &Options, debounce: Duration, mut report: F) -> Result<(), Error>
+where
+    F: FnMut(Result<Option<SaveSummary>, Error>) -> bool,
+{
+    let (tx, rx) = mpsc::channel();
+    let mut _watcher = watch_sources(&options.sources, tx.clone())?;
+
+    loop {
+        if !report(run(options)) {
+            return Ok(());
+        }
+
+        // We keep a sender ourselves, so this cannot fail.
+        let _ = rx.recv();
+        while rx.recv_timeout(debounce).is_ok() {}
+
+        // Watch again, in case a source dir was replaced by another dir. If
+        // a source is missing for now, keep the current watcher, which still
+        // notices through the parent dir when the source is back.
+        match watch_sources(&options.sources, tx.clone()) {
+            Ok(watcher) => _watcher = watcher,
+            Err(e) => warn!("Keeping the current watch on the sources: {}", e),
+        }
+    }
+}
+
+/// Watches the sources, as well as their parent dirs, so that it is noticed
+/// when a source is replaced, e.g. by renaming another dir to it.
+fn watch_sources(sources: &[PathBuf], tx: Sender<()>) -> Result<RecommendedWatcher, Error> {
+    let cwd = env::current_dir()?;
+    let sources: Vec<PathBuf> = sources.iter().map(|s| cwd.join(s)).collect();
+
+    let paths = sources.clone();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+        if let Ok(event) = res {
+            if event
+                .paths
+                .iter()
+                .any(|p| paths.iter().any(|s| p.starts_with(s)))
+            {
+                let _ = tx.send(());
+            }
+        }
+    })
+    .map_err(watch_error)?;
+
+    for source in &sources {
+        let parent = source.parent().unwrap_or_else(|| Path::new("/"));
+        watcher
+            .watch(parent, RecursiveMode::NonRecursive)
+            .map_err(watch_error)?;
+        watcher
+            .watch(source, RecursiveMode::Recursive)
+            .map_err(watch_error)?;
+    }
+
+    Ok(watcher)
+}
+
+fn watch_error(e: notify::Error) -> Error {
+    Error::Watch(e.to_string())
+}
+
+//------------ Tests ---------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::mpsc::RecvTimeoutError;
+    use std::thread;
+    use sync::{self, HttpsUri, RsyncUri};
+
+    #[test]
+    fn regenerate_on_change() {
+        let work_dir = PathBuf::from("./test-work/regenerate_on_change/");
+        let source = work_dir.join("source");
+        let target = work_dir.join("target");
+        let _ = fs::remove_dir_all(&work_dir);
+        sync::save(b"one", &source.join("one.cer")).unwrap();
+        fs::create_dir_all(&target).unwrap();
+
+        let options = Options::new(
+            vec![source.clone()],
+            target,
+            RsyncUri::base_uri("rsync://localhost/repo/").unwrap(),
+            HttpsUri::base_uri("https://localhost/rrdp/").unwrap(),
+            false,
+            25,
+        );
+
+        let (tx, rx) = mpsc::channel();
+        let watcher = thread::spawn(move || {
+            watch(&options, Duration::from_millis(200), |res| {
+                match res.unwrap() {
+                    Some(summary) => {
+                        tx.send(summary.serial()).unwrap();
+                        summary.serial() < 2
+                    }
+                    None => true,
+                }
+            })
+        });
+
+        let timeout = Duration::from_secs(10);
+        assert_eq!(Ok(1), rx.recv_timeout(timeout));
+
+        sync::save(b"two", &source.join("two.cer")).unwrap();
+        assert_eq!(Ok(2), rx.recv_timeout(timeout));
+
+        watcher.join().unwrap().unwrap();
+        assert_eq!(
+            Err(RecvTimeoutError::Disconnected),
+            rx.recv_timeout(timeout)
+        );
+    }
+
+    #[test]
+    fn keep_watching_missing_source() {
+        let work_dir = PathBuf::from("./test-work/keep_watching_missing_source/");
+        let source = work_dir.join("source");
+        let moved = work_dir.join("moved");
+        let _ = fs::remove_dir_all(&work_dir);
+        sync::save(b"one", &source.join("one.cer")).unwrap();
+
+        let options = Options::new(
+            vec![source.clone()],
+            work_dir.join("target"),
+            RsyncUri::base_uri("rsync://localhost/repo/").unwrap(),
+            HttpsUri::base_uri("https://localhost/rrdp/").unwrap(),
+            false,
+            25,
+        );
+
+        // A failed run is reported as serial 0.
+        let (tx, rx) = mpsc::channel();
+        let watcher = thread::spawn(move || {
+            watch(&options, Duration::from_millis(200), |res| match res {
+                Ok(Some(summary)) => {
+                    tx.send(summary.serial()).unwrap();
+                    summary.serial() < 2
+                }
+                Ok(None) => true,
+                Err(_) => {
+                    tx.send(0).unwrap();
+                    true
+                }
+            })
+        });
+
+        let timeout = Duration::from_secs(10);
+        assert_eq!(Ok(1), rx.recv_timeout(timeout));
+
+        fs::rename(&source, &moved).unwrap();
+        assert_eq!(Ok(0), rx.recv_timeout(timeout));
+
+        sync::save(b"two", &moved.join("two.cer")).unwrap();
+        fs::rename(&moved, &source).unwrap();
+        assert_eq!(Ok(2), rx.recv_timeout(timeout));
+
+        watcher.join().unwrap().unwrap();
+    }
+}
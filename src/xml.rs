@@ -19,7 +19,13 @@ use xmlrs::{EmitterConfig, EventReader, EventWriter, ParserConfig};
 /// This type only exposes things we need for the RPKI XML structures.
 pub struct XmlReader<R: io::Read> {
     /// The underlying xml-rs reader
-    reader: EventReader<R>,
+    reader: EventReader<LimitedRead<R>>,
+
+    /// The limits enforced while reading
+    limits: XmlLimits,
+
+    /// The current depth of nested elements
+    depth: usize,
 
     /// Placeholder for an event so that 'peak' can be supported, as
     /// well as temporarily caching a close event in case a list of
@@ -36,10 +42,32 @@ impl<R: io::Read> XmlReader<R> {
     ///
     /// Will take cached event if there is one
     fn next(&mut self) -> Result<XmlEvent, XmlReaderErr> {
-        match self.cached_event.take() {
-            Some(e) => Ok(e),
-            None => Ok(self.reader.next()?),
+        if let Some(e) = self.cached_event.take() {
+            return Ok(e);
         }
+
+        let e = self.reader.next().map_err(|e| {
+            match (self.limits.max_bytes, self.reader.source().exceeded) {
+                (Some(max), true) => XmlReaderErr::TooLarge(max),
+                _ => XmlReaderErr::from(e),
+            }
+        })?;
+
+        // Cached events were already counted when they were first read.
+        // The depth saturates, so that no input can make it underflow.
+        match e {
+            XmlEvent::StartElement { .. } => {
+                self.depth += 1;
+                if let Some(max) = self.limits.max_depth {
+                    if self.depth > max {
+                        return Err(XmlReaderErr::TooDeep(max));
+                    }
+                }
+            }
+            XmlEvent::EndElement { .. } => self.depth = self.depth.saturating_sub(1),
+            _ => {}
+        }
+        Ok(e)
     }
 
     /// Puts an XmlEvent back so that it can be retrieved by 'next'
@@ -115,7 +143,20 @@ impl<R: io::Read> XmlReader<R> {
     /// This method checks that the document starts, then passes a reader
     /// instance to the provided closure, and will return the result from
     /// that after checking that the XML document is fully processed.
+    ///
+    /// The default limits are enforced, see XmlLimits. A leading UTF-8 BOM
+    /// and an XML declaration, with any of its optional attributes, are
+    /// accepted by xml-rs.
     pub fn decode<F, T, E>(source: R, op: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut Self) -> Result<T, E>,
+        E: From<XmlReaderErr>,
+    {
+        Self::decode_with(source, XmlLimits::default(), op)
+    }
+
+    /// Decodes an XML structure, enforcing the given limits.
+    pub fn decode_with<F, T, E>(source: R, limits: XmlLimits, op: F) -> Result<T, E>
     where
         F: FnOnce(&mut Self) -> Result<T, E>,
         E: From<XmlReaderErr>,
@@ -124,8 +165,16 @@ impl<R: io::Read> XmlReader<R> {
         config.trim_whitespace = true;
         config.ignore_comments = true;
 
+        let source = LimitedRead {
+            inner: source,
+            remaining: limits.max_bytes,
+            exceeded: false,
+        };
+
         let mut xml = XmlReader {
             reader: config.create_reader(source),
+            limits,
+            depth: 0,
             cached_event: None,
             next_start_name: None,
         };
@@ -207,7 +256,8 @@ impl<R: io::Read> XmlReader<R> {
         }
     }
 
-    /// Takes characters
+    /// Takes characters. Empty or whitespace only content is not reported
+    /// as characters by the parser, so it results in an error.
     pub fn take_chars(&mut self) -> Result<String, XmlReaderErr> {
         match self.next() {
             Ok(reader::XmlEvent::Characters(chars)) => Ok(chars),
@@ -244,6 +294,26 @@ impl<R: io::Read> XmlReader<R> {
         Ok(())
     }
 
+    /// Skips all content of the current element, including any nested
+    /// elements, so that unknown elements can be ignored.
+    pub fn skip_content(&mut self) -> Result<(), XmlReaderErr> {
+        let mut depth = 0;
+        loop {
+            match self.next()? {
+                XmlEvent::StartElement { .. } => depth += 1,
+                XmlEvent::EndElement { name } => {
+                    if depth == 0 {
+                        self.cache(XmlEvent::EndElement { name });
+                        return Ok(());
+                    }
+                    depth -= 1;
+                }
+                XmlEvent::EndDocument => return Err(XmlReaderErr::ExpectedEnd),
+                _ => {}
+            }
+        }
+    }
+
     /// Returns the name of the next start element or None if the next
     /// element is not a start element. Also ensures that the next element
     /// is kept in the cache for normal subsequent processing.
@@ -278,6 +348,60 @@ impl XmlReader<fs::File> {
     }
 }
 
+//------------ XmlLimits -----------------------------------------------------
+
+/// The default maximum size of an XML document, 2 GiB.
+pub const DEFAULT_MAX_BYTES: u64 = 1 << 31;
+
+/// The default maximum depth of nested elements. RRDP files only need a
+/// depth of two, but unknown extensions may nest a bit deeper.
+pub const DEFAULT_MAX_DEPTH: usize = 32;
+
+/// Limits enforced while parsing XML, so that corrupt or malicious files
+/// cannot exhaust resources.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct XmlLimits {
+    /// The maximum number of bytes read from the source, if any.
+    pub max_bytes: Option<u64>,
+
+    /// The maximum depth of nested elements, if any.
+    pub max_depth: Option<usize>,
+}
+
+impl Default for XmlLimits {
+    fn default() -> Self {
+        XmlLimits {
+            max_bytes: Some(DEFAULT_MAX_BYTES),
+            max_depth: Some(DEFAULT_MAX_DEPTH),
+        }
+    }
+}
+
+/// Passes reads on to the inner reader, until the maximum number of bytes
+/// has been read. Reading beyond that is an error.
+struct LimitedRead<R> {
+    inner: R,
+    remaining: Option<u64>,
+    exceeded: bool,
+}
+
+impl<R: io::Read> io::Read for LimitedRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(remaining) = self.remaining {
+            if read as u64 > remaining {
+                self.exceeded = true;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "XML document too large",
+                ));
+            }
+            self.remaining = Some(remaining - read as u64);
+        }
+        Ok(read)
+    }
+}
+
 //------------ XmlReaderErr --------------------------------------------------
 
 #[derive(Debug, Display)]
@@ -311,6 +435,12 @@ pub enum XmlReaderErr {
 
     #[display(fmt = "Base64 decoding issue: {}", _0)]
     Base64Error(DecodeError),
+
+    #[display(fmt = "XML document larger than {} bytes", _0)]
+    TooLarge(u64),
+
+    #[display(fmt = "XML elements nested deeper than {}", _0)]
+    TooDeep(usize),
 }
 
 impl From<io::Error> for XmlReaderErr {
@@ -426,8 +556,14 @@ pub struct Tag {
 pub struct XmlWriter<W> {
     /// The underlying xml-rs writer
     writer: EventWriter<W>,
+
+    /// Whether long text, such as base64, is wrapped over multiple lines
+    pretty: bool,
 }
 
+/// The width of lines of wrapped text in pretty mode.
+const WRAP_WIDTH: usize = 64;
+
 /// Generate the XML.
 impl<W: io::Write> XmlWriter<W> {
     fn unwrap_emitter_error<T>(r: Result<T, writer::Error>) -> Result<T, io::Error> {
@@ -448,7 +584,8 @@ impl<W: io::Write> XmlWriter<W> {
         }
     }
 
-    /// Adds an element
+    /// Adds an element. Attribute values are escaped by xml-rs, e.g. '&'
+    /// becomes '&amp;', and are unescaped again by the XmlReader.
     pub fn put_element<F>(
         &mut self,
         name: &str,
@@ -479,6 +616,20 @@ impl<W: io::Write> XmlWriter<W> {
         Ok(())
     }
 
+    /// Puts text that may be wrapped over multiple lines in pretty mode, such
+    /// as base64. The text is expected to be ASCII without whitespace. The
+    /// lines are written one by one, rather than copied into a new string.
+    pub fn put_wrapped_text(&mut self, text: &str) -> Result<(), io::Error> {
+        if !self.pretty || text.len() <= WRAP_WIDTH {
+            return self.put_text(text);
+        }
+        for line in text.as_bytes().chunks(WRAP_WIDTH) {
+            self.put_text("\n")?;
+            self.put_text(&String::from_utf8_lossy(line))?;
+        }
+        self.put_text("\n")
+    }
+
     /// Converts bytes to base64 encoded Characters as the content, using the
     /// Standard character set, without padding.
     pub fn put_base64_std(&mut self, bytes: &Bytes) -> Result<(), io::Error> {
@@ -504,7 +655,7 @@ impl<W: io::Write> XmlWriter<W> {
     /// This method is private because one should use the pub encode_vec
     /// method, and in future others like it, to set up the writer for a
     /// specific type (Vec<u8>, File, etc.).
-    fn encode<F>(w: W, op: F) -> Result<(), io::Error>
+    fn encode<F>(w: W, pretty: bool, op: F) -> Result<(), io::Error>
     where
         F: FnOnce(&mut Self) -> Result<(), io::Error>,
     {
@@ -514,7 +665,7 @@ impl<W: io::Write> XmlWriter<W> {
             .perform_indent(true)
             .create_writer(w);
 
-        let mut x = XmlWriter { writer };
+        let mut x = XmlWriter { writer, pretty };
 
         op(&mut x)
     }
@@ -523,11 +674,21 @@ impl<W: io::Write> XmlWriter<W> {
 impl XmlWriter<()> {
     /// Call this to encode XML into a Vec<u8>
     pub fn encode_vec<F>(op: F) -> Vec<u8>
+    where
+        F: FnOnce(&mut XmlWriter<&mut Vec<u8>>) -> Result<(), io::Error>,
+    {
+        Self::encode_vec_with(false, op)
+    }
+
+    /// Call this to encode XML into a Vec<u8>, optionally wrapping long text
+    /// over multiple lines. Note that this changes the resulting bytes, and
+    /// therefore the hashes of the XML.
+    pub fn encode_vec_with<F>(pretty: bool, op: F) -> Vec<u8>
     where
         F: FnOnce(&mut XmlWriter<&mut Vec<u8>>) -> Result<(), io::Error>,
     {
         let mut b = Vec::new();
-        XmlWriter::encode(&mut b, op).unwrap(); // IO error impossible for vec
+        XmlWriter::encode(&mut b, pretty, op).unwrap(); // IO error impossible for vec
         b
     }
 
@@ -535,7 +696,26 @@ impl XmlWriter<()> {
     where
         F: FnOnce(&mut XmlWriter<&mut File>) -> Result<(), io::Error>,
     {
-        XmlWriter::encode(file, op)
+        XmlWriter::encode_to(file, op)
+    }
+
+    /// Call this to stream XML to any writer, e.g. a buffered file, without
+    /// holding the whole document in memory.
+    pub fn encode_to<W, F>(w: W, op: F) -> Result<(), io::Error>
+    where
+        W: io::Write,
+        F: FnOnce(&mut XmlWriter<W>) -> Result<(), io::Error>,
+    {
+        Self::encode_to_with(w, false, op)
+    }
+
+    /// Like encode_to, optionally wrapping long text over multiple lines.
+    pub fn encode_to_with<W, F>(w: W, pretty: bool, op: F) -> Result<(), io::Error>
+    where
+        W: io::Write,
+        F: FnOnce(&mut XmlWriter<W>) -> Result<(), io::Error>,
+    {
+        XmlWriter::encode(w, pretty, op)
     }
 }
 
@@ -560,4 +740,54 @@ mod tests {
             "<a xmlns=\"http://ns/\" c=\"d\">\n  <b>WA==</b>\n</a>"
         );
     }
+
+    fn skip_document(xml: &[u8], limits: XmlLimits) -> Result<(), XmlReaderErr> {
+        XmlReader::decode_with(xml, limits, |r| {
+            r.take_named_element("a", |_, r| r.skip_content())
+        })
+    }
+
+    #[test]
+    fn reject_oversized_xml() {
+        let xml = format!("<a>{}</a>", "<b>text</b>".repeat(100));
+        let limits = |max_bytes| XmlLimits {
+            max_bytes,
+            max_depth: None,
+        };
+
+        assert!(skip_document(xml.as_bytes(), limits(None)).is_ok());
+        assert!(skip_document(xml.as_bytes(), limits(Some(xml.len() as u64))).is_ok());
+        match skip_document(xml.as_bytes(), limits(Some(100))) {
+            Err(XmlReaderErr::TooLarge(100)) => {}
+            res => panic!("Expected too large, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn reject_deeply_nested_xml() {
+        let nested = |depth| format!("{}{}", "<a>".repeat(depth), "</a>".repeat(depth));
+
+        let limits = XmlLimits::default();
+        assert!(skip_document(nested(DEFAULT_MAX_DEPTH).as_bytes(), limits).is_ok());
+        match skip_document(nested(100_000).as_bytes(), limits) {
+            Err(XmlReaderErr::TooDeep(DEFAULT_MAX_DEPTH)) => {}
+            res => panic!("Expected too deep, got: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn should_wrap_text_when_pretty() {
+        let text = "A".repeat(100);
+        let write = |pretty| {
+            XmlWriter::encode_vec_with(pretty, |w| {
+                w.put_element("a", None, |w| w.put_wrapped_text(&text))
+            })
+        };
+
+        assert_eq!(format!("<a>{}</a>", text).into_bytes(), write(false));
+        assert_eq!(
+            format!("<a>\n{}\n{}\n</a>", &text[..64], &text[64..]).into_bytes(),
+            write(true)
+        );
+    }
 }
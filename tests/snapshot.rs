@@ -0,0 +1,34 @@
+extern crate bytes;
+extern crate rrdpit;
+
+use std::path::Path;
+use std::process::Command;
+
+use bytes::Bytes;
+use rrdpit::rrdp::Snapshot;
+use rrdpit::sync::{crawl_disk, RsyncUri};
+
+#[test]
+fn write_snapshot_to_stdout() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rrdpit"))
+        .args([
+            "snapshot",
+            "--source",
+            "./test-resources/source-1/",
+            "--rsync",
+            "rsync://localhost/repo/",
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let snapshot = Snapshot::from_xml(Bytes::from(output.stdout.clone())).unwrap();
+    assert_eq!(1, snapshot.serial());
+
+    let rsync = RsyncUri::base_uri("rsync://localhost/repo/").unwrap();
+    let files = crawl_disk(Path::new("./test-resources/source-1/"), &rsync).unwrap();
+    assert_eq!(files.as_slice(), snapshot.current_objects());
+
+    // The same bytes as the snapshot file written to disk.
+    assert_eq!(snapshot.write_xml().as_ref(), output.stdout.as_slice());
+}